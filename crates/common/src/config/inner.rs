@@ -6,10 +6,10 @@
 
 use super::server::tls::{build_self_signed_cert, parse_certificates};
 use crate::{
-    CacheSwap, Caches, Data, DavResource, DavResources, MailboxCache, MessageStoreCache,
-    MessageUidCache, TlsConnectors,
+    CacheSwap, CachedSieve, Caches, Data, DavResource, DavResources, MailboxCache,
+    MessageStoreCache, MessageUidCache, TlsConnectors,
     auth::{AccessToken, roles::RolePermissions},
-    config::smtp::resolver::{Policy, Tlsa},
+    config::smtp::resolver::{Policy, Srv, Tlsa},
     listener::blocked::BlockedIps,
     manager::webadmin::WebAdminManager,
 };
@@ -24,6 +24,7 @@ use std::{
     sync::Arc,
 };
 use utils::{
+    BlobHash,
     cache::{Cache, CacheWithTtl},
     config::Config,
     snowflake::SnowflakeIdGenerator,
@@ -64,6 +65,9 @@ impl Data {
             queue_id_gen: id_generator.clone(),
             span_id_gen: id_generator,
             queue_status: true.into(),
+            queue_draining: false.into(),
+            queue_domain_limiters: Default::default(),
+            remote_ip_limiters: Default::default(),
             webadmin: config
                 .value("webadmin.path")
                 .map(|path| WebAdminManager::new(path.into()))
@@ -138,6 +142,13 @@ impl Caches {
                 (std::mem::size_of::<DavResources>() + (500 * std::mem::size_of::<DavResource>()))
                     as u64,
             ),
+            sieve_scripts: Cache::from_config(
+                config,
+                "sieve",
+                MB_5,
+                (std::mem::size_of::<BlobHash>() + std::mem::size_of::<CachedSieve>() + 4096)
+                    as u64,
+            ),
             bayes: CacheWithTtl::from_config(
                 config,
                 "bayes",
@@ -180,6 +191,18 @@ impl Caches {
                 MB_1,
                 (std::mem::size_of::<Tlsa>() + 255) as u64,
             ),
+            dns_srv: CacheWithTtl::from_config(
+                config,
+                "dns.srv",
+                MB_1,
+                (std::mem::size_of::<Srv>() + 255) as u64,
+            ),
+            dns_soa: CacheWithTtl::from_config(
+                config,
+                "dns.soa",
+                MB_1,
+                (std::mem::size_of::<u64>() + 255) as u64,
+            ),
             dbs_mta_sts: CacheWithTtl::from_config(
                 config,
                 "dns.mta-sts",
@@ -230,6 +253,9 @@ impl Default for Data {
             queue_id_gen: Default::default(),
             span_id_gen: Default::default(),
             queue_status: true.into(),
+            queue_draining: false.into(),
+            queue_domain_limiters: Default::default(),
+            remote_ip_limiters: Default::default(),
             webadmin: Default::default(),
             logos: Default::default(),
             smtp_connectors: Default::default(),
@@ -246,3 +272,9 @@ impl Default for TlsConnectors {
         }
     }
 }
+
+impl std::fmt::Debug for TlsConnectors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsConnectors").finish_non_exhaustive()
+    }
+}
@@ -11,7 +11,7 @@ use mail_auth::{
     common::crypto::{Algorithm, Ed25519Key, HashAlgorithm, RsaKey, Sha256, SigningKey},
     dkim::{Canonicalization, Done},
 };
-use mail_parser::decoders::base64::base64_decode;
+use mail_parser::{DateTime, decoders::base64::base64_decode};
 use utils::config::{
     Config,
     utils::{AsKey, ParseValue},
@@ -31,6 +31,7 @@ pub struct MailAuthConfig {
     pub spf: SpfAuthConfig,
     pub dmarc: DmarcAuthConfig,
     pub iprev: IpRevAuthConfig,
+    pub batv: BatvAuthConfig,
     pub signatures: AHashMap<String, Arc<ArcSwap<LazySignature>>>,
 }
 
@@ -45,6 +46,10 @@ pub enum LazySignature {
 pub struct ResolvedSignature {
     pub signer: Arc<DkimSigner>,
     pub sealer: Arc<ArcSealer>,
+    pub domain: String,
+    pub selector: String,
+    pub active_from: Option<u64>,
+    pub active_until: Option<u64>,
 }
 
 #[derive(Clone)]
@@ -69,6 +74,7 @@ pub struct SpfAuthConfig {
 #[derive(Clone)]
 pub struct DmarcAuthConfig {
     pub verify: IfBlock,
+    pub trusted_forwarder: IfBlock,
 }
 
 #[derive(Clone)]
@@ -76,6 +82,14 @@ pub struct IpRevAuthConfig {
     pub verify: IfBlock,
 }
 
+#[derive(Clone)]
+pub struct BatvAuthConfig {
+    pub sign: IfBlock,
+    pub verify: IfBlock,
+    pub secret: Option<String>,
+    pub max_age: u64,
+}
+
 #[derive(Debug, Clone, Copy, Default)]
 pub enum VerifyStrategy {
     #[default]
@@ -150,6 +164,7 @@ impl Default for MailAuthConfig {
                     #[cfg(feature = "test_mode")]
                     "relaxed",
                 ),
+                trusted_forwarder: IfBlock::new::<()>("auth.dmarc.trusted-forwarder", [], "false"),
             },
             iprev: IpRevAuthConfig {
                 verify: IfBlock::new::<VerifyStrategy>(
@@ -161,6 +176,12 @@ impl Default for MailAuthConfig {
                     "relaxed",
                 ),
             },
+            batv: BatvAuthConfig {
+                sign: IfBlock::new::<()>("auth.batv.sign", [], "false"),
+                verify: IfBlock::new::<()>("auth.batv.verify", [], "false"),
+                secret: None,
+                max_age: 7,
+            },
             signatures: Default::default(),
         }
     }
@@ -192,7 +213,14 @@ impl MailAuthConfig {
                 &conn_vars,
             ),
             (&mut mail_auth.dmarc.verify, "auth.dmarc.verify", &rcpt_vars),
+            (
+                &mut mail_auth.dmarc.trusted_forwarder,
+                "auth.dmarc.trusted-forwarder",
+                &rcpt_vars,
+            ),
             (&mut mail_auth.iprev.verify, "auth.iprev.verify", &conn_vars),
+            (&mut mail_auth.batv.sign, "auth.batv.sign", &rcpt_vars),
+            (&mut mail_auth.batv.verify, "auth.batv.verify", &rcpt_vars),
         ] {
             if let Some(if_block) = IfBlock::try_parse(config, key, token_map) {
                 *value = if_block;
@@ -201,6 +229,12 @@ impl MailAuthConfig {
         mail_auth.dkim.strict = config
             .property_or_default("auth.dkim.strict", "true")
             .unwrap_or(true);
+        mail_auth.batv.secret = config
+            .value("auth.batv.secret")
+            .map(|secret| secret.to_string());
+        mail_auth.batv.max_age = config
+            .property_or_default::<Duration>("auth.batv.max-age", "7d")
+            .map_or(7, |max_age| max_age.as_secs() / 86400);
 
         // Parse signatures
         let mut signatures: AHashMap<&str, Config> = AHashMap::new();
@@ -236,7 +270,58 @@ impl MailAuthConfig {
     }
 }
 
-pub fn build_signature(config: &mut Config, id: &str) -> Option<(DkimSigner, ArcSealer)> {
+impl ResolvedSignature {
+    pub fn is_active(&self, now: u64) -> bool {
+        self.active_from.is_none_or(|from| now >= from)
+            && self.active_until.is_none_or(|until| now < until)
+    }
+}
+
+pub fn build_signature(config: &mut Config, id: &str) -> Option<ResolvedSignature> {
+    let (signer, sealer) = build_signer_and_sealer(config, id)?;
+    let active_from = parse_timestamp(config, ("signature", id, "valid-from"))?;
+    let active_until = parse_timestamp(config, ("signature", id, "valid-until"))?;
+    if matches!((active_from, active_until), (Some(from), Some(until)) if from >= until) {
+        config.new_build_error(
+            ("signature", id, "valid-until"),
+            "Signing window must end after it starts.",
+        );
+        return None;
+    }
+
+    Some(ResolvedSignature {
+        signer: Arc::new(signer),
+        sealer: Arc::new(sealer),
+        domain: config
+            .value(("signature", id, "domain"))
+            .unwrap_or_default()
+            .to_string(),
+        selector: config
+            .value(("signature", id, "selector"))
+            .unwrap_or_default()
+            .to_string(),
+        active_from,
+        active_until,
+    })
+}
+
+fn parse_timestamp(config: &mut Config, key: impl AsKey) -> Option<Option<u64>> {
+    let key = key.as_key();
+    match config.value(key.as_str()) {
+        Some(value) => {
+            if let Some(dt) = DateTime::parse_rfc3339(value) {
+                Some(Some(dt.to_timestamp() as u64))
+            } else {
+                let err = format!("Invalid RFC 3339 timestamp {value:?}.");
+                config.new_parse_error(key, err);
+                None
+            }
+        }
+        None => Some(None),
+    }
+}
+
+fn build_signer_and_sealer(config: &mut Config, id: &str) -> Option<(DkimSigner, ArcSealer)> {
     match config.property_require::<Algorithm>(("signature", id, "algorithm"))? {
         Algorithm::RsaSha256 => {
             let pk = config
@@ -373,6 +458,25 @@ fn parse_signature<T: SigningKey, U: SigningKey<Hasher = Sha256>>(
         ];
     }
 
+    // Oversigned headers are listed once more than they appear in the message,
+    // which prevents additional instances from being added after signing
+    for header in config
+        .values(("signature", id, "oversign"))
+        .filter_map(|(_, v)| {
+            if !v.is_empty() {
+                v.to_string().into()
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>()
+    {
+        if !headers.iter().any(|h| h.eq_ignore_ascii_case(&header)) {
+            headers.push(header.clone());
+        }
+        headers.push(header);
+    }
+
     let mut signer = mail_auth::dkim::DkimSigner::from_key(key_dkim)
         .domain(&domain)
         .selector(&selector)
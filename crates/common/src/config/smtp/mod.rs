@@ -79,7 +79,7 @@ pub(crate) const SMTP_MAIL_FROM_VARS: &[u32; 12] = &[
     V_ASN,
     V_COUNTRY,
 ];
-pub(crate) const SMTP_RCPT_TO_VARS: &[u32; 17] = &[
+pub(crate) const SMTP_RCPT_TO_VARS: &[u32; 18] = &[
     V_SENDER,
     V_SENDER_DOMAIN,
     V_RECIPIENTS,
@@ -97,6 +97,7 @@ pub(crate) const SMTP_RCPT_TO_VARS: &[u32; 17] = &[
     V_HELO_DOMAIN,
     V_ASN,
     V_COUNTRY,
+    V_DNSBL,
 ];
 pub(crate) const SMTP_QUEUE_HOST_VARS: &[u32; 20] = &[
     V_SENDER,
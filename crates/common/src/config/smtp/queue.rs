@@ -7,20 +7,32 @@
 use self::throttle::parse_queue_rate_limiter;
 use super::*;
 use crate::{
+    TlsConnectors,
     config::server::ServerProtocol,
     expr::{if_block::IfBlock, *},
+    listener::tls::TLS13_VERSION,
 };
 use ahash::AHashMap;
 use mail_auth::IpLookupStrategy;
 use mail_send::Credentials;
+use rustls::{ALL_VERSIONS, ClientConfig, SupportedCipherSuite, crypto::ring::default_provider};
 use std::{
     fmt::Display,
     hash::{Hash, Hasher},
     net::IpAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
     time::Duration,
 };
 use throttle::parse_queue_rate_limiter_key;
-use utils::config::{Config, utils::ParseValue};
+use tokio_rustls::TlsConnector;
+use utils::{
+    config::{Config, utils::ParseValue},
+    rustls_client_config_with,
+    template::{Template, TemplateItem},
+};
 
 #[derive(
     Debug,
@@ -55,6 +67,26 @@ pub struct QueueConfig {
     pub outbound_limiters: QueueRateLimiters,
     pub quota: QueueQuotas,
 
+    // Concurrency limits
+    pub concurrency: IfBlock,
+
+    // Headers added to or removed from outbound messages
+    pub add_headers: IfBlock,
+    pub remove_headers: IfBlock,
+
+    // Sender address masquerading on outbound messages
+    pub rewrite_sender: IfBlock,
+    pub rewrite_headers: IfBlock,
+
+    // Signatures applied again to outbound messages modified at delivery time
+    pub dkim_sign: IfBlock,
+
+    // 8-bit messages sent to hosts without 8BITMIME
+    pub eight_bit_mime: EightBitMime,
+
+    // Message headers referenced by queue expressions
+    pub headers: Vec<String>,
+
     // Strategies
     pub queue_strategy: AHashMap<String, QueueStrategy>,
     pub connection_strategy: AHashMap<String, ConnectionStrategy>,
@@ -75,6 +107,8 @@ pub struct MxConfig {
     pub max_mx: usize,
     pub max_multi_homed: usize,
     pub ip_lookup_strategy: IpLookupStrategy,
+    pub implicit_mx: bool,
+    pub srv_lookup: bool,
 }
 
 #[derive(Clone)]
@@ -82,6 +116,18 @@ pub struct Dsn {
     pub name: IfBlock,
     pub address: IfBlock,
     pub sign: IfBlock,
+    pub template: Option<Template<DsnTemplateVariable>>,
+    pub delay_window: Duration,
+    pub tls_details: bool,
+    pub include_transcript: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DsnTemplateVariable {
+    Rcpt,
+    Host,
+    SmtpResponse,
+    Diagnostic,
 }
 
 #[derive(Clone, Debug)]
@@ -92,9 +138,24 @@ pub struct VirtualQueue {
 #[derive(Clone, Debug)]
 pub struct QueueStrategy {
     pub retry: Vec<u64>,
+    pub retry_jitter: Option<RetryJitter>,
     pub notify: Vec<u64>,
     pub expiry: QueueExpiry,
     pub virtual_queue: QueueName,
+    pub window: Option<DeliveryWindow>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryJitter {
+    Percentage(u64),
+    Fixed(u64),
+}
+
+// Daily delivery hours in seconds since midnight UTC, the end may wrap around midnight
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeliveryWindow {
+    pub start: u64,
+    pub end: u64,
 }
 
 #[derive(
@@ -119,6 +180,9 @@ pub struct TlsStrategy {
     pub mta_sts: RequireOptional,
     pub tls: RequireOptional,
     pub allow_invalid_certs: bool,
+    pub on_handshake_failure: HandshakeFailure,
+    pub downgrade_protection: Option<Duration>,
+    pub connectors: Option<TlsConnectors>,
 
     pub timeout_tls: Duration,
     pub timeout_mta_sts: Duration,
@@ -128,7 +192,12 @@ pub struct TlsStrategy {
 pub struct ConnectionStrategy {
     pub source_ipv4: Vec<IpAndHost>,
     pub source_ipv6: Vec<IpAndHost>,
+    pub next_source_ipv4: RoundRobin,
+    pub next_source_ipv6: RoundRobin,
     pub ehlo_hostname: Option<String>,
+    pub chunk_size: usize,
+    pub happy_eyeballs: Option<Duration>,
+    pub transient_failure: TransientFailure,
 
     pub timeout_connect: Duration,
     pub timeout_greeting: Duration,
@@ -136,8 +205,12 @@ pub struct ConnectionStrategy {
     pub timeout_mail: Duration,
     pub timeout_rcpt: Duration,
     pub timeout_data: Duration,
+    pub timeout_data_end: Duration,
 }
 
+#[derive(Debug, Default)]
+pub struct RoundRobin(AtomicUsize);
+
 #[derive(Clone, Debug)]
 pub struct IpAndHost {
     pub ip: IpAddr,
@@ -177,6 +250,29 @@ pub struct RelayConfig {
     pub tls_allow_invalid_certs: bool,
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TransientFailure {
+    #[default]
+    NextHost,
+    Defer,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EightBitMime {
+    #[default]
+    Convert,
+    Defer,
+    Send,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HandshakeFailure {
+    #[default]
+    Defer,
+    Plaintext,
+    Bounce,
+}
+
 #[derive(Debug, Clone, Copy, Default)]
 pub enum RequireOptional {
     #[default]
@@ -211,10 +307,22 @@ impl Default for QueueConfig {
                     [],
                     "['rsa-' + config_get('report.domain'), 'ed25519-' + config_get('report.domain')]",
                 ),
+                template: None,
+                delay_window: Duration::ZERO,
+                tls_details: false,
+                include_transcript: false,
             },
             inbound_limiters: QueueRateLimiters::default(),
             outbound_limiters: QueueRateLimiters::default(),
             quota: QueueQuotas::default(),
+            concurrency: IfBlock::new::<()>("queue.outbound.concurrency", [], "0"),
+            add_headers: IfBlock::empty("queue.outbound.add-headers"),
+            remove_headers: IfBlock::empty("queue.outbound.remove-headers"),
+            rewrite_sender: IfBlock::empty("queue.outbound.rewrite.sender"),
+            rewrite_headers: IfBlock::new::<()>("queue.outbound.rewrite.headers", [], "['From']"),
+            dkim_sign: IfBlock::empty("queue.outbound.dkim-sign"),
+            eight_bit_mime: EightBitMime::default(),
+            headers: Vec::new(),
             queue_strategy: Default::default(),
             virtual_queues: Default::default(),
             connection_strategy: Default::default(),
@@ -247,12 +355,107 @@ impl QueueConfig {
                 &sender_vars,
             ),
             (&mut queue.dsn.sign, "report.dsn.sign", &sender_vars),
+            (
+                &mut queue.concurrency,
+                "queue.outbound.concurrency",
+                &rcpt_vars,
+            ),
+            (
+                &mut queue.add_headers,
+                "queue.outbound.add-headers",
+                &rcpt_vars,
+            ),
+            (
+                &mut queue.remove_headers,
+                "queue.outbound.remove-headers",
+                &rcpt_vars,
+            ),
+            (
+                &mut queue.rewrite_sender,
+                "queue.outbound.rewrite.sender",
+                &rcpt_vars,
+            ),
+            (
+                &mut queue.rewrite_headers,
+                "queue.outbound.rewrite.headers",
+                &rcpt_vars,
+            ),
+            (&mut queue.dkim_sign, "queue.outbound.dkim-sign", &rcpt_vars),
         ] {
             if let Some(if_block) = IfBlock::try_parse(config, key, token_map) {
                 *value = if_block;
             }
         }
 
+        // Parse DSN template
+        if let Some(template) = config.value("report.dsn.template") {
+            match Template::<DsnTemplateVariable>::parse(template) {
+                Ok(mut template) => {
+                    // DSN text is plain text, do not HTML escape variables
+                    for item in &mut template.items {
+                        if let TemplateItem::Variable { escape, .. } = item {
+                            *escape = false;
+                        }
+                    }
+                    queue.dsn.template = Some(template);
+                }
+                Err(err) => {
+                    config.new_build_error(
+                        "report.dsn.template",
+                        format!("Invalid template: {err}"),
+                    );
+                }
+            }
+        }
+
+        // Delay notifications due within this window are sent in a single DSN
+        queue.dsn.delay_window = config
+            .property::<Duration>("report.dsn.delay-window")
+            .unwrap_or_default();
+        queue.dsn.tls_details = config
+            .property("report.dsn.tls-details")
+            .unwrap_or(false);
+        queue.dsn.include_transcript = config
+            .property("report.dsn.include-transcript")
+            .unwrap_or(false);
+
+        // As per RFC6152 Section 3, 8-bit messages are either downgraded or not relayed
+        // to hosts that do not advertise 8BITMIME
+        queue.eight_bit_mime = config
+            .property::<EightBitMime>("queue.outbound.8bitmime")
+            .unwrap_or_default();
+
+        // Keep a copy of the headers referenced as 'header.<name>' in the queue metadata,
+        // as expressions are also evaluated when the message body is not loaded
+        for if_block in [
+            &queue.gateway,
+            &queue.queue,
+            &queue.connection,
+            &queue.tls,
+            &queue.concurrency,
+            &queue.add_headers,
+            &queue.remove_headers,
+            &queue.rewrite_sender,
+            &queue.rewrite_headers,
+            &queue.dkim_sign,
+        ] {
+            for expr in if_block
+                .if_then
+                .iter()
+                .flat_map(|if_then| [&if_then.expr, &if_then.then])
+                .chain([&if_block.default])
+            {
+                for item in expr.items() {
+                    if let ExpressionItem::Global(name) = item
+                        && let Some(header) = name.strip_prefix("header.")
+                        && !queue.headers.iter().any(|h| h == header)
+                    {
+                        queue.headers.push(header.to_string());
+                    }
+                }
+            }
+        }
+
         // Parse strategies
         queue.virtual_queues = parse_virtual_queues(config);
         queue.queue_strategy = parse_queue_strategies(config, &queue.virtual_queues);
@@ -278,9 +481,11 @@ fn parse_queue_strategies(
         &[
             ".queue-name",
             ".retry",
+            ".retry-jitter",
             ".notify",
             ".expire",
             ".max-attempts",
+            ".window",
         ],
     ) {
         if let Some(strategy) = parse_queue_strategy(config, &key, queues) {
@@ -328,6 +533,7 @@ fn parse_queue_strategy(
 
     Some(QueueStrategy {
         retry,
+        retry_jitter: config.property::<RetryJitter>(("queue.schedule", id, "retry-jitter")),
         notify,
         expiry: match (
             config.property::<Duration>(("queue.schedule", id, "expire")),
@@ -345,6 +551,7 @@ fn parse_queue_strategy(
             (None, None) => QueueExpiry::Duration(60 * 60 * 24 * 3), // Default to 3 days
         },
         virtual_queue,
+        window: config.property::<DeliveryWindow>(("queue.schedule", id, "window")),
     })
 }
 
@@ -420,6 +627,12 @@ fn parse_gateway(config: &mut Config, id: &str) -> Option<GatewayStrategy> {
             ip_lookup_strategy: config
                 .property_require(("queue.gateway", id, "ip-lookup"))
                 .unwrap_or(IpLookupStrategy::Ipv4thenIpv6),
+            implicit_mx: config
+                .property(("queue.gateway", id, "implicit-mx"))
+                .unwrap_or(true),
+            srv_lookup: config
+                .property(("queue.gateway", id, "srv-lookup"))
+                .unwrap_or(false),
         })
         .into(),
         invalid => {
@@ -437,9 +650,13 @@ fn parse_tls_strategies(config: &mut Config) -> AHashMap<String, TlsStrategy> {
         "queue.tls",
         &[
             ".allow-invalid-certs",
+            ".on-handshake-failure",
+            ".downgrade-protection",
             ".dane",
             ".mta-sts",
             ".starttls",
+            ".min-version",
+            ".ciphers",
             ".timeout.tls",
             ".timeout.mta-sts",
         ],
@@ -465,6 +682,16 @@ fn parse_tls(config: &mut Config, id: &str) -> Option<TlsStrategy> {
         allow_invalid_certs: config
             .property_require::<bool>(("queue.tls", id, "allow-invalid-certs"))
             .unwrap_or(false),
+        on_handshake_failure: config
+            .property::<HandshakeFailure>(("queue.tls", id, "on-handshake-failure"))
+            .unwrap_or_default(),
+        downgrade_protection: config
+            .property_or_default::<Option<Duration>>(
+                ("queue.tls", id, "downgrade-protection"),
+                "30d",
+            )
+            .unwrap_or(None),
+        connectors: parse_tls_connectors(config, id),
         timeout_tls: config
             .property_require::<Duration>(("queue.tls", id, "timeout.tls"))
             .unwrap_or(Duration::from_secs(3 * 60)),
@@ -474,6 +701,64 @@ fn parse_tls(config: &mut Config, id: &str) -> Option<TlsStrategy> {
     })
 }
 
+fn parse_tls_connectors(config: &mut Config, id: &str) -> Option<TlsConnectors> {
+    let min_version = config
+        .value(("queue.tls", id, "min-version"))
+        .map(|v| v.to_string());
+    let ciphers = config
+        .properties::<SupportedCipherSuite>(("queue.tls", id, "ciphers"))
+        .into_iter()
+        .map(|(_, cipher)| cipher)
+        .collect::<Vec<_>>();
+    if min_version.is_none() && ciphers.is_empty() {
+        return None;
+    }
+
+    // Parse minimum protocol version
+    let versions = match min_version.as_deref() {
+        None | Some("TLSv1.2" | "0x0303") => ALL_VERSIONS,
+        Some("TLSv1.3" | "0x0304") => TLS13_VERSION,
+        Some(version) => {
+            config.new_parse_error(
+                ("queue.tls", id, "min-version"),
+                format!("Unsupported TLS protocol {version:?}"),
+            );
+            return None;
+        }
+    };
+
+    // Build client provider
+    let mut provider = default_provider();
+    if !ciphers.is_empty() {
+        provider.cipher_suites = ciphers;
+    }
+    let provider = Arc::new(provider);
+    let build_connector = |allow_invalid_certs: bool| {
+        ClientConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(versions)
+            .map(|builder| {
+                TlsConnector::from(Arc::new(rustls_client_config_with(
+                    builder,
+                    allow_invalid_certs,
+                )))
+            })
+    };
+
+    match (build_connector(false), build_connector(true)) {
+        (Ok(pki_verify), Ok(dummy_verify)) => Some(TlsConnectors {
+            pki_verify,
+            dummy_verify,
+        }),
+        (Err(err), _) | (_, Err(err)) => {
+            config.new_build_error(
+                ("queue.tls", id),
+                format!("Failed to build TLS client config: {err}"),
+            );
+            None
+        }
+    }
+}
+
 fn parse_connection_strategies(config: &mut Config) -> AHashMap<String, ConnectionStrategy> {
     let mut entries = AHashMap::new();
     for key in config.sub_keys_with_suffixes(
@@ -485,7 +770,11 @@ fn parse_connection_strategies(config: &mut Config) -> AHashMap<String, Connecti
             ".timeout.mail-from",
             ".timeout.rcpt-to",
             ".timeout.data",
+            ".timeout.data-end",
             ".ehlo-hostname",
+            ".chunk-size",
+            ".happy-eyeballs",
+            ".transient-failure",
         ],
     ) {
         if let Some(strategy) = parse_connection(config, &key) {
@@ -529,7 +818,16 @@ fn parse_connection(config: &mut Config, id: &str) -> Option<ConnectionStrategy>
     Some(ConnectionStrategy {
         source_ipv4,
         source_ipv6,
+        next_source_ipv4: RoundRobin::new(),
+        next_source_ipv6: RoundRobin::new(),
         ehlo_hostname: config.property::<String>(("queue.connection", id, "ehlo-hostname")),
+        chunk_size: config
+            .property::<usize>(("queue.connection", id, "chunk-size"))
+            .unwrap_or_default(),
+        happy_eyeballs: config.property::<Duration>(("queue.connection", id, "happy-eyeballs")),
+        transient_failure: config
+            .property::<TransientFailure>(("queue.connection", id, "transient-failure"))
+            .unwrap_or_default(),
         timeout_connect: config
             .property_require::<Duration>(("queue.connection", id, "timeout.connect"))
             .unwrap_or(Duration::from_secs(5 * 60)),
@@ -548,6 +846,9 @@ fn parse_connection(config: &mut Config, id: &str) -> Option<ConnectionStrategy>
         timeout_data: config
             .property_require::<Duration>(("queue.connection", id, "timeout.data"))
             .unwrap_or(Duration::from_secs(10 * 60)),
+        timeout_data_end: config
+            .property_require::<Duration>(("queue.connection", id, "timeout.data-end"))
+            .unwrap_or(Duration::from_secs(10 * 60)),
     })
 }
 
@@ -743,6 +1044,121 @@ fn parse_queue_quota_item(config: &mut Config, prefix: impl AsKey, id: &str) ->
     }
 }
 
+impl RoundRobin {
+    pub const fn new() -> Self {
+        RoundRobin(AtomicUsize::new(0))
+    }
+
+    pub fn next(&self, len: usize) -> usize {
+        self.0.fetch_add(1, Ordering::Relaxed) % len
+    }
+}
+
+impl Clone for RoundRobin {
+    fn clone(&self) -> Self {
+        RoundRobin(AtomicUsize::new(self.0.load(Ordering::Relaxed)))
+    }
+}
+
+impl RetryJitter {
+    pub fn max_jitter(&self, interval: u64) -> u64 {
+        match self {
+            RetryJitter::Percentage(pct) => interval * pct / 100,
+            RetryJitter::Fixed(secs) => *secs,
+        }
+    }
+}
+
+impl ParseValue for RetryJitter {
+    fn parse_value(value: &str) -> Result<Self, String> {
+        if let Some(pct) = value.strip_suffix('%') {
+            match pct.trim().parse::<u64>() {
+                Ok(pct) if pct <= 100 => Ok(RetryJitter::Percentage(pct)),
+                _ => Err(format!("Invalid retry jitter percentage {:?}.", value)),
+            }
+        } else {
+            Duration::parse_value(value).map(|d| RetryJitter::Fixed(d.as_secs()))
+        }
+    }
+}
+
+impl DeliveryWindow {
+    pub fn contains(&self, timestamp: u64) -> bool {
+        let time = timestamp % 86400;
+        if self.start <= self.end {
+            time >= self.start && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    /// Returns the earliest time at or after the timestamp that falls within the window.
+    pub fn next_open(&self, timestamp: u64) -> u64 {
+        let time = timestamp % 86400;
+        if self.contains(timestamp) {
+            timestamp
+        } else if time < self.start {
+            timestamp - time + self.start
+        } else {
+            timestamp - time + 86400 + self.start
+        }
+    }
+}
+
+impl ParseValue for DeliveryWindow {
+    fn parse_value(value: &str) -> Result<Self, String> {
+        fn parse_time(time: &str) -> Option<u64> {
+            let (hour, minute) = time.trim().split_once(':')?;
+            let hour = hour.parse::<u64>().ok().filter(|hour| *hour <= 24)?;
+            let minute = minute.parse::<u64>().ok().filter(|minute| *minute < 60)?;
+            Some(hour * 3600 + minute * 60).filter(|time| *time <= 86400)
+        }
+
+        value
+            .split_once('-')
+            .and_then(|(start, end)| {
+                Some(DeliveryWindow {
+                    start: parse_time(start)? % 86400,
+                    end: parse_time(end)? % 86400,
+                })
+            })
+            .filter(|window| window.start != window.end)
+            .ok_or_else(|| format!("Invalid delivery window {:?}.", value))
+    }
+}
+
+impl ParseValue for TransientFailure {
+    fn parse_value(value: &str) -> Result<Self, String> {
+        match value {
+            "next-host" | "next-mx" => Ok(TransientFailure::NextHost),
+            "defer" => Ok(TransientFailure::Defer),
+            _ => Err(format!("Invalid transient failure action {:?}.", value,)),
+        }
+    }
+}
+
+impl ParseValue for EightBitMime {
+    fn parse_value(value: &str) -> Result<Self, String> {
+        match value {
+            "convert" | "downgrade" => Ok(EightBitMime::Convert),
+            "defer" => Ok(EightBitMime::Defer),
+            "send" => Ok(EightBitMime::Send),
+            _ => Err(format!("Invalid 8BITMIME action {:?}.", value,)),
+        }
+    }
+}
+
+impl ParseValue for HandshakeFailure {
+    fn parse_value(value: &str) -> Result<Self, String> {
+        match value {
+            "defer" => Ok(HandshakeFailure::Defer),
+            "plaintext" | "downgrade" => Ok(HandshakeFailure::Plaintext),
+            "bounce" | "reject" => Ok(HandshakeFailure::Bounce),
+            _ => Err(format!("Invalid TLS handshake failure action {:?}.", value,)),
+        }
+    }
+}
+
 impl ParseValue for RequireOptional {
     fn parse_value(value: &str) -> Result<Self, String> {
         match value {
@@ -957,3 +1373,17 @@ impl AsRef<[u8]> for QueueName {
         &self.0
     }
 }
+
+impl std::str::FromStr for DsnTemplateVariable {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rcpt" => Ok(DsnTemplateVariable::Rcpt),
+            "host" => Ok(DsnTemplateVariable::Host),
+            "smtp_response" => Ok(DsnTemplateVariable::SmtpResponse),
+            "diagnostic" => Ok(DsnTemplateVariable::Diagnostic),
+            _ => Err(format!("Unknown DSN template variable: {}", s)),
+        }
+    }
+}
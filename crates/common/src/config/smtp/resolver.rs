@@ -31,6 +31,7 @@ use crate::Server;
 pub struct Resolvers {
     pub dns: MessageAuthenticator,
     pub dnssec: DnssecResolver,
+    pub negative_ttl: Duration,
 }
 
 #[derive(Clone)]
@@ -53,6 +54,19 @@ pub struct Tlsa {
     pub has_intermediates: bool,
 }
 
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SrvEntry {
+    pub target: String,
+    pub port: u16,
+    pub priority: u16,
+    pub weight: u16,
+}
+
+#[derive(Debug, Clone, Default, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Srv {
+    pub entries: Vec<SrvEntry>,
+}
+
 #[derive(Debug, PartialEq, Eq, Hash, Default, Clone, Copy, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub enum Mode {
@@ -87,6 +101,16 @@ impl CacheItemWeight for Tlsa {
     }
 }
 
+impl CacheItemWeight for Srv {
+    fn weight(&self) -> u64 {
+        self.entries
+            .iter()
+            .map(|entry| (entry.target.len() + std::mem::size_of::<SrvEntry>()) as u64)
+            .sum::<u64>()
+            + std::mem::size_of::<Srv>() as u64
+    }
+}
+
 impl CacheItemWeight for Policy {
     fn weight(&self) -> u64 {
         (std::mem::size_of::<Policy>()
@@ -225,6 +249,11 @@ impl Resolvers {
         // We already have a cache, so disable the built-in cache
         opts.cache_size = 0;
 
+        // Upper bound for caching non-existent domains, disabled by default
+        let negative_ttl = config
+            .property::<Duration>("resolver.cache.negative-ttl")
+            .unwrap_or_default();
+
         // Prepare DNSSEC resolver options
         let config_dnssec = resolver_config.clone();
         let mut opts_dnssec = opts.clone();
@@ -240,6 +269,7 @@ impl Resolvers {
                 .with_options(opts_dnssec)
                 .build(),
             },
+            negative_ttl,
         }
     }
 }
@@ -370,6 +400,7 @@ impl Default for Resolvers {
                 .with_options(opts_dnssec)
                 .build(),
             },
+            negative_ttl: Duration::ZERO,
         }
     }
 }
@@ -414,6 +445,7 @@ impl Clone for Resolvers {
         Self {
             dns: self.dns.clone(),
             dnssec: self.dnssec.clone(),
+            negative_ttl: self.negative_ttl,
         }
     }
 }
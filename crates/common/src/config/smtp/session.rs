@@ -34,6 +34,7 @@ pub struct SessionConfig {
     pub timeout: IfBlock,
     pub duration: IfBlock,
     pub transfer_limit: IfBlock,
+    pub tarpit: IfBlock,
 
     pub connect: Connect,
     pub ehlo: Ehlo,
@@ -50,9 +51,13 @@ pub struct SessionConfig {
 
 #[derive(Clone)]
 pub struct Connect {
+    pub allow: IfBlock,
+    pub reject_message: IfBlock,
     pub hostname: IfBlock,
     pub script: IfBlock,
     pub greeting: IfBlock,
+    pub greeting_delay: IfBlock,
+    pub max_connections_per_ip: u64,
 }
 
 #[derive(Clone)]
@@ -74,6 +79,10 @@ pub struct Extensions {
     pub future_release: IfBlock,
     pub deliver_by: IfBlock,
     pub mt_priority: IfBlock,
+
+    // Advertisement
+    pub order: Vec<String>,
+    pub custom: Vec<String>,
 }
 
 #[derive(Clone)]
@@ -107,11 +116,40 @@ pub struct Rcpt {
     // Limits
     pub max_recipients: IfBlock,
 
+    // DNS blocklists
+    pub dnsbl_ip: Vec<String>,
+    pub dnsbl_domain: Vec<String>,
+    pub dnsbl_reject: IfBlock,
+
+    // Null sender correlation
+    pub bounce_correlation: IfBlock,
+    pub bounce_correlation_expiry: Duration,
+
+    // Recipient verification
+    pub verify: IfBlock,
+    pub callout_timeout: Duration,
+    pub callout_cache_valid: Duration,
+    pub callout_cache_invalid: Duration,
+
     // Catch-all and sub-addressing
     pub catch_all: AddressMapping,
     pub subaddressing: AddressMapping,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RcptVerify {
+    Callout,
+    Disable,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BareLineEndings {
+    #[default]
+    Allow,
+    Convert,
+    Reject,
+}
+
 #[derive(Debug, Default, Clone)]
 pub enum AddressMapping {
     Enable,
@@ -124,11 +162,22 @@ pub enum AddressMapping {
 pub struct Data {
     pub script: IfBlock,
     pub spam_filter: IfBlock,
+    pub notify_accepted: IfBlock,
 
     // Limits
     pub max_messages: IfBlock,
     pub max_message_size: IfBlock,
     pub max_received_headers: IfBlock,
+    pub max_line_length: usize,
+
+    // Line endings
+    pub bare_line_endings: BareLineEndings,
+    pub reject_smuggling: bool,
+
+    // Per-account sending quota
+    pub send_quota: IfBlock,
+    pub send_quota_period: Duration,
+    pub send_quota_hard: bool,
 
     // Headers
     pub add_received: IfBlock,
@@ -197,6 +246,7 @@ impl SessionConfig {
         let has_rcpt_vars = TokenMap::default().with_variables(SMTP_RCPT_TO_VARS);
         let mt_priority_vars = has_sender_vars.clone().with_constants::<MtPriority>();
         let mechanisms_vars = has_ehlo_hars.clone().with_constants::<Mechanism>();
+        let verify_vars = has_rcpt_vars.clone().with_constants::<RcptVerify>();
 
         let mut session = SessionConfig::default();
         session.rcpt.catch_all = AddressMapping::parse(config, "session.rcpt.catch-all");
@@ -212,6 +262,44 @@ impl SessionConfig {
             .filter_map(|id| parse_hooks(config, &id, &has_rcpt_vars))
             .collect();
         session.mta_sts_policy = Policy::try_parse(config);
+        session.rcpt.dnsbl_ip = config
+            .values("session.rcpt.dnsbl.ip")
+            .map(|(_, zone)| zone.trim_end_matches('.').to_string())
+            .collect();
+        session.rcpt.dnsbl_domain = config
+            .values("session.rcpt.dnsbl.domain")
+            .map(|(_, zone)| zone.trim_end_matches('.').to_string())
+            .collect();
+        session.connect.max_connections_per_ip = config
+            .property_or_default("session.connect.max-connections-per-ip", "0")
+            .unwrap_or(0);
+        session.extensions.order = config
+            .values("session.extensions.order")
+            .map(|(_, keyword)| keyword.trim().to_ascii_uppercase())
+            .collect();
+        for (key, keyword) in config
+            .values("session.extensions.custom")
+            .map(|(key, keyword)| (key.to_string(), keyword.trim().to_string()))
+            .collect::<Vec<_>>()
+        {
+            if is_valid_ehlo_keyword(&keyword) {
+                session.extensions.custom.push(keyword);
+            } else if !keyword.is_empty() {
+                config.new_parse_error(key, format!("Invalid EHLO keyword {keyword:?}."));
+            }
+        }
+        session.rcpt.bounce_correlation_expiry = config
+            .property_or_default("session.rcpt.bounce-correlation.expire", "7d")
+            .unwrap_or_else(|| Duration::from_secs(7 * 86400));
+        session.rcpt.callout_timeout = config
+            .property_or_default("session.rcpt.callout.timeout", "30s")
+            .unwrap_or_else(|| Duration::from_secs(30));
+        session.rcpt.callout_cache_valid = config
+            .property_or_default("session.rcpt.callout.cache.valid", "1d")
+            .unwrap_or_else(|| Duration::from_secs(86400));
+        session.rcpt.callout_cache_invalid = config
+            .property_or_default("session.rcpt.callout.cache.invalid", "1h")
+            .unwrap_or_else(|| Duration::from_secs(3600));
 
         for (value, key, token_map) in [
             (&mut session.duration, "session.duration", &has_conn_vars),
@@ -221,6 +309,17 @@ impl SessionConfig {
                 &has_conn_vars,
             ),
             (&mut session.timeout, "session.timeout", &has_conn_vars),
+            (&mut session.tarpit, "session.tarpit.delay", &has_rcpt_vars),
+            (
+                &mut session.connect.allow,
+                "session.connect.allow",
+                &has_conn_vars,
+            ),
+            (
+                &mut session.connect.reject_message,
+                "session.connect.reject-message",
+                &has_conn_vars,
+            ),
             (
                 &mut session.connect.script,
                 "session.connect.script",
@@ -236,6 +335,11 @@ impl SessionConfig {
                 "session.connect.greeting",
                 &has_conn_vars,
             ),
+            (
+                &mut session.connect.greeting_delay,
+                "session.connect.greeting-delay",
+                &has_conn_vars,
+            ),
             (
                 &mut session.extensions.pipelining,
                 "session.extensions.pipelining",
@@ -376,6 +480,21 @@ impl SessionConfig {
                 "session.rcpt.max-recipients",
                 &has_sender_vars,
             ),
+            (
+                &mut session.rcpt.dnsbl_reject,
+                "session.rcpt.dnsbl.reject",
+                &has_rcpt_vars,
+            ),
+            (
+                &mut session.rcpt.bounce_correlation,
+                "session.rcpt.bounce-correlation.enable",
+                &has_rcpt_vars,
+            ),
+            (
+                &mut session.rcpt.verify,
+                "session.rcpt.verify",
+                &verify_vars,
+            ),
             (
                 &mut session.rcpt.rewrite,
                 "session.rcpt.rewrite",
@@ -386,6 +505,11 @@ impl SessionConfig {
                 "session.data.script",
                 &has_rcpt_vars,
             ),
+            (
+                &mut session.data.notify_accepted,
+                "session.data.notify-accepted",
+                &has_rcpt_vars,
+            ),
             (
                 &mut session.data.max_messages,
                 "session.data.limits.messages",
@@ -401,6 +525,11 @@ impl SessionConfig {
                 "session.data.limits.received-headers",
                 &has_rcpt_vars,
             ),
+            (
+                &mut session.data.send_quota,
+                "session.data.send-quota.messages",
+                &has_rcpt_vars,
+            ),
             (
                 &mut session.data.spam_filter,
                 "session.data.spam-filter",
@@ -444,6 +573,21 @@ impl SessionConfig {
         session.data.add_delivered_to = config
             .property_or_default("session.data.add-headers.delivered-to", "true")
             .unwrap_or(true);
+        session.data.send_quota_period = config
+            .property_or_default("session.data.send-quota.period", "1d")
+            .unwrap_or_else(|| Duration::from_secs(86400));
+        session.data.send_quota_hard = config
+            .property_or_default("session.data.send-quota.hard", "true")
+            .unwrap_or(true);
+        session.data.max_line_length = config
+            .property_or_default("session.data.limits.line-length", "0")
+            .unwrap_or(0);
+        session.data.bare_line_endings = config
+            .property_or_default("session.data.bare-line-endings", "allow")
+            .unwrap_or_default();
+        session.data.reject_smuggling = config
+            .property_or_default("session.data.reject-smuggling", "false")
+            .unwrap_or(false);
         session
     }
 }
@@ -623,13 +767,34 @@ fn parse_stages(config: &mut Config, prefix: &str, id: &str) -> AHashSet<Stage>
     stages
 }
 
+// ehlo-line = ehlo-keyword *( SP ehlo-param ) (RFC 5321, section 4.1.1.1)
+fn is_valid_ehlo_keyword(line: &str) -> bool {
+    let mut parts = line.split(' ');
+    parts.next().is_some_and(|keyword| {
+        keyword
+            .as_bytes()
+            .first()
+            .is_some_and(|ch| ch.is_ascii_alphanumeric())
+            && keyword
+                .bytes()
+                .all(|ch| ch.is_ascii_alphanumeric() || ch == b'-')
+    }) && parts.all(|param| !param.is_empty() && param.bytes().all(|ch| (33..=126).contains(&ch)))
+}
+
 impl Default for SessionConfig {
     fn default() -> Self {
         Self {
             timeout: IfBlock::new::<()>("session.timeout", [], "5m"),
             duration: IfBlock::new::<()>("session.duration", [], "10m"),
             transfer_limit: IfBlock::new::<()>("session.transfer-limit", [], "262144000"),
+            tarpit: IfBlock::empty("session.tarpit.delay"),
             connect: Connect {
+                allow: IfBlock::new::<()>("session.connect.allow", [], "true"),
+                reject_message: IfBlock::new::<()>(
+                    "session.connect.reject-message",
+                    [],
+                    "'5.7.1 Your IP address is not allowed to connect.'",
+                ),
                 hostname: IfBlock::new::<()>(
                     "server.connect.hostname",
                     [],
@@ -641,6 +806,8 @@ impl Default for SessionConfig {
                     [],
                     "config_get('server.hostname') + ' Stalwart ESMTP at your service'",
                 ),
+                greeting_delay: IfBlock::empty("session.connect.greeting-delay"),
+                max_connections_per_ip: 0,
             },
             ehlo: Ehlo {
                 script: IfBlock::empty("session.ehlo.script"),
@@ -711,12 +878,26 @@ impl Default for SessionConfig {
                 errors_max: IfBlock::new::<()>("session.rcpt.errors.total", [], "5"),
                 errors_wait: IfBlock::new::<()>("session.rcpt.errors.wait", [], "5s"),
                 max_recipients: IfBlock::new::<()>("session.rcpt.max-recipients", [], "100"),
+                dnsbl_ip: Vec::new(),
+                dnsbl_domain: Vec::new(),
+                dnsbl_reject: IfBlock::new::<()>(
+                    "session.rcpt.dnsbl.reject",
+                    [],
+                    "is_empty(authenticated_as) && !is_empty(dnsbl)",
+                ),
+                bounce_correlation: IfBlock::empty("session.rcpt.bounce-correlation.enable"),
+                bounce_correlation_expiry: Duration::from_secs(7 * 86400),
+                verify: IfBlock::new::<RcptVerify>("session.rcpt.verify", [], "false"),
+                callout_timeout: Duration::from_secs(30),
+                callout_cache_valid: Duration::from_secs(86400),
+                callout_cache_invalid: Duration::from_secs(3600),
                 catch_all: AddressMapping::Enable,
                 subaddressing: AddressMapping::Enable,
             },
             data: Data {
                 script: IfBlock::empty("session.data.script"),
                 spam_filter: IfBlock::new::<()>("session.data.spam-filter", [], "true"),
+                notify_accepted: IfBlock::new::<()>("session.data.notify-accepted", [], "false"),
                 max_messages: IfBlock::new::<()>("session.data.limits.messages", [], "10"),
                 max_message_size: IfBlock::new::<()>("session.data.limits.size", [], "104857600"),
                 max_received_headers: IfBlock::new::<()>(
@@ -724,6 +905,12 @@ impl Default for SessionConfig {
                     [],
                     "50",
                 ),
+                max_line_length: 0,
+                bare_line_endings: BareLineEndings::Allow,
+                reject_smuggling: false,
+                send_quota: IfBlock::new::<()>("session.data.send-quota.messages", [], "0"),
+                send_quota_period: Duration::from_secs(86400),
+                send_quota_hard: true,
                 add_received: IfBlock::new::<()>(
                     "session.data.add-headers.received",
                     [("local_port == 25", "true")],
@@ -791,6 +978,8 @@ impl Default for SessionConfig {
                     [("!is_empty(authenticated_as)", "mixer")],
                     "false",
                 ),
+                order: Vec::new(),
+                custom: Vec::new(),
             },
             mta_sts_policy: None,
             milters: Default::default(),
@@ -938,3 +1127,58 @@ impl ConstantValue for MtPriority {
             .add_constant("nsep", MtPriority::Nsep);
     }
 }
+
+impl ParseValue for BareLineEndings {
+    fn parse_value(value: &str) -> Result<Self, String> {
+        match value {
+            "allow" | "true" => Ok(BareLineEndings::Allow),
+            "convert" | "normalize" => Ok(BareLineEndings::Convert),
+            "reject" | "false" => Ok(BareLineEndings::Reject),
+            _ => Err(format!("Invalid bare line endings policy {:?}.", value)),
+        }
+    }
+}
+
+impl ParseValue for RcptVerify {
+    fn parse_value(value: &str) -> Result<Self, String> {
+        match value {
+            "callout" => Ok(RcptVerify::Callout),
+            "disable" | "disabled" | "none" | "false" => Ok(RcptVerify::Disable),
+            _ => Err(format!(
+                "Invalid recipient verification method {:?}.",
+                value
+            )),
+        }
+    }
+}
+
+impl<'x> TryFrom<Variable<'x>> for RcptVerify {
+    type Error = ();
+
+    fn try_from(value: Variable<'x>) -> Result<Self, Self::Error> {
+        match value {
+            Variable::Integer(1) => Ok(RcptVerify::Callout),
+            Variable::Integer(0) => Ok(RcptVerify::Disable),
+            Variable::String(value) => RcptVerify::parse_value(value.as_str()).map_err(|_| ()),
+            _ => Err(()),
+        }
+    }
+}
+
+impl From<RcptVerify> for Constant {
+    fn from(value: RcptVerify) -> Self {
+        Constant::Integer(match value {
+            RcptVerify::Callout => 1,
+            RcptVerify::Disable => 0,
+        })
+    }
+}
+
+impl ConstantValue for RcptVerify {
+    fn add_constants(token_map: &mut TokenMap) {
+        token_map
+            .add_constant("callout", RcptVerify::Callout)
+            .add_constant("disable", RcptVerify::Disable)
+            .add_constant("false", RcptVerify::Disable);
+    }
+}
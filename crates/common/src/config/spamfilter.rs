@@ -54,6 +54,7 @@ pub struct SpamFilterScoreConfig {
 #[derive(Debug, Clone, Default)]
 pub struct SpamFilterExpiryConfig {
     pub grey_list: Option<u64>,
+    pub grey_list_delay: u64,
     pub trusted_reply: Option<u64>,
 }
 
@@ -597,6 +598,11 @@ impl SpamFilterExpiryConfig {
                 .property::<Option<Duration>>("spam-filter.grey-list.duration")
                 .unwrap_or_default()
                 .map(|d| d.as_secs()),
+            grey_list_delay: config
+                .property::<Option<Duration>>("spam-filter.grey-list.delay")
+                .unwrap_or_default()
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
             trusted_reply: config
                 .property_or_default::<Option<Duration>>(
                     "spam-filter.trusted-reply.duration",
@@ -65,6 +65,7 @@ pub struct OtelMetrics {
 pub struct ConsoleTracer {
     pub ansi: bool,
     pub multiline: bool,
+    pub json: bool,
     pub buffered: bool,
 }
 
@@ -75,6 +76,7 @@ pub struct LogTracer {
     pub rotate: RotationStrategy,
     pub ansi: bool,
     pub multiline: bool,
+    pub json: bool,
 }
 
 #[derive(Debug)]
@@ -222,6 +224,7 @@ impl Tracers {
                             multiline: config
                                 .property_or_default(("tracer", id, "multiline"), "false")
                                 .unwrap_or(false),
+                            json: parse_log_format(config, id),
                         })
                     } else {
                         continue;
@@ -239,6 +242,7 @@ impl Tracers {
                             multiline: config
                                 .property_or_default(("tracer", id, "multiline"), "false")
                                 .unwrap_or(false),
+                            json: parse_log_format(config, id),
                             buffered: config
                                 .property_or_default(("tracer", id, "buffered"), "true")
                                 .unwrap_or(true),
@@ -568,6 +572,7 @@ impl Tracers {
                     ansi: true,
                     multiline: false,
                     buffered: true,
+                    json: false,
                 }),
                 lossy: false,
             });
@@ -798,6 +803,18 @@ enum EventOrMany {
     All,
 }
 
+fn parse_log_format(config: &mut Config, id: &str) -> bool {
+    match config.value(("tracer", id, "format")).unwrap_or("text") {
+        "text" => false,
+        "json" => true,
+        format => {
+            let err = format!("Invalid log format: {format}");
+            config.new_parse_error(("tracer", id, "format"), err);
+            false
+        }
+    }
+}
+
 fn apply_events(
     event_types: impl IntoIterator<Item = EventOrMany>,
     inclusive: bool,
@@ -10,8 +10,9 @@ use crate::{
     config::smtp::{
         auth::{ArcSealer, DkimSigner, LazySignature, ResolvedSignature, build_signature},
         queue::{
-            ConnectionStrategy, DEFAULT_QUEUE_NAME, GatewayStrategy, MxConfig, QueueExpiry,
-            QueueName, QueueStrategy, RequireOptional, TlsStrategy, VirtualQueue,
+            ConnectionStrategy, DEFAULT_QUEUE_NAME, GatewayStrategy, HandshakeFailure, MxConfig,
+            QueueExpiry, QueueName, QueueStrategy, RequireOptional, RoundRobin, TlsStrategy,
+            TransientFailure, VirtualQueue,
         },
     },
     ipc::{BroadcastEvent, StateEvent},
@@ -120,27 +121,38 @@ impl Server {
     }
 
     pub fn get_arc_sealer(&self, name: &str, session_id: u64) -> Option<Arc<ArcSealer>> {
-        self.resolve_signature(name).map(|s| s.sealer).or_else(|| {
-            trc::event!(
-                Arc(trc::ArcEvent::SealerNotFound),
-                Id = name.to_string(),
-                SpanId = session_id,
-            );
+        match self.resolve_signature(name) {
+            Some(signature) => signature.is_active(now()).then_some(signature.sealer),
+            None => {
+                trc::event!(
+                    Arc(trc::ArcEvent::SealerNotFound),
+                    Id = name.to_string(),
+                    SpanId = session_id,
+                );
 
-            None
-        })
+                None
+            }
+        }
     }
 
     pub fn get_dkim_signer(&self, name: &str, session_id: u64) -> Option<Arc<DkimSigner>> {
-        self.resolve_signature(name).map(|s| s.signer).or_else(|| {
-            trc::event!(
-                Dkim(trc::DkimEvent::SignerNotFound),
-                Id = name.to_string(),
-                SpanId = session_id,
-            );
+        self.get_dkim_signature(name, session_id)
+            .map(|signature| signature.signer)
+    }
 
-            None
-        })
+    pub fn get_dkim_signature(&self, name: &str, session_id: u64) -> Option<ResolvedSignature> {
+        match self.resolve_signature(name) {
+            Some(signature) => signature.is_active(now()).then_some(signature),
+            None => {
+                trc::event!(
+                    Dkim(trc::DkimEvent::SignerNotFound),
+                    Id = name.to_string(),
+                    SpanId = session_id,
+                );
+
+                None
+            }
+        }
     }
 
     fn resolve_signature(&self, name: &str) -> Option<ResolvedSignature> {
@@ -149,11 +161,7 @@ impl Server {
             LazySignature::Resolved(resolved_signature) => Some(resolved_signature.clone()),
             LazySignature::Pending(config) => {
                 let mut config = config.clone();
-                if let Some((signer, sealer)) = build_signature(&mut config, name) {
-                    let resolved = ResolvedSignature {
-                        signer: Arc::new(signer),
-                        sealer: Arc::new(sealer),
-                    };
+                if let Some(resolved) = build_signature(&mut config, name) {
                     lazy_resolver_.store(Arc::new(LazySignature::Resolved(resolved.clone())));
                     Some(resolved)
                 } else {
@@ -196,6 +204,8 @@ impl Server {
             max_mx: 5,
             max_multi_homed: 2,
             ip_lookup_strategy: IpLookupStrategy::Ipv4thenIpv6,
+            implicit_mx: true,
+            srv_lookup: false,
         });
         self.core
             .smtp
@@ -248,12 +258,14 @@ impl Server {
                 3600, // 1 hour
                 7200, // 2 hours
             ],
+            retry_jitter: None,
             notify: vec![
                 86400,  // 1 day
                 259200, // 3 days
             ],
             expiry: QueueExpiry::Duration(432000), // 5 days
             virtual_queue: QueueName::default(),
+            window: None,
         });
         self.core
             .smtp
@@ -280,6 +292,9 @@ impl Server {
             mta_sts: RequireOptional::Optional,
             tls: RequireOptional::Optional,
             allow_invalid_certs: false,
+            on_handshake_failure: HandshakeFailure::Defer,
+            downgrade_protection: Some(Duration::from_secs(30 * 86400)),
+            connectors: None,
             timeout_tls: Duration::from_secs(3 * 60),
             timeout_mta_sts: Duration::from_secs(5 * 60),
         };
@@ -306,13 +321,19 @@ impl Server {
         static DEFAULT_CONNECTION: ConnectionStrategy = ConnectionStrategy {
             source_ipv4: Vec::new(),
             source_ipv6: Vec::new(),
+            next_source_ipv4: RoundRobin::new(),
+            next_source_ipv6: RoundRobin::new(),
             ehlo_hostname: None,
+            chunk_size: 0,
+            happy_eyeballs: None,
+            transient_failure: TransientFailure::NextHost,
             timeout_connect: Duration::from_secs(5 * 60),
             timeout_greeting: Duration::from_secs(5 * 60),
             timeout_ehlo: Duration::from_secs(5 * 60),
             timeout_mail: Duration::from_secs(5 * 60),
             timeout_rcpt: Duration::from_secs(5 * 60),
             timeout_data: Duration::from_secs(10 * 60),
+            timeout_data_end: Duration::from_secs(10 * 60),
         };
 
         self.core
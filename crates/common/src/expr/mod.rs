@@ -44,6 +44,7 @@ pub const V_QUEUE_NAME: u32 = 29;
 pub const V_SOURCE: u32 = 30;
 pub const V_SIZE: u32 = 31;
 pub const V_QUEUE_AGE: u32 = 32;
+pub const V_DNSBL: u32 = 33;
 
 pub const VARIABLES_MAP: &[(&str, u32)] = &[
     ("rcpt", V_RECIPIENT),
@@ -79,6 +80,7 @@ pub const VARIABLES_MAP: &[(&str, u32)] = &[
     ("source", V_SOURCE),
     ("size", V_SIZE),
     ("queue_age", V_QUEUE_AGE),
+    ("dnsbl", V_DNSBL),
 ];
 
 use compact_str::CompactString;
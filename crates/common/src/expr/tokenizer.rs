@@ -76,7 +76,9 @@ impl<'x> Tokenizer<'x> {
                     self.is_eof = true;
                     break;
                 }
-                b'-' if self.buf.last().is_some_and(|c| *c == b'[') => {
+                b'-' if self.buf.last().is_some_and(|c| *c == b'[')
+                    || self.buf.starts_with(b"header.") =>
+                {
                     self.buf.push(ch);
                 }
                 b':' if self.buf.contains(&b'.') => {
@@ -313,6 +315,10 @@ impl<'x> Tokenizer<'x> {
                 } else {
                     Ok(Token::Global(variable.into()))
                 }
+            } else if let Some(header) = buf.strip_prefix("header.").filter(|s| !s.is_empty()) {
+                Ok(Token::Global(
+                    format!("header.{}", header.to_ascii_lowercase()).into(),
+                ))
             } else if let Some((idx, (name, _, num_args))) = FUNCTIONS
                 .iter()
                 .enumerate()
@@ -369,6 +375,7 @@ impl TokenMap {
             V_QUEUE_LAST_ERROR,
             V_ASN,
             V_COUNTRY,
+            V_DNSBL,
         ])
     }
 
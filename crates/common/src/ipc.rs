@@ -109,6 +109,7 @@ pub enum QueueEvent {
         status: QueueEventStatus,
     },
     Paused(bool),
+    Drain,
     ReloadSettings,
     Stop,
 }
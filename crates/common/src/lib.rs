@@ -18,7 +18,7 @@ use config::{
     scripts::Scripting,
     smtp::{
         SmtpConfig,
-        resolver::{Policy, Tlsa},
+        resolver::{Policy, Srv, Tlsa},
     },
     spamfilter::{IpResolver, SpamFilterConfig},
     storage::Storage,
@@ -26,12 +26,15 @@ use config::{
 };
 use ipc::{BroadcastEvent, HousekeeperEvent, QueueEvent, ReportingEvent, StateEvent};
 use jmap_proto::types::value::AclGrant;
-use listener::{asn::AsnGeoLookupData, blocked::Security, tls::AcmeProviders};
+use listener::{
+    asn::AsnGeoLookupData, blocked::Security, limiter::ConcurrencyLimiter, tls::AcmeProviders,
+};
 use mail_auth::{MX, Txt};
 use manager::webadmin::{Resource, WebAdminManager};
 use nlp::bayes::{TokenHash, Weights};
 use parking_lot::{Mutex, RwLock};
 use rustls::sign::CertifiedKey;
+use sieve::Sieve;
 use std::{
     hash::{BuildHasher, Hash, Hasher},
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
@@ -42,6 +45,7 @@ use tinyvec::TinyVec;
 use tokio::sync::{Notify, Semaphore, mpsc};
 use tokio_rustls::TlsConnector;
 use utils::{
+    BlobHash,
     cache::{Cache, CacheItemWeight, CacheWithTtl},
     snowflake::SnowflakeIdGenerator,
 };
@@ -79,10 +83,11 @@ Schema history:
 1 - v0.12.0
 2 - v0.12.4
 3 - v0.13.0
+4 - v0.14.0
 
 */
 
-pub const DATABASE_SCHEMA_VERSION: u32 = 3;
+pub const DATABASE_SCHEMA_VERSION: u32 = 4;
 
 pub const LONG_1D_SLUMBER: Duration = Duration::from_secs(60 * 60 * 24);
 pub const LONG_1Y_SLUMBER: Duration = Duration::from_secs(60 * 60 * 24 * 365);
@@ -116,6 +121,10 @@ pub const KV_LOCK_TASK: u8 = 23;
 pub const KV_LOCK_HOUSEKEEPER: u8 = 24;
 pub const KV_LOCK_DAV: u8 = 25;
 pub const KV_SIEVE_ID: u8 = 26;
+pub const KV_SEND_QUOTA: u8 = 27;
+pub const KV_BOUNCE_CORRELATION: u8 = 28;
+pub const KV_CALLOUT: u8 = 29;
+pub const KV_STARTTLS: u8 = 30;
 
 pub const IDX_UID: u8 = 0;
 pub const IDX_EMAIL: u8 = 1;
@@ -146,6 +155,9 @@ pub struct Data {
     pub queue_id_gen: SnowflakeIdGenerator,
     pub span_id_gen: SnowflakeIdGenerator,
     pub queue_status: AtomicBool,
+    pub queue_draining: AtomicBool,
+    pub queue_domain_limiters: Mutex<AHashMap<String, ConcurrencyLimiter>>,
+    pub remote_ip_limiters: Mutex<AHashMap<IpAddr, ConcurrencyLimiter>>,
 
     pub webadmin: WebAdminManager,
     pub logos: Mutex<AHashMap<String, Option<Resource<Vec<u8>>>>>,
@@ -164,6 +176,8 @@ pub struct Caches {
     pub events: Cache<u32, CacheSwap<DavResources>>,
     pub scheduling: Cache<u32, CacheSwap<DavResources>>,
 
+    pub sieve_scripts: Cache<BlobHash, CachedSieve>,
+
     pub bayes: CacheWithTtl<TokenHash, Weights>,
 
     pub dns_txt: CacheWithTtl<String, Txt>,
@@ -172,6 +186,8 @@ pub struct Caches {
     pub dns_ipv4: CacheWithTtl<String, Arc<Vec<Ipv4Addr>>>,
     pub dns_ipv6: CacheWithTtl<String, Arc<Vec<Ipv6Addr>>>,
     pub dns_tlsa: CacheWithTtl<String, Arc<Tlsa>>,
+    pub dns_srv: CacheWithTtl<String, Arc<Srv>>,
+    pub dns_soa: CacheWithTtl<String, u64>,
     pub dbs_mta_sts: CacheWithTtl<String, Arc<Policy>>,
     pub dns_rbl: CacheWithTtl<String, Option<Arc<IpResolver>>>,
 }
@@ -179,6 +195,12 @@ pub struct Caches {
 #[derive(Debug, Clone)]
 pub struct CacheSwap<T>(pub Arc<ArcSwap<T>>);
 
+#[derive(Clone)]
+pub struct CachedSieve {
+    pub script: Arc<Sieve>,
+    pub size: u64,
+}
+
 #[derive(Debug, Clone)]
 pub struct MessageStoreCache {
     pub emails: Arc<MessagesCache>,
@@ -248,6 +270,7 @@ pub struct Ipc {
     pub broadcast_tx: Option<mpsc::Sender<BroadcastEvent>>,
 }
 
+#[derive(Clone)]
 pub struct TlsConnectors {
     pub pki_verify: TlsConnector,
     pub dummy_verify: TlsConnector,
@@ -355,6 +378,12 @@ impl CacheItemWeight for MessageStoreCache {
     }
 }
 
+impl CacheItemWeight for CachedSieve {
+    fn weight(&self) -> u64 {
+        std::mem::size_of::<CachedSieve>() as u64 + self.size
+    }
+}
+
 impl CacheItemWeight for HttpAuthCache {
     fn weight(&self) -> u64 {
         std::mem::size_of::<HttpAuthCache>() as u64
@@ -470,6 +499,7 @@ impl Default for Caches {
             contacts: Cache::new(1024, 10 * 1024 * 1024),
             events: Cache::new(1024, 10 * 1024 * 1024),
             scheduling: Cache::new(1024, 10 * 1024 * 1024),
+            sieve_scripts: Cache::new(1024, 10 * 1024 * 1024),
             bayes: CacheWithTtl::new(1024, 10 * 1024 * 1024),
             dns_rbl: CacheWithTtl::new(1024, 10 * 1024 * 1024),
             dns_txt: CacheWithTtl::new(1024, 10 * 1024 * 1024),
@@ -478,6 +508,8 @@ impl Default for Caches {
             dns_ipv4: CacheWithTtl::new(1024, 10 * 1024 * 1024),
             dns_ipv6: CacheWithTtl::new(1024, 10 * 1024 * 1024),
             dns_tlsa: CacheWithTtl::new(1024, 10 * 1024 * 1024),
+            dns_srv: CacheWithTtl::new(1024, 10 * 1024 * 1024),
+            dns_soa: CacheWithTtl::new(1024, 10 * 1024 * 1024),
             dbs_mta_sts: CacheWithTtl::new(1024, 10 * 1024 * 1024),
         }
     }
@@ -12,7 +12,7 @@ use std::{
 
 use proxy_header::io::ProxiedStream;
 use rustls::crypto::ring::cipher_suite::TLS13_AES_128_GCM_SHA256;
-use tokio::{net::TcpStream, sync::watch};
+use tokio::{io::AsyncWriteExt, net::TcpStream, sync::watch};
 use tokio_rustls::server::TlsStream;
 use trc::{EventType, HttpEvent, ImapEvent, ManageSieveEvent, Pop3Event, SmtpEvent};
 use utils::{UnwrapFailure, config::Config};
@@ -258,6 +258,19 @@ impl BuildSession for Arc<ServerInstance> {
                 Limit = self.limiter.max_concurrent,
             );
 
+            // Let SMTP clients know they should try again later
+            if matches!(self.protocol, ServerProtocol::Smtp | ServerProtocol::Lmtp)
+                && !matches!(self.acceptor, TcpAcceptor::Tls { implicit: true, .. })
+            {
+                let mut stream = stream;
+                tokio::spawn(async move {
+                    let _ = stream
+                        .write_all(b"421 4.3.2 Too many connections, try again later.\r\n")
+                        .await;
+                    let _ = stream.shutdown().await;
+                });
+            }
+
             None
         }
     }
@@ -27,6 +27,7 @@ pub enum ScriptModification {
         name: Arc<String>,
         value: Arc<String>,
     },
+    Quarantine,
 }
 
 pub fn into_sieve_value(value: Value) -> Variable {
@@ -86,6 +86,7 @@ impl Telemetry {
             crate::config::telemetry::ConsoleTracer {
                 ansi: true,
                 multiline: false,
+                json: false,
                 buffered: false,
             },
         );
@@ -21,7 +21,8 @@ pub(crate) fn spawn_log_tracer(builder: SubscriberBuilder, settings: LogTracer)
         if let Some(writer) = settings.build_writer().await {
             let mut buf = FmtWriter::new(writer)
                 .with_ansi(settings.ansi)
-                .with_multiline(settings.multiline);
+                .with_multiline(settings.multiline)
+                .with_json(settings.json);
             let mut roatation_timestamp = settings.next_rotation();
 
             while let Some(events) = rx.recv().await {
@@ -10,7 +10,7 @@ use mail_parser::DateTime;
 use opentelemetry::{
     InstrumentationScope, Key, KeyValue, Value,
     logs::{AnyValue, Severity},
-    trace::{SpanContext, SpanKind, Status, TraceFlags, TraceState},
+    trace::{Link, SpanContext, SpanId, SpanKind, Status, TraceFlags, TraceState},
 };
 use opentelemetry_sdk::{
     Resource,
@@ -18,10 +18,18 @@ use opentelemetry_sdk::{
     trace::{SpanData, SpanEvents, SpanExporter, SpanLinks},
 };
 use opentelemetry_semantic_conventions::resource::SERVICE_VERSION;
-use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
-use trc::{Event, EventDetails, Level, TelemetryEvent, ipc::subscriber::SubscriberBuilder};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use trc::{
+    DeliveryEvent, Event, EventDetails, EventType, Level, QueueEvent, SmtpEvent, TelemetryEvent,
+    ipc::subscriber::SubscriberBuilder,
+};
+use utils::snowflake::SnowflakeIdGenerator;
 
 const MAX_EVENTS: usize = 2048;
+const MAX_MESSAGE_SPANS: usize = 10_000;
 
 pub(crate) fn spawn_otel_tracer(builder: SubscriberBuilder, mut otel: OtelTracer) {
     let (_, mut rx) = builder.register();
@@ -31,10 +39,6 @@ pub(crate) fn spawn_otel_tracer(builder: SubscriberBuilder, mut otel: OtelTracer
             .with_attribute(KeyValue::new(SERVICE_VERSION, env!("CARGO_PKG_VERSION")))
             .build();
 
-        let instrumentation = InstrumentationScope::builder("stalwart")
-            .with_version(env!("CARGO_PKG_VERSION"))
-            .build();
-
         otel.log_exporter.set_resource(&resource);
         otel.span_exporter.set_resource(&resource);
 
@@ -44,7 +48,7 @@ pub(crate) fn spawn_otel_tracer(builder: SubscriberBuilder, mut otel: OtelTracer
         let mut pending_logs = Vec::new();
         let mut pending_spans = Vec::new();
 
-        let mut active_spans = AHashMap::new();
+        let mut span_collector = SpanCollector::default();
 
         loop {
             // Wait for the next event or timeout
@@ -58,23 +62,7 @@ pub(crate) fn spawn_otel_tracer(builder: SubscriberBuilder, mut otel: OtelTracer
                         }
 
                         if otel.span_exporter_enable {
-                            if let Some(span) = event.inner.span.as_ref() {
-                                let span_id = span.span_id().unwrap();
-                                if !event.inner.typ.is_span_end() {
-                                    let events =
-                                        active_spans.entry(span_id).or_insert_with(Vec::new);
-                                    if events.len() < MAX_EVENTS {
-                                        events.push(event);
-                                    }
-                                } else if let Some(events) = active_spans.remove(&span_id) {
-                                    pending_spans.push(build_span_data(
-                                        span,
-                                        &event,
-                                        events.iter().chain(std::iter::once(&event)),
-                                        &instrumentation,
-                                    ));
-                                }
-                            }
+                            span_collector.ingest(event, &mut pending_spans);
                         }
                     }
                 }
@@ -108,7 +96,7 @@ pub(crate) fn spawn_otel_tracer(builder: SubscriberBuilder, mut otel: OtelTracer
                     if !pending_logs.is_empty() {
                         let logs = pending_logs
                             .iter()
-                            .map(|log| (log, &instrumentation))
+                            .map(|log| (log, &span_collector.instrumentation))
                             .collect::<Vec<_>>();
 
                         if let Err(err) = otel.log_exporter.export(LogBatch::new(&logs)).await {
@@ -136,18 +124,207 @@ pub(crate) fn spawn_otel_tracer(builder: SubscriberBuilder, mut otel: OtelTracer
     });
 }
 
-fn build_span_data<I, T>(
-    start_span: &Event<EventDetails>,
-    end_span: &Event<EventDetails>,
-    span_events: I,
-    instrumentation: &InstrumentationScope,
-) -> SpanData
+/// Groups the events of each span and builds the OpenTelemetry spans once the span ends.
+///
+/// Each queued message has its own trace, derived from its queue id. Its root span covers
+/// the transaction that queued it and is linked to the inbound session span, which belongs to
+/// a trace of its own as a session may queue several messages. Outbound delivery attempts are
+/// children of the root span of the message being delivered.
+pub struct SpanCollector {
+    pub instrumentation: InstrumentationScope,
+    active_spans: AHashMap<u64, Vec<Arc<Event<EventDetails>>>>,
+    session_links: AHashMap<u64, Vec<SpanContext>>,
+    message_spans: AHashMap<u64, u64>,
+    span_id_gen: SnowflakeIdGenerator,
+}
+
+impl Default for SpanCollector {
+    fn default() -> Self {
+        Self {
+            instrumentation: InstrumentationScope::builder("stalwart")
+                .with_version(env!("CARGO_PKG_VERSION"))
+                .build(),
+            active_spans: AHashMap::new(),
+            session_links: AHashMap::new(),
+            message_spans: AHashMap::new(),
+            span_id_gen: SnowflakeIdGenerator::new(),
+        }
+    }
+}
+
+impl SpanCollector {
+    pub fn ingest(&mut self, event: Arc<Event<EventDetails>>, spans: &mut Vec<SpanData>) {
+        if let Some(span) = event.inner.span.as_ref() {
+            let span_id = span.span_id().unwrap();
+            if !event.inner.typ.is_span_end() {
+                // Message spans are built as soon as the message is queued, as its
+                // delivery may start before the session ends
+                if is_queue_event(event.inner.typ)
+                    && span.inner.typ != EventType::Delivery(DeliveryEvent::AttemptStart)
+                    && let Some(queue_id) = event.value_as_uint(trc::Key::QueueId)
+                {
+                    self.build_message_span(span, &event, queue_id, spans);
+                }
+
+                let events = self.active_spans.entry(span_id).or_default();
+                if events.len() < MAX_EVENTS {
+                    events.push(event);
+                }
+            } else if let Some(mut events) = self.active_spans.remove(&span_id) {
+                events.push(event.clone());
+                self.build_span(span, &event, &events, spans);
+            }
+        }
+    }
+
+    fn build_message_span(
+        &mut self,
+        start_span: &Event<EventDetails>,
+        event: &Event<EventDetails>,
+        queue_id: u64,
+        spans: &mut Vec<SpanData>,
+    ) {
+        let span_id = start_span.span_id().unwrap();
+        let message_span_id = self.span_id_gen.generate();
+        let message_context = span_context(queue_trace_id(queue_id), message_span_id);
+
+        // The span starts with the transaction that queued the message
+        let start_time = self
+            .active_spans
+            .get(&span_id)
+            .and_then(|events| {
+                events
+                    .iter()
+                    .rev()
+                    .find(|event| event.inner.typ == EventType::Smtp(SmtpEvent::MailFrom))
+            })
+            .map_or(start_span.inner.timestamp, |event| event.inner.timestamp);
+
+        if self.message_spans.len() >= MAX_MESSAGE_SPANS {
+            self.message_spans.clear();
+        }
+        self.message_spans.insert(queue_id, message_span_id);
+        self.session_links
+            .entry(span_id)
+            .or_default()
+            .push(message_context.clone());
+
+        spans.push(SpanData {
+            span_context: message_context,
+            dropped_attributes_count: 0,
+            parent_span_id: SpanId::INVALID,
+            name: event.inner.typ.name().into(),
+            start_time: UNIX_EPOCH + Duration::from_secs(start_time),
+            end_time: UNIX_EPOCH + Duration::from_secs(event.inner.timestamp),
+            attributes: event.keys.iter().filter_map(build_key_value).collect(),
+            events: SpanEvents::default(),
+            links: build_span_links([span_context(span_id as u128, span_id)]),
+            status: Status::default(),
+            span_kind: SpanKind::Internal,
+            instrumentation_scope: self.instrumentation.clone(),
+        });
+    }
+
+    fn build_span(
+        &mut self,
+        start_span: &Event<EventDetails>,
+        end_span: &Event<EventDetails>,
+        span_events: &[Arc<Event<EventDetails>>],
+        spans: &mut Vec<SpanData>,
+    ) {
+        let span_id = start_span.span_id().unwrap();
+        let is_delivery = start_span.inner.typ == EventType::Delivery(DeliveryEvent::AttemptStart);
+
+        let (trace_id, parent_span_id) =
+            if is_delivery && let Some(queue_id) = start_span.value_as_uint(trc::Key::QueueId) {
+                let parent_span_id = if span_events
+                    .iter()
+                    .any(|event| event.inner.typ == EventType::Delivery(DeliveryEvent::Completed))
+                {
+                    self.message_spans.remove(&queue_id)
+                } else {
+                    self.message_spans.get(&queue_id).copied()
+                };
+                (queue_trace_id(queue_id), parent_span_id.unwrap_or(0))
+            } else {
+                (span_id as u128, 0)
+            };
+
+        let mut attributes = start_span
+            .keys
+            .iter()
+            .filter_map(build_key_value)
+            .collect::<Vec<_>>();
+        if is_delivery {
+            for (name, event_type, key) in [
+                (
+                    "rcpt_domain",
+                    EventType::Delivery(DeliveryEvent::DomainDeliveryStart),
+                    trc::Key::Domain,
+                ),
+                (
+                    "mx_host",
+                    EventType::Delivery(DeliveryEvent::Connect),
+                    trc::Key::Hostname,
+                ),
+                (
+                    "smtp_status",
+                    EventType::Delivery(DeliveryEvent::Delivered),
+                    trc::Key::Code,
+                ),
+            ] {
+                if let Some(value) = span_events
+                    .iter()
+                    .filter(|event| event.inner.typ == event_type)
+                    .find_map(|event| event.value(key))
+                {
+                    attributes.push(KeyValue::new(name, build_value(value)));
+                }
+            }
+        }
+
+        spans.push(SpanData {
+            span_context: span_context(trace_id, span_id),
+            dropped_attributes_count: 0,
+            parent_span_id: parent_span_id.into(),
+            name: start_span.inner.typ.name().into(),
+            start_time: UNIX_EPOCH + Duration::from_secs(start_span.inner.timestamp),
+            end_time: UNIX_EPOCH + Duration::from_secs(end_span.inner.timestamp),
+            attributes,
+            events: build_span_events(span_events.iter()),
+            links: build_span_links(self.session_links.remove(&span_id).into_iter().flatten()),
+            status: Status::default(),
+            span_kind: if is_delivery {
+                SpanKind::Client
+            } else {
+                SpanKind::Server
+            },
+            instrumentation_scope: self.instrumentation.clone(),
+        });
+    }
+}
+
+fn span_context(trace_id: u128, span_id: u64) -> SpanContext {
+    SpanContext::new(
+        trace_id.into(),
+        span_id.into(),
+        TraceFlags::default(),
+        false,
+        TraceState::default(),
+    )
+}
+
+fn build_span_links(contexts: impl IntoIterator<Item = SpanContext>) -> SpanLinks {
+    let mut links = SpanLinks::default();
+    links.links = contexts.into_iter().map(Link::with_context).collect();
+    links
+}
+
+fn build_span_events<I, T>(span_events: I) -> SpanEvents
 where
     I: IntoIterator<Item = T>,
     T: AsRef<Event<EventDetails>>,
 {
-    let span_id = start_span.span_id().unwrap();
-
     let mut events = SpanEvents::default();
     events.events = span_events
         .into_iter()
@@ -162,27 +339,25 @@ where
             )
         })
         .collect();
+    events
+}
 
-    SpanData {
-        span_context: SpanContext::new(
-            (span_id as u128).into(),
-            span_id.into(),
-            TraceFlags::default(),
-            false,
-            TraceState::default(),
-        ),
-        dropped_attributes_count: 0,
-        parent_span_id: 0.into(),
-        name: start_span.inner.typ.name().into(),
-        start_time: UNIX_EPOCH + Duration::from_secs(start_span.inner.timestamp),
-        end_time: UNIX_EPOCH + Duration::from_secs(end_span.inner.timestamp),
-        attributes: start_span.keys.iter().filter_map(build_key_value).collect(),
-        events,
-        links: SpanLinks::default(),
-        status: Status::default(),
-        span_kind: SpanKind::Server,
-        instrumentation_scope: instrumentation.clone(),
-    }
+fn is_queue_event(event_type: EventType) -> bool {
+    matches!(
+        event_type,
+        EventType::Queue(
+            QueueEvent::QueueMessage
+                | QueueEvent::QueueMessageAuthenticated
+                | QueueEvent::QueueReport
+                | QueueEvent::QueueDsn
+                | QueueEvent::QueueAutogenerated
+        )
+    )
+}
+
+// Queue-derived trace ids are tagged in the upper half so they never clash with span ids
+fn queue_trace_id(queue_id: u64) -> u128 {
+    (1u128 << 64) | queue_id as u128
 }
 
 impl OtelTracer {
@@ -212,28 +387,28 @@ impl OtelTracer {
 }
 
 fn build_key_value(key_value: &(trc::Key, trc::Value)) -> Option<KeyValue> {
-    (key_value.0 != trc::Key::SpanId).then(|| {
-        KeyValue::new(
-            build_key(&key_value.0),
-            match &key_value.1 {
-                trc::Value::String(v) => Value::String(v.to_string().into()),
-                trc::Value::UInt(v) => Value::I64(*v as i64),
-                trc::Value::Int(v) => Value::I64(*v),
-                trc::Value::Float(v) => Value::F64(*v),
-                trc::Value::Timestamp(v) => {
-                    Value::String(DateTime::from_timestamp(*v as i64).to_rfc3339().into())
-                }
-                trc::Value::Duration(v) => Value::I64(*v as i64),
-                trc::Value::Bytes(_) => Value::String("[binary data]".into()),
-                trc::Value::Bool(v) => Value::Bool(*v),
-                trc::Value::Ipv4(v) => Value::String(v.to_string().into()),
-                trc::Value::Ipv6(v) => Value::String(v.to_string().into()),
-                trc::Value::Event(_) => Value::String("[event data]".into()),
-                trc::Value::Array(_) => Value::String("[array]".into()),
-                trc::Value::None => Value::Bool(false),
-            },
-        )
-    })
+    (key_value.0 != trc::Key::SpanId)
+        .then(|| KeyValue::new(build_key(&key_value.0), build_value(&key_value.1)))
+}
+
+fn build_value(value: &trc::Value) -> Value {
+    match value {
+        trc::Value::String(v) => Value::String(v.to_string().into()),
+        trc::Value::UInt(v) => Value::I64(*v as i64),
+        trc::Value::Int(v) => Value::I64(*v),
+        trc::Value::Float(v) => Value::F64(*v),
+        trc::Value::Timestamp(v) => {
+            Value::String(DateTime::from_timestamp(*v as i64).to_rfc3339().into())
+        }
+        trc::Value::Duration(v) => Value::I64(*v as i64),
+        trc::Value::Bytes(_) => Value::String("[binary data]".into()),
+        trc::Value::Bool(v) => Value::Bool(*v),
+        trc::Value::Ipv4(v) => Value::String(v.to_string().into()),
+        trc::Value::Ipv6(v) => Value::String(v.to_string().into()),
+        trc::Value::Event(_) => Value::String("[event data]".into()),
+        trc::Value::Array(_) => Value::String("[array]".into()),
+        trc::Value::None => Value::Bool(false),
+    }
 }
 
 fn build_key(key: &trc::Key) -> Key {
@@ -20,7 +20,8 @@ pub(crate) fn spawn_console_tracer(builder: SubscriberBuilder, settings: Console
     tokio::spawn(async move {
         let mut buf = FmtWriter::new(StdErrWriter::default())
             .with_ansi(settings.ansi)
-            .with_multiline(settings.multiline);
+            .with_multiline(settings.multiline)
+            .with_json(settings.json);
 
         while let Some(events) = rx.recv().await {
             for event in events {
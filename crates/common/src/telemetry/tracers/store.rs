@@ -394,6 +394,7 @@ impl StoreTracer {
                                 | QueueEvent::RateLimitExceeded
                                 | QueueEvent::ConcurrencyLimitExceeded
                                 | QueueEvent::QuotaExceeded
+                                | QueueEvent::Quarantined
                         )
                         | EventType::Limit(_)
                         | EventType::Tls(_)
@@ -14,7 +14,8 @@ use crate::{
     },
 };
 use common::{
-    Server, auth::AccessToken, config::jmap::settings::SpecialUse, scripts::plugins::PluginContext,
+    CachedSieve, Server, auth::AccessToken, config::jmap::settings::SpecialUse,
+    scripts::plugins::PluginContext,
 };
 use directory::{Permission, QueryBy};
 use jmap_proto::types::{collection::Collection, id::Id, keyword::Keyword, property::Property};
@@ -30,7 +31,7 @@ use store::{
     write::{AlignedBytes, Archive, ArchiveVersion, Archiver, BatchBuilder, BlobOp},
 };
 use trc::{AddContext, SieveEvent};
-use utils::config::utils::ParseValue;
+use utils::{BlobHash, config::utils::ParseValue};
 
 struct SieveMessage<'x> {
     pub raw_message: Cow<'x, [u8]>,
@@ -61,7 +62,7 @@ pub trait SieveScriptIngest: Sync + Send {
         &self,
         account_id: u32,
         name: &str,
-    ) -> impl Future<Output = trc::Result<Option<Sieve>>> + Send;
+    ) -> impl Future<Output = trc::Result<Option<Arc<Sieve>>>> + Send;
 
     fn sieve_script_compile(
         &self,
@@ -566,7 +567,7 @@ impl SieveScriptIngest for Server {
 
             Ok(Some(ActiveScript {
                 document_id,
-                script: Arc::new(script.script),
+                script: script.script,
                 script_name: script.name,
                 version: script.version,
             }))
@@ -579,7 +580,7 @@ impl SieveScriptIngest for Server {
         &self,
         account_id: u32,
         name: &str,
-    ) -> trc::Result<Option<Sieve>> {
+    ) -> trc::Result<Option<Arc<Sieve>>> {
         // Find the script by name
         if let Some(document_id) = self
             .store()
@@ -625,12 +626,22 @@ impl SieveScriptIngest for Server {
             .caused_by(trc::location!())?;
         let script_offset = u32::from(unarchived_script.size) as usize;
 
+        // Scripts are cached by blob hash, any change to the script produces a new hash
+        let blob_hash = BlobHash::from(&unarchived_script.blob_hash);
+        if let Some(cached) = self.inner.cache.sieve_scripts.get(&blob_hash) {
+            return Ok(CompiledScript {
+                script: cached.script,
+                name: unarchived_script.name.as_str().into(),
+                version,
+            });
+        }
+
         // Obtain the sieve script blob
         let script_bytes = self
             .core
             .storage
             .blob
-            .get_blob(unarchived_script.blob_hash.0.as_ref(), 0..usize::MAX)
+            .get_blob(blob_hash.as_ref(), 0..usize::MAX)
             .await
             .caused_by(trc::location!())?
             .ok_or_else(|| {
@@ -647,6 +658,15 @@ impl SieveScriptIngest for Server {
                 .deserialize::<Sieve>()
                 .ok()
         }) {
+            let script = Arc::new(script);
+            self.inner.cache.sieve_scripts.insert(
+                blob_hash,
+                CachedSieve {
+                    script: script.clone(),
+                    size: script_bytes.len() as u64,
+                },
+            );
+
             Ok(CompiledScript {
                 script,
                 name: unarchived_script.name.as_str().into(),
@@ -676,6 +696,14 @@ impl SieveScriptIngest for Server {
                         .put_blob(account_id, &updated_sieve_bytes, false)
                         .await?
                         .hash;
+                    let script = Arc::new(sieve.into_inner());
+                    self.inner.cache.sieve_scripts.insert(
+                        new_blob_hash.clone(),
+                        CachedSieve {
+                            script: script.clone(),
+                            size: updated_sieve_bytes.len() as u64,
+                        },
+                    );
                     let mut new_script_object =
                         rkyv::deserialize(unarchived_script).caused_by(trc::location!())?;
                     let blob_hash =
@@ -706,7 +734,7 @@ impl SieveScriptIngest for Server {
                         .caused_by(trc::location!())?;
 
                     Ok(CompiledScript {
-                        script: sieve.into_inner(),
+                        script,
                         name: new_archive.into_inner().name,
                         version,
                     })
@@ -721,7 +749,7 @@ impl SieveScriptIngest for Server {
 }
 
 pub struct CompiledScript {
-    pub script: Sieve,
+    pub script: Arc<Sieve>,
     pub name: String,
     pub version: ArchiveVersion,
 }
@@ -7,6 +7,7 @@
 use utils::map::vec_map::VecMap;
 
 pub mod index;
+pub mod status;
 
 #[derive(
     rkyv::Archive, rkyv::Deserialize, rkyv::Serialize, Debug, Default, Clone, PartialEq, Eq,
@@ -0,0 +1,70 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs LLC <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use super::{DeliveryStatus, EmailSubmission};
+use common::{Server, storage::index::ObjectIndexBuilder};
+use jmap_proto::types::collection::Collection;
+use std::future::Future;
+use store::write::BatchBuilder;
+use trc::AddContext;
+
+pub trait EmailSubmissionStatus: Sync + Send {
+    fn update_delivery_status(
+        &self,
+        account_id: u32,
+        document_id: u32,
+        queue_id: u64,
+        delivery_status: Vec<(String, DeliveryStatus)>,
+    ) -> impl Future<Output = trc::Result<bool>> + Send;
+}
+
+impl EmailSubmissionStatus for Server {
+    async fn update_delivery_status(
+        &self,
+        account_id: u32,
+        document_id: u32,
+        queue_id: u64,
+        delivery_status: Vec<(String, DeliveryStatus)>,
+    ) -> trc::Result<bool> {
+        let submission = if let Some(submission) = self
+            .get_archive(account_id, Collection::EmailSubmission, document_id)
+            .await?
+        {
+            submission
+                .into_deserialized::<EmailSubmission>()
+                .caused_by(trc::location!())?
+        } else {
+            return Ok(false);
+        };
+
+        // Skip submissions that were destroyed and replaced by a different one
+        if submission.inner.queue_id != Some(queue_id) {
+            return Ok(false);
+        }
+
+        let mut new_submission = submission.inner.clone();
+        for (rcpt, status) in delivery_status {
+            *new_submission.delivery_status.get_mut_or_insert(rcpt) = status;
+        }
+
+        let mut batch = BatchBuilder::new();
+        batch
+            .with_account_id(account_id)
+            .with_collection(Collection::EmailSubmission)
+            .update_document(document_id)
+            .custom(
+                ObjectIndexBuilder::new()
+                    .with_current(submission)
+                    .with_changes(new_submission),
+            )
+            .caused_by(trc::location!())?
+            .commit_point();
+        self.commit_batch(batch)
+            .await
+            .caused_by(trc::location!())
+            .map(|_| true)
+    }
+}
@@ -89,6 +89,19 @@ pub struct Recipient {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub orcpt: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<Tls>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+pub struct Tls {
+    pub version: String,
+
+    pub cipher_suite: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer_subject: Option<String>,
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
@@ -557,7 +570,11 @@ impl QueueManagement for Server {
                     .inner
                     .ipc
                     .queue_tx
-                    .send(QueueEvent::Paused(action == "stop"))
+                    .send(if action == "drain" {
+                        QueueEvent::Drain
+                    } else {
+                        QueueEvent::Paused(action == "stop")
+                    })
                     .await;
 
                 Ok(JsonResponse::new(json!({
@@ -611,6 +628,11 @@ impl Message {
                         None
                     },
                     orcpt: rcpt.orcpt.as_ref().map(|orcpt| orcpt.to_string()),
+                    tls: rcpt.tls.as_ref().map(|tls| Tls {
+                        version: tls.version.to_string(),
+                        cipher_suite: tls.cipher_suite.to_string(),
+                        peer_subject: tls.peer_subject.as_ref().map(|subject| subject.to_string()),
+                    }),
                 })
                 .collect(),
 
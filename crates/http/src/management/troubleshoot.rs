@@ -348,8 +348,10 @@ async fn delivery_troubleshoot(
         max_mx: mxs.len(),
         max_multi_homed: 10,
         ip_lookup_strategy: IpLookupStrategy::Ipv4thenIpv6,
+        implicit_mx: true,
+        srv_lookup: false,
     };
-    let hosts = if let Some(hosts) = mxs.to_remote_hosts(&domain, &mx_config) {
+    let hosts = if let Some(hosts) = mxs.to_remote_hosts(&domain, &mx_config, None) {
         tx.send(DeliveryStage::MxLookupSuccess {
             mxs: mxs
                 .iter()
@@ -237,6 +237,7 @@ fn format_archived_error_details(response: &ArchivedErrorDetails) -> String {
     match &response.details {
         ArchivedError::UnexpectedResponse(response) => format_archived_response(&response.response),
         ArchivedError::DnsError(details)
+        | ArchivedError::DomainNotFound(details)
         | ArchivedError::Io(details)
         | ArchivedError::ConnectionError(details)
         | ArchivedError::TlsError(details)
@@ -244,5 +245,8 @@ fn format_archived_error_details(response: &ArchivedErrorDetails) -> String {
         | ArchivedError::MtaStsError(details) => details.to_string(),
         ArchivedError::RateLimited => "Rate limited".to_string(),
         ArchivedError::ConcurrencyLimited => "Concurrency limited".to_string(),
+        ArchivedError::MessageTooLarge(limit) => {
+            format!("Message exceeds remote size limit of {limit} bytes")
+        }
     }
 }
@@ -37,7 +37,7 @@ use jmap_proto::{
 use mail_parser::{ArchivedHeaderName, ArchivedHeaderValue};
 use smtp::{
     core::{Session, SessionData},
-    queue::spool::SmtpSpool,
+    queue::{SubmissionId, spool::SmtpSpool},
 };
 use smtp_proto::{MailFrom, RcptTo, request::parser::Rfc5321Parser};
 use std::future::Future;
@@ -57,6 +57,7 @@ pub trait EmailSubmissionSet: Sync + Send {
     fn send_message(
         &self,
         account_id: u32,
+        document_id: u32,
         response: &SetResponse,
         instance: &Arc<ServerInstance>,
         object: Object<SetValue>,
@@ -78,8 +79,15 @@ impl EmailSubmissionSet for Server {
         let mut success_email_ids = HashMap::new();
         let mut batch = BatchBuilder::new();
         for (id, object) in request.unwrap_create() {
+            // The queue uses the document id to report delivery status changes
+            let document_id = self
+                .store()
+                .assign_document_ids(account_id, Collection::EmailSubmission, 1)
+                .await
+                .caused_by(trc::location!())?;
+
             match self
-                .send_message(account_id, &response, instance, object)
+                .send_message(account_id, document_id, &response, instance, object)
                 .await?
             {
                 Ok(submission) => {
@@ -90,11 +98,6 @@ impl EmailSubmissionSet for Server {
                     );
 
                     // Insert record
-                    let document_id = self
-                        .store()
-                        .assign_document_ids(account_id, Collection::EmailSubmission, 1)
-                        .await
-                        .caused_by(trc::location!())?;
                     batch
                         .with_account_id(account_id)
                         .with_collection(Collection::EmailSubmission)
@@ -310,6 +313,7 @@ impl EmailSubmissionSet for Server {
     async fn send_message(
         &self,
         account_id: u32,
+        document_id: u32,
         response: &SetResponse,
         instance: &Arc<ServerInstance>,
         object: Object<SetValue>,
@@ -591,6 +595,12 @@ impl EmailSubmissionSet for Server {
             ),
         );
 
+        // Link the queued message to this submission
+        session.data.submission = Some(SubmissionId {
+            account_id,
+            document_id,
+        });
+
         // Spawn SMTP session to avoid overflowing the stack
         let handle = tokio::spawn(async move {
             // MAIL FROM
@@ -6,7 +6,7 @@
 
 use crate::{
     calendar::migrate_calendar_events,
-    queue::{migrate_queue_v011, migrate_queue_v012},
+    queue::{migrate_queue_v011, migrate_queue_v012, migrate_queue_v013},
     tasks::migrate_tasks_v011,
 };
 use changelog::reset_changelog;
@@ -50,6 +50,11 @@ pub async fn try_migrate(server: &Server) -> trc::Result<()> {
             .await
             .caused_by(trc::location!())?;
         return Ok(());
+    } else if std::env::var("FORCE_MIGRATE_QUEUE_V013").is_ok() {
+        migrate_queue_v013(server)
+            .await
+            .caused_by(trc::location!())?;
+        return Ok(());
     } else if std::env::var("FORCE_MIGRATE_QUEUE_V011").is_ok() {
         migrate_queue_v011(server)
             .await
@@ -87,6 +92,9 @@ pub async fn try_migrate(server: &Server) -> trc::Result<()> {
                 .await
                 .caused_by(trc::location!())?;
         }
+        Some(3) => {
+            migrate_v0_13(server).await.caused_by(trc::location!())?;
+        }
         Some(version) => {
             panic!(
                 "Unknown database schema version, expected {} or below, found {}",
@@ -117,6 +125,43 @@ pub async fn try_migrate(server: &Server) -> trc::Result<()> {
     Ok(())
 }
 
+async fn migrate_v0_13(server: &Server) -> trc::Result<()> {
+    let force_lock = std::env::var("FORCE_LOCK").is_ok();
+    let in_memory = server.in_memory_store();
+
+    loop {
+        if force_lock
+            || in_memory
+                .try_lock(
+                    KV_LOCK_HOUSEKEEPER,
+                    b"migrate_core_lock",
+                    LOCK_WAIT_TIME_CORE,
+                )
+                .await
+                .caused_by(trc::location!())?
+        {
+            migrate_queue_v013(server)
+                .await
+                .caused_by(trc::location!())?;
+
+            in_memory
+                .remove_lock(KV_LOCK_HOUSEKEEPER, b"migrate_core_lock")
+                .await
+                .caused_by(trc::location!())?;
+            break;
+        } else {
+            trc::event!(
+                Server(trc::ServerEvent::Startup),
+                Details = format!("Migration lock busy, waiting 30 seconds.",)
+            );
+
+            tokio::time::sleep(LOCK_RETRY_TIME).await;
+        }
+    }
+
+    Ok(())
+}
+
 async fn migrate_v0_12(server: &Server, migrate_tasks: bool) -> trc::Result<()> {
     let force_lock = std::env::var("FORCE_LOCK").is_ok();
     let in_memory = server.in_memory_store();
@@ -13,6 +13,7 @@ use smtp::queue::{
     Error, ErrorDetails, HostResponse, Message, QueueId, QuotaKey, Recipient, Schedule, Status,
     UnexpectedResponse,
 };
+use smtp_proto::Response;
 use std::net::{IpAddr, Ipv4Addr};
 use store::{
     IterateParams, Serialize, U64_LEN, ValueKey,
@@ -123,7 +124,7 @@ pub(crate) async fn migrate_queue_v011(server: &Server) -> trc::Result<()> {
     Ok(())
 }
 
-pub(crate) async fn migrate_queue_v012(server: &Server) -> trc::Result<()> {
+pub async fn migrate_queue_v012(server: &Server) -> trc::Result<()> {
     let from_key = ValueKey::from(ValueClass::Queue(QueueClass::MessageEvent(
         store::write::QueueEvent {
             due: 0,
@@ -234,6 +235,188 @@ pub(crate) async fn migrate_queue_v012(server: &Server) -> trc::Result<()> {
     Ok(())
 }
 
+pub async fn migrate_queue_v013(server: &Server) -> trc::Result<()> {
+    let from_key = ValueKey::from(ValueClass::Queue(QueueClass::MessageEvent(
+        store::write::QueueEvent {
+            due: 0,
+            queue_id: 0,
+            queue_name: [0; 8],
+        },
+    )));
+    let to_key = ValueKey::from(ValueClass::Queue(QueueClass::MessageEvent(
+        store::write::QueueEvent {
+            due: u64::MAX,
+            queue_id: u64::MAX,
+            queue_name: [u8::MAX; 8],
+        },
+    )));
+
+    let mut queue_ids = AHashSet::new();
+    server
+        .store()
+        .iterate(
+            IterateParams::new(from_key, to_key).ascending().no_values(),
+            |key, _| {
+                queue_ids.insert(key.deserialize_be_u64(U64_LEN)?);
+
+                Ok(true)
+            },
+        )
+        .await
+        .caused_by(trc::location!())?;
+
+    let from_key = ValueKey::from(ValueClass::Queue(QueueClass::Message(0)));
+    let to_key = ValueKey::from(ValueClass::Queue(QueueClass::Message(u64::MAX)));
+    server
+        .store()
+        .iterate(
+            IterateParams::new(from_key, to_key).ascending().no_values(),
+            |key, _| {
+                queue_ids.insert(key.deserialize_be_u64(0)?);
+
+                Ok(true)
+            },
+        )
+        .await
+        .caused_by(trc::location!())?;
+
+    let mut count = 0;
+
+    for queue_id in queue_ids {
+        match server
+            .store()
+            .get_value::<Archive<AlignedBytes>>(ValueKey::from(ValueClass::Queue(
+                QueueClass::Message(queue_id),
+            )))
+            .await
+            .and_then(|archive| {
+                if let Some(archive) = archive {
+                    archive.deserialize::<MessageV013>().map(Some)
+                } else {
+                    Ok(None)
+                }
+            }) {
+            Ok(Some(archive)) => {
+                let mut batch = BatchBuilder::new();
+                batch.set(
+                    ValueClass::Queue(QueueClass::Message(queue_id)),
+                    Archiver::new(Message::from(archive))
+                        .serialize()
+                        .caused_by(trc::location!())?,
+                );
+                count += 1;
+                server
+                    .store()
+                    .write(batch.build_all())
+                    .await
+                    .caused_by(trc::location!())?;
+            }
+            Ok(None) => (),
+            Err(err) => {
+                if server
+                    .store()
+                    .get_value::<Archive<AlignedBytes>>(ValueKey::from(ValueClass::Queue(
+                        QueueClass::Message(queue_id),
+                    )))
+                    .await
+                    .and_then(|archive| {
+                        if let Some(archive) = archive {
+                            archive.deserialize::<Message>().map(Some)
+                        } else {
+                            Ok(None)
+                        }
+                    })
+                    .is_err()
+                {
+                    return Err(err
+                        .ctx(trc::Key::QueueId, queue_id)
+                        .caused_by(trc::location!()));
+                }
+            }
+        }
+    }
+
+    if count > 0 {
+        trc::event!(
+            Server(trc::ServerEvent::Startup),
+            Details = format!("Migrated {count} queued messages",)
+        );
+    }
+
+    Ok(())
+}
+
+impl From<MessageV013> for Message {
+    fn from(message: MessageV013) -> Self {
+        Message {
+            created: message.created,
+            blob_hash: message.blob_hash,
+            received_from_ip: message.received_from_ip,
+            received_via_port: message.received_via_port,
+            return_path: message.return_path,
+            return_path_lcase: message.return_path_lcase,
+            return_path_domain: message.return_path_domain,
+            recipients: message
+                .recipients
+                .into_iter()
+                .map(|r| Recipient {
+                    address: r.address,
+                    address_lcase: r.address_lcase,
+                    retry: r.retry,
+                    notify: r.notify,
+                    expires: r.expires,
+                    queue: r.queue,
+                    next_hop: None,
+                    status: match r.status {
+                        StatusV013::Scheduled => Status::Scheduled,
+                        StatusV013::Completed(response) => Status::Completed(HostResponse {
+                            hostname: response.hostname,
+                            response: response.response,
+                        }),
+                        StatusV013::TemporaryFailure(err) => Status::TemporaryFailure(err.into()),
+                        StatusV013::PermanentFailure(err) => Status::PermanentFailure(err.into()),
+                    },
+                    flags: r.flags,
+                    orcpt: r.orcpt,
+                    tls: None,
+                    transcript: None,
+                })
+                .collect(),
+            flags: message.flags,
+            env_id: message.env_id,
+            priority: message.priority,
+            size: message.size,
+            quota_keys: message.quota_keys,
+            headers: Vec::new(),
+            submission: None,
+        }
+    }
+}
+
+impl From<ErrorDetailsV013> for ErrorDetails {
+    fn from(err: ErrorDetailsV013) -> Self {
+        ErrorDetails {
+            entity: err.entity,
+            details: match err.details {
+                ErrorV013::DnsError(err) => Error::DnsError(err),
+                ErrorV013::UnexpectedResponse(err) => {
+                    Error::UnexpectedResponse(UnexpectedResponse {
+                        command: err.command,
+                        response: err.response,
+                    })
+                }
+                ErrorV013::ConnectionError(err) => Error::ConnectionError(err),
+                ErrorV013::TlsError(err) => Error::TlsError(err),
+                ErrorV013::DaneError(err) => Error::DaneError(err),
+                ErrorV013::MtaStsError(err) => Error::MtaStsError(err),
+                ErrorV013::RateLimited => Error::RateLimited,
+                ErrorV013::ConcurrencyLimited => Error::ConcurrencyLimited,
+                ErrorV013::Io(err) => Error::Io(err),
+            },
+        }
+    }
+}
+
 impl<SIZE, IDX> From<LegacyMessage<SIZE, IDX>> for Message
 where
     SIZE: AsU64,
@@ -275,9 +458,12 @@ where
                         },
                         flags: r.flags,
                         orcpt: r.orcpt,
+                        tls: None,
+                        transcript: None,
                         retry: domain.retry.clone(),
                         notify: domain.notify.clone(),
                         queue: QueueName::default(),
+                        next_hop: None,
                         expires: QueueExpiry::Duration(domain.expires.saturating_sub(now())),
                     }
                 })
@@ -289,6 +475,8 @@ where
             quota_keys: message.quota_keys,
             received_from_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
             received_via_port: 0,
+            headers: Vec::new(),
+            submission: None,
         }
     }
 }
@@ -366,6 +554,83 @@ fn migrate_host_response(response: HostResponse<LegacyErrorDetails>) -> ErrorDet
     }
 }
 
+#[derive(rkyv::Serialize, rkyv::Deserialize, rkyv::Archive, Debug, Clone, PartialEq, Eq)]
+pub struct MessageV013 {
+    pub created: u64,
+    pub blob_hash: BlobHash,
+
+    pub received_from_ip: IpAddr,
+    pub received_via_port: u16,
+
+    pub return_path: String,
+    pub return_path_lcase: String,
+    pub return_path_domain: String,
+    pub recipients: Vec<RecipientV013>,
+
+    pub flags: u64,
+    pub env_id: Option<String>,
+    pub priority: i16,
+
+    pub size: u64,
+    pub quota_keys: Vec<QuotaKey>,
+}
+
+#[derive(rkyv::Serialize, rkyv::Deserialize, rkyv::Archive, Debug, Clone, PartialEq, Eq)]
+pub struct RecipientV013 {
+    pub address: String,
+    pub address_lcase: String,
+
+    pub retry: Schedule<u32>,
+    pub notify: Schedule<u32>,
+    pub expires: QueueExpiry,
+
+    pub queue: QueueName,
+    pub status: StatusV013,
+    pub flags: u64,
+    pub orcpt: Option<String>,
+}
+
+// Frozen copies of the v0.13 status types, the live types may gain variants
+// that change their archived layout
+#[derive(rkyv::Serialize, rkyv::Deserialize, rkyv::Archive, Debug, Clone, PartialEq, Eq)]
+pub enum StatusV013 {
+    Scheduled,
+    Completed(HostResponseV013),
+    TemporaryFailure(ErrorDetailsV013),
+    PermanentFailure(ErrorDetailsV013),
+}
+
+#[derive(rkyv::Serialize, rkyv::Deserialize, rkyv::Archive, Debug, Clone, PartialEq, Eq)]
+pub struct HostResponseV013 {
+    pub hostname: String,
+    pub response: Response<String>,
+}
+
+#[derive(rkyv::Serialize, rkyv::Deserialize, rkyv::Archive, Debug, Clone, PartialEq, Eq)]
+pub struct ErrorDetailsV013 {
+    pub entity: String,
+    pub details: ErrorV013,
+}
+
+#[derive(rkyv::Serialize, rkyv::Deserialize, rkyv::Archive, Debug, Clone, PartialEq, Eq)]
+pub enum ErrorV013 {
+    DnsError(String),
+    UnexpectedResponse(UnexpectedResponseV013),
+    ConnectionError(String),
+    TlsError(String),
+    DaneError(String),
+    MtaStsError(String),
+    RateLimited,
+    ConcurrencyLimited,
+    Io(String),
+}
+
+#[derive(rkyv::Serialize, rkyv::Deserialize, rkyv::Archive, Debug, Clone, PartialEq, Eq)]
+pub struct UnexpectedResponseV013 {
+    pub command: String,
+    pub response: Response<String>,
+}
+
 pub type MessageV011 = LegacyMessage<usize, usize>;
 pub type MessageV012 = LegacyMessage<u64, u32>;
 
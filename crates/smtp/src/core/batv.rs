@@ -0,0 +1,80 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs LLC <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use common::config::smtp::auth::BatvAuthConfig;
+
+const PRVS_PREFIX: &str = "prvs=";
+const KEY_NUM: char = '0';
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum BatvAddress {
+    Untagged,
+    Valid(String),
+    Invalid,
+}
+
+pub trait BatvTag {
+    fn batv_sign(&self, address: &str, now: u64) -> Option<String>;
+    fn batv_verify(&self, address: &str, now: u64) -> BatvAddress;
+}
+
+impl BatvTag for BatvAuthConfig {
+    // Tags an address as prvs=KDDDSSSSSS=local@domain, as described in
+    // draft-levine-smtp-batv-01 Section 4.1
+    fn batv_sign(&self, address: &str, now: u64) -> Option<String> {
+        let secret = self.secret.as_deref()?;
+        if address.is_empty() || address.starts_with(PRVS_PREFIX) {
+            return None;
+        }
+        let day = (now / 86400) % 1000;
+
+        Some(format!(
+            "{PRVS_PREFIX}{KEY_NUM}{day:03}{}={address}",
+            batv_hash(secret, day, address)
+        ))
+    }
+
+    fn batv_verify(&self, address: &str, now: u64) -> BatvAddress {
+        let secret = if let Some(secret) = &self.secret {
+            secret
+        } else {
+            return BatvAddress::Untagged;
+        };
+        let tagged = match address.get(..PRVS_PREFIX.len()) {
+            Some(prefix) if prefix.eq_ignore_ascii_case(PRVS_PREFIX) => {
+                &address[PRVS_PREFIX.len()..]
+            }
+            _ => return BatvAddress::Untagged,
+        };
+
+        if let Some((tag, address)) = tagged.split_once('=')
+            && tag.len() == 10
+            && tag.is_ascii()
+            && tag.starts_with(KEY_NUM)
+            && address.contains('@')
+            && let Ok(day) = tag[1..4].parse::<u64>()
+        {
+            let age = ((now / 86400) % 1000 + 1000 - day) % 1000;
+            if age <= self.max_age
+                && batv_hash(secret, day, address).eq_ignore_ascii_case(&tag[4..])
+            {
+                return BatvAddress::Valid(address.to_string());
+            }
+        }
+
+        BatvAddress::Invalid
+    }
+}
+
+fn batv_hash(secret: &str, day: u64, address: &str) -> String {
+    let mut hasher = blake3::Hasher::new_keyed(blake3::hash(secret.as_bytes()).as_bytes());
+    hasher.update(format!("{KEY_NUM}{day:03}").as_bytes());
+    hasher.update(address.to_lowercase().as_bytes());
+    let hash = hasher.finalize();
+    let hash = hash.as_bytes();
+
+    format!("{:02x}{:02x}{:02x}", hash[0], hash[1], hash[2])
+}
@@ -11,6 +11,7 @@ use std::{
     time::{Duration, Instant},
 };
 
+use ahash::AHashMap;
 use common::{
     Inner, Server,
     auth::AccessToken,
@@ -27,9 +28,10 @@ use tokio::io::{AsyncRead, AsyncWrite};
 
 use crate::{
     inbound::auth::SaslToken,
-    queue::{DomainPart, QueueId},
+    queue::{DomainPart, QueueId, SubmissionId},
 };
 
+pub mod batv;
 pub mod params;
 pub mod throttle;
 
@@ -88,15 +90,18 @@ pub struct SessionData {
     pub priority: i16,
     pub delivery_by: i64,
     pub future_release: u64,
+    pub submission: Option<SubmissionId>,
 
     pub valid_until: Instant,
     pub bytes_left: usize,
     pub messages_sent: usize,
+    pub tarpit: Option<Duration>,
 
     pub iprev: Option<IprevOutput>,
     pub spf_ehlo: Option<SpfOutput>,
     pub spf_mail_from: Option<SpfOutput>,
-    pub dnsbl_error: Option<Vec<u8>>,
+    pub dnsbl: Vec<String>,
+    pub dnsbl_cache: AHashMap<String, bool>,
 }
 
 #[derive(Clone, Debug)]
@@ -168,12 +173,15 @@ impl SessionData {
             auth_errors: 0,
             messages_sent: 0,
             bytes_left: 0,
+            tarpit: None,
             delivery_by: 0,
             future_release: 0,
+            submission: None,
             iprev: None,
             spf_ehlo: None,
             spf_mail_from: None,
-            dnsbl_error: None,
+            dnsbl: Vec::new(),
+            dnsbl_cache: AHashMap::new(),
         }
     }
 }
@@ -291,13 +299,16 @@ impl SessionData {
             priority: 0,
             delivery_by: 0,
             future_release: 0,
+            submission: None,
             valid_until: Instant::now(),
             bytes_left: 0,
             messages_sent: 0,
+            tarpit: None,
             iprev: None,
             spf_ehlo: None,
             spf_mail_from: None,
-            dnsbl_error: None,
+            dnsbl: Vec::new(),
+            dnsbl_cache: AHashMap::new(),
         }
     }
 }
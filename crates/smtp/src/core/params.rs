@@ -106,6 +106,9 @@ impl<T: SessionStream> Session<T> {
             .eval_if(&ec.vrfy, self, self.data.session_id)
             .await
             .unwrap_or(false);
+
+        // Tarpit delay, evaluated again after each command
+        self.data.tarpit = self.eval_tarpit().await;
     }
 
     pub async fn eval_post_auth_params(&mut self) {
@@ -121,6 +124,17 @@ impl<T: SessionStream> Session<T> {
             .eval_if(&ec.vrfy, self, self.data.session_id)
             .await
             .unwrap_or(false);
+
+        // Refresh the maximum message size
+        self.params.max_message_size = self
+            .server
+            .eval_if(
+                &self.server.core.smtp.session.data.max_message_size,
+                self,
+                self.data.session_id,
+            )
+            .await
+            .unwrap_or(25 * 1024 * 1024);
     }
 
     pub async fn eval_rcpt_params(&mut self) {
@@ -5,10 +5,13 @@
  */
 
 use common::{
-    KV_RATE_LIMIT_SMTP, ThrottleKey,
+    KV_RATE_LIMIT_SMTP, KV_SEND_QUOTA, ThrottleKey,
     config::smtp::*,
     expr::{functions::ResolveVariable, *},
-    listener::SessionStream,
+    listener::{
+        SessionStream,
+        limiter::{ConcurrencyLimiter, LimiterResult},
+    },
 };
 use queue::QueueQuota;
 use trc::SmtpEvent;
@@ -16,6 +19,8 @@ use utils::config::Rate;
 
 use super::Session;
 
+const MAX_IP_LIMITERS: usize = 1024;
+
 pub trait NewKey: Sized {
     fn new_key(&self, e: &impl ResolveVariable, context: &str) -> ThrottleKey;
 }
@@ -140,6 +145,25 @@ impl NewKey for QueueRateLimiter {
 }
 
 impl<T: SessionStream> Session<T> {
+    pub fn is_concurrency_allowed(&self) -> LimiterResult {
+        let max_concurrent = self.server.core.smtp.session.connect.max_connections_per_ip;
+        if max_concurrent == 0 {
+            return LimiterResult::Disabled;
+        }
+        let mut limiters = self.server.inner.data.remote_ip_limiters.lock();
+
+        // Remove idle limiters before the map grows too large
+        if limiters.len() > MAX_IP_LIMITERS {
+            limiters.retain(|_, limiter| limiter.is_active());
+        }
+
+        let limiter = limiters
+            .entry(self.data.remote_ip)
+            .or_insert_with(|| ConcurrencyLimiter::new(max_concurrent));
+        limiter.max_concurrent = max_concurrent;
+        limiter.is_allowed()
+    }
+
     pub async fn is_allowed(&mut self) -> bool {
         let throttles = if !self.data.rcpt_to.is_empty() {
             &self.server.core.smtp.queue.inbound_limiters.rcpt
@@ -209,6 +233,61 @@ impl<T: SessionStream> Session<T> {
         true
     }
 
+    pub async fn has_send_quota(&self, soft_check: bool) -> bool {
+        let account_id = if let Some(access_token) = &self.data.authenticated_as {
+            access_token.primary_id()
+        } else {
+            return true;
+        };
+        let dc = &self.server.core.smtp.session.data;
+        let max_messages = self
+            .server
+            .eval_if::<u64, _>(&dc.send_quota, self, self.data.session_id)
+            .await
+            .unwrap_or(0);
+        if max_messages == 0 {
+            return true;
+        }
+
+        // Each accepted submission counts towards the account's quota for the current period,
+        // soft checks only read the counter
+        let rate = Rate {
+            requests: max_messages,
+            period: dc.send_quota_period,
+        };
+        match self
+            .server
+            .core
+            .storage
+            .lookup
+            .is_rate_allowed(KV_SEND_QUOTA, &account_id.to_be_bytes(), &rate, soft_check)
+            .await
+        {
+            Ok(None) => true,
+            Ok(Some(_)) => {
+                trc::event!(
+                    Smtp(SmtpEvent::RateLimitExceeded),
+                    SpanId = self.data.session_id,
+                    Id = "send-quota",
+                    AccountId = account_id,
+                    Limit = vec![
+                        trc::Value::from(rate.requests),
+                        trc::Value::from(rate.period)
+                    ],
+                );
+
+                false
+            }
+            Err(err) => {
+                trc::error!(
+                    err.span_id(self.data.session_id)
+                        .caused_by(trc::location!())
+                );
+                true
+            }
+        }
+    }
+
     pub async fn throttle_rcpt(&self, rcpt: &str, rate: &Rate, ctx: &str) -> bool {
         let mut hasher = blake3::Hasher::new();
         hasher.update(rcpt.as_bytes());
@@ -101,6 +101,7 @@ impl<T: SessionStream> Session<T> {
     pub async fn authenticate(&mut self, credentials: Credentials<String>) -> Result<bool, ()> {
         if let Some(directory) = &self.params.auth_directory {
             // Authenticate
+            let is_bearer = matches!(credentials, Credentials::OAuthBearer { .. });
             let result = self
                 .server
                 .authenticate(
@@ -137,6 +138,12 @@ impl<T: SessionStream> Session<T> {
                                 .auth_error(b"535 5.7.8 Authentication credentials invalid.\r\n")
                                 .await;
                         }
+                        trc::EventType::Auth(trc::AuthEvent::Error) if is_bearer => {
+                            // Tokens that fail to decode or decrypt are invalid credentials
+                            return self
+                                .auth_error(b"535 5.7.8 Authentication credentials invalid.\r\n")
+                                .await;
+                        }
                         trc::EventType::Auth(trc::AuthEvent::TokenExpired) => {
                             return self.auth_error(b"535 5.7.8 OAuth token expired.\r\n").await;
                         }
@@ -0,0 +1,389 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs LLC <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::{net::SocketAddr, time::Instant};
+
+use common::{
+    KV_CALLOUT,
+    config::{
+        server::ServerProtocol,
+        smtp::{queue::GatewayStrategy, session::RcptVerify},
+    },
+    listener::SessionStream,
+};
+use smtp_proto::EhloResponse;
+use store::dispatch::lookup::KeyValue;
+use tokio::io::{AsyncRead, AsyncWrite};
+use trc::SmtpEvent;
+
+use crate::{
+    core::{Session, SessionAddress},
+    outbound::{
+        NextHop,
+        client::SmtpClient,
+        lookup::{DnsLookup, SourceIp, ToNextHop},
+        mta_sts::verify::VerifyPolicy,
+        remote::{RemoteLookup, SmtpConnection, TlsParams},
+        session::SessionParams,
+    },
+    queue::Status,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalloutResult {
+    Valid,
+    Invalid,
+    Unavailable,
+    Loop,
+    Local,
+}
+
+impl<T: SessionStream> Session<T> {
+    pub async fn is_callout_enabled(&self) -> bool {
+        matches!(
+            self.server
+                .eval_if(
+                    &self.server.core.smtp.session.rcpt.verify,
+                    self,
+                    self.data.session_id,
+                )
+                .await,
+            Some(RcptVerify::Callout)
+        )
+    }
+
+    pub async fn verify_callout(&self) -> CalloutResult {
+        let rcpt = self.data.rcpt_to.last().unwrap();
+        let config = &self.server.core.smtp.session.rcpt;
+        let key = KeyValue::<()>::build_key(KV_CALLOUT, rcpt.address_lcase.as_bytes());
+
+        // Positive and negative results are cached
+        match self
+            .server
+            .in_memory_store()
+            .key_get::<String>(key.clone())
+            .await
+        {
+            Ok(Some(result)) => {
+                return if result == "1" {
+                    CalloutResult::Valid
+                } else {
+                    CalloutResult::Invalid
+                };
+            }
+            Ok(None) => (),
+            Err(err) => {
+                trc::error!(
+                    err.span_id(self.data.session_id)
+                        .caused_by(trc::location!())
+                        .details("Failed to obtain cached callout result.")
+                );
+            }
+        }
+
+        let time = Instant::now();
+        let result = tokio::time::timeout(config.callout_timeout, self.callout(&rcpt.domain))
+            .await
+            .unwrap_or(CalloutResult::Unavailable);
+
+        trc::event!(
+            Smtp(SmtpEvent::RcptToCallout),
+            SpanId = self.data.session_id,
+            To = rcpt.address_lcase.clone(),
+            Result = format!("{result:?}"),
+            Elapsed = time.elapsed(),
+        );
+
+        let (value, expires) = match result {
+            CalloutResult::Valid => ("1", config.callout_cache_valid),
+            CalloutResult::Invalid => ("0", config.callout_cache_invalid),
+            CalloutResult::Unavailable | CalloutResult::Loop | CalloutResult::Local => {
+                return result;
+            }
+        };
+        if let Err(err) = self
+            .server
+            .in_memory_store()
+            .key_set(KeyValue::new(key, value.as_bytes().to_vec()).expires(expires.as_secs()))
+            .await
+        {
+            trc::error!(
+                err.span_id(self.data.session_id)
+                    .caused_by(trc::location!())
+                    .details("Failed to cache callout result.")
+            );
+        }
+
+        result
+    }
+
+    async fn callout(&self, domain: &str) -> CalloutResult {
+        let server = &self.server;
+        let queue_config = &server.core.smtp.queue;
+        let session_id = self.data.session_id;
+
+        // Route the recipient as outbound delivery would
+        let gateway = server.get_gateway_or_default(
+            &server
+                .eval_if::<String, _>(&queue_config.gateway, self, session_id)
+                .await
+                .unwrap_or_else(|| "default".to_string()),
+            session_id,
+        );
+        let mx_hosts;
+        let (remote_hosts, is_mx, is_smtp) = match gateway {
+            // Local recipients are not verified at a next hop
+            GatewayStrategy::Local => return CalloutResult::Local,
+            GatewayStrategy::Mx(mx_config) => {
+                mx_hosts = match server.mx_hosts(domain, mx_config, session_id).await {
+                    Ok(mx_hosts) => mx_hosts,
+                    Err(Status::PermanentFailure(_)) => return CalloutResult::Invalid,
+                    Err(_) => return CalloutResult::Unavailable,
+                };
+                match mx_hosts.to_remote_hosts(domain, mx_config, None) {
+                    Some(remote_hosts) => (remote_hosts, true, true),
+                    None => return CalloutResult::Invalid,
+                }
+            }
+            GatewayStrategy::Relay(relay_config) => (
+                vec![NextHop::Relay(relay_config)],
+                false,
+                relay_config.protocol == ServerProtocol::Smtp,
+            ),
+        };
+
+        // Obtain TLS and connection strategies
+        let tls_strategy = server.get_tls_or_default(
+            &server
+                .eval_if::<String, _>(&queue_config.tls, self, session_id)
+                .await
+                .unwrap_or_else(|| "default".to_string()),
+            session_id,
+        );
+        let conn_strategy = server.get_connection_or_default(
+            &server
+                .eval_if::<String, _>(&queue_config.connection, self, session_id)
+                .await
+                .unwrap_or_else(|| "default".to_string()),
+            session_id,
+        );
+        let tls_connectors = tls_strategy
+            .connectors
+            .as_ref()
+            .unwrap_or(&server.inner.data.smtp_connectors);
+
+        // Obtain MTA-STS policy for domain
+        let mta_sts_policy = if is_mx && is_smtp && tls_strategy.try_mta_sts() {
+            match server
+                .mta_sts_policy(domain, tls_strategy, None, session_id)
+                .await
+            {
+                Ok(mta_sts_policy) => mta_sts_policy,
+                Err(_) => return CalloutResult::Unavailable,
+            }
+        } else {
+            None
+        };
+
+        let rcpt = self.data.rcpt_to.last().unwrap();
+        let local_addr = SocketAddr::new(self.data.local_ip, self.data.local_port);
+        for remote_host in &remote_hosts {
+            let hostname = remote_host.hostname();
+
+            // Skip hosts not authorized by an enforced MTA-STS policy
+            if mta_sts_policy
+                .as_ref()
+                .is_some_and(|policy| policy.enforce() && !policy.verify(hostname))
+            {
+                continue;
+            }
+
+            let remote_ips = match server.resolve_host(remote_host, self).await {
+                Ok(result) => result.remote_ips,
+                Err(_) => continue,
+            };
+
+            // Lookup DANE policy
+            let dane_policy = if is_smtp && tls_strategy.try_dane() {
+                match server
+                    .dane_policy(domain, hostname, tls_strategy, None, session_id)
+                    .await
+                {
+                    Ok(dane_policy) => dane_policy,
+                    Err(_) => continue,
+                }
+            } else {
+                None
+            };
+
+            // Prepare TLS connector
+            let is_strict_tls =
+                tls_strategy.is_tls_required() || mta_sts_policy.is_some() || dane_policy.is_some();
+            let tls_connector = if tls_strategy.allow_invalid_certs
+                || remote_host.allow_invalid_certs()
+                || dane_policy.is_some()
+            {
+                &tls_connectors.dummy_verify
+            } else {
+                &tls_connectors.pki_verify
+            };
+
+            for remote_ip in remote_ips {
+                // Do not call out to the listener that accepted this session
+                let remote_addr = SocketAddr::new(remote_ip, remote_host.port());
+                if remote_addr == local_addr {
+                    return CalloutResult::Loop;
+                }
+
+                let ip_host = conn_strategy.source_ip(remote_ip.is_ipv4());
+                let local_hostname = ip_host
+                    .and_then(|ip| ip.host.as_deref())
+                    .or(conn_strategy.ehlo_hostname.as_deref())
+                    .unwrap_or(server.core.network.server_name.as_str());
+                let mut smtp_client = match SmtpClient::connect_from(
+                    ip_host.map(|ip| ip.ip),
+                    remote_addr,
+                    conn_strategy.timeout_connect,
+                    session_id,
+                )
+                .await
+                {
+                    Ok(smtp_client) => smtp_client,
+                    Err(_) => continue,
+                };
+                let params = SessionParams {
+                    server,
+                    hostname,
+                    credentials: None,
+                    is_smtp: remote_host.is_smtp(),
+                    local_hostname,
+                    conn_strategy,
+                    session_id,
+                    add_headers: &[],
+                    remove_headers: &[],
+                    return_path: "",
+                    rewrite_sender: None,
+                    rewrite_headers: &[],
+                    dkim_sign: &[],
+                    is_dry_run: false,
+                    tls_version: None,
+                };
+
+                let smtp_client = if !remote_host.implicit_tls() {
+                    smtp_client.timeout = conn_strategy.timeout_greeting;
+                    if smtp_client.read_greeting(hostname).await.is_err() {
+                        continue;
+                    }
+                    let capabilities = match smtp_client.say_helo(&params).await {
+                        Ok(capabilities) => capabilities,
+                        Err(_) => continue,
+                    };
+
+                    // Upgrade the connection as delivery would
+                    let tls_params = TlsParams {
+                        server,
+                        session_id,
+                        domain,
+                        hostname,
+                        local_ip: ip_host.map(|ip| ip.ip),
+                        remote_addr,
+                        tls_strategy,
+                        conn_strategy,
+                        tls_connector,
+                        is_strict_tls,
+                        mta_sts_policy: &mta_sts_policy,
+                        dane_policy: &dane_policy,
+                        tls_report: None,
+                        include_transcript: false,
+                        is_dry_run: false,
+                    };
+                    match smtp_client.negotiate_tls(&capabilities, &tls_params).await {
+                        Ok(smtp_client) => smtp_client,
+                        Err(_) => continue,
+                    }
+                } else {
+                    smtp_client.timeout = tls_strategy.timeout_tls;
+                    let mut smtp_client = match smtp_client.into_tls(tls_connector, hostname).await
+                    {
+                        Ok(smtp_client) => smtp_client,
+                        Err(_) => continue,
+                    };
+                    smtp_client.timeout = conn_strategy.timeout_greeting;
+                    if smtp_client.read_greeting(hostname).await.is_err() {
+                        continue;
+                    }
+                    SmtpConnection::Tls(smtp_client)
+                };
+
+                let result = match smtp_client {
+                    SmtpConnection::Plain(smtp_client) => {
+                        self.verify_rcpt(smtp_client, rcpt, &params).await
+                    }
+                    SmtpConnection::Tls(smtp_client) => {
+                        self.verify_rcpt(smtp_client, rcpt, &params).await
+                    }
+                };
+
+                // Try the next address if the host could not verify the recipient
+                if result != CalloutResult::Unavailable {
+                    return result;
+                }
+            }
+        }
+
+        CalloutResult::Unavailable
+    }
+
+    async fn verify_rcpt<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut smtp_client: SmtpClient<S>,
+        rcpt: &SessionAddress,
+        params: &SessionParams<'_>,
+    ) -> CalloutResult {
+        let result = match smtp_client.say_helo(params).await {
+            Ok(capabilities) if self.is_callout_loop(&capabilities, params.local_hostname) => {
+                CalloutResult::Loop
+            }
+            Ok(_) => {
+                smtp_client.timeout = params.conn_strategy.timeout_mail;
+                let result = match smtp_client.cmd(b"MAIL FROM:<>\r\n").await {
+                    Ok(response) if response.code == 250 => {
+                        smtp_client.timeout = params.conn_strategy.timeout_rcpt;
+                        match smtp_client
+                            .cmd(format!("RCPT TO:<{}>\r\n", rcpt.address))
+                            .await
+                        {
+                            Ok(response) if (200..300).contains(&response.code) => {
+                                CalloutResult::Valid
+                            }
+                            Ok(response) if (500..600).contains(&response.code) => {
+                                CalloutResult::Invalid
+                            }
+                            _ => CalloutResult::Unavailable,
+                        }
+                    }
+                    _ => CalloutResult::Unavailable,
+                };
+                let _ = smtp_client.cmd(b"RSET\r\n").await;
+                result
+            }
+            Err(_) => CalloutResult::Unavailable,
+        };
+        smtp_client.quit().await;
+
+        result
+    }
+
+    fn is_callout_loop(&self, capabilities: &EhloResponse<String>, local_hostname: &str) -> bool {
+        [
+            self.hostname.as_str(),
+            local_hostname,
+            self.server.core.network.server_name.as_str(),
+        ]
+        .iter()
+        .any(|hostname| capabilities.hostname.eq_ignore_ascii_case(hostname))
+    }
+}
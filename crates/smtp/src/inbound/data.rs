@@ -4,12 +4,12 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use super::{ArcSeal, AuthResult, DkimSign};
+use super::{ArcSeal, AuthResult, DkimSign, milter::Modification};
 use crate::{
     core::{Session, SessionAddress, State},
-    inbound::milter::Modification,
     queue::{
-        self, Message, MessageSource, MessageWrapper, QueueEnvelope, Schedule, quota::HasQueueQuota,
+        self, Message, MessageSource, MessageWrapper, QueueEnvelope, Schedule, TLS_OPTIONAL,
+        quota::HasQueueQuota,
     },
     reporting::analysis::AnalyzeReport,
     scripts::ScriptResult,
@@ -19,7 +19,7 @@ use common::{
         smtp::{
             auth::VerifyStrategy,
             queue::{QueueExpiry, QueueName},
-            session::Stage,
+            session::{BareLineEndings, Stage},
         },
         spamfilter::SpamFilterAction,
     },
@@ -36,7 +36,8 @@ use mail_builder::headers::{date::Date, message_id::generate_message_id_header};
 use mail_parser::MessageParser;
 use sieve::runtime::Variable;
 use smtp_proto::{
-    MAIL_BY_RETURN, RCPT_NOTIFY_DELAY, RCPT_NOTIFY_FAILURE, RCPT_NOTIFY_NEVER, RCPT_NOTIFY_SUCCESS,
+    MAIL_BODY_BINARYMIME, MAIL_BY_RETURN, MAIL_REQUIRETLS, RCPT_NOTIFY_DELAY, RCPT_NOTIFY_FAILURE,
+    RCPT_NOTIFY_NEVER, RCPT_NOTIFY_SUCCESS,
 };
 use std::{
     borrow::Cow,
@@ -47,6 +48,11 @@ use utils::config::Rate;
 
 impl<T: SessionStream> Session<T> {
     pub async fn queue_message(&mut self) -> Cow<'static, [u8]> {
+        // Normalize line endings and enforce line length limits
+        if let Err(response) = self.normalize_lines() {
+            return response;
+        }
+
         // Parse message
         let raw_message = std::mem::take(&mut self.data.message);
         let parsed_message = match MessageParser::new()
@@ -71,6 +77,38 @@ impl<T: SessionStream> Session<T> {
         );
         let has_date_header = auth_message.has_date_header();
         let has_message_id_header = auth_message.has_message_id_header();
+        let has_tls_optional_header = parsed_message
+            .header("TLS-Required")
+            .and_then(|header| header.as_text())
+            .is_some_and(|value| value.trim().eq_ignore_ascii_case("no"));
+        let message_id_header = parsed_message.message_id().map(|id| id.to_string());
+        let subject_header = parsed_message.subject().map(|subject| subject.to_string());
+        let queue_headers = self
+            .server
+            .core
+            .smtp
+            .queue
+            .headers
+            .iter()
+            .filter_map(|name| {
+                parsed_message
+                    .headers()
+                    .iter()
+                    .find(|header| header.name().eq_ignore_ascii_case(name))
+                    .map(|header| {
+                        (
+                            name.clone(),
+                            String::from_utf8_lossy(
+                                raw_message
+                                    .get(header.offset_start as usize..header.offset_end as usize)
+                                    .unwrap_or_default(),
+                            )
+                            .trim()
+                            .to_string(),
+                        )
+                    })
+            })
+            .collect::<Vec<_>>();
 
         // Loop detection
         let dc = &self.server.core.smtp.session.data;
@@ -269,7 +307,8 @@ impl<T: SessionStream> Session<T> {
                 let pass = matches!(dmarc_output.spf_result(), DmarcResult::Pass)
                     || matches!(dmarc_output.dkim_result(), DmarcResult::Pass);
                 let strict = dmarc.is_strict();
-                let rejected = strict && dmarc_output.policy() == dmarc::Policy::Reject && !pass;
+                let mut rejected =
+                    strict && dmarc_output.policy() == dmarc::Policy::Reject && !pass;
                 let is_temp_fail = rejected
                     && matches!(dmarc_output.spf_result(), DmarcResult::TempError(_))
                     || matches!(dmarc_output.dkim_result(), DmarcResult::TempError(_));
@@ -301,6 +340,27 @@ impl<T: SessionStream> Session<T> {
                     Elapsed = time.elapsed(),
                 );
 
+                // Do not enforce the policy on mail relayed by trusted forwarders,
+                // such as mailing lists, which commonly break DKIM and SPF alignment
+                if rejected
+                    && self
+                        .server
+                        .eval_if(&ac.dmarc.trusted_forwarder, self, self.data.session_id)
+                        .await
+                        .unwrap_or(false)
+                {
+                    trc::event!(
+                        Smtp(SmtpEvent::DmarcOverride),
+                        SpanId = self.data.session_id,
+                        Domain = dmarc_output.domain().to_string(),
+                        Policy = dmarc_policy.to_string(),
+                        RemoteIp = self.data.remote_ip,
+                        Details = self.data.helo_domain.clone(),
+                    );
+
+                    rejected = false;
+                }
+
                 // Send DMARC report
                 if dmarc_output.requested_reports() && !is_report {
                     self.send_dmarc_report(
@@ -405,20 +465,61 @@ impl<T: SessionStream> Session<T> {
             }
         }
 
+        // Generate any missing headers
+        let mut missing_headers = Vec::new();
+        if !has_date_header
+            && self
+                .server
+                .eval_if(&dc.add_date, self, self.data.session_id)
+                .await
+                .unwrap_or(true)
+        {
+            missing_headers.extend_from_slice(b"Date: ");
+            missing_headers.extend_from_slice(Date::now().to_rfc822().as_bytes());
+            missing_headers.extend_from_slice(b"\r\n");
+        }
+        if !has_message_id_header
+            && self
+                .server
+                .eval_if(&dc.add_message_id, self, self.data.session_id)
+                .await
+                .unwrap_or(true)
+        {
+            missing_headers.extend_from_slice(b"Message-ID: ");
+            let _ = generate_message_id_header(&mut missing_headers, &self.hostname);
+            missing_headers.extend_from_slice(b"\r\n");
+        }
+
         // ARC Seal
-        if let (Some(arc_sealer), Some(arc_output)) = (arc_sealer, &arc_output) {
-            if !dkim_output.is_empty() && arc_output.can_be_sealed() {
-                match arc_sealer.seal(&auth_message, &auth_results, arc_output) {
-                    Ok(set) => {
-                        set.write_header(&mut headers);
-                    }
-                    Err(err) => {
-                        trc::error!(
-                            trc::Error::from(err)
-                                .span_id(self.data.session_id)
-                                .details("Failed to ARC seal message")
-                        );
-                    }
+        if let (Some(arc_sealer), Some(arc_output)) = (arc_sealer, &arc_output)
+            && !dkim_output.is_empty()
+            && arc_output.can_be_sealed()
+        {
+            // The seal has to cover the missing headers added below, otherwise
+            // they would break the ARC-Message-Signature
+            let sealed_message = (!missing_headers.is_empty())
+                .then(|| [missing_headers.as_slice(), raw_message.as_slice()].concat());
+            let sealed_auth_message = sealed_message.as_deref().and_then(|message| {
+                AuthenticatedMessage::parse_with_opts(
+                    message,
+                    self.server.core.smtp.mail_auth.dkim.strict,
+                )
+            });
+
+            match arc_sealer.seal(
+                sealed_auth_message.as_ref().unwrap_or(&auth_message),
+                &auth_results,
+                arc_output,
+            ) {
+                Ok(set) => {
+                    set.write_header(&mut headers);
+                }
+                Err(err) => {
+                    trc::error!(
+                        trc::Error::from(err)
+                            .span_id(self.data.session_id)
+                            .details("Failed to ARC seal message")
+                    );
                 }
             }
         }
@@ -459,41 +560,50 @@ impl<T: SessionStream> Session<T> {
         }
 
         // Run Milter filters
-        let mut modifications = Vec::new();
-        match self.run_milters(Stage::Data, (&auth_message).into()).await {
-            Ok(modifications_) => {
-                if !modifications_.is_empty() {
-                    modifications = modifications_;
+        let (mut modifications, milter_message) =
+            match self.run_milters(Stage::Data, (&auth_message).into()).await {
+                Ok(result) => result,
+                Err(response) => {
+                    return response.into_bytes();
                 }
-            }
-            Err(response) => {
-                return response.into_bytes();
-            }
-        };
+            };
 
-        // Run MTA Hooks
-        match self
-            .run_mta_hooks(Stage::Data, (&auth_message).into(), message_id.into())
-            .await
-        {
-            Ok(modifications_) => {
-                if !modifications_.is_empty() {
-                    modifications.retain(|m| !matches!(m, Modification::ReplaceBody { .. }));
+        let mut is_quarantined = false;
+        let mut edited_message = {
+            // MTA Hooks see the message as modified by the milters
+            let milter_auth_message = milter_message
+                .as_deref()
+                .and_then(AuthenticatedMessage::parse);
+            let auth_message = milter_auth_message.as_ref().unwrap_or(&auth_message);
+
+            // Run MTA Hooks
+            match self
+                .run_mta_hooks(Stage::Data, auth_message.into(), message_id.into())
+                .await
+            {
+                Ok(modifications_) => {
                     modifications.extend(modifications_);
                 }
+                Err(response) => {
+                    return response.into_bytes();
+                }
+            };
+
+            // Apply modifications
+            if modifications
+                .iter()
+                .any(|m| matches!(m, Modification::Quarantine { .. }))
+            {
+                is_quarantined = true;
             }
-            Err(response) => {
-                return response.into_bytes();
+            if !modifications.is_empty() {
+                self.data
+                    .apply_milter_modifications(modifications, auth_message)
+            } else {
+                None
             }
-        };
-
-        // Apply modifications
-        let mut edited_message = if !modifications.is_empty() {
-            self.data
-                .apply_milter_modifications(modifications, &auth_message)
-        } else {
-            None
-        };
+        }
+        .or(milter_message);
 
         // Sieve filtering
         if let Some((script, script_id)) = self
@@ -586,6 +696,9 @@ impl<T: SessionStream> Session<T> {
                     ScriptModification::SetEnvelope { name, value } => {
                         self.data.apply_envelope_modification(name, value);
                     }
+                    ScriptModification::Quarantine => {
+                        is_quarantined = true;
+                    }
                 }
             }
         }
@@ -594,9 +707,30 @@ impl<T: SessionStream> Session<T> {
         let mail_from = self.data.mail_from.clone().unwrap();
         let rcpt_to = std::mem::take(&mut self.data.rcpt_to);
         let mut message = self
-            .build_message(mail_from, rcpt_to, message_id, self.data.session_id)
+            .build_message(
+                mail_from,
+                rcpt_to,
+                queue_headers,
+                message_id,
+                self.data.session_id,
+            )
             .await;
 
+        // Suspicious messages are held until released
+        if is_quarantined {
+            message.quarantine();
+            trc::event!(
+                Queue(trc::QueueEvent::Quarantined),
+                SpanId = self.data.session_id,
+                QueueId = message.queue_id,
+            );
+        }
+
+        // As per RFC8689 Section 5, the TLS-Required header is ignored when REQUIRETLS is set
+        if has_tls_optional_header && (message.message.flags & MAIL_REQUIRETLS) == 0 {
+            message.message.flags |= TLS_OPTIONAL;
+        }
+
         // Add Return-Path
         if self
             .server
@@ -610,28 +744,7 @@ impl<T: SessionStream> Session<T> {
         }
 
         // Add any missing headers
-        if !has_date_header
-            && self
-                .server
-                .eval_if(&dc.add_date, self, self.data.session_id)
-                .await
-                .unwrap_or(true)
-        {
-            headers.extend_from_slice(b"Date: ");
-            headers.extend_from_slice(Date::now().to_rfc822().as_bytes());
-            headers.extend_from_slice(b"\r\n");
-        }
-        if !has_message_id_header
-            && self
-                .server
-                .eval_if(&dc.add_message_id, self, self.data.session_id)
-                .await
-                .unwrap_or(true)
-        {
-            headers.extend_from_slice(b"Message-ID: ");
-            let _ = generate_message_id_header(&mut headers, &self.hostname);
-            headers.extend_from_slice(b"\r\n");
-        }
+        headers.extend_from_slice(&missing_headers);
 
         // DKIM sign
         let raw_message = edited_message.as_deref().unwrap_or(raw_message.as_slice());
@@ -660,12 +773,49 @@ impl<T: SessionStream> Session<T> {
         // Update size
         message.message.size = (raw_message.len() + headers.len()) as u64;
 
+        // Verify the account's sending quota, which is only consumed once
+        // the message is also within the queue quota
+        let send_quota_exceeded = || -> Cow<'static, [u8]> {
+            if dc.send_quota_hard {
+                (b"552 5.3.4 Sending quota exceeded.\r\n"[..]).into()
+            } else {
+                (b"451 4.3.1 Sending quota exceeded, try again later.\r\n"[..]).into()
+            }
+        };
+        if !self.has_send_quota(true).await {
+            return send_quota_exceeded();
+        }
+
         // Verify queue quota
         if self.server.has_quota(&mut message).await {
+            if !self.has_send_quota(false).await {
+                return send_quota_exceeded();
+            }
+
             // Prepare webhook event
             let queue_id = message.queue_id;
+            let accepted_event = if self
+                .server
+                .eval_if(&dc.notify_accepted, self, self.data.session_id)
+                .await
+                .unwrap_or(false)
+            {
+                Some((
+                    message.message.return_path.clone(),
+                    message
+                        .message
+                        .recipients
+                        .iter()
+                        .map(|r| r.address.clone())
+                        .collect::<Vec<_>>(),
+                    message.message.size,
+                ))
+            } else {
+                None
+            };
 
             // Queue message
+            let return_path = message.message.return_path_lcase.clone();
             let source = if !self.is_authenticated() {
                 MessageSource::Unauthenticated(
                     dmarc_result.is_some_and(|result| result == DmarcResult::Pass),
@@ -683,6 +833,24 @@ impl<T: SessionStream> Session<T> {
                 )
                 .await
             {
+                if let Some((from, to, size)) = accepted_event {
+                    trc::event!(
+                        Queue(trc::QueueEvent::MessageAccepted),
+                        SpanId = self.data.session_id,
+                        QueueId = queue_id,
+                        From = if !from.is_empty() { from } else { "<>".into() },
+                        To = to,
+                        MessageId = message_id_header,
+                        Details = subject_header,
+                        Size = size,
+                    );
+                }
+
+                // Allow bounces addressed to this sender
+                if self.is_authenticated() {
+                    self.add_bounce_correlation(&return_path).await;
+                }
+
                 self.state = State::Accepted(queue_id);
                 self.data.messages_sent += 1;
                 format!("250 2.0.0 Message queued with id {queue_id:x}.\r\n")
@@ -700,6 +868,7 @@ impl<T: SessionStream> Session<T> {
         &self,
         mail_from: SessionAddress,
         mut rcpt_to: Vec<SessionAddress>,
+        headers: Vec<(String, String)>,
         queue_id: u64,
         span_id: u64,
     ) -> MessageWrapper {
@@ -721,6 +890,8 @@ impl<T: SessionStream> Session<T> {
             quota_keys: Vec::new(),
             received_from_ip: self.data.remote_ip,
             received_via_port: self.data.local_port,
+            headers,
+            submission: self.data.submission,
         };
 
         // Add recipients
@@ -743,10 +914,13 @@ impl<T: SessionStream> Session<T> {
                     rcpt.flags | RCPT_NOTIFY_DELAY | RCPT_NOTIFY_FAILURE
                 },
                 orcpt: rcpt.dsn_info,
+                tls: None,
+                transcript: None,
                 retry: Schedule::now(),
                 notify: Schedule::now(),
                 expires: QueueExpiry::Count(0),
                 queue: QueueName::default(),
+                next_hop: None,
             });
 
             let envelope = QueueEnvelope::new(&message, message.recipients.last().unwrap());
@@ -821,11 +995,21 @@ impl<T: SessionStream> Session<T> {
             };
 
             // Update recipient
+            let created = message.created;
             let recipient = message.recipients.last_mut().unwrap();
             recipient.retry = retry;
             recipient.notify = notify;
             recipient.expires = expires;
             recipient.queue = queue.virtual_queue;
+
+            // Messages received outside the delivery window wait until it opens,
+            // but never past their expiration
+            if let Some(window) = &queue.window {
+                recipient.retry.due = window.next_open(recipient.retry.due);
+                if let Some(expires_at) = recipient.expiration_time(created) {
+                    recipient.retry.due = std::cmp::min(recipient.retry.due, expires_at);
+                }
+            }
         }
 
         MessageWrapper {
@@ -873,6 +1057,121 @@ impl<T: SessionStream> Session<T> {
         }
     }
 
+    fn normalize_lines(&mut self) -> Result<(), Cow<'static, [u8]>> {
+        let dc = &self.server.core.smtp.session.data;
+        if (dc.bare_line_endings == BareLineEndings::Allow
+            && dc.max_line_length == 0
+            && !dc.reject_smuggling)
+            || self
+                .data
+                .mail_from
+                .as_ref()
+                .is_some_and(|mail_from| (mail_from.flags & MAIL_BODY_BINARYMIME) != 0)
+        {
+            return Ok(());
+        }
+
+        // Reject end-of-data sequences delimited by bare CR or LF characters
+        let message = &self.data.message;
+        if dc.reject_smuggling && has_smuggling_sequence(message) {
+            trc::event!(
+                Smtp(SmtpEvent::SmugglingDetected),
+                SpanId = self.data.session_id,
+            );
+
+            return Err(
+                (&b"554 5.7.0 Message rejected, possible SMTP smuggling attempt.\r\n"[..]).into(),
+            );
+        }
+
+        // Bare CR and LF characters are counted as line endings
+        let mut has_bare_endings = false;
+        let mut line_length = 0;
+        let mut max_line_length = 0;
+        for (pos, &ch) in message.iter().enumerate() {
+            match ch {
+                b'\n' => {
+                    has_bare_endings |= pos == 0 || message[pos - 1] != b'\r';
+                    max_line_length = std::cmp::max(max_line_length, line_length);
+                    line_length = 0;
+                }
+                b'\r' if message.get(pos + 1) != Some(&b'\n') => {
+                    has_bare_endings = true;
+                    max_line_length = std::cmp::max(max_line_length, line_length);
+                    line_length = 0;
+                }
+                b'\r' => (),
+                _ => {
+                    line_length += 1;
+                }
+            }
+        }
+        max_line_length = std::cmp::max(max_line_length, line_length);
+
+        if has_bare_endings {
+            match dc.bare_line_endings {
+                BareLineEndings::Reject => {
+                    trc::event!(
+                        Smtp(SmtpEvent::BareLineEnding),
+                        SpanId = self.data.session_id,
+                        Details = "reject",
+                    );
+
+                    return Err(
+                        (&b"550 5.6.0 Message contains bare CR or LF characters.\r\n"[..]).into(),
+                    );
+                }
+                BareLineEndings::Convert => {
+                    trc::event!(
+                        Smtp(SmtpEvent::BareLineEnding),
+                        SpanId = self.data.session_id,
+                        Details = "convert",
+                    );
+
+                    let mut normalized = Vec::with_capacity(message.len() + 64);
+                    let mut iter = message.iter().peekable();
+                    while let Some(&ch) = iter.next() {
+                        match ch {
+                            b'\r' => {
+                                if iter.peek() == Some(&&b'\n') {
+                                    iter.next();
+                                }
+                                normalized.extend_from_slice(b"\r\n");
+                            }
+                            b'\n' => {
+                                normalized.extend_from_slice(b"\r\n");
+                            }
+                            _ => {
+                                normalized.push(ch);
+                            }
+                        }
+                    }
+                    self.data.message = normalized;
+                }
+                BareLineEndings::Allow => (),
+            }
+        }
+
+        // RFC 5321 limits lines to 998 octets excluding the CRLF
+        if dc.max_line_length > 0 && max_line_length > dc.max_line_length {
+            trc::event!(
+                Smtp(SmtpEvent::LineTooLong),
+                SpanId = self.data.session_id,
+                Size = max_line_length,
+                Limit = dc.max_line_length,
+            );
+
+            return Err(format!(
+                "550 5.6.0 Line length exceeds the maximum of {} octets.\r\n",
+                dc.max_line_length
+            )
+            .into_bytes()
+            .into());
+        }
+
+        Ok(())
+    }
+
     fn write_received(&self, headers: &mut Vec<u8>, id: u64) {
         headers.extend_from_slice(b"Received: from ");
         headers.extend_from_slice(self.data.helo_domain.as_bytes());
@@ -932,3 +1231,23 @@ impl<T: SessionStream> Session<T> {
         headers.extend_from_slice(b"\r\n");
     }
 }
+
+fn has_smuggling_sequence(message: &[u8]) -> bool {
+    message.iter().enumerate().any(|(pos, &ch)| {
+        if ch != b'.' {
+            return false;
+        }
+        let (has_eol_before, is_bare_before) = match message.get(..pos) {
+            Some(prefix) if prefix.ends_with(b"\r\n") => (true, false),
+            Some([.., b'\r' | b'\n']) => (true, true),
+            _ => (false, false),
+        };
+        let (has_eol_after, is_bare_after) = match message.get(pos + 1..) {
+            Some(suffix) if suffix.starts_with(b"\r\n") => (true, false),
+            Some([b'\r' | b'\n', ..]) => (true, true),
+            _ => (false, false),
+        };
+
+        has_eol_before && has_eol_after && (is_bare_before || is_bare_after)
+    })
+}
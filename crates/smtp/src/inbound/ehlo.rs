@@ -16,7 +16,7 @@ use mail_auth::{
     SpfResult,
     spf::verify::{HasValidLabels, SpfParameters},
 };
-use smtp_proto::*;
+use smtp_proto::{response::generate::BitToString, *};
 use trc::SmtpEvent;
 
 impl<T: SessionStream> Session<T> {
@@ -286,7 +286,114 @@ impl<T: SessionStream> Session<T> {
 
         // Generate response
         let mut buf = Vec::with_capacity(64);
-        response.write(&mut buf).ok();
+        if ec.order.is_empty() && ec.custom.is_empty() {
+            response.write(&mut buf).ok();
+        } else {
+            write_capabilities(&mut buf, &response, &ec.order, &ec.custom);
+        }
         self.write(&buf).await
     }
 }
+
+// Writes an EHLO response listing the configured keywords first, followed by
+// the remaining ones in their default order.
+fn write_capabilities(
+    buf: &mut Vec<u8>,
+    response: &EhloResponse<&str>,
+    order: &[String],
+    custom: &[String],
+) {
+    let mut capabilities = Vec::with_capacity(custom.len() + 16);
+    let mut pending = response.capabilities;
+    while pending != 0 {
+        let capability = 1 << (31 - pending.leading_zeros());
+        pending ^= capability;
+        if let Some(line) = capability_line(response, capability) {
+            capabilities.push(line);
+        }
+    }
+    capabilities.extend(custom.iter().cloned());
+    capabilities.sort_by_key(|capability| {
+        let keyword = capability
+            .split_once(' ')
+            .map_or(capability.as_str(), |(k, _)| k);
+        order
+            .iter()
+            .position(|item| item.eq_ignore_ascii_case(keyword))
+            .unwrap_or(order.len())
+    });
+
+    buf.extend_from_slice(b"250");
+    buf.push(if capabilities.is_empty() { b' ' } else { b'-' });
+    buf.extend_from_slice(response.hostname.as_bytes());
+    buf.extend_from_slice(b" you had me at EHLO\r\n");
+    let mut capabilities = capabilities.into_iter().peekable();
+    while let Some(capability) = capabilities.next() {
+        buf.extend_from_slice(if capabilities.peek().is_some() {
+            b"250-"
+        } else {
+            b"250 "
+        });
+        buf.extend_from_slice(capability.as_bytes());
+        buf.extend_from_slice(b"\r\n");
+    }
+}
+
+fn capability_line(response: &EhloResponse<&str>, capability: u32) -> Option<String> {
+    Some(match capability {
+        EXT_8BIT_MIME => "8BITMIME".into(),
+        EXT_ATRN => "ATRN".into(),
+        EXT_AUTH => {
+            let mut line = String::from("AUTH");
+            let mut mechanisms = response.auth_mechanisms;
+            while mechanisms != 0 {
+                let item = 1 << (63 - mechanisms.leading_zeros());
+                mechanisms ^= item;
+                line.push(' ');
+                line.push_str(item.to_mechanism());
+            }
+            line
+        }
+        EXT_BINARY_MIME => "BINARYMIME".into(),
+        EXT_BURL => "BURL".into(),
+        EXT_CHECKPOINT => "CHECKPOINT".into(),
+        EXT_CHUNKING => "CHUNKING".into(),
+        EXT_CONNEG => "CONNEG".into(),
+        EXT_CONPERM => "CONPERM".into(),
+        EXT_DELIVER_BY if response.deliver_by > 0 => format!("DELIVERBY {}", response.deliver_by),
+        EXT_DELIVER_BY => "DELIVERBY".into(),
+        EXT_DSN => "DSN".into(),
+        EXT_ENHANCED_STATUS_CODES => "ENHANCEDSTATUSCODES".into(),
+        EXT_ETRN => "ETRN".into(),
+        EXT_EXPN => "EXPN".into(),
+        EXT_VRFY => "VRFY".into(),
+        EXT_FUTURE_RELEASE => format!(
+            "FUTURERELEASE {} {}",
+            response.future_release_interval, response.future_release_datetime
+        ),
+        EXT_HELP => "HELP".into(),
+        EXT_MT_PRIORITY => format!(
+            "MT-PRIORITY {}",
+            match response.mt_priority {
+                MtPriority::Mixer => "MIXER",
+                MtPriority::Stanag4406 => "STANAG4406",
+                MtPriority::Nsep => "NSEP",
+            }
+        ),
+        EXT_MTRK => "MTRK".into(),
+        EXT_NO_SOLICITING => match &response.no_soliciting {
+            Some(keywords) => format!("NO-SOLICITING {keywords}"),
+            None => "NO-SOLICITING".into(),
+        },
+        EXT_ONEX => "ONEX".into(),
+        EXT_PIPELINING => "PIPELINING".into(),
+        EXT_REQUIRE_TLS => "REQUIRETLS".into(),
+        EXT_RRVS => "RRVS".into(),
+        EXT_SIZE if response.size > 0 => format!("SIZE {}", response.size),
+        EXT_SIZE => "SIZE".into(),
+        EXT_SMTP_UTF8 => "SMTPUTF8".into(),
+        EXT_START_TLS => "STARTTLS".into(),
+        EXT_VERB => "VERB".into(),
+        _ => return None,
+    })
+}
@@ -131,9 +131,8 @@ impl<T: SessionStream> Session<T> {
                         Action::Discard => FilterResponse::accept(),
                         Action::Reject => FilterResponse::reject(),
                         Action::Quarantine => {
-                            modifications.push(Modification::AddHeader {
-                                name: "X-Quarantine".into(),
-                                value: "true".into(),
+                            modifications.push(Modification::Quarantine {
+                                reason: "true".into(),
                             });
                             FilterResponse::accept()
                         }
@@ -4,7 +4,10 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use std::time::{Duration, Instant, SystemTime};
+use std::{
+    sync::atomic::Ordering,
+    time::{Duration, Instant, SystemTime},
+};
 
 use common::{config::smtp::session::Stage, listener::SessionStream, scripts::ScriptModification};
 
@@ -52,6 +55,22 @@ impl<T: SessionStream> Session<T> {
             return self
                 .write(b"503 5.5.1 You must authenticate first.\r\n")
                 .await;
+        } else if self
+            .server
+            .inner
+            .data
+            .queue_draining
+            .load(Ordering::Relaxed)
+        {
+            trc::event!(
+                Smtp(SmtpEvent::MailFromNotAllowed),
+                SpanId = self.data.session_id,
+                Reason = "Queue is draining",
+            );
+
+            return self
+                .write(b"452 4.3.2 Mail system is not accepting messages, try again later.\r\n")
+                .await;
         } else if self.data.iprev.is_none() && self.params.iprev.verify() {
             let time = Instant::now();
             let iprev = self
@@ -35,13 +35,14 @@ impl<T: SessionStream> Session<T> {
         &self,
         stage: Stage,
         message: Option<&AuthenticatedMessage<'_>>,
-    ) -> Result<Vec<Modification>, FilterResponse> {
+    ) -> Result<(Vec<Modification>, Option<Vec<u8>>), FilterResponse> {
         let milters = &self.server.core.smtp.session.milters;
         if milters.is_empty() {
-            return Ok(Vec::new());
+            return Ok((Vec::new(), None));
         }
 
         let mut modifications = Vec::new();
+        let mut edited_message: Option<Vec<u8>> = None;
         for milter in milters {
             if !milter.run_on_stage.contains(&stage)
                 || !self
@@ -53,8 +54,14 @@ impl<T: SessionStream> Session<T> {
                 continue;
             }
 
+            // Milters further down the chain see the changes made by previous ones
+            let edited_auth_message = edited_message
+                .as_deref()
+                .and_then(AuthenticatedMessage::parse);
+            let milter_message = edited_auth_message.as_ref().or(message);
+
             let time = Instant::now();
-            match self.connect_and_run(milter, message).await {
+            match self.connect_and_run(milter, milter_message).await {
                 Ok(new_modifications) => {
                     trc::event!(
                         Milter(MilterEvent::ActionAccept),
@@ -63,19 +70,19 @@ impl<T: SessionStream> Session<T> {
                         Elapsed = time.elapsed(),
                     );
 
-                    if !modifications.is_empty() {
-                        // The message body can only be replaced once, so we need to remove
-                        // any previous replacements.
-                        if new_modifications
-                            .iter()
-                            .any(|m| matches!(m, Modification::ReplaceBody { .. }))
-                        {
-                            modifications
-                                .retain(|m| !matches!(m, Modification::ReplaceBody { .. }));
-                        }
-                        modifications.extend(new_modifications);
-                    } else {
-                        modifications = new_modifications;
+                    let (envelope_modifications, message_modifications): (Vec<_>, Vec<_>) =
+                        new_modifications
+                            .into_iter()
+                            .partition(Modification::is_envelope_change);
+                    modifications.extend(envelope_modifications);
+                    if let Some(new_message) = milter_message
+                        .filter(|_| !message_modifications.is_empty())
+                        .and_then(|message| {
+                            apply_message_modifications(message_modifications, message)
+                        })
+                    {
+                        drop(edited_auth_message);
+                        edited_message = Some(new_message);
                     }
                 }
                 Err(Rejection::Action(action)) => {
@@ -153,7 +160,7 @@ impl<T: SessionStream> Session<T> {
             }
         }
 
-        Ok(modifications)
+        Ok((modifications, edited_message))
     }
 
     async fn connect_and_run(
@@ -296,9 +303,7 @@ impl SessionData {
         modifications: Vec<Modification>,
         message: &AuthenticatedMessage<'_>,
     ) -> Option<Vec<u8>> {
-        let mut body = Vec::new();
-        let mut header_changes = Vec::new();
-        let mut needs_rewrite = false;
+        let mut message_modifications = Vec::new();
 
         for modification in modifications {
             match modification {
@@ -378,147 +383,184 @@ impl SessionData {
                     let recipient = strip_brackets(&recipient);
                     self.rcpt_to.retain(|r| r.address_lcase != recipient);
                 }
-                Modification::ReplaceBody { value } => {
-                    body.extend(value);
-                }
-                Modification::AddHeader { name, value } => {
-                    header_changes.push((0, name, value, false));
-                }
-                Modification::InsertHeader { index, name, value } => {
-                    header_changes.push((index, name, value, false));
-                    needs_rewrite = true;
-                }
-                Modification::ChangeHeader { index, name, value } => {
-                    if value.is_empty()
-                        || message
-                            .raw_parsed_headers()
-                            .iter()
-                            .any(|(n, _)| n.eq_ignore_ascii_case(name.as_bytes()))
-                    {
-                        header_changes.push((index, name, value, true));
-                        needs_rewrite = true;
-                    } else {
-                        header_changes.push((0, name, value, false));
-                    }
-                }
-                Modification::Quarantine { reason } => {
-                    header_changes.push((0, "X-Quarantine".into(), reason, false));
+                modification => {
+                    message_modifications.push(modification);
                 }
             }
         }
 
-        // If there are no header changes return
-        if header_changes.is_empty() {
-            return if !body.is_empty() {
-                let mut new_message = Vec::with_capacity(body.len() + message.raw_headers().len());
-                new_message.extend_from_slice(message.raw_headers());
-                new_message.extend(body);
-                Some(new_message)
-            } else {
-                None
-            };
+        if !message_modifications.is_empty() {
+            apply_message_modifications(message_modifications, message)
+        } else {
+            None
+        }
+    }
+}
+
+fn apply_message_modifications(
+    modifications: Vec<Modification>,
+    message: &AuthenticatedMessage<'_>,
+) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    let mut header_changes = Vec::new();
+    let mut needs_rewrite = false;
+
+    for modification in modifications {
+        match modification {
+            Modification::ChangeFrom { .. }
+            | Modification::AddRcpt { .. }
+            | Modification::DeleteRcpt { .. } => {}
+            Modification::ReplaceBody { value } => {
+                body.extend(value);
+            }
+            Modification::AddHeader { name, value } => {
+                header_changes.push((0, name, value, false));
+            }
+            Modification::InsertHeader { index, name, value } => {
+                header_changes.push((index, name, value, false));
+                needs_rewrite = true;
+            }
+            Modification::ChangeHeader { index, name, value } => {
+                if value.is_empty()
+                    || message
+                        .raw_parsed_headers()
+                        .iter()
+                        .any(|(n, _)| n.eq_ignore_ascii_case(name.as_bytes()))
+                {
+                    header_changes.push((index, name, value, true));
+                    needs_rewrite = true;
+                } else {
+                    header_changes.push((0, name, value, false));
+                }
+            }
+            Modification::Quarantine { reason } => {
+                header_changes.push((0, "X-Quarantine".into(), reason, false));
+            }
         }
+    }
 
-        let new_body = if !body.is_empty() {
-            &body[..]
+    // If there are no header changes return
+    if header_changes.is_empty() {
+        return if !body.is_empty() {
+            let mut new_message = Vec::with_capacity(body.len() + message.raw_headers().len());
+            new_message.extend_from_slice(message.raw_headers());
+            new_message.extend(body);
+            Some(new_message)
         } else {
-            message.raw_body()
+            None
         };
+    }
 
-        if needs_rewrite {
-            let mut headers = message
-                .raw_parsed_headers()
-                .iter()
-                .map(|(h, v)| (Cow::from(*h), Cow::from(*v)))
-                .collect::<Vec<_>>();
-
-            // Perform changes
-            for (index, header_name, header_value, is_change) in header_changes {
-                if is_change {
+    let new_body = if !body.is_empty() {
+        &body[..]
+    } else {
+        message.raw_body()
+    };
+
+    if needs_rewrite {
+        let mut headers = message
+            .raw_parsed_headers()
+            .iter()
+            .map(|(h, v)| (Cow::from(*h), Cow::from(*v)))
+            .collect::<Vec<_>>();
+
+        // Perform changes
+        for (index, header_name, header_value, is_change) in header_changes {
+            if is_change {
+                let mut header_count = 0;
+                for (pos, (name, value)) in headers.iter_mut().enumerate() {
+                    if name.eq_ignore_ascii_case(header_name.as_bytes()) {
+                        header_count += 1;
+                        if header_count == index {
+                            if !header_value.is_empty() {
+                                *value = Cow::from(header_value.as_bytes().to_vec());
+                            } else {
+                                headers.remove(pos);
+                            }
+                            break;
+                        }
+                    }
+                }
+            } else {
+                let mut header_pos = 0;
+                if index > 0 {
                     let mut header_count = 0;
-                    for (pos, (name, value)) in headers.iter_mut().enumerate() {
+                    for (pos, (name, _)) in headers.iter().enumerate() {
                         if name.eq_ignore_ascii_case(header_name.as_bytes()) {
+                            header_pos = pos;
                             header_count += 1;
                             if header_count == index {
-                                if !header_value.is_empty() {
-                                    *value = Cow::from(header_value.as_bytes().to_vec());
-                                } else {
-                                    headers.remove(pos);
-                                }
                                 break;
                             }
                         }
                     }
-                } else {
-                    let mut header_pos = 0;
-                    if index > 0 {
-                        let mut header_count = 0;
-                        for (pos, (name, _)) in headers.iter().enumerate() {
-                            if name.eq_ignore_ascii_case(header_name.as_bytes()) {
-                                header_pos = pos;
-                                header_count += 1;
-                                if header_count == index {
-                                    break;
-                                }
-                            }
-                        }
-                    }
-
-                    headers.insert(
-                        header_pos,
-                        (
-                            Cow::from(header_name.as_bytes().to_vec()),
-                            Cow::from(header_value.as_bytes().to_vec()),
-                        ),
-                    );
                 }
-            }
 
-            // Write new headers
-            let mut new_message = Vec::with_capacity(
-                new_body.len()
-                    + message.raw_headers().len()
-                    + headers
-                        .iter()
-                        .map(|(h, v)| h.len() + v.len() + 4)
-                        .sum::<usize>(),
-            );
-            for (header, value) in headers {
-                new_message.extend_from_slice(header.as_ref());
-                if value.first().is_some_and(|c| c.is_ascii_whitespace()) {
-                    new_message.extend_from_slice(b":");
-                } else {
-                    new_message.extend_from_slice(b": ");
-                }
-                new_message.extend_from_slice(value.as_ref());
-                if value.last().is_none_or(|c| *c != b'\n') {
-                    new_message.extend_from_slice(b"\r\n");
-                }
+                headers.insert(
+                    header_pos,
+                    (
+                        Cow::from(header_name.as_bytes().to_vec()),
+                        Cow::from(header_value.as_bytes().to_vec()),
+                    ),
+                );
             }
-            new_message.extend_from_slice(b"\r\n");
-            new_message.extend(new_body);
-            Some(new_message)
-        } else {
-            let mut new_message = Vec::with_capacity(
-                new_body.len()
-                    + message.raw_headers().len()
-                    + header_changes
-                        .iter()
-                        .map(|(_, h, v, _)| h.len() + v.len() + 4)
-                        .sum::<usize>(),
-            );
-            for (_, header, value, _) in header_changes {
-                new_message.extend_from_slice(header.as_bytes());
+        }
+
+        // Write new headers
+        let mut new_message = Vec::with_capacity(
+            new_body.len()
+                + message.raw_headers().len()
+                + headers
+                    .iter()
+                    .map(|(h, v)| h.len() + v.len() + 4)
+                    .sum::<usize>(),
+        );
+        for (header, value) in headers {
+            new_message.extend_from_slice(header.as_ref());
+            if value.first().is_some_and(|c| c.is_ascii_whitespace()) {
+                new_message.extend_from_slice(b":");
+            } else {
                 new_message.extend_from_slice(b": ");
-                new_message.extend_from_slice(value.as_bytes());
-                if !value.ends_with('\n') {
-                    new_message.extend_from_slice(b"\r\n");
-                }
             }
-            new_message.extend_from_slice(message.raw_headers());
-            new_message.extend(new_body);
-            Some(new_message)
+            new_message.extend_from_slice(value.as_ref());
+            if value.last().is_none_or(|c| *c != b'\n') {
+                new_message.extend_from_slice(b"\r\n");
+            }
         }
+        new_message.extend_from_slice(b"\r\n");
+        new_message.extend(new_body);
+        Some(new_message)
+    } else {
+        let mut new_message = Vec::with_capacity(
+            new_body.len()
+                + message.raw_headers().len()
+                + header_changes
+                    .iter()
+                    .map(|(_, h, v, _)| h.len() + v.len() + 4)
+                    .sum::<usize>(),
+        );
+        for (_, header, value, _) in header_changes {
+            new_message.extend_from_slice(header.as_bytes());
+            new_message.extend_from_slice(b": ");
+            new_message.extend_from_slice(value.as_bytes());
+            if !value.ends_with('\n') {
+                new_message.extend_from_slice(b"\r\n");
+            }
+        }
+        new_message.extend_from_slice(message.raw_headers());
+        new_message.extend(new_body);
+        Some(new_message)
+    }
+}
+
+impl Modification {
+    pub fn is_envelope_change(&self) -> bool {
+        matches!(
+            self,
+            Modification::ChangeFrom { .. }
+                | Modification::AddRcpt { .. }
+                | Modification::DeleteRcpt { .. }
+        )
     }
 }
 
@@ -13,6 +13,7 @@ use mail_auth::{
 };
 
 pub mod auth;
+pub mod callout;
 pub mod data;
 pub mod ehlo;
 pub mod hooks;
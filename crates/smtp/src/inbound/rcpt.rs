@@ -4,19 +4,33 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
+use std::{
+    net::Ipv4Addr,
+    sync::{Arc, atomic::Ordering},
+    time::{Duration, Instant},
+};
+
 use common::{
-    KV_GREYLIST, config::smtp::session::Stage, listener::SessionStream, scripts::ScriptModification,
+    KV_BOUNCE_CORRELATION, KV_GREYLIST,
+    config::{smtp::session::Stage, spamfilter::IpResolver},
+    listener::SessionStream,
+    scripts::ScriptModification,
 };
 
 use directory::backend::RcptType;
+use mail_auth::common::resolver::{IntoFqdn, ToReverseName};
 use smtp_proto::{
     RCPT_NOTIFY_DELAY, RCPT_NOTIFY_FAILURE, RCPT_NOTIFY_NEVER, RCPT_NOTIFY_SUCCESS, RcptTo,
 };
-use store::dispatch::lookup::KeyValue;
-use trc::{SecurityEvent, SmtpEvent};
+use store::{U32_LEN, dispatch::lookup::KeyValue, write::now};
+use trc::{SecurityEvent, SmtpEvent, SpamEvent};
 
 use crate::{
-    core::{Session, SessionAddress},
+    core::{
+        Session, SessionAddress,
+        batv::{BatvAddress, BatvTag},
+    },
+    inbound::callout::CalloutResult,
     queue::DomainPart,
     scripts::ScriptResult,
 };
@@ -52,6 +66,16 @@ impl<T: SessionStream> Session<T> {
                 Limit = self.params.rcpt_max,
             );
             return self.write(b"455 4.5.3 Too many recipients.\r\n").await;
+        } else if self
+            .server
+            .inner
+            .data
+            .queue_draining
+            .load(Ordering::Relaxed)
+        {
+            return self
+                .write(b"452 4.3.2 Mail system is not accepting messages, try again later.\r\n")
+                .await;
         }
 
         // Verify parameters
@@ -88,6 +112,49 @@ impl<T: SessionStream> Session<T> {
         }
         self.data.rcpt_to.push(rcpt);
 
+        // Bounce address tag validation
+        match self.verify_batv().await {
+            Some(BatvAddress::Valid(address)) => {
+                let address_lcase = address.to_lowercase();
+                let rcpt = self.data.rcpt_to.last_mut().unwrap();
+                rcpt.domain = address_lcase.domain_part().into();
+                rcpt.address_lcase = address_lcase;
+                rcpt.address = address;
+
+                let (rcpt, rcpts) = self.data.rcpt_to.split_last().unwrap();
+                if rcpts.contains(rcpt) {
+                    trc::event!(
+                        Smtp(SmtpEvent::RcptToDuplicate),
+                        SpanId = self.data.session_id,
+                        To = rcpt.address_lcase.clone(),
+                    );
+                    self.data.rcpt_to.pop();
+                    self.data.rcpt_oks += 1;
+                    return self.write(b"250 2.1.5 OK\r\n").await;
+                }
+            }
+            Some(BatvAddress::Untagged)
+                if self
+                    .data
+                    .mail_from
+                    .as_ref()
+                    .is_some_and(|mail_from| !mail_from.address_lcase.is_empty()) => {}
+            Some(_) => {
+                let rcpt_to = self.data.rcpt_to.pop().unwrap().address_lcase;
+
+                trc::event!(
+                    Smtp(SmtpEvent::RcptToBackscatter),
+                    SpanId = self.data.session_id,
+                    To = rcpt_to.clone(),
+                );
+
+                return self
+                    .rcpt_error(b"550 5.7.1 Invalid bounce address tag.\r\n", rcpt_to)
+                    .await;
+            }
+            None => {}
+        }
+
         // Address rewriting and Sieve filtering
         let rcpt_script = self
             .server
@@ -303,6 +370,47 @@ impl<T: SessionStream> Session<T> {
                 .await;
         }
 
+        // DNS blocklists
+        if self.is_dnsbl_rejected().await {
+            trc::event!(
+                Smtp(SmtpEvent::RcptToBlocklisted),
+                SpanId = self.data.session_id,
+                To = self.data.rcpt_to.last().unwrap().address_lcase.clone(),
+                Details = self
+                    .data
+                    .dnsbl
+                    .iter()
+                    .map(|zone| trc::Value::from(zone.clone()))
+                    .collect::<Vec<_>>(),
+            );
+
+            let rcpt_to = self.data.rcpt_to.pop().unwrap().address_lcase;
+            return self
+                .rcpt_error(
+                    b"550 5.7.1 Service unavailable, sender is listed on a DNS blocklist.\r\n",
+                    rcpt_to,
+                )
+                .await;
+        }
+
+        // Bounces are only accepted for addresses that sent mail recently
+        if self.is_backscatter().await {
+            let rcpt_to = self.data.rcpt_to.pop().unwrap().address_lcase;
+
+            trc::event!(
+                Smtp(SmtpEvent::RcptToBackscatter),
+                SpanId = self.data.session_id,
+                To = rcpt_to.clone(),
+            );
+
+            return self
+                .rcpt_error(
+                    b"550 5.7.1 Bounce rejected, recipient did not send any messages.\r\n",
+                    rcpt_to,
+                )
+                .await;
+        }
+
         if self.is_allowed().await {
             // Greylist
             if let Some(greylist_duration) = self
@@ -321,47 +429,45 @@ impl<T: SessionStream> Session<T> {
                     .address_lcase
                     .as_bytes();
                 let to_addr = self.data.rcpt_to.last().unwrap().address_lcase.as_bytes();
-                let mut key = Vec::with_capacity(from_addr.len() + to_addr.len() + 1);
+                let remote_ip = self.data.remote_ip_str.as_bytes();
+                let mut key = Vec::with_capacity(
+                    remote_ip.len() + from_addr.len() + to_addr.len() + (2 * U32_LEN) + 1,
+                );
                 key.push(KV_GREYLIST);
-                key.extend_from_slice(from_addr);
+                for part in [remote_ip, from_addr] {
+                    key.extend_from_slice(&(part.len() as u32).to_be_bytes());
+                    key.extend_from_slice(part);
+                }
                 key.extend_from_slice(to_addr);
 
-                match self.server.in_memory_store().key_exists(key.clone()).await {
-                    Ok(true) => (),
-                    Ok(false) => {
-                        match self
-                            .server
-                            .in_memory_store()
-                            .key_set(KeyValue::new(key, vec![]).expires(greylist_duration))
-                            .await
-                        {
-                            Ok(_) => {
-                                let rcpt = self.data.rcpt_to.pop().unwrap();
-
-                                trc::event!(
-                                    Smtp(SmtpEvent::RcptToGreylisted),
-                                    SpanId = self.data.session_id,
-                                    To = rcpt.address_lcase,
-                                );
-
-                                return self
-                                    .write(
-                                        concat!(
-                                            "452 4.2.2 Greylisted, please try ",
-                                            "again in a few moments.\r\n"
-                                        )
-                                        .as_bytes(),
-                                    )
-                                    .await;
-                            }
-                            Err(err) => {
-                                trc::error!(
-                                    err.span_id(self.data.session_id)
-                                        .caused_by(trc::location!())
-                                        .details("Failed to set greylist.")
-                                );
-                            }
-                        }
+                // Unknown triplets are stored with the time of first contact and
+                // allowlisted once the sender retries after the configured delay
+                let greylist_delay = self.server.core.spam.expiry.grey_list_delay;
+                let is_greylisted = match self
+                    .server
+                    .in_memory_store()
+                    .key_get::<String>(key.clone())
+                    .await
+                    .map(|entry| entry.and_then(|entry| GreylistEntry::parse(&entry)))
+                {
+                    Ok(Some(GreylistEntry::Allowed)) => false,
+                    Ok(Some(GreylistEntry::Pending { first_seen }))
+                        if now() < first_seen + greylist_delay =>
+                    {
+                        true
+                    }
+                    Ok(Some(GreylistEntry::Pending { .. })) => {
+                        self.set_greylist(key, GreylistEntry::Allowed, greylist_duration)
+                            .await;
+                        false
+                    }
+                    Ok(None) => {
+                        self.set_greylist(
+                            key,
+                            GreylistEntry::Pending { first_seen: now() },
+                            greylist_duration,
+                        )
+                        .await
                     }
                     Err(err) => {
                         trc::error!(
@@ -369,6 +475,56 @@ impl<T: SessionStream> Session<T> {
                                 .caused_by(trc::location!())
                                 .details("Failed to check greylist.")
                         );
+                        false
+                    }
+                };
+
+                if is_greylisted {
+                    let rcpt = self.data.rcpt_to.pop().unwrap();
+
+                    trc::event!(
+                        Smtp(SmtpEvent::RcptToGreylisted),
+                        SpanId = self.data.session_id,
+                        To = rcpt.address_lcase,
+                    );
+
+                    return self
+                        .write(
+                            concat!(
+                                "451 4.7.1 Greylisted, please try ",
+                                "again in a few moments.\r\n"
+                            )
+                            .as_bytes(),
+                        )
+                        .await;
+                }
+            }
+
+            // Recipient verification callout
+            if self.is_callout_enabled().await {
+                match self.verify_callout().await {
+                    CalloutResult::Valid | CalloutResult::Loop | CalloutResult::Local => {}
+                    CalloutResult::Invalid => {
+                        let rcpt_to = self.data.rcpt_to.pop().unwrap().address_lcase;
+
+                        trc::event!(
+                            Smtp(SmtpEvent::MailboxDoesNotExist),
+                            SpanId = self.data.session_id,
+                            To = rcpt_to.clone(),
+                        );
+
+                        return self
+                            .rcpt_error(
+                                b"550 5.1.1 Mailbox does not exist at next hop.\r\n",
+                                rcpt_to,
+                            )
+                            .await;
+                    }
+                    CalloutResult::Unavailable => {
+                        self.data.rcpt_to.pop();
+                        return self
+                            .write(b"451 4.4.3 Unable to verify address at this time.\r\n")
+                            .await;
                     }
                 }
             }
@@ -411,6 +567,251 @@ impl<T: SessionStream> Session<T> {
         self.write(b"250 2.1.5 OK\r\n").await
     }
 
+    async fn is_dnsbl_rejected(&mut self) -> bool {
+        let rc = &self.server.core.smtp.session.rcpt;
+        if rc.dnsbl_ip.is_empty() && rc.dnsbl_domain.is_empty() {
+            return false;
+        }
+
+        // Remote IP and sender domain lookups, results are cached for the session
+        let mut lookups = Vec::with_capacity(rc.dnsbl_ip.len() + rc.dnsbl_domain.len());
+        let reverse_ip = self.data.remote_ip.to_reverse_name();
+        for zone in &rc.dnsbl_ip {
+            lookups.push((zone, format!("{reverse_ip}.{zone}"), "ip"));
+        }
+        if let Some(domain) = self
+            .data
+            .mail_from
+            .as_ref()
+            .map(|mail_from| mail_from.domain.as_str())
+            .filter(|domain| !domain.is_empty())
+        {
+            for zone in &rc.dnsbl_domain {
+                lookups.push((zone, format!("{domain}.{zone}"), "domain"));
+            }
+        }
+
+        self.data.dnsbl.clear();
+        for (zone, name, element) in lookups {
+            let is_listed = if let Some(is_listed) = self.data.dnsbl_cache.get(&name) {
+                *is_listed
+            } else {
+                let is_listed = self.dnsbl_lookup(&name, element).await;
+                self.data.dnsbl_cache.insert(name, is_listed);
+                is_listed
+            };
+
+            if is_listed {
+                self.data.dnsbl.push(zone.clone());
+            }
+        }
+
+        !self.data.dnsbl.is_empty()
+            && self
+                .server
+                .eval_if(&rc.dnsbl_reject, self, self.data.session_id)
+                .await
+                .unwrap_or(false)
+    }
+
+    async fn verify_batv(&self) -> Option<BatvAddress> {
+        let batv = &self.server.core.smtp.mail_auth.batv;
+        if batv.secret.is_some()
+            && self
+                .server
+                .eval_if(&batv.verify, self, self.data.session_id)
+                .await
+                .unwrap_or(false)
+        {
+            Some(batv.batv_verify(&self.data.rcpt_to.last().unwrap().address, now()))
+        } else {
+            None
+        }
+    }
+
+    async fn is_backscatter(&self) -> bool {
+        if !self
+            .data
+            .mail_from
+            .as_ref()
+            .is_some_and(|mail_from| mail_from.address_lcase.is_empty())
+            || !self
+                .server
+                .eval_if(
+                    &self.server.core.smtp.session.rcpt.bounce_correlation,
+                    self,
+                    self.data.session_id,
+                )
+                .await
+                .unwrap_or(false)
+        {
+            return false;
+        }
+
+        let rcpt = self.data.rcpt_to.last().unwrap();
+        match self
+            .server
+            .in_memory_store()
+            .key_exists(KeyValue::<()>::build_key(
+                KV_BOUNCE_CORRELATION,
+                rcpt.address_lcase.as_bytes(),
+            ))
+            .await
+        {
+            Ok(has_sent) => !has_sent,
+            Err(err) => {
+                trc::error!(
+                    err.span_id(self.data.session_id)
+                        .caused_by(trc::location!())
+                        .details("Failed to check bounce correlation.")
+                );
+                false
+            }
+        }
+    }
+
+    pub async fn add_bounce_correlation(&self, return_path: &str) {
+        if self
+            .server
+            .core
+            .smtp
+            .session
+            .rcpt
+            .bounce_correlation
+            .is_empty()
+            || return_path.is_empty()
+        {
+            return;
+        }
+
+        if let Err(err) = self
+            .server
+            .in_memory_store()
+            .key_set(
+                KeyValue::with_prefix(KV_BOUNCE_CORRELATION, return_path.as_bytes(), vec![])
+                    .expires(
+                        self.server
+                            .core
+                            .smtp
+                            .session
+                            .rcpt
+                            .bounce_correlation_expiry
+                            .as_secs(),
+                    ),
+            )
+            .await
+        {
+            trc::error!(
+                err.span_id(self.data.session_id)
+                    .caused_by(trc::location!())
+                    .details("Failed to set bounce correlation.")
+            );
+        }
+    }
+
+    async fn dnsbl_lookup(&self, name: &str, element: &'static str) -> bool {
+        if let Some(result) = self.server.inner.cache.dns_rbl.get(name) {
+            return result.is_some();
+        }
+
+        let time = Instant::now();
+        match self
+            .server
+            .core
+            .smtp
+            .resolvers
+            .dns
+            .ipv4_lookup_raw(name.into_fqdn().as_ref())
+            .await
+        {
+            Ok(result) => match dnsbl_listing(&result.entry) {
+                Ok(listed) => {
+                    trc::event!(
+                        Spam(SpamEvent::Dnsbl),
+                        SpanId = self.data.session_id,
+                        Hostname = name.to_string(),
+                        Result = result
+                            .entry
+                            .iter()
+                            .map(|ip| trc::Value::from(ip.to_string()))
+                            .collect::<Vec<_>>(),
+                        Details = element,
+                        Elapsed = time.elapsed()
+                    );
+
+                    self.server.inner.cache.dns_rbl.insert_with_expiry(
+                        name.to_string(),
+                        listed.map(|ip| Arc::new(IpResolver::new(ip.into()))),
+                        result.expires,
+                    );
+
+                    listed.is_some()
+                }
+                Err(code) => {
+                    trc::event!(
+                        Spam(SpamEvent::DnsblError),
+                        SpanId = self.data.session_id,
+                        Hostname = name.to_string(),
+                        Elapsed = time.elapsed(),
+                        Details = element,
+                        CausedBy = format!("DNSBL returned error code {code}")
+                    );
+
+                    false
+                }
+            },
+            Err(mail_auth::Error::DnsRecordNotFound(_)) => {
+                trc::event!(
+                    Spam(SpamEvent::Dnsbl),
+                    SpanId = self.data.session_id,
+                    Hostname = name.to_string(),
+                    Result = trc::Value::None,
+                    Details = element,
+                    Elapsed = time.elapsed()
+                );
+
+                self.server.inner.cache.dns_rbl.insert(
+                    name.to_string(),
+                    None,
+                    Duration::from_secs(86400),
+                );
+
+                false
+            }
+            Err(err) => {
+                trc::event!(
+                    Spam(SpamEvent::DnsblError),
+                    SpanId = self.data.session_id,
+                    Hostname = name.to_string(),
+                    Elapsed = time.elapsed(),
+                    Details = element,
+                    CausedBy = err.to_string()
+                );
+
+                false
+            }
+        }
+    }
+
+    async fn set_greylist(&self, key: Vec<u8>, entry: GreylistEntry, expires: u64) -> bool {
+        match self
+            .server
+            .in_memory_store()
+            .key_set(KeyValue::new(key, entry.serialize()).expires(expires))
+            .await
+        {
+            Ok(_) => true,
+            Err(err) => {
+                trc::error!(
+                    err.span_id(self.data.session_id)
+                        .caused_by(trc::location!())
+                        .details("Failed to set greylist.")
+                );
+                false
+            }
+        }
+    }
+
     async fn rcpt_error(&mut self, response: &[u8], rcpt: String) -> Result<(), ()> {
         tokio::time::sleep(self.params.rcpt_errors_wait).await;
         self.data.rcpt_errors += 1;
@@ -457,3 +858,41 @@ impl<T: SessionStream> Session<T> {
         }
     }
 }
+
+/// Returns the listing address of a DNSBL response, only 127.0.0.2 to 127.0.0.255
+/// indicate a listing while codes in 127.255.255.0/24 signal a query error.
+pub fn dnsbl_listing(ips: &[Ipv4Addr]) -> Result<Option<Ipv4Addr>, Ipv4Addr> {
+    if let Some(code) = ips.iter().find(|ip| ip.octets()[..3] == [127, 255, 255]) {
+        Err(*code)
+    } else {
+        Ok(ips
+            .iter()
+            .find(|ip| ip.octets()[..3] == [127, 0, 0] && ip.octets()[3] >= 2)
+            .copied())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum GreylistEntry {
+    Pending { first_seen: u64 },
+    Allowed,
+}
+
+impl GreylistEntry {
+    fn serialize(&self) -> Vec<u8> {
+        match self {
+            GreylistEntry::Pending { first_seen } => first_seen.to_string().into_bytes(),
+            GreylistEntry::Allowed => b"allowed".to_vec(),
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "allowed" => Some(GreylistEntry::Allowed),
+            first_seen => first_seen
+                .parse()
+                .ok()
+                .map(|first_seen| GreylistEntry::Pending { first_seen }),
+        }
+    }
+}
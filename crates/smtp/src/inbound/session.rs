@@ -4,6 +4,8 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
+use std::time::Duration;
+
 use common::{
     config::{server::ServerProtocol, smtp::session::Mechanism},
     expr::{self, functions::ResolveVariable, *},
@@ -342,6 +344,10 @@ impl<T: SessionStream> Session<T> {
                             }
                         },
                     }
+
+                    // Evaluate the tarpit delay for the next replies once the command
+                    // has updated the session state
+                    self.data.tarpit = self.eval_tarpit().await;
                 },
                 State::Data(receiver) => {
                     if self.data.message.len() + bytes.len() < self.params.max_message_size {
@@ -462,6 +468,17 @@ impl<T: SessionStream> Session<T> {
 
         Ok(true)
     }
+
+    pub async fn eval_tarpit(&self) -> Option<Duration> {
+        let tarpit = &self.server.core.smtp.session.tarpit;
+        if !tarpit.is_empty() {
+            self.server
+                .eval_if::<Duration, _>(tarpit, self, self.data.session_id)
+                .await
+        } else {
+            None
+        }
+    }
 }
 
 impl<T: AsyncWrite + AsyncRead + Unpin> Session<T> {
@@ -478,6 +495,11 @@ impl<T: AsyncWrite + AsyncRead + Unpin> Session<T> {
 
     #[inline(always)]
     pub async fn write(&mut self, bytes: &[u8]) -> Result<(), ()> {
+        // Delay responses to suspicious senders
+        if let Some(delay) = self.data.tarpit {
+            tokio::time::sleep(delay).await;
+        }
+
         match self.stream.write_all(bytes).await {
             Ok(_) => match self.stream.flush().await {
                 Ok(_) => {
@@ -602,6 +624,13 @@ impl<T: SessionStream> ResolveVariable for Session<T> {
                 .map(|c| c.as_str())
                 .unwrap_or_default()
                 .into(),
+            V_DNSBL => self
+                .data
+                .dnsbl
+                .iter()
+                .map(|zone| Variable::from(zone.as_str()))
+                .collect::<Vec<_>>()
+                .into(),
             _ => expr::Variable::default(),
         }
     }
@@ -4,16 +4,19 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use common::{
     config::smtp::session::Stage,
     core::BuildServer,
-    listener::{self, SessionManager, SessionStream},
+    listener::{
+        self, SessionManager, SessionStream,
+        limiter::{InFlight, LimiterResult},
+    },
 };
 
 use tokio_rustls::server::TlsStream;
-use trc::{SecurityEvent, SmtpEvent};
+use trc::{LimitEvent, SecurityEvent, SmtpEvent};
 
 use crate::{
     core::{Session, SessionData, SessionParameters, SmtpSessionManager, State},
@@ -42,6 +45,31 @@ impl SessionManager for SmtpSessionManager {
             params: SessionParameters::default(),
         };
 
+        // Enforce concurrency per remote IP
+        let _in_flight_ip = match session.is_concurrency_allowed() {
+            LimiterResult::Forbidden => {
+                trc::event!(
+                    Limit(LimitEvent::ConcurrentConnection),
+                    SpanId = session.data.session_id,
+                    RemoteIp = session.data.remote_ip,
+                    RemotePort = session.data.remote_port,
+                    Limit = session
+                        .server
+                        .core
+                        .smtp
+                        .session
+                        .connect
+                        .max_connections_per_ip,
+                );
+
+                let _ = session
+                    .write(b"421 4.7.0 Too many connections from your IP address.\r\n")
+                    .await;
+                return;
+            }
+            result => Option::<InFlight>::from(result),
+        };
+
         // Enforce throttle
         if session.is_allowed().await
             && session.init_conn().await
@@ -79,6 +107,31 @@ impl<T: SessionStream> Session<T> {
 
         let config = &self.server.core.smtp.session.connect;
 
+        // Connection allowlist and blocklist
+        if !self
+            .server
+            .eval_if(&config.allow, self, self.data.session_id)
+            .await
+            .unwrap_or(true)
+        {
+            trc::event!(
+                Security(SecurityEvent::IpBlocked),
+                SpanId = self.data.session_id,
+                RemoteIp = self.data.remote_ip,
+                RemotePort = self.data.remote_port,
+            );
+
+            if let Some(message) = self
+                .server
+                .eval_if::<String, _>(&config.reject_message, self, self.data.session_id)
+                .await
+                .filter(|message| !message.is_empty())
+            {
+                let _ = self.write(format!("554 {message}\r\n").as_bytes()).await;
+            }
+            return false;
+        }
+
         // Sieve filtering
         if let Some((script, script_id)) = self
             .server
@@ -129,10 +182,39 @@ impl<T: SessionStream> Session<T> {
             self.hostname = "localhost".into();
         }
 
+        // Delay the greeting and reject clients that talk first
+        if let Some(delay) = self
+            .server
+            .eval_if::<Duration, _>(&config.greeting_delay, self, self.data.session_id)
+            .await
+        {
+            let mut buf = [0u8; 1024];
+            match tokio::time::timeout(delay, self.read(&mut buf)).await {
+                Err(_) => (),
+                Ok(Ok(0)) | Ok(Err(_)) => return false,
+                Ok(Ok(_)) => {
+                    trc::event!(
+                        Smtp(SmtpEvent::EarlyTalker),
+                        SpanId = self.data.session_id,
+                        Elapsed = delay,
+                    );
+
+                    let _ = self
+                        .write(b"554 5.5.0 Protocol violation, data sent before greeting.\r\n")
+                        .await;
+                    return false;
+                }
+            }
+        }
+
         // Obtain greeting
         let greeting = self
             .server
-            .eval_if::<String, _>(&config.greeting, self, self.data.session_id)
+            .eval_if::<String, _>(
+                &self.server.core.smtp.session.connect.greeting,
+                self,
+                self.data.session_id,
+            )
             .await
             .filter(|g| !g.is_empty())
             .map(|g| format!("220 {}\r\n", g))
@@ -5,7 +5,16 @@
  */
 
 use super::session::SessionParams;
-use crate::queue::{Error, ErrorDetails, HostResponse, MessageWrapper, Status};
+use crate::{
+    inbound::DkimSign,
+    queue::{Error, ErrorDetails, HostResponse, MessageWrapper, Status},
+};
+use common::config::smtp::queue::EightBitMime;
+use mail_auth::common::headers::HeaderWriter;
+use mail_builder::encoders::{
+    base64::base64_encode_mime, quoted_printable::quoted_printable_encode,
+};
+use mail_parser::{MessageParser, PartType};
 use mail_send::{Credentials, smtp::AssertReply};
 use rustls::ClientConnection;
 use rustls_pki_types::ServerName;
@@ -32,6 +41,7 @@ pub struct SmtpClient<T: AsyncRead + AsyncWrite> {
     pub stream: T,
     pub timeout: Duration,
     pub session_id: u64,
+    pub transcript: Option<String>,
 }
 
 impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
@@ -89,13 +99,14 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
         U: AsRef<str> + PartialEq + Eq + std::hash::Hash,
     {
         let mut reply = if (mechanism & (AUTH_PLAIN | AUTH_XOAUTH2 | AUTH_OAUTHBEARER)) != 0 {
-            self.cmd(
+            self.send_cmd(
                 format!(
                     "AUTH {} {}\r\n",
                     mechanism.to_mechanism(),
                     credentials.encode(mechanism, "")?,
                 )
                 .as_bytes(),
+                Some(&format!("AUTH {} [redacted]", mechanism.to_mechanism())),
             )
             .await?
         } else {
@@ -107,9 +118,10 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
             match reply.code() {
                 334 => {
                     reply = self
-                        .cmd(
+                        .send_cmd(
                             format!("{}\r\n", credentials.encode(mechanism, reply.message())?)
                                 .as_bytes(),
+                            Some("[redacted]"),
                         )
                         .await?;
                 }
@@ -174,6 +186,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
         &mut self,
         message: &MessageWrapper,
         bdat_cmd: &Option<String>,
+        has_8bit_mime: bool,
         params: &SessionParams<'_>,
     ) -> Result<(), Status<HostResponse<String>, ErrorDetails>> {
         match params
@@ -183,17 +196,89 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
             .await
         {
             Ok(Some(raw_message)) => {
+                let mut is_modified = !params.add_headers.is_empty()
+                    || !params.remove_headers.is_empty()
+                    || (params.rewrite_sender.is_some() && !params.rewrite_headers.is_empty());
+                let raw_message =
+                    rewrite_headers(raw_message, params.add_headers, params.remove_headers);
+                let raw_message = if let Some(rewrite_sender) = params.rewrite_sender {
+                    rewrite_address(raw_message, params.rewrite_headers, rewrite_sender)
+                } else {
+                    raw_message
+                };
+
+                // As per RFC6152 Section 3, 8-bit content is downgraded or not relayed
+                // to hosts that do not advertise 8BITMIME
+                let raw_message = if has_8bit_mime || raw_message.is_ascii() {
+                    raw_message
+                } else {
+                    match params.server.core.smtp.queue.eight_bit_mime {
+                        EightBitMime::Send => Some(raw_message),
+                        EightBitMime::Convert => {
+                            is_modified = true;
+                            downgrade_8bit(&raw_message)
+                        }
+                        EightBitMime::Defer => None,
+                    }
+                    .ok_or_else(|| {
+                        Status::TemporaryFailure(ErrorDetails {
+                            entity: params.hostname.into(),
+                            details: Error::ConnectionError(
+                                "8BITMIME not advertised by host.".into(),
+                            ),
+                        })
+                    })?
+                };
+
+                // Signing is always the last step, no changes are made to the message after this
+                let raw_message = if is_modified {
+                    dkim_resign(raw_message, params)
+                } else {
+                    raw_message
+                };
                 tokio::time::timeout(params.conn_strategy.timeout_data, async {
-                    if let Some(bdat_cmd) = bdat_cmd {
-                        trc::event!(
-                            Delivery(DeliveryEvent::RawOutput),
-                            SpanId = self.session_id,
-                            Contents = bdat_cmd.clone(),
-                            Size = bdat_cmd.len()
-                        );
+                    let chunk_size = params.conn_strategy.chunk_size;
+                    if bdat_cmd.is_some() {
+                        if chunk_size == 0 || raw_message.len() <= chunk_size {
+                            let bdat_cmd = format!("BDAT {} LAST\r\n", raw_message.len());
+                            trc::event!(
+                                Delivery(DeliveryEvent::RawOutput),
+                                SpanId = self.session_id,
+                                Contents = bdat_cmd.clone(),
+                                Size = bdat_cmd.len()
+                            );
+                            self.transcribe_output(bdat_cmd.as_bytes());
 
-                        self.write_chunks(&[bdat_cmd.as_bytes(), &raw_message])
-                            .await
+                            self.write_chunks(&[bdat_cmd.as_bytes(), &raw_message])
+                                .await
+                        } else {
+                            let mut chunks = raw_message.chunks(chunk_size).peekable();
+                            while let Some(chunk) = chunks.next() {
+                                let is_last = chunks.peek().is_none();
+                                let bdat_cmd = if is_last {
+                                    format!("BDAT {} LAST\r\n", chunk.len())
+                                } else {
+                                    format!("BDAT {}\r\n", chunk.len())
+                                };
+
+                                trc::event!(
+                                    Delivery(DeliveryEvent::RawOutput),
+                                    SpanId = self.session_id,
+                                    Contents = bdat_cmd.clone(),
+                                    Size = bdat_cmd.len()
+                                );
+                                self.transcribe_output(bdat_cmd.as_bytes());
+
+                                self.write_chunks(&[bdat_cmd.as_bytes(), chunk]).await?;
+
+                                // The response to the last chunk is read by the caller
+                                if !is_last {
+                                    self.read().await?.assert_positive_completion()?;
+                                }
+                            }
+
+                            Ok(())
+                        }
                     } else {
                         trc::event!(
                             Delivery(DeliveryEvent::RawOutput),
@@ -201,6 +286,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
                             Contents = "DATA\r\n",
                             Size = 6
                         );
+                        self.transcribe_output(b"DATA\r\n");
 
                         self.write_chunks(&[b"DATA\r\n"]).await?;
                         self.read().await?.assert_code(354)?;
@@ -262,6 +348,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
             Contents = cmd.clone(),
             Size = cmd.len()
         );
+        self.transcribe_output(cmd.as_bytes());
 
         tokio::time::timeout(params.conn_strategy.timeout_ehlo, async {
             self.stream.write_all(cmd.as_bytes()).await?;
@@ -273,13 +360,14 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
         .map_err(|err| Status::from_smtp_error(params.hostname, &cmd, err))
     }
 
-    pub async fn quit(mut self: SmtpClient<T>) {
+    pub async fn quit(mut self: SmtpClient<T>) -> Option<String> {
         trc::event!(
             Delivery(DeliveryEvent::RawOutput),
             SpanId = self.session_id,
             Contents = "QUIT\r\n",
             Size = 6
         );
+        self.transcribe_output(b"QUIT\r\n");
 
         let _ = tokio::time::timeout(Duration::from_secs(10), async {
             if self.stream.write_all(b"QUIT\r\n").await.is_ok() && self.stream.flush().await.is_ok()
@@ -289,6 +377,8 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
             }
         })
         .await;
+
+        self.transcript
     }
 
     pub async fn read_ehlo(&mut self) -> mail_send::Result<EhloResponse<String>> {
@@ -308,6 +398,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
                 Contents = trc::Value::from_maybe_string(&buf[..br]),
                 Size = br,
             );
+            self.transcribe_input(&buf[..br]);
 
             let mut iter = if buf_concat.is_empty() {
                 buf[..br].iter()
@@ -361,6 +452,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
                     Contents = trc::Value::from_maybe_string(&buf[..br]),
                     Size = br
                 );
+                self.transcribe_input(&buf[..br]);
 
                 match parser.parse(&mut buf[..br].iter()) {
                     Ok(reply) => return Ok(reply),
@@ -394,6 +486,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
                     Contents = trc::Value::from_maybe_string(&buf[..br]),
                     Size = br
                 );
+                self.transcribe_input(&buf[..br]);
 
                 loop {
                     match parser.parse(&mut iter) {
@@ -423,15 +516,44 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
 
     /// Sends a command to the SMTP server and waits for a reply.
     pub async fn cmd(&mut self, cmd: impl AsRef<[u8]>) -> mail_send::Result<Response<String>> {
+        self.send_cmd(cmd.as_ref(), None).await
+    }
+
+    /// Sends a batch of pipelined commands and reads their replies in order.
+    pub async fn pipeline(&mut self, cmds: &[&str]) -> mail_send::Result<Vec<Response<String>>> {
         tokio::time::timeout(self.timeout, async {
-            let cmd = cmd.as_ref();
+            for cmd in cmds {
+                trc::event!(
+                    Delivery(DeliveryEvent::RawOutput),
+                    SpanId = self.session_id,
+                    Contents = cmd.to_string(),
+                    Size = cmd.len()
+                );
+                self.transcribe_output(cmd.as_bytes());
 
+                self.stream.write_all(cmd.as_bytes()).await?;
+            }
+            self.stream.flush().await?;
+            self.read_many(cmds.len()).await
+        })
+        .await
+        .map_err(|_| mail_send::Error::Timeout)?
+    }
+
+    /// Sends a command, writing `redacted` to the transcript in its place if provided.
+    async fn send_cmd(
+        &mut self,
+        cmd: &[u8],
+        redacted: Option<&str>,
+    ) -> mail_send::Result<Response<String>> {
+        tokio::time::timeout(self.timeout, async {
             trc::event!(
                 Delivery(DeliveryEvent::RawOutput),
                 SpanId = self.session_id,
                 Contents = trc::Value::from_maybe_string(cmd),
                 Size = cmd.len()
             );
+            self.transcribe_output(redacted.map_or(cmd, |redacted| redacted.as_bytes()));
 
             self.stream.write_all(cmd).await?;
             self.stream.flush().await?;
@@ -441,6 +563,26 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
         .map_err(|_| mail_send::Error::Timeout)?
     }
 
+    fn transcribe_output(&mut self, data: &[u8]) {
+        self.transcribe("C: ", data);
+    }
+
+    fn transcribe_input(&mut self, data: &[u8]) {
+        self.transcribe("S: ", data);
+    }
+
+    fn transcribe(&mut self, prefix: &str, data: &[u8]) {
+        if let Some(transcript) = &mut self.transcript {
+            for line in String::from_utf8_lossy(data).lines() {
+                if !line.is_empty() {
+                    transcript.push_str(prefix);
+                    transcript.push_str(line);
+                    transcript.push_str("\r\n");
+                }
+            }
+        }
+    }
+
     pub async fn write_message(&mut self, message: &[u8]) -> tokio::io::Result<()> {
         // Transparency procedure
         let mut is_cr_or_lf = false;
@@ -524,6 +666,7 @@ impl SmtpClient<TcpStream> {
                     })?,
                 timeout: self.timeout,
                 session_id: self.session_id,
+                transcript: self.transcript,
             })
         })
         .await
@@ -543,6 +686,7 @@ impl SmtpClient<TcpStream> {
                 stream: TcpStream::connect(remote_addr).await?,
                 timeout,
                 session_id,
+                transcript: None,
             })
         })
         .await
@@ -568,12 +712,91 @@ impl SmtpClient<TcpStream> {
                 stream: socket.connect(remote_addr).await?,
                 timeout,
                 session_id,
+                transcript: None,
             })
         })
         .await
         .map_err(|_| mail_send::Error::Timeout)?
     }
 
+    /// Connects to a remote host address, using the local IP if provided
+    pub async fn connect_from(
+        local_ip: Option<IpAddr>,
+        remote_addr: SocketAddr,
+        timeout: Duration,
+        session_id: u64,
+    ) -> mail_send::Result<Self> {
+        if let Some(local_ip) = local_ip {
+            Self::connect_using(local_ip, remote_addr, timeout, session_id).await
+        } else {
+            Self::connect(remote_addr, timeout, session_id).await
+        }
+    }
+
+    /// Connects to the primary address and, if it has not connected after the
+    /// stagger delay, races it against the secondary address as described in
+    /// RFC8305. The secondary address is only obtained once the race starts and
+    /// is not raced if none is returned.
+    pub async fn connect_happy_eyeballs(
+        primary: (Option<IpAddr>, SocketAddr),
+        secondary: impl Future<Output = Option<(Option<IpAddr>, SocketAddr)>>,
+        stagger: Duration,
+        timeout: Duration,
+        session_id: u64,
+    ) -> RacedConnection {
+        let primary_conn = Self::connect_from(primary.0, primary.1, timeout, session_id);
+        tokio::pin!(primary_conn);
+
+        let primary_error = tokio::select! {
+            result = &mut primary_conn => match result {
+                Ok(smtp_client) => return RacedConnection::Primary(smtp_client),
+                Err(err) => Some(err),
+            },
+            _ = tokio::time::sleep(stagger) => None,
+        };
+
+        let Some((local_ip, remote_addr)) = secondary.await else {
+            let primary = match primary_error {
+                Some(err) => err,
+                None => match primary_conn.await {
+                    Ok(smtp_client) => return RacedConnection::Primary(smtp_client),
+                    Err(err) => err,
+                },
+            };
+            return RacedConnection::Failed {
+                primary,
+                secondary: None,
+            };
+        };
+        let secondary_conn = Self::connect_from(local_ip, remote_addr, timeout, session_id);
+        tokio::pin!(secondary_conn);
+
+        let (primary, secondary) = match primary_error {
+            Some(primary) => (primary, secondary_conn.await),
+            None => tokio::select! {
+                result = &mut primary_conn => match result {
+                    Ok(smtp_client) => return RacedConnection::Primary(smtp_client),
+                    Err(primary) => (primary, secondary_conn.await),
+                },
+                result = &mut secondary_conn => match result {
+                    Ok(smtp_client) => return RacedConnection::Secondary(smtp_client),
+                    Err(secondary) => match primary_conn.await {
+                        Ok(smtp_client) => return RacedConnection::Primary(smtp_client),
+                        Err(primary) => (primary, Err(secondary)),
+                    },
+                },
+            },
+        };
+
+        match secondary {
+            Ok(smtp_client) => RacedConnection::Secondary(smtp_client),
+            Err(secondary) => RacedConnection::Failed {
+                primary,
+                secondary: Some(secondary),
+            },
+        }
+    }
+
     pub async fn try_start_tls(
         mut self,
         tls_connector: &TlsConnector,
@@ -612,6 +835,248 @@ impl SmtpClient<TlsStream<TcpStream>> {
     }
 }
 
+/// Adds and removes headers on the copy of a message sent to a recipient domain.
+fn rewrite_headers(message: Vec<u8>, add: &[String], remove: &[String]) -> Vec<u8> {
+    if add.is_empty() && remove.is_empty() {
+        return message;
+    }
+
+    let mut rewritten = Vec::with_capacity(
+        message.len() + add.iter().map(|header| header.len() + 2).sum::<usize>(),
+    );
+    for header in add {
+        rewritten.extend_from_slice(header.trim_end().as_bytes());
+        rewritten.extend_from_slice(b"\r\n");
+    }
+
+    // Copy the header section, skipping removed headers and their folded lines
+    let mut pos = 0;
+    let mut is_removed = false;
+    while pos < message.len() {
+        let end = message[pos..]
+            .iter()
+            .position(|&ch| ch == b'\n')
+            .map_or(message.len(), |end| pos + end + 1);
+        let line = &message[pos..end];
+        if line == b"\r\n" || line == b"\n" {
+            break;
+        }
+        if !matches!(line.first(), Some(b' ' | b'\t')) {
+            is_removed = line.iter().position(|&ch| ch == b':').is_some_and(|colon| {
+                let name = line[..colon].trim_ascii();
+                remove
+                    .iter()
+                    .any(|header| header.as_bytes().eq_ignore_ascii_case(name))
+            });
+        }
+        if !is_removed {
+            rewritten.extend_from_slice(line);
+        }
+        pos = end;
+    }
+    rewritten.extend_from_slice(&message[pos..]);
+
+    rewritten
+}
+
+/// Replaces the original sender address with its masqueraded form in the
+/// listed header fields, including their folded lines.
+fn rewrite_address(message: Vec<u8>, headers: &[String], address: (&str, &str)) -> Vec<u8> {
+    let (from, to) = (address.0.as_bytes(), address.1.as_bytes());
+    if headers.is_empty() || from.is_empty() {
+        return message;
+    }
+
+    let mut rewritten = Vec::with_capacity(message.len() + to.len());
+    let mut pos = 0;
+    let mut is_rewritten = false;
+    while pos < message.len() {
+        let end = message[pos..]
+            .iter()
+            .position(|&ch| ch == b'\n')
+            .map_or(message.len(), |end| pos + end + 1);
+        let line = &message[pos..end];
+        if line == b"\r\n" || line == b"\n" {
+            break;
+        }
+        if !matches!(line.first(), Some(b' ' | b'\t')) {
+            is_rewritten = line.iter().position(|&ch| ch == b':').is_some_and(|colon| {
+                let name = line[..colon].trim_ascii();
+                headers
+                    .iter()
+                    .any(|header| header.as_bytes().eq_ignore_ascii_case(name))
+            });
+        }
+        if is_rewritten {
+            let mut line_pos = 0;
+            while line_pos < line.len() {
+                if line[line_pos..]
+                    .get(..from.len())
+                    .is_some_and(|value| value.eq_ignore_ascii_case(from))
+                    && !line[..line_pos].last().is_some_and(is_address_char)
+                    && !line.get(line_pos + from.len()).is_some_and(is_address_char)
+                {
+                    rewritten.extend_from_slice(to);
+                    line_pos += from.len();
+                } else {
+                    rewritten.push(line[line_pos]);
+                    line_pos += 1;
+                }
+            }
+        } else {
+            rewritten.extend_from_slice(line);
+        }
+        pos = end;
+    }
+    rewritten.extend_from_slice(&message[pos..]);
+
+    rewritten
+}
+
+/// Removes the signatures added at queue time by the listed signers, which are
+/// no longer valid once the message is modified, and signs the final copy again.
+fn dkim_resign(message: Vec<u8>, params: &SessionParams<'_>) -> Vec<u8> {
+    let signatures = params
+        .dkim_sign
+        .iter()
+        .filter_map(|name| params.server.get_dkim_signature(name, params.session_id))
+        .collect::<Vec<_>>();
+    if signatures.is_empty() {
+        return message;
+    }
+
+    // Copy the header section, skipping stale signatures and their folded lines
+    let mut stripped = Vec::with_capacity(message.len());
+    let mut resign = vec![false; signatures.len()];
+    let mut pos = 0;
+    let mut is_removed = false;
+    while pos < message.len() {
+        let end = message[pos..]
+            .iter()
+            .position(|&ch| ch == b'\n')
+            .map_or(message.len(), |end| pos + end + 1);
+        let line = &message[pos..end];
+        if line == b"\r\n" || line == b"\n" {
+            break;
+        }
+        if !matches!(line.first(), Some(b' ' | b'\t')) {
+            is_removed = false;
+            if line
+                .get(..15)
+                .is_some_and(|name| name.eq_ignore_ascii_case(b"DKIM-Signature:"))
+            {
+                // Unfold the header to obtain its tags
+                let mut field_end = end;
+                while matches!(message.get(field_end), Some(b' ' | b'\t')) {
+                    field_end = message[field_end..]
+                        .iter()
+                        .position(|&ch| ch == b'\n')
+                        .map_or(message.len(), |end| field_end + end + 1);
+                }
+                let field = String::from_utf8_lossy(&message[pos + 15..field_end]);
+                let (domain, selector) = signature_tags(&field);
+                if let Some(idx) = signatures.iter().position(|signature| {
+                    signature.domain.eq_ignore_ascii_case(domain)
+                        && signature.selector.eq_ignore_ascii_case(selector)
+                }) {
+                    resign[idx] = true;
+                    is_removed = true;
+                }
+            }
+        }
+        if !is_removed {
+            stripped.extend_from_slice(line);
+        }
+        pos = end;
+    }
+    stripped.extend_from_slice(&message[pos..]);
+
+    // Only signers that had signed the original message sign it again
+    let mut headers = Vec::new();
+    for (signature, _) in signatures.iter().zip(resign).filter(|(_, resign)| *resign) {
+        match signature.signer.sign(&stripped) {
+            Ok(signature) => {
+                signature.write_header(&mut headers);
+            }
+            Err(err) => {
+                trc::error!(
+                    trc::Error::from(err)
+                        .span_id(params.session_id)
+                        .details("Failed to DKIM sign message")
+                );
+            }
+        }
+    }
+    headers.extend_from_slice(&stripped);
+    headers
+}
+
+/// Obtains the signing domain and selector from a DKIM-Signature header value.
+fn signature_tags(value: &str) -> (&str, &str) {
+    let mut domain = "";
+    let mut selector = "";
+    for tag in value.split(';') {
+        if let Some((name, value)) = tag.split_once('=') {
+            match name.trim() {
+                "d" => domain = value.trim(),
+                "s" => selector = value.trim(),
+                _ => (),
+            }
+        }
+    }
+    (domain, selector)
+}
+
+#[inline(always)]
+fn is_address_char(ch: &u8) -> bool {
+    ch.is_ascii_alphanumeric() || matches!(ch, b'.' | b'_' | b'+' | b'-')
+}
+
+/// Re-encodes 8-bit MIME parts as quoted-printable or base64, returning `None`
+/// when the message cannot be represented in 7-bit.
+fn downgrade_8bit(message: &[u8]) -> Option<Vec<u8>> {
+    let parsed = MessageParser::new().parse(message)?;
+    let mut downgraded = Vec::with_capacity(message.len() + message.len() / 2);
+    let mut pos = 0;
+
+    for part in &parsed.parts {
+        let offset_header = part.offset_header as usize;
+        let offset_body = part.offset_body as usize;
+        let offset_end = part.offset_end as usize;
+        let body = message.get(offset_body..offset_end)?;
+        if body.is_ascii()
+            || offset_header < pos
+            || matches!(part.body, PartType::Multipart(_) | PartType::Message(_))
+        {
+            continue;
+        }
+
+        // Text parts remain readable as quoted-printable, anything else is base64 encoded
+        let is_text = matches!(part.body, PartType::Text(_) | PartType::Html(_));
+        let encoding = if is_text {
+            "quoted-printable"
+        } else {
+            "base64"
+        };
+        downgraded.extend_from_slice(message.get(pos..offset_header)?);
+        downgraded.extend_from_slice(&rewrite_headers(
+            message.get(offset_header..offset_body)?.to_vec(),
+            &[format!("Content-Transfer-Encoding: {encoding}")],
+            &["Content-Transfer-Encoding".to_string()],
+        ));
+        if is_text {
+            quoted_printable_encode(body, &mut downgraded, false, true).ok()?;
+        } else {
+            base64_encode_mime(body, &mut downgraded, false).ok()?;
+        }
+        pos = offset_end;
+    }
+    downgraded.extend_from_slice(message.get(pos..)?);
+
+    // Headers and nested messages cannot be downgraded
+    downgraded.is_ascii().then_some(downgraded)
+}
+
 #[allow(clippy::large_enum_variant)]
 pub enum StartTlsResult {
     Success {
@@ -626,6 +1091,15 @@ pub enum StartTlsResult {
     },
 }
 
+pub enum RacedConnection {
+    Primary(SmtpClient<TcpStream>),
+    Secondary(SmtpClient<TcpStream>),
+    Failed {
+        primary: mail_send::Error,
+        secondary: Option<mail_send::Error>,
+    },
+}
+
 pub(crate) fn from_mail_send_error(error: &mail_send::Error) -> trc::Error {
     let event = trc::EventType::Smtp(trc::SmtpEvent::Error).into_err();
     match error {
@@ -669,6 +1143,7 @@ pub(crate) fn from_error_details(err: &Error) -> trc::Error {
     let event = trc::EventType::Smtp(trc::SmtpEvent::Error).into_err();
     match err {
         Error::DnsError(err) => event.details("DNS Error").reason(err),
+        Error::DomainNotFound(err) => event.details("Domain Not Found").reason(err),
         Error::UnexpectedResponse(reply) => event
             .details("Unexpected SMTP Response")
             .ctx(trc::Key::Code, reply.response.code)
@@ -687,5 +1162,8 @@ pub(crate) fn from_error_details(err: &Error) -> trc::Error {
         Error::RateLimited => event.details("Rate Limited"),
         Error::ConcurrencyLimited => event.details("Concurrency Limited"),
         Error::Io(err) => event.details("I/O Error").reason(err),
+        Error::MessageTooLarge(limit) => event
+            .details("Message Too Large")
+            .ctx(trc::Key::Limit, *limit),
     }
 }
@@ -142,7 +142,8 @@ impl TlsaVerify for Tlsa {
                 Hostname = hostname.to_string(),
             );
 
-            Err(Status::PermanentFailure(ErrorDetails {
+            // Defer rather than bounce, a mismatch is often a transient misconfiguration
+            Err(Status::TemporaryFailure(ErrorDetails {
                 entity: hostname.into(),
                 details: Error::DaneError("No matching certificates found in TLSA records".into()),
             }))
@@ -4,43 +4,47 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use super::{NextHop, lookup::ToNextHop, mta_sts, session::SessionParams};
-use crate::outbound::DeliveryResult;
+use super::{NextHop, lookup::ToNextHop, session::SessionParams};
+use crate::core::batv::BatvTag;
 use crate::outbound::client::{
-    SmtpClient, from_error_details, from_error_status, from_mail_send_error,
+    RacedConnection, SmtpClient, from_error_details, from_error_status, from_mail_send_error,
 };
-use crate::outbound::dane::dnssec::TlsaLookup;
 use crate::outbound::lookup::{DnsLookup, SourceIp};
-use crate::outbound::mta_sts::lookup::MtaStsLookup;
 use crate::outbound::mta_sts::verify::VerifyPolicy;
-use crate::outbound::{client::StartTlsResult, dane::verify::TlsaVerify};
+use crate::outbound::remote::{MxHosts, RemoteLookup, SmtpConnection, TlsParams};
+use crate::outbound::{DeliveryReport, DeliveryResult, DeliveryStep};
 use crate::queue::dsn::SendDsn;
 use crate::queue::spool::SmtpSpool;
 use crate::queue::throttle::IsAllowed;
 use crate::queue::{
     DomainPart, Error, FROM_REPORT, HostResponse, MessageWrapper, QueueEnvelope, QueuedMessage,
-    Status,
+    Status, TLS_OPTIONAL, TlsDetails,
 };
 use crate::reporting::SmtpReporting;
 use crate::{queue::ErrorDetails, reporting::tls::TlsRptOptions};
 use ahash::AHashMap;
 use common::Server;
-use common::config::smtp::queue::GatewayStrategy;
+use common::config::smtp::queue::{ConnectionStrategy, GatewayStrategy, TransientFailure};
 use common::config::{server::ServerProtocol, smtp::report::AggregateFrequency};
-use common::ipc::{PolicyType, QueueEvent, QueueEventStatus, TlsEvent};
+use common::ipc::{QueueEvent, QueueEventStatus, TlsEvent};
 use compact_str::ToCompactString;
 use mail_auth::{
     mta_sts::TlsRpt,
     report::tlsrpt::{FailureDetails, ResultType},
 };
-use smtp_proto::MAIL_REQUIRETLS;
-use std::sync::Arc;
+use smtp_proto::{MAIL_BY_RETURN, MAIL_REQUIRETLS};
 use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr},
     time::Instant,
 };
 use store::write::{BatchBuilder, QueueClass, ValueClass, now};
-use trc::{DaneEvent, DeliveryEvent, MtaStsEvent, ServerEvent, TlsRptEvent};
+use trc::{DeliveryEvent, MtaStsEvent, ServerEvent, TlsRptEvent};
+
+// Seconds to wait before retrying a domain that reached its concurrency limit
+const CONCURRENCY_RETRY: u64 = 1;
+
+#[cfg(feature = "test_mode")]
+pub static RETRY_JITTER_SEED: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
 
 impl QueuedMessage {
     pub fn try_deliver(self, server: Server) {
@@ -73,6 +77,7 @@ impl QueuedMessage {
                                     r.status,
                                     Status::Scheduled | Status::TemporaryFailure(_)
                                 ) && r.queue == message.queue_name
+                                    && !r.is_held()
                                 {
                                     Some(trc::Value::String(r.address_lcase.as_str().into()))
                                 } else {
@@ -86,7 +91,7 @@ impl QueuedMessage {
 
                     // Attempt delivery
                     let start_time = Instant::now();
-                    let queue_event = self.deliver_task(server.clone(), message).await;
+                    let queue_event = self.deliver_task(server.clone(), message, None).await;
 
                     trc::event!(
                         Delivery(DeliveryEvent::AttemptEnd),
@@ -147,93 +152,121 @@ impl QueuedMessage {
         });
     }
 
-    async fn deliver_task(self, server: Server, mut message: MessageWrapper) -> QueueEventStatus {
-        // Check that the message still has recipients to be delivered
-        let has_pending_delivery = message.has_pending_delivery();
+    pub(crate) async fn deliver_task(
+        self,
+        server: Server,
+        mut message: MessageWrapper,
+        report: Option<&mut DeliveryReport>,
+    ) -> QueueEventStatus {
         let span_id = message.span_id;
+        let is_dry_run = report.is_some();
+
+        // Dry runs do not send notifications, reschedule the message or consume rate limits
+        if !is_dry_run {
+            // Check that the message still has recipients to be delivered
+            let has_pending_delivery = message.has_pending_delivery();
+
+            // Send any due Delivery Status Notifications
+            server.send_dsn(&mut message).await;
+
+            match has_pending_delivery {
+                PendingDelivery::Yes(true)
+                    if message
+                        .message
+                        .next_delivery_event(self.queue_name.into())
+                        .is_some_and(|due| due <= now()) => {}
+                PendingDelivery::No => {
+                    trc::event!(
+                        Delivery(DeliveryEvent::Completed),
+                        SpanId = span_id,
+                        Elapsed = trc::Value::Duration((now() - message.message.created) * 1000)
+                    );
 
-        // Send any due Delivery Status Notifications
-        server.send_dsn(&mut message).await;
-
-        match has_pending_delivery {
-            PendingDelivery::Yes(true)
-                if message
-                    .message
-                    .next_delivery_event(self.queue_name.into())
-                    .is_some_and(|due| due <= now()) => {}
-            PendingDelivery::No => {
-                trc::event!(
-                    Delivery(DeliveryEvent::Completed),
-                    SpanId = span_id,
-                    Elapsed = trc::Value::Duration((now() - message.message.created) * 1000)
-                );
-
-                // All message recipients expired, do not re-queue. (DSN has been already sent)
-                message.remove(&server, self.due.into()).await;
+                    // All message recipients expired, do not re-queue. (DSN has been already sent)
+                    message.update_email_submission(&server).await;
+                    message.remove(&server, self.due.into()).await;
 
-                return QueueEventStatus::Completed;
-            }
-            _ => {
-                // Re-queue the message if its not yet due for delivery
-                message.save_changes(&server, self.due.into()).await;
-                return QueueEventStatus::Deferred;
+                    return QueueEventStatus::Completed;
+                }
+                _ => {
+                    // Re-queue the message if its not yet due for delivery
+                    message.save_changes(&server, self.due.into()).await;
+                    return QueueEventStatus::Deferred;
+                }
             }
-        }
 
-        // Throttle sender
-        for throttle in &server.core.smtp.queue.outbound_limiters.sender {
-            if let Err(retry_at) = server
-                .is_allowed(throttle, &message.message, message.span_id)
-                .await
-            {
-                trc::event!(
-                    Delivery(DeliveryEvent::RateLimitExceeded),
-                    Id = throttle.id.clone(),
-                    SpanId = span_id,
-                    NextRetry = trc::Value::Timestamp(retry_at)
-                );
+            // Throttle sender
+            for throttle in &server.core.smtp.queue.outbound_limiters.sender {
+                if let Err(retry_at) = server
+                    .is_allowed(throttle, &message.message, message.span_id)
+                    .await
+                {
+                    trc::event!(
+                        Delivery(DeliveryEvent::RateLimitExceeded),
+                        Id = throttle.id.clone(),
+                        SpanId = span_id,
+                        NextRetry = trc::Value::Timestamp(retry_at)
+                    );
 
-                let now = now();
-                for rcpt in message.message.recipients.iter_mut() {
-                    if matches!(
-                        &rcpt.status,
-                        Status::Scheduled | Status::TemporaryFailure(_)
-                    ) && rcpt.retry.due <= now
-                        && rcpt.queue == message.queue_name
-                    {
-                        rcpt.retry.due = retry_at;
-                        rcpt.status = Status::TemporaryFailure(ErrorDetails {
-                            entity: "localhost".to_string(),
-                            details: Error::RateLimited,
-                        });
+                    let now = now();
+                    for rcpt in message.message.recipients.iter_mut() {
+                        if matches!(
+                            &rcpt.status,
+                            Status::Scheduled | Status::TemporaryFailure(_)
+                        ) && rcpt.retry.due <= now
+                            && rcpt.queue == message.queue_name
+                            && !rcpt.is_held()
+                        {
+                            rcpt.retry.due = retry_at;
+                            rcpt.status = Status::TemporaryFailure(ErrorDetails {
+                                entity: "localhost".to_string(),
+                                details: Error::RateLimited,
+                            });
+                        }
                     }
-                }
 
-                message.save_changes(&server, self.due.into()).await;
+                    message.save_changes(&server, self.due.into()).await;
 
-                return QueueEventStatus::Deferred;
+                    return QueueEventStatus::Deferred;
+                }
             }
         }
 
-        // Group recipients by gateway
+        // Group recipients by gateway, rerouted recipients use their pinned next hop
         let queue_config = &server.core.smtp.queue;
         let now_ = now();
+        let next_hops = message
+            .message
+            .recipients
+            .iter()
+            .map(|rcpt| rcpt.next_hop_gateway())
+            .collect::<Vec<_>>();
         let mut gateways: AHashMap<(&str, &GatewayStrategy), Vec<usize>> = AHashMap::new();
         for (rcpt_idx, rcpt) in message.message.recipients.iter().enumerate() {
             if matches!(
                 &rcpt.status,
                 Status::Scheduled | Status::TemporaryFailure(_)
-            ) && rcpt.retry.due <= now_
-                && rcpt.queue == message.queue_name
+            ) && (is_dry_run
+                || (rcpt.retry.due <= now_
+                    && rcpt.queue == message.queue_name
+                    && !rcpt.is_held()))
             {
-                let envelope = QueueEnvelope::new(&message.message, rcpt);
-                let gateway = server.get_gateway_or_default(
-                    &server
-                        .eval_if::<String, _>(&queue_config.gateway, &envelope, message.span_id)
-                        .await
-                        .unwrap_or_else(|| "default".to_string()),
-                    message.span_id,
-                );
+                let gateway = if let Some(gateway) = &next_hops[rcpt_idx] {
+                    gateway
+                } else {
+                    let envelope = QueueEnvelope::new(&message.message, rcpt);
+                    server.get_gateway_or_default(
+                        &server
+                            .eval_if::<String, _>(
+                                &queue_config.gateway,
+                                &envelope,
+                                message.span_id,
+                            )
+                            .await
+                            .unwrap_or_else(|| "default".to_string()),
+                        message.span_id,
+                    )
+                };
 
                 gateways
                     .entry((rcpt.address_lcase.domain_part(), gateway))
@@ -244,6 +277,7 @@ impl QueuedMessage {
 
         let no_ip = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
         let mut delivery_results: Vec<DeliveryResult> = Vec::new();
+        let mut tls_results: Vec<(usize, TlsDetails)> = Vec::new();
         'next_gateway: for ((domain, gateway), rcpt_idxs) in gateways {
             trc::event!(
                 Delivery(DeliveryEvent::DomainDeliveryStart),
@@ -256,30 +290,120 @@ impl QueuedMessage {
                 QueueEnvelope::new(&message.message, &message.message.recipients[rcpt_idxs[0]]);
 
             // Throttle recipient domain
-            for throttle in &queue_config.outbound_limiters.rcpt {
-                if let Err(retry_at) = server
-                    .is_allowed(throttle, &envelope, message.span_id)
-                    .await
-                {
+            if !is_dry_run {
+                for throttle in &queue_config.outbound_limiters.rcpt {
+                    if let Err(retry_at) = server
+                        .is_allowed(throttle, &envelope, message.span_id)
+                        .await
+                    {
+                        trc::event!(
+                            Delivery(DeliveryEvent::RateLimitExceeded),
+                            Id = throttle.id.clone(),
+                            SpanId = span_id,
+                            Domain = domain.to_string(),
+                        );
+
+                        delivery_results.push(DeliveryResult::rate_limited(rcpt_idxs, retry_at));
+                        continue 'next_gateway;
+                    }
+                }
+            }
+
+            // Limit concurrent deliveries to the recipient domain
+            let max_concurrent = server
+                .eval_if::<u64, _>(&queue_config.concurrency, &envelope, message.span_id)
+                .await
+                .unwrap_or(0);
+            let _in_flight = if max_concurrent > 0 && !is_dry_run {
+                if let Some(in_flight) = server.is_domain_allowed(domain, max_concurrent) {
+                    Some(in_flight)
+                } else {
                     trc::event!(
-                        Delivery(DeliveryEvent::RateLimitExceeded),
-                        Id = throttle.id.clone(),
+                        Delivery(DeliveryEvent::ConcurrencyLimitExceeded),
                         SpanId = span_id,
                         Domain = domain.to_string(),
+                        Limit = max_concurrent,
                     );
 
-                    delivery_results.push(DeliveryResult::rate_limited(rcpt_idxs, retry_at));
+                    delivery_results.push(DeliveryResult::concurrency_limited(rcpt_idxs));
                     continue 'next_gateway;
                 }
+            } else {
+                None
+            };
+
+            // Headers added to or removed from the copy sent to this domain
+            let add_headers = server
+                .eval_if::<Vec<String>, _>(&queue_config.add_headers, &envelope, message.span_id)
+                .await
+                .unwrap_or_default();
+            let remove_headers = server
+                .eval_if::<Vec<String>, _>(
+                    &queue_config.remove_headers,
+                    &envelope,
+                    message.span_id,
+                )
+                .await
+                .unwrap_or_default();
+
+            // Masquerade the sender address in the envelope and headers
+            let rewrite_sender = if !message.message.return_path.is_empty() {
+                server
+                    .eval_if::<String, _>(&queue_config.rewrite_sender, &envelope, message.span_id)
+                    .await
+                    .filter(|address| address.contains('@'))
+            } else {
+                None
+            };
+            let rewrite_headers = if rewrite_sender.is_some() {
+                server
+                    .eval_if::<Vec<String>, _>(
+                        &queue_config.rewrite_headers,
+                        &envelope,
+                        message.span_id,
+                    )
+                    .await
+                    .unwrap_or_default()
+            } else {
+                vec![]
+            };
+
+            // Signatures applied again if the copy sent to this domain is modified
+            let dkim_sign = server
+                .eval_if::<Vec<String>, _>(&queue_config.dkim_sign, &envelope, message.span_id)
+                .await
+                .unwrap_or_default();
+            let sender = rewrite_sender
+                .as_deref()
+                .unwrap_or(message.message.return_path.as_str());
+
+            // Tag the envelope sender for bounce address validation
+            let batv = &server.core.smtp.mail_auth.batv;
+            let return_path = if server
+                .eval_if(&batv.sign, &envelope, message.span_id)
+                .await
+                .unwrap_or(false)
+            {
+                batv.batv_sign(sender, now_)
+            } else {
+                None
             }
+            .unwrap_or_else(|| sender.to_string());
 
             // Obtain next hop
             let (mut remote_hosts, mx_config, is_smtp) = match gateway {
                 GatewayStrategy::Local => {
-                    // Deliver message locally
-                    message
-                        .deliver_local(&rcpt_idxs, &mut delivery_results, &server)
-                        .await;
+                    if is_dry_run {
+                        delivery_results.push(DeliveryResult::report(DeliveryStep::NextHop {
+                            domain: domain.to_string(),
+                            hosts: vec!["local".to_string()],
+                        }));
+                    } else {
+                        // Deliver message locally
+                        message
+                            .deliver_local(&rcpt_idxs, &mut delivery_results, &server)
+                            .await;
+                    }
                     continue 'next_gateway;
                 }
                 GatewayStrategy::Mx(mx_config) => (Vec::with_capacity(0), Some(mx_config), true),
@@ -372,177 +496,61 @@ impl QueuedMessage {
                     None
                 };
 
+            // Dry runs do not schedule TLS reports
+            let tls_report = tls_report.filter(|_| !is_dry_run);
+
             // Obtain MTA-STS policy for domain
-            let mta_sts_policy = if mx_config.is_some() && tls_strategy.try_mta_sts() && is_smtp {
-                let time = Instant::now();
-                match server
-                    .lookup_mta_sts_policy(domain, tls_strategy.timeout_mta_sts)
-                    .await
+            let is_tls_optional = (message.message.flags & TLS_OPTIONAL) != 0;
+            let mta_sts_policy =
+                if mx_config.is_some() && tls_strategy.try_mta_sts() && is_smtp && !is_tls_optional
                 {
-                    Ok(mta_sts_policy) => {
-                        trc::event!(
-                            MtaSts(MtaStsEvent::PolicyFetch),
-                            SpanId = message.span_id,
-                            Domain = domain.to_string(),
-                            Strict = mta_sts_policy.enforce(),
-                            Details = mta_sts_policy
-                                .mx
-                                .iter()
-                                .map(|mx| trc::Value::String(mx.to_compact_string()))
-                                .collect::<Vec<_>>(),
-                            Elapsed = time.elapsed(),
-                        );
-
-                        mta_sts_policy.into()
-                    }
-                    Err(err) => {
-                        // Report MTA-STS error
-                        let strict = tls_strategy.is_mta_sts_required();
-                        if let Some(tls_report) = &tls_report {
-                            match &err {
-                                mta_sts::Error::Dns(mail_auth::Error::DnsRecordNotFound(_)) => {
-                                    if strict {
-                                        server.schedule_report(TlsEvent {
-                                            policy: PolicyType::Sts(None),
-                                            domain: domain.to_string(),
-                                            failure: FailureDetails::new(ResultType::Other)
-                                                .with_failure_reason_code(
-                                                    "MTA-STS is required and no policy was found.",
-                                                )
-                                                .into(),
-                                            tls_record: tls_report.record.clone(),
-                                            interval: tls_report.interval,
-                                        })
-                                        .await;
-                                    }
-                                }
-                                mta_sts::Error::Dns(mail_auth::Error::DnsError(_)) => (),
-                                _ => {
-                                    server
-                                        .schedule_report(TlsEvent {
-                                            policy: PolicyType::Sts(None),
-                                            domain: domain.to_string(),
-                                            failure: FailureDetails::new(&err)
-                                                .with_failure_reason_code(err.to_string())
-                                                .into(),
-                                            tls_record: tls_report.record.clone(),
-                                            interval: tls_report.interval,
-                                        })
-                                        .await;
-                                }
-                            }
-                        }
-
-                        match &err {
-                            mta_sts::Error::Dns(mail_auth::Error::DnsRecordNotFound(_)) => {
-                                trc::event!(
-                                    MtaSts(MtaStsEvent::PolicyNotFound),
-                                    SpanId = message.span_id,
-                                    Domain = domain.to_string(),
-                                    Strict = strict,
-                                    Elapsed = time.elapsed(),
-                                );
-                            }
-                            mta_sts::Error::Dns(err) => {
-                                trc::event!(
-                                    MtaSts(MtaStsEvent::PolicyFetchError),
-                                    SpanId = message.span_id,
-                                    Domain = domain.to_string(),
-                                    CausedBy = trc::Error::from(err.clone()),
-                                    Strict = strict,
-                                    Elapsed = time.elapsed(),
-                                );
-                            }
-                            mta_sts::Error::Http(err) => {
-                                trc::event!(
-                                    MtaSts(MtaStsEvent::PolicyFetchError),
-                                    SpanId = message.span_id,
-                                    Domain = domain.to_string(),
-                                    Reason = err.to_string(),
-                                    Strict = strict,
-                                    Elapsed = time.elapsed(),
-                                );
-                            }
-                            mta_sts::Error::InvalidPolicy(reason) => {
-                                trc::event!(
-                                    MtaSts(MtaStsEvent::InvalidPolicy),
-                                    SpanId = message.span_id,
-                                    Domain = domain.to_string(),
-                                    Reason = reason.clone(),
-                                    Strict = strict,
-                                    Elapsed = time.elapsed(),
-                                );
-                            }
-                        }
-
-                        if strict {
-                            delivery_results.push(DeliveryResult::domain(
-                                Status::from_mta_sts_error(domain, err),
-                                rcpt_idxs,
-                            ));
+                    match server
+                        .mta_sts_policy(domain, tls_strategy, tls_report.as_ref(), message.span_id)
+                        .await
+                    {
+                        Ok(mta_sts_policy) => mta_sts_policy,
+                        Err(status) => {
+                            delivery_results.push(DeliveryResult::domain(status, rcpt_idxs));
                             continue 'next_gateway;
                         }
-
-                        None
                     }
-                }
-            } else {
-                None
-            };
+                } else {
+                    None
+                };
 
             // Obtain remote hosts list
-            let mx_list;
+            let mx_hosts;
             if let Some(mx_config) = mx_config {
-                // Lookup MX
                 let time = Instant::now();
-                mx_list = match server
-                    .core
-                    .smtp
-                    .resolvers
-                    .dns
-                    .mx_lookup(domain, Some(&server.inner.cache.dns_mx))
-                    .await
-                {
-                    Ok(mx) => mx,
-                    Err(mail_auth::Error::DnsRecordNotFound(_)) => {
-                        trc::event!(
-                            Delivery(DeliveryEvent::MxLookupFailed),
-                            SpanId = message.span_id,
-                            Domain = domain.to_string(),
-                            Details = "No MX records were found, attempting implicit MX.",
-                            Elapsed = time.elapsed(),
-                        );
-
-                        Arc::new(vec![])
+                mx_hosts = match server.mx_hosts(domain, mx_config, message.span_id).await {
+                    Ok(mx_hosts) => mx_hosts,
+                    Err(status) => {
+                        delivery_results.push(DeliveryResult::domain(status, rcpt_idxs));
+                        continue 'next_gateway;
                     }
-                    Err(err) => {
+                };
+
+                // Obtain the host that failed during the last delivery attempt
+                let last_failed = match &message.message.recipients[rcpt_idxs[0]].status {
+                    Status::TemporaryFailure(err) => Some(err.entity.as_str()),
+                    _ => None,
+                };
+
+                if let Some(remote_hosts_) =
+                    mx_hosts.to_remote_hosts(domain, mx_config, last_failed)
+                {
+                    if matches!(mx_hosts, MxHosts::Mx(_)) {
                         trc::event!(
-                            Delivery(DeliveryEvent::MxLookupFailed),
+                            Delivery(DeliveryEvent::MxLookup),
                             SpanId = message.span_id,
                             Domain = domain.to_string(),
-                            CausedBy = trc::Error::from(err.clone()),
+                            Details = remote_hosts_
+                                .iter()
+                                .map(|h| trc::Value::String(h.hostname().into()))
+                                .collect::<Vec<_>>(),
                             Elapsed = time.elapsed(),
                         );
-
-                        delivery_results.push(DeliveryResult::domain(
-                            Status::from_mail_auth_error(domain, err),
-                            rcpt_idxs,
-                        ));
-                        continue 'next_gateway;
                     }
-                };
-
-                if let Some(remote_hosts_) = mx_list.to_remote_hosts(domain, mx_config) {
-                    trc::event!(
-                        Delivery(DeliveryEvent::MxLookup),
-                        SpanId = message.span_id,
-                        Domain = domain.to_string(),
-                        Details = remote_hosts_
-                            .iter()
-                            .map(|h| trc::Value::String(h.hostname().into()))
-                            .collect::<Vec<_>>(),
-                        Elapsed = time.elapsed(),
-                    );
                     remote_hosts = remote_hosts_;
                 } else {
                     trc::event!(
@@ -565,6 +573,16 @@ impl QueuedMessage {
                 }
             }
 
+            if is_dry_run {
+                delivery_results.push(DeliveryResult::report(DeliveryStep::NextHop {
+                    domain: domain.to_string(),
+                    hosts: remote_hosts
+                        .iter()
+                        .map(|host| host.hostname().to_string())
+                        .collect(),
+                }));
+            }
+
             // Try delivering message
             let mut last_status: Status<HostResponse<String>, ErrorDetails> = Status::Scheduled;
             'next_host: for remote_host in &remote_hosts {
@@ -673,160 +691,21 @@ impl QueuedMessage {
                 );
 
                 // Lookup DANE policy
-                let dane_policy = if tls_strategy.try_dane() && is_smtp {
-                    let time = Instant::now();
-                    let strict = tls_strategy.is_dane_required();
+                let dane_policy = if tls_strategy.try_dane() && is_smtp && !is_tls_optional {
                     match server
-                        .tlsa_lookup(format!("_25._tcp.{}.", envelope.mx))
+                        .dane_policy(
+                            domain,
+                            envelope.mx,
+                            tls_strategy,
+                            tls_report.as_ref(),
+                            message.span_id,
+                        )
                         .await
                     {
-                        Ok(Some(tlsa)) => {
-                            if tlsa.has_end_entities {
-                                trc::event!(
-                                    Dane(DaneEvent::TlsaRecordFetch),
-                                    SpanId = message.span_id,
-                                    Domain = domain.to_string(),
-                                    Hostname = envelope.mx.to_string(),
-                                    Details = format!("{tlsa:?}"),
-                                    Strict = strict,
-                                    Elapsed = time.elapsed(),
-                                );
-
-                                tlsa.into()
-                            } else {
-                                trc::event!(
-                                    Dane(DaneEvent::TlsaRecordInvalid),
-                                    SpanId = message.span_id,
-                                    Domain = domain.to_string(),
-                                    Hostname = envelope.mx.to_string(),
-                                    Details = format!("{tlsa:?}"),
-                                    Strict = strict,
-                                    Elapsed = time.elapsed(),
-                                );
-
-                                // Report invalid TLSA record
-                                if let Some(tls_report) = &tls_report {
-                                    server
-                                        .schedule_report(TlsEvent {
-                                            policy: tlsa.into(),
-                                            domain: domain.to_string(),
-                                            failure: FailureDetails::new(ResultType::TlsaInvalid)
-                                                .with_receiving_mx_hostname(envelope.mx)
-                                                .with_failure_reason_code("Invalid TLSA record.")
-                                                .into(),
-                                            tls_record: tls_report.record.clone(),
-                                            interval: tls_report.interval,
-                                        })
-                                        .await;
-                                }
-
-                                if strict {
-                                    last_status = Status::PermanentFailure(ErrorDetails {
-                                        entity: envelope.mx.to_string(),
-                                        details: Error::DaneError(
-                                            "No valid TLSA records were found".into(),
-                                        ),
-                                    });
-                                    continue 'next_host;
-                                }
-                                None
-                            }
-                        }
-                        Ok(None) => {
-                            trc::event!(
-                                Dane(DaneEvent::TlsaRecordNotDnssecSigned),
-                                SpanId = message.span_id,
-                                Domain = domain.to_string(),
-                                Hostname = envelope.mx.to_string(),
-                                Strict = strict,
-                                Elapsed = time.elapsed(),
-                            );
-
-                            if strict {
-                                // Report DANE required
-                                if let Some(tls_report) = &tls_report {
-                                    server
-                                        .schedule_report(TlsEvent {
-                                            policy: PolicyType::Tlsa(None),
-                                            domain: domain.to_string(),
-                                            failure: FailureDetails::new(ResultType::DaneRequired)
-                                                .with_receiving_mx_hostname(envelope.mx)
-                                                .with_failure_reason_code(
-                                                    "No TLSA DNSSEC records found.",
-                                                )
-                                                .into(),
-                                            tls_record: tls_report.record.clone(),
-                                            interval: tls_report.interval,
-                                        })
-                                        .await;
-                                }
-
-                                last_status = Status::PermanentFailure(ErrorDetails {
-                                    entity: envelope.mx.into(),
-                                    details: Error::DaneError(
-                                        "No TLSA DNSSEC records found".into(),
-                                    ),
-                                });
-                                continue 'next_host;
-                            }
-                            None
-                        }
-                        Err(err) => {
-                            let not_found = matches!(&err, mail_auth::Error::DnsRecordNotFound(_));
-
-                            if not_found {
-                                trc::event!(
-                                    Dane(DaneEvent::TlsaRecordNotFound),
-                                    SpanId = message.span_id,
-                                    Domain = domain.to_string(),
-                                    Hostname = envelope.mx.to_string(),
-                                    Strict = strict,
-                                    Elapsed = time.elapsed(),
-                                );
-                            } else {
-                                trc::event!(
-                                    Dane(DaneEvent::TlsaRecordFetchError),
-                                    SpanId = message.span_id,
-                                    Domain = domain.to_string(),
-                                    Hostname = envelope.mx.to_string(),
-                                    CausedBy = trc::Error::from(err.clone()),
-                                    Strict = strict,
-                                    Elapsed = time.elapsed(),
-                                );
-                            }
-
-                            if strict {
-                                last_status = if not_found {
-                                    // Report DANE required
-                                    if let Some(tls_report) = &tls_report {
-                                        server
-                                            .schedule_report(TlsEvent {
-                                                policy: PolicyType::Tlsa(None),
-                                                domain: domain.to_string(),
-                                                failure: FailureDetails::new(
-                                                    ResultType::DaneRequired,
-                                                )
-                                                .with_receiving_mx_hostname(envelope.mx)
-                                                .with_failure_reason_code(
-                                                    "No TLSA records found for MX.",
-                                                )
-                                                .into(),
-                                                tls_record: tls_report.record.clone(),
-                                                interval: tls_report.interval,
-                                            })
-                                            .await;
-                                    }
-
-                                    Status::PermanentFailure(ErrorDetails {
-                                        entity: envelope.mx.into(),
-                                        details: Error::DaneError("No TLSA records found".into()),
-                                    })
-                                } else {
-                                    Status::from_mail_auth_error(envelope.mx, err)
-                                };
-                                continue 'next_host;
-                            }
-                            None
+                        Ok(dane_policy) => dane_policy,
+                        Err(status) => {
+                            last_status = status;
+                            continue 'next_host;
                         }
                     }
                 } else {
@@ -834,26 +713,16 @@ impl QueuedMessage {
                 };
 
                 // Try each IP address
-                'next_ip: for remote_ip in resolve_result.remote_ips {
-                    // Throttle remote host
-                    envelope.remote_ip = remote_ip;
-                    for throttle in &queue_config.outbound_limiters.remote {
-                        if let Err(retry_at) = server
-                            .is_allowed(throttle, &envelope, message.span_id)
-                            .await
-                        {
-                            trc::event!(
-                                Delivery(DeliveryEvent::RateLimitExceeded),
-                                SpanId = message.span_id,
-                                Id = throttle.id.clone(),
-                                RemoteIp = remote_ip,
-                            );
-                            delivery_results
-                                .push(DeliveryResult::rate_limited(rcpt_idxs, retry_at));
-                            continue 'next_gateway;
-                        }
+                let remote_ips = resolve_result.remote_ips;
+                let mut raced_ip = None;
+                'next_ip: for (ip_pos, &remote_ip) in remote_ips.iter().enumerate() {
+                    // Skip addresses already used while racing connections
+                    if raced_ip == Some(remote_ip) {
+                        continue 'next_ip;
                     }
 
+                    envelope.remote_ip = remote_ip;
+
                     // Obtain connection parameters
                     let conn_strategy = server.get_connection_or_default(
                         &server
@@ -870,27 +739,143 @@ impl QueuedMessage {
                     // Set source IP, if any
                     let ip_host = conn_strategy.source_ip(remote_ip.is_ipv4());
 
+                    // Throttle remote host, limiters may be keyed on the source IP
+                    envelope.local_ip = ip_host.map_or(no_ip, |ip| ip.ip);
+                    if !is_dry_run {
+                        for throttle in &queue_config.outbound_limiters.remote {
+                            if let Err(retry_at) = server
+                                .is_allowed(throttle, &envelope, message.span_id)
+                                .await
+                            {
+                                trc::event!(
+                                    Delivery(DeliveryEvent::RateLimitExceeded),
+                                    SpanId = message.span_id,
+                                    Id = throttle.id.clone(),
+                                    RemoteIp = remote_ip,
+                                );
+                                delivery_results
+                                    .push(DeliveryResult::rate_limited(rcpt_idxs, retry_at));
+                                continue 'next_gateway;
+                            }
+                        }
+                    }
+
+                    // Race an address from the other family, if enabled
+                    let fallback = conn_strategy.happy_eyeballs.and_then(|stagger| {
+                        remote_ips[ip_pos + 1..]
+                            .iter()
+                            .find(|ip| ip.is_ipv4() != remote_ip.is_ipv4())
+                            .map(|ip| (*ip, stagger))
+                    });
+
                     // Connect
                     let time = Instant::now();
-                    let mut smtp_client = match if let Some(ip_host) = ip_host {
-                        envelope.local_ip = ip_host.ip;
-                        SmtpClient::connect_using(
-                            ip_host.ip,
-                            SocketAddr::new(remote_ip, remote_host.port()),
-                            conn_strategy.timeout_connect,
-                            span_id,
-                        )
-                        .await
-                    } else {
-                        envelope.local_ip = no_ip;
-                        SmtpClient::connect(
-                            SocketAddr::new(remote_ip, remote_host.port()),
-                            conn_strategy.timeout_connect,
-                            span_id,
-                        )
-                        .await
-                    } {
-                        Ok(smtp_client) => {
+                    let (remote_ip, ip_host, result) = match fallback {
+                        Some((fallback_ip, stagger)) => {
+                            // The raced address is assigned a source IP and throttled only once
+                            // it is launched, it is not raced if any limiter is exceeded
+                            let mut fallback_ip_host = None;
+                            let result = SmtpClient::connect_happy_eyeballs(
+                                (
+                                    ip_host.map(|ip| ip.ip),
+                                    SocketAddr::new(remote_ip, remote_host.port()),
+                                ),
+                                async {
+                                    let ip_host = conn_strategy.source_ip(fallback_ip.is_ipv4());
+                                    if !is_dry_run {
+                                        envelope.remote_ip = fallback_ip;
+                                        envelope.local_ip = ip_host.map_or(no_ip, |ip| ip.ip);
+                                        for throttle in &queue_config.outbound_limiters.remote {
+                                            if server
+                                                .is_allowed(throttle, &envelope, message.span_id)
+                                                .await
+                                                .is_err()
+                                            {
+                                                trc::event!(
+                                                    Delivery(DeliveryEvent::RateLimitExceeded),
+                                                    SpanId = message.span_id,
+                                                    Id = throttle.id.clone(),
+                                                    RemoteIp = fallback_ip,
+                                                );
+                                                return None;
+                                            }
+                                        }
+                                    }
+                                    fallback_ip_host = Some(ip_host);
+
+                                    Some((
+                                        ip_host.map(|ip| ip.ip),
+                                        SocketAddr::new(fallback_ip, remote_host.port()),
+                                    ))
+                                },
+                                stagger,
+                                conn_strategy.timeout_connect,
+                                span_id,
+                            )
+                            .await;
+                            let fallback_ip_host = fallback_ip_host.flatten();
+
+                            match result {
+                                RacedConnection::Primary(smtp_client) => {
+                                    (remote_ip, ip_host, Ok(smtp_client))
+                                }
+                                RacedConnection::Secondary(smtp_client) => {
+                                    raced_ip = Some(fallback_ip);
+                                    (fallback_ip, fallback_ip_host, Ok(smtp_client))
+                                }
+                                RacedConnection::Failed { primary, secondary } => {
+                                    // Both addresses failed, the raced one is not retried
+                                    if let Some(err) = secondary {
+                                        raced_ip = Some(fallback_ip);
+                                        if is_dry_run {
+                                            delivery_results.push(DeliveryResult::report(
+                                                DeliveryStep::Connect {
+                                                    hostname: envelope.mx.to_string(),
+                                                    remote_ip: fallback_ip,
+                                                    result: Err(err.to_string()),
+                                                },
+                                            ));
+                                        }
+                                        trc::event!(
+                                            Delivery(DeliveryEvent::ConnectError),
+                                            SpanId = message.span_id,
+                                            Domain = domain.to_string(),
+                                            Hostname = envelope.mx.to_string(),
+                                            LocalIp = fallback_ip_host.map_or(no_ip, |ip| ip.ip),
+                                            RemoteIp = fallback_ip,
+                                            RemotePort = remote_host.port(),
+                                            CausedBy = from_mail_send_error(&err),
+                                            Elapsed = time.elapsed(),
+                                        );
+                                    }
+
+                                    (remote_ip, ip_host, Err(primary))
+                                }
+                            }
+                        }
+                        None => {
+                            let result = SmtpClient::connect_from(
+                                ip_host.map(|ip| ip.ip),
+                                SocketAddr::new(remote_ip, remote_host.port()),
+                                conn_strategy.timeout_connect,
+                                span_id,
+                            )
+                            .await;
+
+                            (remote_ip, ip_host, result)
+                        }
+                    };
+                    envelope.remote_ip = remote_ip;
+                    envelope.local_ip = ip_host.map_or(no_ip, |ip| ip.ip);
+                    if is_dry_run {
+                        delivery_results.push(DeliveryResult::report(DeliveryStep::Connect {
+                            hostname: envelope.mx.to_string(),
+                            remote_ip,
+                            result: result.as_ref().map(|_| ()).map_err(|err| err.to_string()),
+                        }));
+                    }
+                    let mut smtp_client = match result {
+                        Ok(mut smtp_client) => {
                             trc::event!(
                                 Delivery(DeliveryEvent::Connect),
                                 SpanId = message.span_id,
@@ -902,6 +887,10 @@ impl QueuedMessage {
                                 Elapsed = time.elapsed(),
                             );
 
+                            if queue_config.dsn.include_transcript {
+                                smtp_client.transcript = Some(String::new());
+                            }
+
                             smtp_client
                         }
                         Err(err) => {
@@ -935,6 +924,16 @@ impl QueuedMessage {
                         hostname: envelope.mx,
                         local_hostname,
                         conn_strategy,
+                        add_headers: &add_headers,
+                        remove_headers: &remove_headers,
+                        return_path: &return_path,
+                        rewrite_sender: rewrite_sender
+                            .as_deref()
+                            .map(|address| (message.message.return_path.as_str(), address)),
+                        rewrite_headers: &rewrite_headers,
+                        dkim_sign: &dkim_sign,
+                        is_dry_run,
+                        tls_version: None,
                     };
 
                     // Prepare TLS connector
@@ -942,17 +941,21 @@ impl QueuedMessage {
                         || (message.message.flags & MAIL_REQUIRETLS) != 0
                         || mta_sts_policy.is_some()
                         || dane_policy.is_some();
+                    let tls_connectors = tls_strategy
+                        .connectors
+                        .as_ref()
+                        .unwrap_or(&server.inner.data.smtp_connectors);
                     // As per RFC7671 Section 5.1, DANE-EE(3) allows name mismatch
                     let tls_connector = if tls_strategy.allow_invalid_certs
                         || remote_host.allow_invalid_certs()
                         || dane_policy.as_ref().is_some_and(|t| t.has_end_entities)
                     {
-                        &server.inner.data.smtp_connectors.dummy_verify
+                        &tls_connectors.dummy_verify
                     } else {
-                        &server.inner.data.smtp_connectors.pki_verify
+                        &tls_connectors.pki_verify
                     };
 
-                    if !remote_host.implicit_tls() {
+                    let smtp_client = if !remote_host.implicit_tls() {
                         // Read greeting
                         smtp_client.timeout = conn_strategy.timeout_greeting;
                         if let Err(status) = smtp_client.read_greeting(envelope.mx).await {
@@ -965,6 +968,9 @@ impl QueuedMessage {
                             );
 
                             last_status = status;
+                            if is_deferred(conn_strategy, &last_status) {
+                                break 'next_host;
+                            }
                             continue 'next_host;
                         }
 
@@ -994,206 +1000,37 @@ impl QueuedMessage {
                                 );
 
                                 last_status = status;
+                                if is_deferred(conn_strategy, &last_status) {
+                                    break 'next_host;
+                                }
                                 continue 'next_host;
                             }
                         };
 
                         // Try starting TLS
-                        if tls_strategy.try_start_tls() {
-                            let time = Instant::now();
-                            smtp_client.timeout = tls_strategy.timeout_tls;
-                            match smtp_client
-                                .try_start_tls(tls_connector, envelope.mx, &capabilities)
-                                .await
-                            {
-                                StartTlsResult::Success { smtp_client } => {
-                                    trc::event!(
-                                        Delivery(DeliveryEvent::StartTls),
-                                        SpanId = message.span_id,
-                                        Domain = domain.to_string(),
-                                        Hostname = envelope.mx.to_string(),
-                                        Version = format!(
-                                            "{:?}",
-                                            smtp_client
-                                                .tls_connection()
-                                                .protocol_version()
-                                                .unwrap()
-                                        ),
-                                        Details = format!(
-                                            "{:?}",
-                                            smtp_client
-                                                .tls_connection()
-                                                .negotiated_cipher_suite()
-                                                .unwrap()
-                                        ),
-                                        Elapsed = time.elapsed(),
-                                    );
-
-                                    // Verify DANE
-                                    if let Some(dane_policy) = &dane_policy {
-                                        if let Err(status) = dane_policy.verify(
-                                            message.span_id,
-                                            envelope.mx,
-                                            smtp_client.tls_connection().peer_certificates(),
-                                        ) {
-                                            // Report DANE verification failure
-                                            if let Some(tls_report) = &tls_report {
-                                                server
-                                                    .schedule_report(TlsEvent {
-                                                        policy: dane_policy.into(),
-                                                        domain: domain.to_string(),
-                                                        failure: FailureDetails::new(
-                                                            ResultType::ValidationFailure,
-                                                        )
-                                                        .with_receiving_mx_hostname(envelope.mx)
-                                                        .with_receiving_ip(remote_ip)
-                                                        .with_failure_reason_code(
-                                                            "No matching certificates found.",
-                                                        )
-                                                        .into(),
-                                                        tls_record: tls_report.record.clone(),
-                                                        interval: tls_report.interval,
-                                                    })
-                                                    .await;
-                                            }
-
-                                            last_status = status;
-                                            continue 'next_host;
-                                        }
-                                    }
-
-                                    // Report TLS success
-                                    if let Some(tls_report) = &tls_report {
-                                        server
-                                            .schedule_report(TlsEvent {
-                                                policy: (&mta_sts_policy, &dane_policy).into(),
-                                                domain: domain.to_string(),
-                                                failure: None,
-                                                tls_record: tls_report.record.clone(),
-                                                interval: tls_report.interval,
-                                            })
-                                            .await;
-                                    }
-
-                                    // Deliver message over TLS
-                                    message
-                                        .deliver(
-                                            smtp_client,
-                                            rcpt_idxs,
-                                            &mut delivery_results,
-                                            params,
-                                        )
-                                        .await
-                                }
-                                StartTlsResult::Unavailable {
-                                    response,
-                                    smtp_client,
-                                } => {
-                                    // Report unavailable STARTTLS
-                                    let reason =
-                                        response.as_ref().map(|r| r.to_string()).unwrap_or_else(
-                                            || "STARTTLS was not advertised by host".to_string(),
-                                        );
-
-                                    trc::event!(
-                                        Delivery(DeliveryEvent::StartTlsUnavailable),
-                                        SpanId = message.span_id,
-                                        Domain = domain.to_string(),
-                                        Hostname = envelope.mx.to_string(),
-                                        Code = response.as_ref().map(|r| r.code()),
-                                        Details = response
-                                            .as_ref()
-                                            .map(|r| r.message().as_str())
-                                            .unwrap_or("STARTTLS was not advertised by host")
-                                            .to_string(),
-                                        Elapsed = time.elapsed(),
-                                    );
-
-                                    if let Some(tls_report) = &tls_report {
-                                        server
-                                            .schedule_report(TlsEvent {
-                                                policy: (&mta_sts_policy, &dane_policy).into(),
-                                                domain: domain.to_string(),
-                                                failure: FailureDetails::new(
-                                                    ResultType::StartTlsNotSupported,
-                                                )
-                                                .with_receiving_mx_hostname(envelope.mx)
-                                                .with_receiving_ip(remote_ip)
-                                                .with_failure_reason_code(reason)
-                                                .into(),
-                                                tls_record: tls_report.record.clone(),
-                                                interval: tls_report.interval,
-                                            })
-                                            .await;
-                                    }
-
-                                    if is_strict_tls {
-                                        last_status =
-                                            Status::from_starttls_error(envelope.mx, response);
-                                        continue 'next_host;
-                                    } else {
-                                        // TLS is not required, proceed in plain-text
-                                        message
-                                            .deliver(
-                                                smtp_client,
-                                                rcpt_idxs,
-                                                &mut delivery_results,
-                                                params,
-                                            )
-                                            .await
-                                    }
-                                }
-                                StartTlsResult::Error { error } => {
-                                    trc::event!(
-                                        Delivery(DeliveryEvent::StartTlsError),
-                                        SpanId = message.span_id,
-                                        Domain = domain.to_string(),
-                                        Hostname = envelope.mx.to_string(),
-                                        Reason = from_mail_send_error(&error),
-                                        Elapsed = time.elapsed(),
-                                    );
-
-                                    // Report TLS failure
-                                    if let (Some(tls_report), mail_send::Error::Tls(error)) =
-                                        (&tls_report, &error)
-                                    {
-                                        server
-                                            .schedule_report(TlsEvent {
-                                                policy: (&mta_sts_policy, &dane_policy).into(),
-                                                domain: domain.to_string(),
-                                                failure: FailureDetails::new(
-                                                    ResultType::CertificateNotTrusted,
-                                                )
-                                                .with_receiving_mx_hostname(envelope.mx)
-                                                .with_receiving_ip(remote_ip)
-                                                .with_failure_reason_code(error.to_string())
-                                                .into(),
-                                                tls_record: tls_report.record.clone(),
-                                                interval: tls_report.interval,
-                                            })
-                                            .await;
-                                    }
-
-                                    last_status = if is_strict_tls {
-                                        Status::from_tls_error(envelope.mx, error)
-                                    } else {
-                                        Status::from_tls_error(envelope.mx, error).into_temporary()
-                                    };
-                                    continue 'next_host;
-                                }
+                        let tls_params = TlsParams {
+                            server: &server,
+                            session_id: message.span_id,
+                            domain,
+                            hostname: envelope.mx,
+                            local_ip: ip_host.map(|ip| ip.ip),
+                            remote_addr: SocketAddr::new(remote_ip, remote_host.port()),
+                            tls_strategy,
+                            conn_strategy,
+                            tls_connector,
+                            is_strict_tls,
+                            mta_sts_policy: &mta_sts_policy,
+                            dane_policy: &dane_policy,
+                            tls_report: tls_report.as_ref(),
+                            include_transcript: queue_config.dsn.include_transcript,
+                            is_dry_run,
+                        };
+                        match smtp_client.negotiate_tls(&capabilities, &tls_params).await {
+                            Ok(smtp_client) => smtp_client,
+                            Err(status) => {
+                                last_status = status;
+                                continue 'next_host;
                             }
-                        } else {
-                            // TLS has been disabled
-                            trc::event!(
-                                Delivery(DeliveryEvent::StartTlsDisabled),
-                                SpanId = message.span_id,
-                                Domain = domain.to_string(),
-                                Hostname = envelope.mx.to_string(),
-                            );
-
-                            message
-                                .deliver(smtp_client, rcpt_idxs, &mut delivery_results, params)
-                                .await
                         }
                     } else {
                         // Start TLS
@@ -1227,13 +1064,50 @@ impl QueuedMessage {
                             );
 
                             last_status = status;
+                            if is_deferred(conn_strategy, &last_status) {
+                                break 'next_host;
+                            }
                             continue 'next_host;
                         }
 
-                        // Deliver message
-                        message
-                            .deliver(smtp_client, rcpt_idxs, &mut delivery_results, params)
-                            .await
+                        SmtpConnection::Tls(smtp_client)
+                    };
+
+                    // Deliver message
+                    match smtp_client {
+                        SmtpConnection::Tls(smtp_client) => {
+                            let tls_details =
+                                TlsDetails::from_connection(smtp_client.tls_connection());
+                            if is_dry_run {
+                                delivery_results.push(DeliveryResult::report(DeliveryStep::Tls {
+                                    hostname: envelope.mx.to_string(),
+                                    details: tls_details.clone(),
+                                }));
+                            }
+                            let results_start = delivery_results.len();
+                            message
+                                .deliver(
+                                    smtp_client,
+                                    rcpt_idxs,
+                                    &mut delivery_results,
+                                    SessionParams {
+                                        tls_version: Some(&tls_details.version),
+                                        ..params
+                                    },
+                                )
+                                .await;
+                            tls_results.extend(
+                                delivery_results[results_start..]
+                                    .iter()
+                                    .flat_map(|result| result.delivered_rcpt_idxs())
+                                    .map(|rcpt_idx| (*rcpt_idx, tls_details.clone())),
+                            );
+                        }
+                        SmtpConnection::Plain(smtp_client) => {
+                            message
+                                .deliver(smtp_client, rcpt_idxs, &mut delivery_results, params)
+                                .await
+                        }
                     }
 
                     // Continue with the next domain/gateway
@@ -1245,6 +1119,29 @@ impl QueuedMessage {
             delivery_results.push(DeliveryResult::domain(last_status, rcpt_idxs));
         }
 
+        // Dry runs only report the steps taken, the message is left untouched
+        if let Some(report) = report {
+            for delivery_result in delivery_results {
+                match delivery_result {
+                    DeliveryResult::Report(step) => report.steps.push(step),
+                    DeliveryResult::Domain {
+                        status: Status::TemporaryFailure(err) | Status::PermanentFailure(err),
+                        ..
+                    }
+                    | DeliveryResult::Account {
+                        status: Status::TemporaryFailure(err) | Status::PermanentFailure(err),
+                        ..
+                    } => report.steps.push(DeliveryStep::Failed {
+                        entity: err.entity,
+                        reason: err.details.to_string(),
+                    }),
+                    _ => {}
+                }
+            }
+
+            return QueueEventStatus::Completed;
+        }
+
         // Apply status changes
         for delivery_result in delivery_results {
             match delivery_result {
@@ -1266,9 +1163,35 @@ impl QueuedMessage {
                         message.set_rcpt_rate_limit(rcpt_idx, retry_at);
                     }
                 }
+                DeliveryResult::ConcurrencyLimited { rcpt_idxs } => {
+                    for rcpt_idx in rcpt_idxs {
+                        message.set_rcpt_concurrency_limit(rcpt_idx);
+                    }
+                }
+                DeliveryResult::Transcript {
+                    transcript,
+                    rcpt_idxs,
+                } => {
+                    // Keep the session transcript of failed recipients for their DSN
+                    for rcpt_idx in rcpt_idxs {
+                        let rcpt = &mut message.message.recipients[rcpt_idx];
+                        if matches!(
+                            &rcpt.status,
+                            Status::TemporaryFailure(_) | Status::PermanentFailure(_)
+                        ) {
+                            rcpt.transcript = Some(transcript.clone());
+                        }
+                    }
+                }
+                DeliveryResult::Report(_) => {}
             }
         }
 
+        // Record the TLS session used for each delivered recipient
+        for (rcpt_idx, tls_details) in tls_results {
+            message.message.recipients[rcpt_idx].tls = Some(tls_details);
+        }
+
         // Send Delivery Status Notifications
         server.send_dsn(&mut message).await;
 
@@ -1297,6 +1220,7 @@ impl QueuedMessage {
             );
 
             // Delete message from queue
+            message.update_email_submission(&server).await;
             message.remove(&server, self.due.into()).await;
 
             QueueEventStatus::Completed
@@ -1318,6 +1242,12 @@ impl MessageWrapper {
         let mut matches_queue = false;
 
         for rcpt in self.message.recipients.iter_mut() {
+            // Held recipients are kept in the queue until released
+            if rcpt.is_held() {
+                has_pending_delivery = true;
+                continue;
+            }
+
             match &rcpt.status {
                 Status::TemporaryFailure(err) if rcpt.is_expired(self.message.created, now) => {
                     trc::event!(
@@ -1384,6 +1314,7 @@ impl MessageWrapper {
     ) {
         let needs_retry = matches!(&status, Status::TemporaryFailure(_) | Status::Scheduled);
         self.message.recipients[rcpt_idx].status = status;
+        self.message.recipients[rcpt_idx].transcript = None;
 
         if needs_retry {
             let envelope = QueueEnvelope::new(&self.message, &self.message.recipients[rcpt_idx]);
@@ -1395,11 +1326,58 @@ impl MessageWrapper {
                 self.span_id,
             );
             let rcpt = &mut self.message.recipients[rcpt_idx];
-            rcpt.retry.due = now()
-                + queue.retry[std::cmp::min(rcpt.retry.inner as usize, queue.retry.len() - 1)];
+
+            // Honor backoff hints included in the remote server's reply
+            let mut retry_idx = rcpt.retry.inner as usize;
+            let mut hint = None;
+            if let Status::TemporaryFailure(ErrorDetails {
+                details: Error::UnexpectedResponse(err),
+                ..
+            }) = &rcpt.status
+            {
+                hint = retry_hint(&err.response.message);
+                if hint.is_none() && err.response.esc[0] == 4 && err.response.esc[1] == 7 {
+                    // Policy deferrals without an explicit hint skip ahead in the schedule
+                    retry_idx += 1;
+                }
+            }
+
+            let mut interval = queue.retry[std::cmp::min(retry_idx, queue.retry.len() - 1)];
+            if let Some(max_jitter) = queue
+                .retry_jitter
+                .map(|jitter| jitter.max_jitter(interval))
+                .filter(|max_jitter| *max_jitter > 0)
+            {
+                interval += retry_jitter(max_jitter);
+            }
+            let is_hinted =
+                hint.is_some_and(|hint| hint > interval) || retry_idx > rcpt.retry.inner as usize;
+            if let Some(hint) = hint {
+                interval = std::cmp::max(interval, hint);
+            }
+            rcpt.retry.due = now() + interval;
             rcpt.retry.inner += 1;
-            rcpt.expires = queue.expiry;
+            if (self.message.flags & MAIL_BY_RETURN) == 0 {
+                // DELIVERBY deadlines in return mode are kept
+                rcpt.expires = queue.expiry;
+            }
             rcpt.queue = queue.virtual_queue;
+
+            // Retries are postponed until the delivery window opens
+            let mut is_deferred = false;
+            if let Some(window) = &queue.window {
+                let due = window.next_open(rcpt.retry.due);
+                is_deferred = due != rcpt.retry.due;
+                rcpt.retry.due = due;
+            }
+
+            // Hinted or deferred retries never go past the message expiration
+            if let Some(expires_at) = rcpt
+                .expiration_time(self.message.created)
+                .filter(|_| is_hinted || is_deferred)
+            {
+                rcpt.retry.due = std::cmp::min(rcpt.retry.due, expires_at);
+            }
         }
     }
 
@@ -1411,4 +1389,68 @@ impl MessageWrapper {
             details: Error::RateLimited,
         });
     }
+
+    pub fn set_rcpt_concurrency_limit(&mut self, rcpt_idx: usize) {
+        let rcpt = &mut self.message.recipients[rcpt_idx];
+        rcpt.retry.due = now() + CONCURRENCY_RETRY;
+        rcpt.status = Status::TemporaryFailure(ErrorDetails {
+            entity: "localhost".to_string(),
+            details: Error::ConcurrencyLimited,
+        });
+    }
+}
+
+fn is_deferred(
+    conn_strategy: &ConnectionStrategy,
+    status: &Status<HostResponse<String>, ErrorDetails>,
+) -> bool {
+    // Temporary failures at greeting or EHLO either try the next host or defer the domain
+    conn_strategy.transient_failure == TransientFailure::Defer
+        && matches!(status, Status::TemporaryFailure(_))
+}
+
+fn retry_hint(message: &str) -> Option<u64> {
+    let message = message.to_lowercase();
+    [
+        "retry in ",
+        "retry after ",
+        "try again in ",
+        "try again after ",
+    ]
+    .iter()
+    .find_map(|pattern| {
+        let text = message[message.find(pattern)? + pattern.len()..].trim_start();
+        let digits = text
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(text.len());
+        let amount = text[..digits].parse::<u64>().ok()?;
+        let multiplier = match text[digits..]
+            .trim_start()
+            .split(|c: char| !c.is_ascii_alphabetic())
+            .next()
+            .unwrap_or_default()
+        {
+            "s" | "sec" | "secs" | "second" | "seconds" => 1,
+            "m" | "min" | "mins" | "minute" | "minutes" => 60,
+            "h" | "hr" | "hrs" | "hour" | "hours" => 3600,
+            "d" | "day" | "days" => 86400,
+            _ => return None,
+        };
+        Some(amount.saturating_mul(multiplier))
+    })
+}
+
+fn retry_jitter(max_jitter: u64) -> u64 {
+    use rand::Rng;
+
+    #[cfg(feature = "test_mode")]
+    {
+        let seed = RETRY_JITTER_SEED.load(std::sync::atomic::Ordering::Relaxed);
+        if seed != 0 {
+            use rand::SeedableRng;
+            return rand::rngs::StdRng::seed_from_u64(seed).random_range(0..=max_jitter);
+        }
+    }
+
+    rand::rng().random_range(0..=max_jitter)
 }
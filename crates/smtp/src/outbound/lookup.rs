@@ -8,12 +8,23 @@ use super::NextHop;
 use crate::queue::{Error, ErrorDetails, HostResponse, Status};
 use common::{
     Server,
-    config::smtp::queue::{ConnectionStrategy, IpAndHost, MxConfig},
+    config::smtp::{
+        queue::{ConnectionStrategy, IpAndHost, MxConfig},
+        resolver::{Srv, SrvEntry},
+    },
     expr::{V_MX, functions::ResolveVariable},
 };
-use mail_auth::{IpLookupStrategy, MX};
-use rand::{Rng, seq::SliceRandom};
-use std::{future::Future, net::IpAddr, sync::Arc};
+use mail_auth::{
+    IpLookupStrategy, MX,
+    hickory_resolver::{Name, proto::ProtoErrorKind},
+};
+use rand::seq::SliceRandom;
+use std::{
+    future::Future,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 pub struct IpLookupResult {
     pub remote_ips: Vec<IpAddr>,
@@ -32,6 +43,10 @@ pub trait DnsLookup: Sync + Send {
         remote_host: &NextHop<'_>,
         envelope: &impl ResolveVariable,
     ) -> impl Future<Output = Result<IpLookupResult, Status<HostResponse<String>, ErrorDetails>>> + Send;
+
+    fn srv_lookup(&self, key: &str) -> impl Future<Output = mail_auth::Result<Arc<Srv>>> + Send;
+
+    fn negative_ttl(&self, key: &str) -> impl Future<Output = Option<Duration>> + Send;
 }
 
 impl DnsLookup for Server {
@@ -41,6 +56,11 @@ impl DnsLookup for Server {
         strategy: IpLookupStrategy,
         max_results: usize,
     ) -> mail_auth::Result<Vec<IpAddr>> {
+        // Relay hosts may be given as IP addresses
+        if let Ok(ip) = key.parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        }
+
         let (has_ipv4, has_ipv6, v4_first) = match strategy {
             IpLookupStrategy::Ipv4Only => (true, false, false),
             IpLookupStrategy::Ipv6Only => (false, true, false),
@@ -128,18 +148,18 @@ impl DnsLookup for Server {
                     ) {
                         Status::PermanentFailure(ErrorDetails {
                             entity: remote_host.hostname().into(),
-                            details: Error::DnsError("no MX record found.".into()),
+                            details: Error::DomainNotFound("no MX record found.".into()),
                         })
                     } else {
                         Status::PermanentFailure(ErrorDetails {
                             entity: remote_host.hostname().into(),
-                            details: Error::ConnectionError("record not found for MX".into()),
+                            details: Error::DnsError("record not found for MX".into()),
                         })
                     }
                 } else {
                     Status::TemporaryFailure(ErrorDetails {
                         entity: remote_host.hostname().into(),
-                        details: Error::ConnectionError(format!("lookup error: {err}")),
+                        details: Error::DnsError(format!("lookup error: {err}")),
                     })
                 }
             })?;
@@ -167,6 +187,98 @@ impl DnsLookup for Server {
             }))
         }
     }
+
+    async fn srv_lookup(&self, key: &str) -> mail_auth::Result<Arc<Srv>> {
+        if let Some(value) = self.inner.cache.dns_srv.get(key) {
+            return Ok(value);
+        }
+
+        #[cfg(any(test, feature = "test_mode"))]
+        if true {
+            return mail_auth::common::resolver::mock_resolve(key);
+        }
+
+        let srv_lookup = self
+            .core
+            .smtp
+            .resolvers
+            .dnssec
+            .resolver
+            .srv_lookup(Name::from_str_relaxed(key)?)
+            .await?;
+        let mut entries = srv_lookup
+            .iter()
+            .map(|srv| SrvEntry {
+                target: srv.target().to_ascii(),
+                port: srv.port(),
+                priority: srv.priority(),
+                weight: srv.weight(),
+            })
+            .collect::<Vec<_>>();
+
+        // Lower priorities first, then higher weights
+        entries.sort_by(|a, b| {
+            a.priority
+                .cmp(&b.priority)
+                .then_with(|| b.weight.cmp(&a.weight))
+        });
+
+        let srv = Arc::new(Srv { entries });
+        self.inner.cache.dns_srv.insert_with_expiry(
+            key.to_string(),
+            srv.clone(),
+            srv_lookup.as_lookup().valid_until(),
+        );
+
+        Ok(srv)
+    }
+
+    async fn negative_ttl(&self, key: &str) -> Option<Duration> {
+        if let Some(ttl) = self.inner.cache.dns_soa.get(key) {
+            return Some(Duration::from_secs(ttl));
+        }
+
+        #[cfg(any(test, feature = "test_mode"))]
+        if true {
+            return mail_auth::common::resolver::mock_resolve::<Duration>(key).ok();
+        }
+
+        // As per RFC 2308 Section 5, negative answers are cached for the
+        // lesser of the SOA TTL and the SOA MINIMUM field
+        let (ttl, valid_until) = match self
+            .core
+            .smtp
+            .resolvers
+            .dnssec
+            .resolver
+            .soa_lookup(Name::from_str_relaxed(key).ok()?)
+            .await
+        {
+            Ok(soa_lookup) => (
+                soa_lookup.as_lookup().records().iter().find_map(|record| {
+                    record
+                        .data()
+                        .as_soa()
+                        .map(|soa| record.ttl().min(soa.minimum()) as u64)
+                })?,
+                soa_lookup.as_lookup().valid_until(),
+            ),
+            Err(err) => match err.kind() {
+                ProtoErrorKind::NoRecordsFound(no_records) => {
+                    let ttl = no_records.negative_ttl? as u64;
+                    (ttl, Instant::now() + Duration::from_secs(ttl))
+                }
+                _ => return None,
+            },
+        };
+
+        self.inner
+            .cache
+            .dns_soa
+            .insert_with_expiry(key.to_string(), ttl, valid_until);
+
+        Some(Duration::from_secs(ttl))
+    }
 }
 
 pub trait SourceIp {
@@ -175,14 +287,14 @@ pub trait SourceIp {
 
 impl SourceIp for ConnectionStrategy {
     fn source_ip(&self, is_v4: bool) -> Option<&IpAndHost> {
-        let ips = if is_v4 {
-            &self.source_ipv4
+        let (ips, next_ip) = if is_v4 {
+            (&self.source_ipv4, &self.next_source_ipv4)
         } else {
-            &self.source_ipv6
+            (&self.source_ipv6, &self.next_source_ipv6)
         };
         match ips.len().cmp(&1) {
             std::cmp::Ordering::Equal => ips.first(),
-            std::cmp::Ordering::Greater => Some(&ips[rand::rng().random_range(0..ips.len())]),
+            std::cmp::Ordering::Greater => Some(&ips[next_ip.next(ips.len())]),
             std::cmp::Ordering::Less => None,
         }
     }
@@ -193,14 +305,19 @@ pub trait ToNextHop {
         &'x self,
         domain: &'y str,
         config: &'x MxConfig,
+        last_failed: Option<&str>,
     ) -> Option<Vec<NextHop<'x>>>;
 }
 
+#[cfg(feature = "test_mode")]
+pub static MX_SHUFFLE_SEED: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
 impl ToNextHop for Vec<MX> {
     fn to_remote_hosts<'x, 'y: 'x>(
         &'x self,
         domain: &'y str,
         config: &'x MxConfig,
+        last_failed: Option<&str>,
     ) -> Option<Vec<NextHop<'x>>> {
         if !self.is_empty() {
             // Obtain max number of MX hosts to process
@@ -208,11 +325,24 @@ impl ToNextHop for Vec<MX> {
 
             'outer: for mx in self.iter() {
                 if mx.exchanges.len() > 1 {
+                    // Randomize hosts sharing the same preference (RFC 5321 section 5.1)
                     let mut slice = mx.exchanges.iter().collect::<Vec<_>>();
-                    slice.shuffle(&mut rand::rng());
+                    shuffle_hosts(&mut slice);
+
+                    // Try the host that failed last time after its peers
+                    if let Some(pos) = last_failed.and_then(|last_failed| {
+                        slice.iter().position(|host| {
+                            host.strip_suffix('.').unwrap_or(host.as_str()) == last_failed
+                        })
+                    }) {
+                        let host = slice.remove(pos);
+                        slice.push(host);
+                    }
+
                     for remote_host in slice {
                         remote_hosts.push(NextHop::MX {
                             host: remote_host.as_str(),
+                            port: None,
                             is_implicit: false,
                             config,
                         });
@@ -227,6 +357,7 @@ impl ToNextHop for Vec<MX> {
                     }
                     remote_hosts.push(NextHop::MX {
                         host: remote_host.as_str(),
+                        port: None,
                         is_implicit: false,
                         config,
                     });
@@ -241,6 +372,7 @@ impl ToNextHop for Vec<MX> {
             // associated with an implicit MX RR with a preference of 0, pointing to that host.
             vec![NextHop::MX {
                 host: domain,
+                port: None,
                 is_implicit: true,
                 config,
             }]
@@ -248,3 +380,43 @@ impl ToNextHop for Vec<MX> {
         }
     }
 }
+
+impl ToNextHop for Srv {
+    fn to_remote_hosts<'x, 'y: 'x>(
+        &'x self,
+        _: &'y str,
+        config: &'x MxConfig,
+        _: Option<&str>,
+    ) -> Option<Vec<NextHop<'x>>> {
+        // A target of "." means the service is not available at this domain (RFC 2782)
+        if !self.entries.is_empty() && self.entries.iter().all(|srv| srv.target != ".") {
+            self.entries
+                .iter()
+                .take(config.max_mx)
+                .map(|srv| NextHop::MX {
+                    host: srv.target.as_str(),
+                    port: srv.port.into(),
+                    is_implicit: false,
+                    config,
+                })
+                .collect::<Vec<_>>()
+                .into()
+        } else {
+            None
+        }
+    }
+}
+
+fn shuffle_hosts<T>(hosts: &mut [T]) {
+    #[cfg(feature = "test_mode")]
+    {
+        let seed = MX_SHUFFLE_SEED.load(std::sync::atomic::Ordering::Relaxed);
+        if seed != 0 {
+            use rand::SeedableRng;
+            hosts.shuffle(&mut rand::rngs::StdRng::seed_from_u64(seed));
+            return;
+        }
+    }
+
+    hosts.shuffle(&mut rand::rng());
+}
@@ -4,15 +4,17 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use crate::queue::{Error, ErrorDetails, HostResponse, Status, UnexpectedResponse};
+use crate::queue::{Error, ErrorDetails, HostResponse, Status, TlsDetails, UnexpectedResponse};
 use common::config::{
     server::ServerProtocol,
     smtp::queue::{MxConfig, RelayConfig},
 };
 use mail_auth::IpLookupStrategy;
 use mail_send::Credentials;
+use rustls::{ClientConnection, ProtocolVersion};
 use smtp_proto::{Response, Severity};
-use std::borrow::Cow;
+use std::{borrow::Cow, net::IpAddr};
+use x509_parser::prelude::{FromDer, X509Certificate};
 
 pub mod client;
 pub mod dane;
@@ -20,7 +22,9 @@ pub mod delivery;
 pub mod local;
 pub mod lookup;
 pub mod mta_sts;
+pub mod remote;
 pub mod session;
+pub mod starttls;
 
 pub(super) enum DeliveryResult {
     Domain {
@@ -35,11 +39,61 @@ pub(super) enum DeliveryResult {
         rcpt_idxs: Vec<usize>,
         retry_at: u64,
     },
+    ConcurrencyLimited {
+        rcpt_idxs: Vec<usize>,
+    },
+    Transcript {
+        transcript: String,
+        rcpt_idxs: Vec<usize>,
+    },
+    Report(DeliveryStep),
+}
+
+/// Steps taken by a dry-run delivery, which stops before the message is sent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeliveryReport {
+    pub steps: Vec<DeliveryStep>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeliveryStep {
+    NextHop {
+        domain: String,
+        hosts: Vec<String>,
+    },
+    Connect {
+        hostname: String,
+        remote_ip: IpAddr,
+        result: Result<(), String>,
+    },
+    Tls {
+        hostname: String,
+        details: TlsDetails,
+    },
+    Ehlo {
+        hostname: String,
+        capabilities: Vec<String>,
+    },
+    Rset {
+        hostname: String,
+        result: Result<Response<String>, String>,
+    },
+    Failed {
+        entity: String,
+        reason: String,
+    },
 }
 
 impl Status<HostResponse<String>, ErrorDetails> {
     pub fn from_smtp_error(hostname: &str, command: &str, err: mail_send::Error) -> Self {
         match err {
+            mail_send::Error::Timeout if !command.is_empty() => Status::timeout(
+                hostname,
+                &format!(
+                    "waiting for {} response",
+                    command.split(':').next().unwrap_or_default().trim()
+                ),
+            ),
             mail_send::Error::Io(_)
             | mail_send::Error::Tls(_)
             | mail_send::Error::Base64(_)
@@ -154,7 +208,7 @@ impl Status<HostResponse<String>, ErrorDetails> {
         match &err {
             mail_auth::Error::DnsRecordNotFound(code) => Status::PermanentFailure(ErrorDetails {
                 entity: entity.to_string(),
-                details: Error::DnsError(format!("Domain not found: {code:?}")),
+                details: Error::DomainNotFound(format!("{code:?}")),
             }),
             _ => Status::TemporaryFailure(ErrorDetails {
                 entity: entity.to_string(),
@@ -222,6 +276,7 @@ pub enum NextHop<'x> {
     MX {
         is_implicit: bool,
         host: &'x str,
+        port: Option<u16>,
         config: &'x MxConfig,
     },
 }
@@ -272,12 +327,12 @@ impl NextHop<'_> {
     }
 
     #[inline(always)]
-    fn port(&self) -> u16 {
+    pub(crate) fn port(&self) -> u16 {
         match self {
             #[cfg(feature = "test_mode")]
-            NextHop::MX { .. } => 9925,
+            NextHop::MX { port, .. } => port.unwrap_or(9925),
             #[cfg(not(feature = "test_mode"))]
-            NextHop::MX { .. } => 25,
+            NextHop::MX { port, .. } => port.unwrap_or(25),
             NextHop::Relay(host) => host.port,
         }
     }
@@ -291,7 +346,7 @@ impl NextHop<'_> {
     }
 
     #[inline(always)]
-    fn allow_invalid_certs(&self) -> bool {
+    pub(crate) fn allow_invalid_certs(&self) -> bool {
         #[cfg(feature = "test_mode")]
         {
             true
@@ -304,7 +359,7 @@ impl NextHop<'_> {
     }
 
     #[inline(always)]
-    fn implicit_tls(&self) -> bool {
+    pub(crate) fn implicit_tls(&self) -> bool {
         match self {
             NextHop::MX { .. } => false,
             NextHop::Relay(host) => host.tls_implicit,
@@ -312,7 +367,7 @@ impl NextHop<'_> {
     }
 
     #[inline(always)]
-    fn is_smtp(&self) -> bool {
+    pub(crate) fn is_smtp(&self) -> bool {
         match self {
             NextHop::MX { .. } => true,
             NextHop::Relay(host) => host.protocol == ServerProtocol::Smtp,
@@ -335,7 +390,59 @@ impl DeliveryResult {
         }
     }
 
+    pub fn concurrency_limited(rcpt_idxs: Vec<usize>) -> Self {
+        DeliveryResult::ConcurrencyLimited { rcpt_idxs }
+    }
+
     pub fn account(status: Status<HostResponse<String>, ErrorDetails>, rcpt_idx: usize) -> Self {
         DeliveryResult::Account { status, rcpt_idx }
     }
+
+    pub fn transcript(transcript: String, rcpt_idxs: Vec<usize>) -> Self {
+        DeliveryResult::Transcript {
+            transcript,
+            rcpt_idxs,
+        }
+    }
+
+    pub fn report(step: DeliveryStep) -> Self {
+        DeliveryResult::Report(step)
+    }
+
+    pub fn delivered_rcpt_idxs(&self) -> &[usize] {
+        match self {
+            DeliveryResult::Domain {
+                status: Status::Completed(_),
+                rcpt_idxs,
+            } => rcpt_idxs,
+            DeliveryResult::Account {
+                status: Status::Completed(_),
+                rcpt_idx,
+            } => std::slice::from_ref(rcpt_idx),
+            _ => &[],
+        }
+    }
+}
+
+impl TlsDetails {
+    pub fn from_connection(conn: &ClientConnection) -> Self {
+        TlsDetails {
+            version: match conn.protocol_version() {
+                Some(ProtocolVersion::TLSv1_2) => "TLSv1.2".to_string(),
+                Some(ProtocolVersion::TLSv1_3) => "TLSv1.3".to_string(),
+                Some(version) => format!("{version:?}"),
+                None => "unknown".to_string(),
+            },
+            cipher_suite: conn
+                .negotiated_cipher_suite()
+                .and_then(|suite| suite.suite().as_str())
+                .unwrap_or("unknown")
+                .to_string(),
+            peer_subject: conn
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(|cert| X509Certificate::from_der(cert.as_ref()).ok())
+                .map(|(_, cert)| cert.subject().to_string()),
+        }
+    }
 }
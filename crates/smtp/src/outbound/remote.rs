@@ -0,0 +1,727 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs LLC <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use super::{
+    NextHop,
+    client::{SmtpClient, StartTlsResult, from_mail_send_error},
+    dane::{dnssec::TlsaLookup, verify::TlsaVerify},
+    lookup::{DnsLookup, ToNextHop},
+    mta_sts::{self, lookup::MtaStsLookup, verify::VerifyPolicy},
+    starttls::StartTlsCache,
+};
+use crate::{
+    queue::{Error, ErrorDetails, HostResponse, Status},
+    reporting::{SmtpReporting, tls::TlsRptOptions},
+};
+use common::{
+    Server,
+    config::smtp::{
+        queue::{ConnectionStrategy, HandshakeFailure, MxConfig, TlsStrategy},
+        resolver::{Policy, Srv, Tlsa},
+    },
+    ipc::{PolicyType, TlsEvent},
+};
+use compact_str::ToCompactString;
+use mail_auth::{
+    MX,
+    common::resolver::IntoFqdn,
+    report::tlsrpt::{FailureDetails, ResultType},
+};
+use std::{
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Instant,
+};
+use tokio::net::TcpStream;
+use tokio_rustls::{TlsConnector, client::TlsStream};
+use trc::{DaneEvent, DeliveryEvent, MtaStsEvent};
+
+pub enum MxHosts {
+    Srv(Arc<Srv>),
+    Mx(Arc<Vec<MX>>),
+}
+
+#[allow(clippy::large_enum_variant)]
+pub enum SmtpConnection {
+    Plain(SmtpClient<TcpStream>),
+    Tls(SmtpClient<TlsStream<TcpStream>>),
+}
+
+pub struct TlsParams<'x> {
+    pub server: &'x Server,
+    pub session_id: u64,
+    pub domain: &'x str,
+    pub hostname: &'x str,
+    pub local_ip: Option<IpAddr>,
+    pub remote_addr: SocketAddr,
+    pub tls_strategy: &'x TlsStrategy,
+    pub conn_strategy: &'x ConnectionStrategy,
+    pub tls_connector: &'x TlsConnector,
+    pub is_strict_tls: bool,
+    pub mta_sts_policy: &'x Option<Arc<Policy>>,
+    pub dane_policy: &'x Option<Arc<Tlsa>>,
+    pub tls_report: Option<&'x TlsRptOptions>,
+    pub include_transcript: bool,
+    pub is_dry_run: bool,
+}
+
+pub trait RemoteLookup: Sync + Send {
+    fn mx_hosts(
+        &self,
+        domain: &str,
+        mx_config: &MxConfig,
+        session_id: u64,
+    ) -> impl Future<Output = Result<MxHosts, Status<HostResponse<String>, ErrorDetails>>> + Send;
+
+    fn mta_sts_policy(
+        &self,
+        domain: &str,
+        tls_strategy: &TlsStrategy,
+        tls_report: Option<&TlsRptOptions>,
+        session_id: u64,
+    ) -> impl Future<
+        Output = Result<Option<Arc<Policy>>, Status<HostResponse<String>, ErrorDetails>>,
+    > + Send;
+
+    fn dane_policy(
+        &self,
+        domain: &str,
+        hostname: &str,
+        tls_strategy: &TlsStrategy,
+        tls_report: Option<&TlsRptOptions>,
+        session_id: u64,
+    ) -> impl Future<Output = Result<Option<Arc<Tlsa>>, Status<HostResponse<String>, ErrorDetails>>> + Send;
+}
+
+impl RemoteLookup for Server {
+    async fn mx_hosts(
+        &self,
+        domain: &str,
+        mx_config: &MxConfig,
+        session_id: u64,
+    ) -> Result<MxHosts, Status<HostResponse<String>, ErrorDetails>> {
+        // Submission SRV records take precedence over MX records when enabled
+        if mx_config.srv_lookup {
+            let time = Instant::now();
+            let srv_list = self
+                .srv_lookup(&format!("_submission._tcp.{domain}."))
+                .await
+                .map_err(|err| {
+                    trc::event!(
+                        Delivery(DeliveryEvent::SrvLookupFailed),
+                        SpanId = session_id,
+                        Domain = domain.to_string(),
+                        CausedBy = trc::Error::from(err),
+                        Elapsed = time.elapsed(),
+                    );
+                })
+                .unwrap_or_default();
+
+            if let Some(remote_hosts) = srv_list.to_remote_hosts(domain, mx_config, None) {
+                trc::event!(
+                    Delivery(DeliveryEvent::SrvLookup),
+                    SpanId = session_id,
+                    Domain = domain.to_string(),
+                    Details = remote_hosts
+                        .iter()
+                        .map(|h| trc::Value::String(h.hostname().into()))
+                        .collect::<Vec<_>>(),
+                    Elapsed = time.elapsed(),
+                );
+
+                return Ok(MxHosts::Srv(srv_list));
+            }
+        }
+
+        // Lookup MX
+        let time = Instant::now();
+        let mx_list = match self
+            .core
+            .smtp
+            .resolvers
+            .dns
+            .mx_lookup(domain, Some(&self.inner.cache.dns_mx))
+            .await
+        {
+            Ok(mx) => mx,
+            Err(mail_auth::Error::DnsRecordNotFound(_)) => {
+                trc::event!(
+                    Delivery(DeliveryEvent::MxLookupFailed),
+                    SpanId = session_id,
+                    Domain = domain.to_string(),
+                    Details = if mx_config.implicit_mx {
+                        "No MX records were found, attempting implicit MX."
+                    } else {
+                        "No MX records were found."
+                    },
+                    Elapsed = time.elapsed(),
+                );
+
+                // Cache the negative result for the TTL published in the zone's SOA
+                let mx_list = Arc::new(vec![]);
+                let max_negative_ttl = self.core.smtp.resolvers.negative_ttl;
+                if !max_negative_ttl.is_zero()
+                    && let Some(negative_ttl) = self.negative_ttl(domain).await
+                {
+                    self.inner.cache.dns_mx.insert_with_expiry(
+                        domain.into_fqdn().into_owned(),
+                        mx_list.clone(),
+                        Instant::now() + negative_ttl.min(max_negative_ttl),
+                    );
+                }
+
+                mx_list
+            }
+            Err(err) => {
+                trc::event!(
+                    Delivery(DeliveryEvent::MxLookupFailed),
+                    SpanId = session_id,
+                    Domain = domain.to_string(),
+                    CausedBy = trc::Error::from(err.clone()),
+                    Elapsed = time.elapsed(),
+                );
+
+                return Err(Status::from_mail_auth_error(domain, err));
+            }
+        };
+
+        // Do not fall back to the domain's address records if implicit MX is disabled
+        if mx_list.is_empty() && !mx_config.implicit_mx {
+            return Err(Status::PermanentFailure(ErrorDetails {
+                entity: domain.to_string(),
+                details: Error::DnsError("No MX records found.".into()),
+            }));
+        }
+
+        Ok(MxHosts::Mx(mx_list))
+    }
+
+    async fn mta_sts_policy(
+        &self,
+        domain: &str,
+        tls_strategy: &TlsStrategy,
+        tls_report: Option<&TlsRptOptions>,
+        session_id: u64,
+    ) -> Result<Option<Arc<Policy>>, Status<HostResponse<String>, ErrorDetails>> {
+        let time = Instant::now();
+        match self
+            .lookup_mta_sts_policy(domain, tls_strategy.timeout_mta_sts)
+            .await
+        {
+            Ok(mta_sts_policy) => {
+                trc::event!(
+                    MtaSts(MtaStsEvent::PolicyFetch),
+                    SpanId = session_id,
+                    Domain = domain.to_string(),
+                    Strict = mta_sts_policy.enforce(),
+                    Details = mta_sts_policy
+                        .mx
+                        .iter()
+                        .map(|mx| trc::Value::String(mx.to_compact_string()))
+                        .collect::<Vec<_>>(),
+                    Elapsed = time.elapsed(),
+                );
+
+                Ok(Some(mta_sts_policy))
+            }
+            Err(err) => {
+                // Report MTA-STS error
+                let strict = tls_strategy.is_mta_sts_required();
+                if let Some(tls_report) = tls_report {
+                    match &err {
+                        mta_sts::Error::Dns(mail_auth::Error::DnsRecordNotFound(_)) => {
+                            if strict {
+                                self.schedule_report(TlsEvent {
+                                    policy: PolicyType::Sts(None),
+                                    domain: domain.to_string(),
+                                    failure: FailureDetails::new(ResultType::Other)
+                                        .with_failure_reason_code(
+                                            "MTA-STS is required and no policy was found.",
+                                        )
+                                        .into(),
+                                    tls_record: tls_report.record.clone(),
+                                    interval: tls_report.interval,
+                                })
+                                .await;
+                            }
+                        }
+                        mta_sts::Error::Dns(mail_auth::Error::DnsError(_)) => (),
+                        _ => {
+                            self.schedule_report(TlsEvent {
+                                policy: PolicyType::Sts(None),
+                                domain: domain.to_string(),
+                                failure: FailureDetails::new(&err)
+                                    .with_failure_reason_code(err.to_string())
+                                    .into(),
+                                tls_record: tls_report.record.clone(),
+                                interval: tls_report.interval,
+                            })
+                            .await;
+                        }
+                    }
+                }
+
+                match &err {
+                    mta_sts::Error::Dns(mail_auth::Error::DnsRecordNotFound(_)) => {
+                        trc::event!(
+                            MtaSts(MtaStsEvent::PolicyNotFound),
+                            SpanId = session_id,
+                            Domain = domain.to_string(),
+                            Strict = strict,
+                            Elapsed = time.elapsed(),
+                        );
+                    }
+                    mta_sts::Error::Dns(err) => {
+                        trc::event!(
+                            MtaSts(MtaStsEvent::PolicyFetchError),
+                            SpanId = session_id,
+                            Domain = domain.to_string(),
+                            CausedBy = trc::Error::from(err.clone()),
+                            Strict = strict,
+                            Elapsed = time.elapsed(),
+                        );
+                    }
+                    mta_sts::Error::Http(err) => {
+                        trc::event!(
+                            MtaSts(MtaStsEvent::PolicyFetchError),
+                            SpanId = session_id,
+                            Domain = domain.to_string(),
+                            Reason = err.to_string(),
+                            Strict = strict,
+                            Elapsed = time.elapsed(),
+                        );
+                    }
+                    mta_sts::Error::InvalidPolicy(reason) => {
+                        trc::event!(
+                            MtaSts(MtaStsEvent::InvalidPolicy),
+                            SpanId = session_id,
+                            Domain = domain.to_string(),
+                            Reason = reason.clone(),
+                            Strict = strict,
+                            Elapsed = time.elapsed(),
+                        );
+                    }
+                }
+
+                if strict {
+                    Err(Status::from_mta_sts_error(domain, err))
+                } else {
+                    Ok(None)
+                }
+            }
+        }
+    }
+
+    async fn dane_policy(
+        &self,
+        domain: &str,
+        hostname: &str,
+        tls_strategy: &TlsStrategy,
+        tls_report: Option<&TlsRptOptions>,
+        session_id: u64,
+    ) -> Result<Option<Arc<Tlsa>>, Status<HostResponse<String>, ErrorDetails>> {
+        let time = Instant::now();
+        let strict = tls_strategy.is_dane_required();
+        match self.tlsa_lookup(format!("_25._tcp.{hostname}.")).await {
+            Ok(Some(tlsa)) => {
+                if tlsa.has_end_entities {
+                    trc::event!(
+                        Dane(DaneEvent::TlsaRecordFetch),
+                        SpanId = session_id,
+                        Domain = domain.to_string(),
+                        Hostname = hostname.to_string(),
+                        Details = format!("{tlsa:?}"),
+                        Strict = strict,
+                        Elapsed = time.elapsed(),
+                    );
+
+                    Ok(Some(tlsa))
+                } else {
+                    trc::event!(
+                        Dane(DaneEvent::TlsaRecordInvalid),
+                        SpanId = session_id,
+                        Domain = domain.to_string(),
+                        Hostname = hostname.to_string(),
+                        Details = format!("{tlsa:?}"),
+                        Strict = strict,
+                        Elapsed = time.elapsed(),
+                    );
+
+                    // Report invalid TLSA record
+                    if let Some(tls_report) = tls_report {
+                        self.schedule_report(TlsEvent {
+                            policy: tlsa.into(),
+                            domain: domain.to_string(),
+                            failure: FailureDetails::new(ResultType::TlsaInvalid)
+                                .with_receiving_mx_hostname(hostname)
+                                .with_failure_reason_code("Invalid TLSA record.")
+                                .into(),
+                            tls_record: tls_report.record.clone(),
+                            interval: tls_report.interval,
+                        })
+                        .await;
+                    }
+
+                    if strict {
+                        Err(Status::PermanentFailure(ErrorDetails {
+                            entity: hostname.to_string(),
+                            details: Error::DaneError("No valid TLSA records were found".into()),
+                        }))
+                    } else {
+                        Ok(None)
+                    }
+                }
+            }
+            Ok(None) => {
+                trc::event!(
+                    Dane(DaneEvent::TlsaRecordNotDnssecSigned),
+                    SpanId = session_id,
+                    Domain = domain.to_string(),
+                    Hostname = hostname.to_string(),
+                    Strict = strict,
+                    Elapsed = time.elapsed(),
+                );
+
+                if strict {
+                    // Report DANE required
+                    if let Some(tls_report) = tls_report {
+                        self.schedule_report(TlsEvent {
+                            policy: PolicyType::Tlsa(None),
+                            domain: domain.to_string(),
+                            failure: FailureDetails::new(ResultType::DaneRequired)
+                                .with_receiving_mx_hostname(hostname)
+                                .with_failure_reason_code("No TLSA DNSSEC records found.")
+                                .into(),
+                            tls_record: tls_report.record.clone(),
+                            interval: tls_report.interval,
+                        })
+                        .await;
+                    }
+
+                    Err(Status::PermanentFailure(ErrorDetails {
+                        entity: hostname.into(),
+                        details: Error::DaneError("No TLSA DNSSEC records found".into()),
+                    }))
+                } else {
+                    Ok(None)
+                }
+            }
+            Err(err) => {
+                let not_found = matches!(&err, mail_auth::Error::DnsRecordNotFound(_));
+
+                if not_found {
+                    trc::event!(
+                        Dane(DaneEvent::TlsaRecordNotFound),
+                        SpanId = session_id,
+                        Domain = domain.to_string(),
+                        Hostname = hostname.to_string(),
+                        Strict = strict,
+                        Elapsed = time.elapsed(),
+                    );
+                } else {
+                    trc::event!(
+                        Dane(DaneEvent::TlsaRecordFetchError),
+                        SpanId = session_id,
+                        Domain = domain.to_string(),
+                        Hostname = hostname.to_string(),
+                        CausedBy = trc::Error::from(err.clone()),
+                        Strict = strict,
+                        Elapsed = time.elapsed(),
+                    );
+                }
+
+                if !strict {
+                    Ok(None)
+                } else if not_found {
+                    // Report DANE required
+                    if let Some(tls_report) = tls_report {
+                        self.schedule_report(TlsEvent {
+                            policy: PolicyType::Tlsa(None),
+                            domain: domain.to_string(),
+                            failure: FailureDetails::new(ResultType::DaneRequired)
+                                .with_receiving_mx_hostname(hostname)
+                                .with_failure_reason_code("No TLSA records found for MX.")
+                                .into(),
+                            tls_record: tls_report.record.clone(),
+                            interval: tls_report.interval,
+                        })
+                        .await;
+                    }
+
+                    Err(Status::PermanentFailure(ErrorDetails {
+                        entity: hostname.into(),
+                        details: Error::DaneError("No TLSA records found".into()),
+                    }))
+                } else {
+                    Err(Status::from_mail_auth_error(hostname, err))
+                }
+            }
+        }
+    }
+}
+
+impl ToNextHop for MxHosts {
+    fn to_remote_hosts<'x, 'y: 'x>(
+        &'x self,
+        domain: &'y str,
+        config: &'x MxConfig,
+        last_failed: Option<&str>,
+    ) -> Option<Vec<NextHop<'x>>> {
+        match self {
+            MxHosts::Srv(srv_list) => srv_list.to_remote_hosts(domain, config, last_failed),
+            MxHosts::Mx(mx_list) => mx_list.to_remote_hosts(domain, config, last_failed),
+        }
+    }
+}
+
+impl SmtpClient<TcpStream> {
+    /// Upgrades the connection with STARTTLS as required by the TLS strategy and
+    /// the domain's MTA-STS and DANE policies, falling back to plaintext only when
+    /// they allow it.
+    pub async fn negotiate_tls(
+        mut self,
+        capabilities: &smtp_proto::EhloResponse<String>,
+        params: &TlsParams<'_>,
+    ) -> Result<SmtpConnection, Status<HostResponse<String>, ErrorDetails>> {
+        let server = params.server;
+        let hostname = params.hostname;
+        let tls_strategy = params.tls_strategy;
+        let remote_ip = params.remote_addr.ip();
+
+        if !tls_strategy.try_start_tls() {
+            // TLS has been disabled
+            trc::event!(
+                Delivery(DeliveryEvent::StartTlsDisabled),
+                SpanId = params.session_id,
+                Domain = params.domain.to_string(),
+                Hostname = hostname.to_string(),
+            );
+
+            return Ok(SmtpConnection::Plain(self));
+        }
+
+        let time = Instant::now();
+        self.timeout = tls_strategy.timeout_tls;
+        match self
+            .try_start_tls(params.tls_connector, hostname, capabilities)
+            .await
+        {
+            StartTlsResult::Success { smtp_client } => {
+                trc::event!(
+                    Delivery(DeliveryEvent::StartTls),
+                    SpanId = params.session_id,
+                    Domain = params.domain.to_string(),
+                    Hostname = hostname.to_string(),
+                    Version = format!(
+                        "{:?}",
+                        smtp_client.tls_connection().protocol_version().unwrap()
+                    ),
+                    Details = format!(
+                        "{:?}",
+                        smtp_client
+                            .tls_connection()
+                            .negotiated_cipher_suite()
+                            .unwrap()
+                    ),
+                    Elapsed = time.elapsed(),
+                );
+                if let Some(expires) = tls_strategy
+                    .downgrade_protection
+                    .filter(|_| !params.is_dry_run)
+                {
+                    server
+                        .set_starttls_support(hostname, expires, params.session_id)
+                        .await;
+                }
+
+                // Verify DANE
+                if let Some(dane_policy) = params.dane_policy
+                    && let Err(status) = dane_policy.verify(
+                        params.session_id,
+                        hostname,
+                        smtp_client.tls_connection().peer_certificates(),
+                    )
+                {
+                    // Report DANE verification failure
+                    if let Some(tls_report) = params.tls_report {
+                        server
+                            .schedule_report(TlsEvent {
+                                policy: dane_policy.into(),
+                                domain: params.domain.to_string(),
+                                failure: FailureDetails::new(ResultType::ValidationFailure)
+                                    .with_receiving_mx_hostname(hostname)
+                                    .with_receiving_ip(remote_ip)
+                                    .with_failure_reason_code("No matching certificates found.")
+                                    .into(),
+                                tls_record: tls_report.record.clone(),
+                                interval: tls_report.interval,
+                            })
+                            .await;
+                    }
+
+                    return Err(status);
+                }
+
+                // Report TLS success
+                if let Some(tls_report) = params.tls_report {
+                    server
+                        .schedule_report(TlsEvent {
+                            policy: (params.mta_sts_policy, params.dane_policy).into(),
+                            domain: params.domain.to_string(),
+                            failure: None,
+                            tls_record: tls_report.record.clone(),
+                            interval: tls_report.interval,
+                        })
+                        .await;
+                }
+
+                Ok(SmtpConnection::Tls(smtp_client))
+            }
+            StartTlsResult::Unavailable {
+                response,
+                smtp_client,
+            } => {
+                // Report unavailable STARTTLS
+                let reason = response
+                    .as_ref()
+                    .map(|r| r.to_string())
+                    .unwrap_or_else(|| "STARTTLS was not advertised by host".to_string());
+
+                trc::event!(
+                    Delivery(DeliveryEvent::StartTlsUnavailable),
+                    SpanId = params.session_id,
+                    Domain = params.domain.to_string(),
+                    Hostname = hostname.to_string(),
+                    Code = response.as_ref().map(|r| r.code()),
+                    Details = response
+                        .as_ref()
+                        .map(|r| r.message().as_str())
+                        .unwrap_or("STARTTLS was not advertised by host")
+                        .to_string(),
+                    Elapsed = time.elapsed(),
+                );
+
+                if let Some(tls_report) = params.tls_report {
+                    server
+                        .schedule_report(TlsEvent {
+                            policy: (params.mta_sts_policy, params.dane_policy).into(),
+                            domain: params.domain.to_string(),
+                            failure: FailureDetails::new(ResultType::StartTlsNotSupported)
+                                .with_receiving_mx_hostname(hostname)
+                                .with_receiving_ip(remote_ip)
+                                .with_failure_reason_code(reason)
+                                .into(),
+                            tls_record: tls_report.record.clone(),
+                            interval: tls_report.interval,
+                        })
+                        .await;
+                }
+
+                if params.is_strict_tls {
+                    Err(Status::from_starttls_error(hostname, response))
+                } else if tls_strategy.downgrade_protection.is_some()
+                    && server
+                        .has_starttls_support(hostname, params.session_id)
+                        .await
+                {
+                    // Do not fall back to plain-text on hosts known to support TLS
+                    trc::event!(
+                        Delivery(DeliveryEvent::StartTlsStripped),
+                        SpanId = params.session_id,
+                        Domain = params.domain.to_string(),
+                        Hostname = hostname.to_string(),
+                    );
+
+                    Err(Status::TemporaryFailure(ErrorDetails {
+                        entity: hostname.into(),
+                        details: Error::TlsError(
+                            "STARTTLS no longer offered by host, possible downgrade attack".into(),
+                        ),
+                    }))
+                } else {
+                    // TLS is not required, proceed in plain-text
+                    Ok(SmtpConnection::Plain(smtp_client))
+                }
+            }
+            StartTlsResult::Error { error } => {
+                trc::event!(
+                    Delivery(DeliveryEvent::StartTlsError),
+                    SpanId = params.session_id,
+                    Domain = params.domain.to_string(),
+                    Hostname = hostname.to_string(),
+                    Reason = from_mail_send_error(&error),
+                    Elapsed = time.elapsed(),
+                );
+
+                // Report TLS failure
+                if let (Some(tls_report), mail_send::Error::Tls(error)) =
+                    (params.tls_report, &error)
+                {
+                    server
+                        .schedule_report(TlsEvent {
+                            policy: (params.mta_sts_policy, params.dane_policy).into(),
+                            domain: params.domain.to_string(),
+                            failure: FailureDetails::new(ResultType::CertificateNotTrusted)
+                                .with_receiving_mx_hostname(hostname)
+                                .with_receiving_ip(remote_ip)
+                                .with_failure_reason_code(error.to_string())
+                                .into(),
+                            tls_record: tls_report.record.clone(),
+                            interval: tls_report.interval,
+                        })
+                        .await;
+                }
+
+                // Do not fall back to plain-text on hosts known to support TLS
+                let allow_plaintext = !params.is_strict_tls
+                    && tls_strategy.on_handshake_failure == HandshakeFailure::Plaintext
+                    && !(tls_strategy.downgrade_protection.is_some()
+                        && server
+                            .has_starttls_support(hostname, params.session_id)
+                            .await);
+
+                match tls_strategy.on_handshake_failure {
+                    HandshakeFailure::Plaintext if allow_plaintext => {
+                        trc::event!(
+                            Delivery(DeliveryEvent::StartTlsDowngrade),
+                            SpanId = params.session_id,
+                            Domain = params.domain.to_string(),
+                            Hostname = hostname.to_string(),
+                        );
+
+                        // Reconnect and continue the session in plaintext
+                        let mut smtp_client = SmtpClient::connect_from(
+                            params.local_ip,
+                            params.remote_addr,
+                            params.conn_strategy.timeout_connect,
+                            params.session_id,
+                        )
+                        .await
+                        .map_err(|err| Status::from_smtp_error(hostname, "", err))?;
+                        if params.include_transcript {
+                            smtp_client.transcript = Some(String::new());
+                        }
+                        smtp_client.timeout = params.conn_strategy.timeout_greeting;
+                        smtp_client.read_greeting(hostname).await?;
+
+                        Ok(SmtpConnection::Plain(smtp_client))
+                    }
+                    HandshakeFailure::Bounce => {
+                        Err(Status::from_tls_error(hostname, error).into_permanent())
+                    }
+                    HandshakeFailure::Defer | HandshakeFailure::Plaintext => {
+                        Err(if params.is_strict_tls {
+                            Status::from_tls_error(hostname, error)
+                        } else {
+                            Status::from_tls_error(hostname, error).into_temporary()
+                        })
+                    }
+                }
+            }
+        }
+    }
+}
@@ -5,19 +5,20 @@
  */
 
 use super::client::SmtpClient;
-use crate::outbound::DeliveryResult;
 use crate::outbound::client::{from_error_status, from_mail_send_error};
+use crate::outbound::{DeliveryResult, DeliveryStep};
 use crate::queue::{Error, MessageWrapper, Recipient, Status};
 use crate::queue::{ErrorDetails, HostResponse, UnexpectedResponse};
 use common::Server;
 use common::config::smtp::queue::ConnectionStrategy;
 use mail_send::Credentials;
 use smtp_proto::{
-    EXT_CHUNKING, EXT_DSN, EXT_REQUIRE_TLS, EXT_SIZE, EXT_SMTP_UTF8, EhloResponse, MAIL_REQUIRETLS,
-    MAIL_RET_FULL, MAIL_RET_HDRS, MAIL_SMTPUTF8, RCPT_NOTIFY_DELAY, RCPT_NOTIFY_FAILURE,
-    RCPT_NOTIFY_NEVER, RCPT_NOTIFY_SUCCESS, Severity,
+    EXT_8BIT_MIME, EXT_BINARY_MIME, EXT_CHUNKING, EXT_DSN, EXT_ENHANCED_STATUS_CODES,
+    EXT_PIPELINING, EXT_REQUIRE_TLS, EXT_SIZE, EXT_SMTP_UTF8, EXT_START_TLS, EhloResponse,
+    MAIL_BODY_8BITMIME, MAIL_REQUIRETLS, MAIL_RET_FULL, MAIL_RET_HDRS, MAIL_SMTPUTF8,
+    RCPT_NOTIFY_DELAY, RCPT_NOTIFY_FAILURE, RCPT_NOTIFY_NEVER, RCPT_NOTIFY_SUCCESS, Severity,
 };
-use std::{fmt::Write, time::Instant};
+use std::{borrow::Cow, collections::VecDeque, fmt::Write, time::Instant};
 use tokio::io::{AsyncRead, AsyncWrite};
 use trc::DeliveryEvent;
 
@@ -29,6 +30,14 @@ pub struct SessionParams<'x> {
     pub local_hostname: &'x str,
     pub conn_strategy: &'x ConnectionStrategy,
     pub session_id: u64,
+    pub add_headers: &'x [String],
+    pub remove_headers: &'x [String],
+    pub return_path: &'x str,
+    pub rewrite_sender: Option<(&'x str, &'x str)>,
+    pub rewrite_headers: &'x [String],
+    pub dkim_sign: &'x [String],
+    pub is_dry_run: bool,
+    pub tls_version: Option<&'x str>,
 }
 
 impl MessageWrapper {
@@ -38,10 +47,28 @@ impl MessageWrapper {
         rcpt_idxs: Vec<usize>,
         statuses: &mut Vec<DeliveryResult>,
         params: SessionParams<'_>,
+    ) {
+        let transcript_rcpt_idxs = smtp_client.transcript.is_some().then(|| rcpt_idxs.clone());
+        self.deliver_session(&mut smtp_client, rcpt_idxs, statuses, &params)
+            .await;
+
+        if let (Some(transcript), Some(rcpt_idxs)) =
+            (smtp_client.quit().await, transcript_rcpt_idxs)
+        {
+            statuses.push(DeliveryResult::transcript(transcript, rcpt_idxs));
+        }
+    }
+
+    async fn deliver_session<T: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        smtp_client: &mut SmtpClient<T>,
+        rcpt_idxs: Vec<usize>,
+        statuses: &mut Vec<DeliveryResult>,
+        params: &SessionParams<'_>,
     ) {
         // Obtain capabilities
         let time = Instant::now();
-        let capabilities = match smtp_client.say_helo(&params).await {
+        let capabilities = match smtp_client.say_helo(params).await {
             Ok(capabilities) => {
                 trc::event!(
                     Delivery(DeliveryEvent::Ehlo),
@@ -57,11 +84,12 @@ impl MessageWrapper {
                 trc::event!(
                     Delivery(DeliveryEvent::EhloRejected),
                     SpanId = params.session_id,
+                    QueueId = self.queue_id,
                     Hostname = params.hostname.to_string(),
                     CausedBy = from_error_status(&status),
+                    Version = params.tls_version.map(String::from),
                     Elapsed = time.elapsed(),
                 );
-                smtp_client.quit().await;
                 statuses.push(DeliveryResult::domain(status, rcpt_idxs));
                 return;
             }
@@ -74,12 +102,13 @@ impl MessageWrapper {
                 trc::event!(
                     Delivery(DeliveryEvent::AuthFailed),
                     SpanId = params.session_id,
+                    QueueId = self.queue_id,
                     Hostname = params.hostname.to_string(),
                     CausedBy = from_mail_send_error(&err),
+                    Version = params.tls_version.map(String::from),
                     Elapsed = time.elapsed(),
                 );
 
-                smtp_client.quit().await;
                 statuses.push(DeliveryResult::domain(
                     Status::from_smtp_error(params.hostname, "AUTH ...", err),
                     rcpt_idxs,
@@ -112,11 +141,188 @@ impl MessageWrapper {
             };*/
         }
 
-        // MAIL FROM
+        // Dry runs reset the session instead of sending the message
+        if params.is_dry_run {
+            statuses.push(DeliveryResult::report(DeliveryStep::Ehlo {
+                hostname: params.hostname.to_string(),
+                capabilities: [
+                    (EXT_8BIT_MIME, "8BITMIME"),
+                    (EXT_BINARY_MIME, "BINARYMIME"),
+                    (EXT_CHUNKING, "CHUNKING"),
+                    (EXT_DSN, "DSN"),
+                    (EXT_ENHANCED_STATUS_CODES, "ENHANCEDSTATUSCODES"),
+                    (EXT_PIPELINING, "PIPELINING"),
+                    (EXT_REQUIRE_TLS, "REQUIRETLS"),
+                    (EXT_SIZE, "SIZE"),
+                    (EXT_SMTP_UTF8, "SMTPUTF8"),
+                    (EXT_START_TLS, "STARTTLS"),
+                ]
+                .into_iter()
+                .filter(|(ext, _)| capabilities.has_capability(*ext))
+                .map(|(_, name)| name.to_string())
+                .collect(),
+            }));
+            statuses.push(DeliveryResult::report(DeliveryStep::Rset {
+                hostname: params.hostname.to_string(),
+                result: smtp_client
+                    .cmd(b"RSET\r\n")
+                    .await
+                    .map_err(|err| err.to_string()),
+            }));
+            return;
+        }
+
+        // As per RFC8689 Section 4.2.1, REQUIRETLS messages are only relayed to hosts that support it
+        if self.has_flag(MAIL_REQUIRETLS) && !capabilities.has_capability(EXT_REQUIRE_TLS) {
+            let status = Status::PermanentFailure(ErrorDetails {
+                entity: params.hostname.into(),
+                details: Error::TlsError("REQUIRETLS not advertised by host.".into()),
+            });
+
+            trc::event!(
+                Delivery(DeliveryEvent::MailFromRejected),
+                SpanId = params.session_id,
+                QueueId = self.queue_id,
+                Hostname = params.hostname.to_string(),
+                CausedBy = from_error_status(&status),
+                Version = params.tls_version.map(String::from),
+                Elapsed = time.elapsed(),
+            );
+
+            statuses.push(DeliveryResult::domain(status, rcpt_idxs));
+            return;
+        }
+
+        // Do not transmit messages that exceed the size limit advertised by the remote host
+        let message_size = self.outbound_size(params);
+        if capabilities.has_capability(EXT_SIZE)
+            && capabilities.size > 0
+            && message_size > capabilities.size as u64
+        {
+            let status = Status::PermanentFailure(ErrorDetails {
+                entity: params.hostname.into(),
+                details: Error::MessageTooLarge(capabilities.size as u64),
+            });
+
+            trc::event!(
+                Delivery(DeliveryEvent::MailFromRejected),
+                SpanId = params.session_id,
+                QueueId = self.queue_id,
+                Hostname = params.hostname.to_string(),
+                CausedBy = from_error_status(&status),
+                Size = message_size,
+                Version = params.tls_version.map(String::from),
+                Elapsed = time.elapsed(),
+            );
+
+            statuses.push(DeliveryResult::domain(status, rcpt_idxs));
+            return;
+        }
+
+        // As per RFC6531 Section 3.4, internationalized addresses require SMTPUTF8,
+        // otherwise IDN domains are sent as A-labels and non-ASCII local parts fail
+        let has_smtp_utf8 = capabilities.has_capability(EXT_SMTP_UTF8);
+        let Some(return_path) = envelope_address(params.return_path, has_smtp_utf8) else {
+            let status = Status::PermanentFailure(ErrorDetails {
+                entity: params.hostname.into(),
+                details: Error::ConnectionError(
+                    "SMTPUTF8 not advertised by host, sender has a non-ASCII local part.".into(),
+                ),
+            });
+
+            trc::event!(
+                Delivery(DeliveryEvent::MailFromRejected),
+                SpanId = params.session_id,
+                QueueId = self.queue_id,
+                Hostname = params.hostname.to_string(),
+                CausedBy = from_error_status(&status),
+                Version = params.tls_version.map(String::from),
+                Elapsed = time.elapsed(),
+            );
+
+            statuses.push(DeliveryResult::domain(status, rcpt_idxs));
+            return;
+        };
+
+        // Obtain recipients to deliver in this session
+        let mut rcpts = Vec::with_capacity(rcpt_idxs.len());
+        for rcpt_idx in &rcpt_idxs {
+            let rcpt = &self.message.recipients[*rcpt_idx];
+            if matches!(
+                &rcpt.status,
+                Status::Completed(_) | Status::PermanentFailure(_)
+            ) {
+                continue;
+            }
+
+            let Some(address) = envelope_address(&rcpt.address, has_smtp_utf8) else {
+                let status = Status::PermanentFailure(ErrorDetails {
+                    entity: params.hostname.into(),
+                    details: Error::ConnectionError(
+                        "SMTPUTF8 not advertised by host, recipient has a non-ASCII local part."
+                            .into(),
+                    ),
+                });
+
+                trc::event!(
+                    Delivery(DeliveryEvent::RcptToRejected),
+                    SpanId = params.session_id,
+                    QueueId = self.queue_id,
+                    Hostname = params.hostname.to_string(),
+                    To = rcpt.address.to_string(),
+                    CausedBy = from_error_status(&status),
+                    Version = params.tls_version.map(String::from),
+                    Elapsed = time.elapsed(),
+                );
+
+                statuses.push(DeliveryResult::account(status, *rcpt_idx));
+                continue;
+            };
+
+            let cmd = self.build_rcpt_to(&address, rcpt, &capabilities);
+            rcpts.push((rcpt_idx, rcpt, cmd));
+        }
+
+        // As per RFC2920, MAIL FROM and RCPT TO are sent in a single batch to hosts
+        // that support pipelining. DATA is only sent once the accepted recipients are known.
         let time = Instant::now();
         smtp_client.timeout = params.conn_strategy.timeout_mail;
-        let cmd = self.build_mail_from(&capabilities);
-        match smtp_client.cmd(cmd.as_bytes()).await.and_then(|r| {
+        let cmd = self.build_mail_from(&return_path, &capabilities, params);
+        let mut pipelined = VecDeque::new();
+        if capabilities.has_capability(EXT_PIPELINING) {
+            let cmds = std::iter::once(cmd.as_str())
+                .chain(rcpts.iter().map(|(_, _, cmd)| cmd.as_str()))
+                .collect::<Vec<_>>();
+            match smtp_client.pipeline(&cmds).await {
+                Ok(responses) => {
+                    pipelined = responses.into();
+                }
+                Err(err) => {
+                    trc::event!(
+                        Delivery(DeliveryEvent::MailFromRejected),
+                        SpanId = params.session_id,
+                        QueueId = self.queue_id,
+                        Hostname = params.hostname.to_string(),
+                        CausedBy = from_mail_send_error(&err),
+                        Version = params.tls_version.map(String::from),
+                        Elapsed = time.elapsed(),
+                    );
+
+                    statuses.push(DeliveryResult::domain(
+                        Status::from_smtp_error(params.hostname, &cmd, err),
+                        rcpt_idxs,
+                    ));
+                    return;
+                }
+            }
+        }
+
+        // MAIL FROM
+        let response = match pipelined.pop_front() {
+            Some(response) => Ok(response),
+            None => smtp_client.cmd(cmd.as_bytes()).await,
+        };
+        match response.and_then(|r| {
             if r.is_positive_completion() {
                 Ok(r)
             } else {
@@ -138,12 +344,13 @@ impl MessageWrapper {
                 trc::event!(
                     Delivery(DeliveryEvent::MailFromRejected),
                     SpanId = params.session_id,
+                    QueueId = self.queue_id,
                     Hostname = params.hostname.to_string(),
                     CausedBy = from_mail_send_error(&err),
+                    Version = params.tls_version.map(String::from),
                     Elapsed = time.elapsed(),
                 );
 
-                smtp_client.quit().await;
                 statuses.push(DeliveryResult::domain(
                     Status::from_smtp_error(params.hostname, &cmd, err),
                     rcpt_idxs,
@@ -155,18 +362,13 @@ impl MessageWrapper {
         // RCPT TO
         let mut accepted_rcpts = Vec::new();
         smtp_client.timeout = params.conn_strategy.timeout_rcpt;
-        for rcpt_idx in &rcpt_idxs {
+        for (rcpt_idx, rcpt, cmd) in rcpts {
             let time = Instant::now();
-            let rcpt = &self.message.recipients[*rcpt_idx];
-            if matches!(
-                &rcpt.status,
-                Status::Completed(_) | Status::PermanentFailure(_)
-            ) {
-                continue;
-            }
-
-            let cmd = self.build_rcpt_to(rcpt, &capabilities);
-            match smtp_client.cmd(cmd.as_bytes()).await {
+            let response = match pipelined.pop_front() {
+                Some(response) => Ok(response),
+                None => smtp_client.cmd(cmd.as_bytes()).await,
+            };
+            match response {
                 Ok(response) => match response.severity() {
                     Severity::PositiveCompletion => {
                         trc::event!(
@@ -192,10 +394,12 @@ impl MessageWrapper {
                         trc::event!(
                             Delivery(DeliveryEvent::RcptToRejected),
                             SpanId = params.session_id,
+                            QueueId = self.queue_id,
                             Hostname = params.hostname.to_string(),
                             To = rcpt.address.to_string(),
                             Code = response.code,
                             Details = response.message.to_string(),
+                            Version = params.tls_version.map(String::from),
                             Elapsed = time.elapsed(),
                         );
 
@@ -220,14 +424,15 @@ impl MessageWrapper {
                     trc::event!(
                         Delivery(DeliveryEvent::RcptToFailed),
                         SpanId = params.session_id,
+                        QueueId = self.queue_id,
                         Hostname = params.hostname.to_string(),
                         To = rcpt.address.to_string(),
                         CausedBy = from_mail_send_error(&err),
+                        Version = params.tls_version.map(String::from),
                         Elapsed = time.elapsed(),
                     );
 
                     // Something went wrong, abort.
-                    smtp_client.quit().await;
                     statuses.push(DeliveryResult::domain(
                         Status::from_smtp_error(params.hostname, "", err),
                         rcpt_idxs,
@@ -242,22 +447,33 @@ impl MessageWrapper {
             let time = Instant::now();
             let bdat_cmd = capabilities
                 .has_capability(EXT_CHUNKING)
-                .then(|| format!("BDAT {} LAST\r\n", self.message.size));
-
-            if let Err(status) = smtp_client.send_message(self, &bdat_cmd, &params).await {
+                .then(|| format!("BDAT {} LAST\r\n", message_size));
+
+            if let Err(status) = smtp_client
+                .send_message(
+                    self,
+                    &bdat_cmd,
+                    capabilities.has_capability(EXT_8BIT_MIME),
+                    params,
+                )
+                .await
+            {
                 trc::event!(
                     Delivery(DeliveryEvent::MessageRejected),
                     SpanId = params.session_id,
+                    QueueId = self.queue_id,
                     Hostname = params.hostname.to_string(),
                     CausedBy = from_error_status(&status),
+                    Version = params.tls_version.map(String::from),
                     Elapsed = time.elapsed(),
                 );
 
-                smtp_client.quit().await;
                 statuses.push(DeliveryResult::domain(status, rcpt_idxs));
                 return;
             }
 
+            // Wait for the end-of-data reply
+            smtp_client.timeout = params.conn_strategy.timeout_data_end;
             if params.is_smtp {
                 // Handle SMTP response
                 match smtp_client
@@ -271,10 +487,12 @@ impl MessageWrapper {
                                 trc::event!(
                                     Delivery(DeliveryEvent::Delivered),
                                     SpanId = params.session_id,
+                                    QueueId = self.queue_id,
                                     Hostname = params.hostname.to_string(),
                                     To = rcpt.address.to_string(),
                                     Code = response.code,
                                     Details = response.message.to_string(),
+                                    Version = params.tls_version.map(String::from),
                                     Elapsed = time.elapsed(),
                                 );
 
@@ -284,13 +502,14 @@ impl MessageWrapper {
                             trc::event!(
                                 Delivery(DeliveryEvent::MessageRejected),
                                 SpanId = params.session_id,
+                                QueueId = self.queue_id,
                                 Hostname = params.hostname.to_string(),
                                 Code = response.code,
                                 Details = response.message.to_string(),
+                                Version = params.tls_version.map(String::from),
                                 Elapsed = time.elapsed(),
                             );
 
-                            smtp_client.quit().await;
                             statuses.push(DeliveryResult::domain(
                                 Status::from_smtp_error(
                                     params.hostname,
@@ -306,12 +525,13 @@ impl MessageWrapper {
                         trc::event!(
                             Delivery(DeliveryEvent::MessageRejected),
                             SpanId = params.session_id,
+                            QueueId = self.queue_id,
                             Hostname = params.hostname.to_string(),
                             CausedBy = from_error_status(&status),
+                            Version = params.tls_version.map(String::from),
                             Elapsed = time.elapsed(),
                         );
 
-                        smtp_client.quit().await;
                         statuses.push(DeliveryResult::domain(status, rcpt_idxs));
                         return;
                     }
@@ -331,10 +551,12 @@ impl MessageWrapper {
                                     trc::event!(
                                         Delivery(DeliveryEvent::Delivered),
                                         SpanId = params.session_id,
+                                        QueueId = self.queue_id,
                                         Hostname = params.hostname.to_string(),
                                         To = rcpt.address.to_string(),
                                         Code = response.code,
                                         Details = response.message.to_string(),
+                                        Version = params.tls_version.map(String::from),
                                         Elapsed = time.elapsed(),
                                     );
 
@@ -347,10 +569,12 @@ impl MessageWrapper {
                                     trc::event!(
                                         Delivery(DeliveryEvent::RcptToRejected),
                                         SpanId = params.session_id,
+                                        QueueId = self.queue_id,
                                         Hostname = params.hostname.to_string(),
                                         To = rcpt.address.to_string(),
                                         Code = response.code,
                                         Details = response.message.to_string(),
+                                        Version = params.tls_version.map(String::from),
                                         Elapsed = time.elapsed(),
                                     );
 
@@ -376,34 +600,53 @@ impl MessageWrapper {
                         trc::event!(
                             Delivery(DeliveryEvent::MessageRejected),
                             SpanId = params.session_id,
+                            QueueId = self.queue_id,
                             Hostname = params.hostname.to_string(),
                             CausedBy = from_error_status(&status),
+                            Version = params.tls_version.map(String::from),
                             Elapsed = time.elapsed(),
                         );
 
-                        smtp_client.quit().await;
                         statuses.push(DeliveryResult::domain(status, rcpt_idxs));
                         return;
                     }
                 }
             }
         }
+    }
 
-        smtp_client.quit().await;
+    /// Size of the copy sent to the remote host, including the headers added for its domain.
+    fn outbound_size(&self, params: &SessionParams<'_>) -> u64 {
+        self.message.size
+            + params
+                .add_headers
+                .iter()
+                .map(|header| header.trim_end().len() as u64 + 2)
+                .sum::<u64>()
     }
 
-    fn build_mail_from(&self, capabilities: &EhloResponse<String>) -> String {
-        let mut mail_from = String::with_capacity(self.message.return_path.len() + 60);
-        let _ = write!(mail_from, "MAIL FROM:<{}>", self.message.return_path);
+    fn build_mail_from(
+        &self,
+        return_path: &str,
+        capabilities: &EhloResponse<String>,
+        params: &SessionParams<'_>,
+    ) -> String {
+        let mut mail_from = String::with_capacity(return_path.len() + 60);
+        let _ = write!(mail_from, "MAIL FROM:<{return_path}>");
         if capabilities.has_capability(EXT_SIZE) {
-            let _ = write!(mail_from, " SIZE={}", self.message.size);
+            let _ = write!(mail_from, " SIZE={}", self.outbound_size(params));
         }
         if self.has_flag(MAIL_REQUIRETLS) & capabilities.has_capability(EXT_REQUIRE_TLS) {
             mail_from.push_str(" REQUIRETLS");
         }
-        if self.has_flag(MAIL_SMTPUTF8) & capabilities.has_capability(EXT_SMTP_UTF8) {
+        if (self.has_flag(MAIL_SMTPUTF8) || self.has_utf8_envelope())
+            && capabilities.has_capability(EXT_SMTP_UTF8)
+        {
             mail_from.push_str(" SMTPUTF8");
         }
+        if self.has_flag(MAIL_BODY_8BITMIME) && capabilities.has_capability(EXT_8BIT_MIME) {
+            mail_from.push_str(" BODY=8BITMIME");
+        }
         if capabilities.has_capability(EXT_DSN) {
             if self.has_flag(MAIL_RET_FULL) {
                 mail_from.push_str(" RET=FULL");
@@ -419,9 +662,23 @@ impl MessageWrapper {
         mail_from
     }
 
-    fn build_rcpt_to(&self, rcpt: &Recipient, capabilities: &EhloResponse<String>) -> String {
-        let mut rcpt_to = String::with_capacity(rcpt.address.len() + 60);
-        let _ = write!(rcpt_to, "RCPT TO:<{}>", rcpt.address);
+    fn has_utf8_envelope(&self) -> bool {
+        !self.message.return_path.is_ascii()
+            || self
+                .message
+                .recipients
+                .iter()
+                .any(|rcpt| !rcpt.address.is_ascii())
+    }
+
+    fn build_rcpt_to(
+        &self,
+        address: &str,
+        rcpt: &Recipient,
+        capabilities: &EhloResponse<String>,
+    ) -> String {
+        let mut rcpt_to = String::with_capacity(address.len() + 60);
+        let _ = write!(rcpt_to, "RCPT TO:<{address}>");
         if capabilities.has_capability(EXT_DSN) {
             if rcpt.has_flag(RCPT_NOTIFY_SUCCESS | RCPT_NOTIFY_FAILURE | RCPT_NOTIFY_DELAY) {
                 rcpt_to.push_str(" NOTIFY=");
@@ -465,3 +722,20 @@ impl Recipient {
         (self.flags & flag) != 0
     }
 }
+
+// Returns the address to use in the envelope, IDN domains are converted to
+// A-labels when the remote host does not support SMTPUTF8.
+fn envelope_address(address: &str, has_smtp_utf8: bool) -> Option<Cow<'_, str>> {
+    if has_smtp_utf8 || address.is_ascii() {
+        Some(address.into())
+    } else {
+        let (local_part, domain) = address.rsplit_once('@')?;
+        if local_part.is_ascii() {
+            idna::domain_to_ascii(domain)
+                .ok()
+                .map(|domain| format!("{local_part}@{domain}").into())
+        } else {
+            None
+        }
+    }
+}
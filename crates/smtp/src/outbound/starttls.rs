@@ -0,0 +1,57 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs LLC <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::{future::Future, time::Duration};
+
+use common::{KV_STARTTLS, Server};
+use store::dispatch::lookup::KeyValue;
+
+pub trait StartTlsCache: Sync + Send {
+    fn set_starttls_support(
+        &self,
+        hostname: &str,
+        expires: Duration,
+        session_id: u64,
+    ) -> impl Future<Output = ()> + Send;
+
+    fn has_starttls_support(
+        &self,
+        hostname: &str,
+        session_id: u64,
+    ) -> impl Future<Output = bool> + Send;
+}
+
+impl StartTlsCache for Server {
+    async fn set_starttls_support(&self, hostname: &str, expires: Duration, session_id: u64) {
+        let key = KeyValue::<()>::build_key(KV_STARTTLS, hostname.to_lowercase().as_bytes());
+        if let Err(err) = self
+            .in_memory_store()
+            .key_set(KeyValue::new(key, b"1".to_vec()).expires(expires.as_secs()))
+            .await
+        {
+            trc::error!(
+                err.span_id(session_id)
+                    .caused_by(trc::location!())
+                    .details("Failed to cache STARTTLS support.")
+            );
+        }
+    }
+
+    async fn has_starttls_support(&self, hostname: &str, session_id: u64) -> bool {
+        let key = KeyValue::<()>::build_key(KV_STARTTLS, hostname.to_lowercase().as_bytes());
+        match self.in_memory_store().key_exists(key).await {
+            Ok(exists) => exists,
+            Err(err) => {
+                trc::error!(
+                    err.span_id(session_id)
+                        .caused_by(trc::location!())
+                        .details("Failed to obtain cached STARTTLS support.")
+                );
+                false
+            }
+        }
+    }
+}
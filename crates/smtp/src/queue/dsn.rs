@@ -7,11 +7,12 @@
 use super::spool::SmtpSpool;
 use super::{
     Error, ErrorDetails, HostResponse, Message, MessageSource, QueueEnvelope, RCPT_DSN_SENT,
-    RCPT_STATUS_CHANGED, Recipient, Status,
+    RCPT_HOLD, RCPT_STATUS_CHANGED, Recipient, Status, TlsDetails,
 };
 use crate::queue::{MessageWrapper, UnexpectedResponse};
 use crate::reporting::SmtpReporting;
 use common::Server;
+use common::config::smtp::queue::DsnTemplateVariable;
 use mail_builder::MessageBuilder;
 use mail_builder::headers::HeaderType;
 use mail_builder::headers::content_type::ContentType;
@@ -23,6 +24,7 @@ use smtp_proto::{
 use std::fmt::Write;
 use std::future::Future;
 use store::write::now;
+use utils::template::{Template, Variables};
 
 pub trait SendDsn: Sync + Send {
     fn send_dsn(&self, message: &mut MessageWrapper) -> impl Future<Output = ()> + Send;
@@ -70,9 +72,12 @@ impl SendDsn for Server {
 
     async fn log_dsn(&self, message: &MessageWrapper) {
         let now = now();
+        let notify_due = message
+            .message
+            .delay_notify_due(now, self.core.smtp.queue.dsn.delay_window.as_secs());
 
         for rcpt in &message.message.recipients {
-            if rcpt.has_flag(RCPT_DSN_SENT) {
+            if rcpt.has_flag(RCPT_DSN_SENT | RCPT_HOLD) {
                 continue;
             }
 
@@ -81,16 +86,18 @@ impl SendDsn for Server {
                     trc::event!(
                         Delivery(trc::DeliveryEvent::DsnSuccess),
                         SpanId = message.span_id,
+                        QueueId = message.queue_id,
                         To = rcpt.address_lcase.clone(),
                         Hostname = response.hostname.clone(),
                         Code = response.response.code,
                         Details = response.response.message.to_string(),
                     );
                 }
-                Status::TemporaryFailure(response) if rcpt.notify.due <= now => {
+                Status::TemporaryFailure(response) if rcpt.notify.due <= notify_due => {
                     trc::event!(
                         Delivery(trc::DeliveryEvent::DsnTempFail),
                         SpanId = message.span_id,
+                        QueueId = message.queue_id,
                         To = rcpt.address_lcase.clone(),
                         Hostname = response.entity.clone(),
                         Details = response.details.to_string(),
@@ -105,16 +112,18 @@ impl SendDsn for Server {
                     trc::event!(
                         Delivery(trc::DeliveryEvent::DsnPermFail),
                         SpanId = message.span_id,
+                        QueueId = message.queue_id,
                         To = rcpt.address_lcase.clone(),
                         Hostname = response.entity.clone(),
                         Details = response.details.to_string(),
                         Total = rcpt.retry.inner,
                     );
                 }
-                Status::Scheduled if rcpt.notify.due <= now => {
+                Status::Scheduled if rcpt.notify.due <= notify_due => {
                     trc::event!(
                         Delivery(trc::DeliveryEvent::DsnTempFail),
                         SpanId = message.span_id,
+                        QueueId = message.queue_id,
                         To = rcpt.address_lcase.clone(),
                         Details = "Concurrency limited",
                         NextRetry = trc::Value::Timestamp(rcpt.retry.due),
@@ -133,7 +142,11 @@ impl SendDsn for Server {
 impl MessageWrapper {
     pub async fn build_dsn(&mut self, server: &Server) -> Option<Vec<u8>> {
         let config = &server.core.smtp.queue;
+        let template = config.dsn.template.as_ref();
         let now = now();
+        let notify_due = self
+            .message
+            .delay_notify_due(now, config.dsn.delay_window.as_secs());
 
         let mut txt_success = String::new();
         let mut txt_delay = String::new();
@@ -141,7 +154,7 @@ impl MessageWrapper {
         let mut dsn = String::new();
 
         for rcpt in &mut self.message.recipients {
-            if rcpt.has_flag(RCPT_DSN_SENT | RCPT_NOTIFY_NEVER) {
+            if rcpt.has_flag(RCPT_DSN_SENT | RCPT_NOTIFY_NEVER | RCPT_HOLD) {
                 continue;
             }
             match &rcpt.status {
@@ -152,15 +165,20 @@ impl MessageWrapper {
                     }
                     rcpt.write_dsn(&mut dsn);
                     rcpt.status.write_dsn(&mut dsn);
-                    response.write_dsn_text(&rcpt.address, &mut txt_success);
+                    response.write_dsn_line(
+                        &rcpt.address,
+                        rcpt.tls.as_ref().filter(|_| config.dsn.tls_details),
+                        template,
+                        &mut txt_success,
+                    );
                 }
                 Status::TemporaryFailure(response)
-                    if rcpt.notify.due <= now && rcpt.has_flag(RCPT_NOTIFY_DELAY) =>
+                    if rcpt.notify.due <= notify_due && rcpt.has_flag(RCPT_NOTIFY_DELAY) =>
                 {
                     rcpt.write_dsn(&mut dsn);
                     rcpt.status.write_dsn(&mut dsn);
                     rcpt.write_dsn_will_retry_until(self.message.created, &mut dsn);
-                    response.write_dsn_text(&rcpt.address, &mut txt_delay);
+                    response.write_dsn_line(&rcpt.address, template, &mut txt_delay);
                 }
                 Status::PermanentFailure(response) => {
                     rcpt.flags |= RCPT_DSN_SENT | RCPT_STATUS_CHANGED;
@@ -169,9 +187,18 @@ impl MessageWrapper {
                     }
                     rcpt.write_dsn(&mut dsn);
                     rcpt.status.write_dsn(&mut dsn);
-                    response.write_dsn_text(&rcpt.address, &mut txt_failed);
+                    response.write_dsn_line(&rcpt.address, template, &mut txt_failed);
+                    if let Some(transcript) = rcpt
+                        .transcript
+                        .as_deref()
+                        .filter(|_| config.dsn.include_transcript)
+                    {
+                        write_dsn_transcript(transcript, &mut txt_failed);
+                    }
                 }
-                Status::Scheduled if rcpt.notify.due <= now && rcpt.has_flag(RCPT_NOTIFY_DELAY) => {
+                Status::Scheduled
+                    if rcpt.notify.due <= notify_due && rcpt.has_flag(RCPT_NOTIFY_DELAY) =>
+                {
                     // This case should not happen under normal circumstances
                     rcpt.write_dsn(&mut dsn);
                     rcpt.status.write_dsn(&mut dsn);
@@ -180,7 +207,7 @@ impl MessageWrapper {
                         entity: "localhost".into(),
                         details: Error::ConcurrencyLimited,
                     }
-                    .write_dsn_text(&rcpt.address, &mut txt_delay);
+                    .write_dsn_line(&rcpt.address, template, &mut txt_delay);
                 }
                 _ => continue,
             }
@@ -259,7 +286,8 @@ impl MessageWrapper {
                 if matches!(
                     &rcpt.status,
                     Status::TemporaryFailure(_) | Status::Scheduled
-                ) && rcpt.notify.due <= now
+                ) && rcpt.notify.due <= notify_due
+                    && !rcpt.is_held()
                 {
                     let envelope = QueueEnvelope::new(&self.message, rcpt);
 
@@ -428,16 +456,38 @@ impl MessageWrapper {
 }
 
 impl HostResponse<String> {
-    fn write_dsn_text(&self, addr: &str, dsn: &mut String) {
+    fn write_dsn_line(
+        &self,
+        addr: &str,
+        tls: Option<&TlsDetails>,
+        template: Option<&Template<DsnTemplateVariable>>,
+        txt: &mut String,
+    ) {
+        if let Some(template) = template {
+            let mut diagnostic = String::new();
+            self.write_dsn_text(addr, tls, &mut diagnostic);
+            write_dsn_template(
+                template,
+                addr,
+                &self.hostname,
+                Some(&self.response),
+                diagnostic,
+                txt,
+            );
+        } else {
+            self.write_dsn_text(addr, tls, txt);
+        }
+    }
+
+    fn write_dsn_text(&self, addr: &str, tls: Option<&TlsDetails>, dsn: &mut String) {
+        let _ = write!(dsn, "<{}> (delivered to '{}'", addr, self.hostname);
+        if let Some(tls) = tls {
+            let _ = write!(dsn, " using {} ({})", tls.version, tls.cipher_suite);
+        }
         let _ = write!(
             dsn,
-            "<{}> (delivered to '{}' with code {} ({}.{}.{}) '",
-            addr,
-            self.hostname,
-            self.response.code,
-            self.response.esc[0],
-            self.response.esc[1],
-            self.response.esc[2]
+            " with code {} ({}.{}.{}) '",
+            self.response.code, self.response.esc[0], self.response.esc[1], self.response.esc[2]
         );
         self.response.write_response(dsn);
         dsn.push_str("')\r\n");
@@ -465,13 +515,38 @@ impl UnexpectedResponse {
 }
 
 impl ErrorDetails {
+    fn write_dsn_line(
+        &self,
+        addr: &str,
+        template: Option<&Template<DsnTemplateVariable>>,
+        txt: &mut String,
+    ) {
+        if let Some(template) = template {
+            let mut diagnostic = String::new();
+            self.write_dsn_text(addr, &mut diagnostic);
+            write_dsn_template(
+                template,
+                addr,
+                &self.entity,
+                match &self.details {
+                    Error::UnexpectedResponse(response) => Some(&response.response),
+                    _ => None,
+                },
+                diagnostic,
+                txt,
+            );
+        } else {
+            self.write_dsn_text(addr, txt);
+        }
+    }
+
     fn write_dsn_text(&self, addr: &str, dsn: &mut String) {
         let entity = self.entity.as_str();
         match &self.details {
             Error::UnexpectedResponse(response) => {
                 response.write_dsn_text(entity, addr, dsn);
             }
-            Error::DnsError(err) => {
+            Error::DnsError(err) | Error::DomainNotFound(err) => {
                 let _ = write!(dsn, "<{addr}> (failed to lookup '{entity}': {err})\r\n",);
             }
             Error::ConnectionError(details) => {
@@ -486,7 +561,7 @@ impl ErrorDetails {
             Error::DaneError(details) => {
                 let _ = write!(
                     dsn,
-                    "<{addr}> (DANE failed to authenticate '{entity}': {details})\r\n",
+                    "<{addr}> (DANE validation failed for '{entity}': {details})\r\n",
                 );
             }
             Error::MtaStsError(details) => {
@@ -507,10 +582,63 @@ impl ErrorDetails {
             Error::Io(err) => {
                 let _ = write!(dsn, "<{addr}> (queue error: {err})\r\n");
             }
+            Error::MessageTooLarge(limit) => {
+                let _ = write!(
+                    dsn,
+                    "<{addr}> (message exceeds the size limit of {limit} bytes advertised by '{entity}')\r\n",
+                );
+            }
+        }
+    }
+}
+
+fn write_dsn_transcript(transcript: &str, txt: &mut String) {
+    txt.push_str("\r\n    ----- Transcript of session follows -----\r\n");
+    for line in transcript.lines() {
+        let _ = write!(txt, "    {line}\r\n");
+    }
+    txt.push_str("\r\n");
+}
+
+// Encodes a value as xtext, as described in RFC 3461 Section 4
+fn write_xtext(value: &str, dsn: &mut String) {
+    for byte in value.bytes() {
+        if (b'!'..=b'~').contains(&byte) && byte != b'+' && byte != b'=' {
+            dsn.push(byte as char);
+        } else {
+            let _ = write!(dsn, "+{byte:02X}");
         }
     }
 }
 
+fn write_dsn_template(
+    template: &Template<DsnTemplateVariable>,
+    addr: &str,
+    host: &str,
+    response: Option<&Response<String>>,
+    diagnostic: String,
+    txt: &mut String,
+) {
+    let mut variables = Variables::new();
+    variables.insert_single(DsnTemplateVariable::Rcpt, addr.to_string());
+    variables.insert_single(DsnTemplateVariable::Host, host.to_string());
+    if let Some(response) = response {
+        let mut smtp_response = format!(
+            "{} ({}.{}.{}) ",
+            response.code, response.esc[0], response.esc[1], response.esc[2]
+        );
+        response.write_response(&mut smtp_response);
+        variables.insert_single(DsnTemplateVariable::SmtpResponse, smtp_response);
+    }
+    variables.insert_single(
+        DsnTemplateVariable::Diagnostic,
+        diagnostic.trim_end().to_string(),
+    );
+
+    txt.push_str(&template.eval(&variables));
+    txt.push_str("\r\n");
+}
+
 impl Message {
     fn write_dsn_headers(&self, dsn: &mut String, reporting_mta: &str) {
         let _ = write!(dsn, "Reporting-MTA: dns;{reporting_mta}\r\n");
@@ -522,12 +650,43 @@ impl Message {
         }
         dsn.push_str("\r\n");
     }
+
+    // Once a delay notification is due, recipients due within the
+    // configured window are included in the same DSN.
+    fn delay_notify_due(&self, now: u64, window: u64) -> u64 {
+        if window > 0
+            && self.recipients.iter().any(|rcpt| {
+                matches!(
+                    &rcpt.status,
+                    Status::TemporaryFailure(_) | Status::Scheduled
+                ) && rcpt.notify.due <= now
+                    && rcpt.has_flag(RCPT_NOTIFY_DELAY)
+                    && !rcpt.has_flag(RCPT_DSN_SENT | RCPT_NOTIFY_NEVER | RCPT_HOLD)
+            })
+        {
+            now.saturating_add(window)
+        } else {
+            now
+        }
+    }
 }
 
 impl Recipient {
     fn write_dsn(&self, dsn: &mut String) {
         if let Some(orcpt) = &self.orcpt {
-            let _ = write!(dsn, "Original-Recipient: rfc822;{orcpt}\r\n");
+            // ORCPT values may or may not include the address type
+            let (addr_type, addr) = orcpt
+                .split_once(';')
+                .filter(|(addr_type, _)| {
+                    !addr_type.is_empty()
+                        && addr_type
+                            .chars()
+                            .all(|ch| ch.is_ascii_alphanumeric() || ch == '-')
+                })
+                .unwrap_or(("rfc822", orcpt.as_str()));
+            let _ = write!(dsn, "Original-Recipient: {addr_type};");
+            write_xtext(addr, dsn);
+            dsn.push_str("\r\n");
         }
         let _ = write!(dsn, "Final-Recipient: rfc822;{}\r\n", self.address);
     }
@@ -588,14 +747,34 @@ impl Status<HostResponse<String>, ErrorDetails> {
                 response.response.write_dsn_status(dsn);
             }
             Status::TemporaryFailure(err) | Status::PermanentFailure(err) => {
-                if let Error::UnexpectedResponse(response) = &err.details {
-                    response.response.write_dsn_status(dsn);
+                let class = if matches!(self, Status::PermanentFailure(_)) {
+                    5
                 } else {
-                    dsn.push_str(if matches!(self, Status::PermanentFailure(_)) {
-                        "5.0.0"
-                    } else {
-                        "4.0.0"
-                    });
+                    4
+                };
+                match &err.details {
+                    Error::UnexpectedResponse(response) => {
+                        response.response.write_dsn_status(dsn);
+                    }
+                    // RFC 3463: bad destination system address
+                    Error::DomainNotFound(_) => {
+                        let _ = write!(dsn, "{class}.1.2");
+                    }
+                    // RFC 3463: unable to route
+                    Error::DnsError(_) => {
+                        let _ = write!(dsn, "{class}.4.4");
+                    }
+                    // RFC 3463: no answer from host
+                    Error::ConnectionError(_) => {
+                        let _ = write!(dsn, "{class}.4.1");
+                    }
+                    // RFC 3463: message too big for system
+                    Error::MessageTooLarge(_) => {
+                        let _ = write!(dsn, "{class}.3.4");
+                    }
+                    _ => {
+                        let _ = write!(dsn, "{class}.0.0");
+                    }
                 }
             }
             Status::Scheduled => {
@@ -616,7 +795,8 @@ impl Status<HostResponse<String>, ErrorDetails> {
                 Error::UnexpectedResponse(_)
                 | Error::ConnectionError(_)
                 | Error::TlsError(_)
-                | Error::DaneError(_) => {
+                | Error::DaneError(_)
+                | Error::MessageTooLarge(_) => {
                     dsn.push_str("Remote-MTA: dns;");
                     dsn.push_str(&err.entity);
                     dsn.push_str("\r\n");
@@ -4,12 +4,15 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use super::{Message, QueueId, Status, spool::SmtpSpool};
-use crate::queue::{Recipient, spool::LOCK_EXPIRY};
+use super::{Message, QueueId, Status, parse_next_hop, spool::SmtpSpool};
+use crate::queue::{RCPT_HOLD, Recipient, spool::LOCK_EXPIRY};
 use ahash::AHashMap;
 use common::{
     Inner,
-    config::smtp::queue::{QueueExpiry, QueueName},
+    config::{
+        server::ServerProtocol,
+        smtp::queue::{GatewayStrategy, QueueExpiry, QueueName, RelayConfig},
+    },
     core::BuildServer,
     ipc::{QueueEvent, QueueEventStatus},
 };
@@ -72,6 +75,7 @@ impl Queue {
 
     pub async fn start(&mut self) {
         let mut is_paused = false;
+        let mut is_draining = false;
 
         loop {
             let refresh_queue = match tokio::time::timeout(
@@ -121,7 +125,24 @@ impl Queue {
                         .data
                         .queue_status
                         .store(!paused, Ordering::Relaxed);
+                    if !paused {
+                        // Resuming the queue also ends a drain
+                        self.core
+                            .data
+                            .queue_draining
+                            .store(false, Ordering::Relaxed);
+                        is_draining = false;
+                    }
                     is_paused = paused;
+                    !paused
+                }
+                Ok(Some(QueueEvent::Drain)) => {
+                    // Stop starting new deliveries and reject new messages while
+                    // in-flight deliveries complete
+                    self.core.data.queue_status.store(false, Ordering::Relaxed);
+                    self.core.data.queue_draining.store(true, Ordering::Relaxed);
+                    is_paused = true;
+                    is_draining = true;
                     false
                 }
                 Ok(Some(QueueEvent::ReloadSettings)) => {
@@ -142,6 +163,12 @@ impl Queue {
                 }
             };
 
+            if is_draining && self.stats.values().all(|stats| stats.in_flight == 0) {
+                // Stop once all in-flight deliveries have completed
+                trc::event!(Queue(trc::QueueEvent::Drained));
+                break;
+            }
+
             if !is_paused {
                 // Deliver scheduled messages
                 if refresh_queue || self.next_wake_up <= Instant::now() {
@@ -151,6 +178,11 @@ impl Queue {
 
                     if queue_events.messages.len() > 3 {
                         queue_events.messages.shuffle(&mut rand::rng());
+
+                        // Higher priority messages are still delivered first
+                        queue_events
+                            .messages
+                            .sort_by_key(|event| std::cmp::Reverse(event.priority));
                     }
 
                     for queue_event in &queue_events.messages {
@@ -213,6 +245,7 @@ impl Message {
         for rcpt in &self.recipients {
             if matches!(rcpt.status, Status::Scheduled | Status::TemporaryFailure(_))
                 && queue.is_none_or(|q| rcpt.queue == q)
+                && !rcpt.is_held()
             {
                 let mut earlier_event = std::cmp::min(rcpt.retry.due, rcpt.notify.due);
 
@@ -239,6 +272,7 @@ impl Message {
         for rcpt in self.recipients.iter().filter(|rcpt| {
             matches!(rcpt.status, Status::Scheduled | Status::TemporaryFailure(_))
                 && queue.is_none_or(|q| rcpt.queue == q)
+                && !rcpt.is_held()
         }) {
             if let Some(next_delivery) = &mut next_delivery {
                 if rcpt.retry.due < *next_delivery {
@@ -258,6 +292,7 @@ impl Message {
         for rcpt in self.recipients.iter().filter(|rcpt| {
             matches!(rcpt.status, Status::Scheduled | Status::TemporaryFailure(_))
                 && queue.is_none_or(|q| rcpt.queue == q)
+                && !rcpt.is_held()
         }) {
             if let Some(next_dsn) = &mut next_dsn {
                 if rcpt.notify.due < *next_dsn {
@@ -277,6 +312,7 @@ impl Message {
         for rcpt in self.recipients.iter().filter(|d| {
             matches!(d.status, Status::Scheduled | Status::TemporaryFailure(_))
                 && queue.is_none_or(|q| d.queue == q)
+                && !d.is_held()
         }) {
             if let Some(rcpt_expires) = rcpt.expiration_time(self.created) {
                 if let Some(expires) = &mut expires {
@@ -296,7 +332,9 @@ impl Message {
         let mut next_events = AHashMap::new();
 
         for rcpt in &self.recipients {
-            if matches!(rcpt.status, Status::Scheduled | Status::TemporaryFailure(_)) {
+            if matches!(rcpt.status, Status::Scheduled | Status::TemporaryFailure(_))
+                && !rcpt.is_held()
+            {
                 let mut earlier_event = std::cmp::min(rcpt.retry.due, rcpt.notify.due);
 
                 if let Some(expires) = rcpt.expiration_time(self.created) {
@@ -329,6 +367,46 @@ impl Recipient {
         }
     }
 
+    pub fn is_held(&self) -> bool {
+        (self.flags & RCPT_HOLD) != 0
+    }
+
+    pub fn hold(&mut self) {
+        // While held, the retry due time records when the hold started
+        self.flags |= RCPT_HOLD;
+        self.retry.due = now();
+    }
+
+    pub fn unhold(&mut self) {
+        // Do not count the time spent on hold against the expiration and notify timers
+        let now = now();
+        let held_for = now.saturating_sub(self.retry.due);
+        if let QueueExpiry::Duration(expires) = &mut self.expires {
+            *expires += held_for;
+        }
+        self.notify.due = self.notify.due.saturating_add(held_for);
+        self.retry.due = now;
+        self.flags &= !RCPT_HOLD;
+    }
+
+    /// Relay pinned by a reroute, it is reached over SMTP without authentication
+    /// and STARTTLS follows the TLS strategy. Implicit TLS is not supported.
+    pub fn next_hop_gateway(&self) -> Option<GatewayStrategy> {
+        self.next_hop
+            .as_deref()
+            .and_then(parse_next_hop)
+            .map(|(address, port)| {
+                GatewayStrategy::Relay(RelayConfig {
+                    address: address.to_string(),
+                    port,
+                    protocol: ServerProtocol::Smtp,
+                    auth: None,
+                    tls_implicit: false,
+                    tls_allow_invalid_certs: false,
+                })
+            })
+    }
+
     pub fn is_expired(&self, created: u64, now: u64) -> bool {
         match self.expires {
             QueueExpiry::Duration(time) => created + time <= now,
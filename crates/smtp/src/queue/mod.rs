@@ -12,7 +12,7 @@ use compact_str::ToCompactString;
 use smtp_proto::{ArchivedResponse, Response};
 use std::{
     fmt::Display,
-    net::{IpAddr, Ipv4Addr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
     time::{Duration, Instant, SystemTime},
 };
 use store::write::now;
@@ -37,6 +37,7 @@ pub struct QueuedMessage {
     pub due: u64,
     pub queue_id: QueueId,
     pub queue_name: QueueName,
+    pub priority: i16,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -67,6 +68,15 @@ pub struct Message {
 
     pub size: u64,
     pub quota_keys: Vec<QuotaKey>,
+
+    pub headers: Vec<(String, String)>,
+    pub submission: Option<SubmissionId>,
+}
+
+#[derive(rkyv::Serialize, rkyv::Deserialize, rkyv::Archive, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubmissionId {
+    pub account_id: u32,
+    pub document_id: u32,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -112,9 +122,12 @@ pub struct Recipient {
     pub expires: QueueExpiry,
 
     pub queue: QueueName,
+    pub next_hop: Option<String>,
     pub status: Status<HostResponse<String>, ErrorDetails>,
     pub flags: u64,
     pub orcpt: Option<String>,
+    pub tls: Option<TlsDetails>,
+    pub transcript: Option<String>,
 }
 
 pub const FROM_AUTHENTICATED: u64 = 1 << 32;
@@ -123,9 +136,12 @@ pub const FROM_UNAUTHENTICATED_DMARC: u64 = 1 << 34;
 pub const FROM_DSN: u64 = 1 << 35;
 pub const FROM_REPORT: u64 = 1 << 36;
 pub const FROM_AUTOGENERATED: u64 = 1 << 37;
+pub const TLS_OPTIONAL: u64 = 1 << 38;
+pub const QUARANTINED: u64 = 1 << 39;
 
 pub const RCPT_DSN_SENT: u64 = 1 << 32;
 pub const RCPT_STATUS_CHANGED: u64 = 1 << 33;
+pub const RCPT_HOLD: u64 = 1 << 34;
 
 #[derive(
     Debug,
@@ -149,6 +165,22 @@ pub enum Status<T, E> {
     PermanentFailure(E),
 }
 
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    rkyv::Serialize,
+    rkyv::Deserialize,
+    rkyv::Archive,
+    serde::Deserialize,
+)]
+pub struct TlsDetails {
+    pub version: String,
+    pub cipher_suite: String,
+    pub peer_subject: Option<String>,
+}
+
 #[derive(
     Debug,
     Clone,
@@ -186,6 +218,8 @@ pub enum Error {
     #[default]
     ConcurrencyLimited,
     Io(String),
+    DomainNotFound(String),
+    MessageTooLarge(u64),
 }
 
 #[derive(
@@ -312,6 +346,8 @@ impl<'x> ResolveVariable for QueueEnvelope<'x> {
                         Error::RateLimited => "rate",
                         Error::ConcurrencyLimited => "concurrency",
                         Error::Io(_) => "io",
+                        Error::DomainNotFound(_) => "domain-not-found",
+                        Error::MessageTooLarge(_) => "message-too-large",
                     }
                 }
             }
@@ -345,8 +381,8 @@ impl<'x> ResolveVariable for QueueEnvelope<'x> {
         }
     }
 
-    fn resolve_global(&self, _: &str) -> Variable<'_> {
-        Variable::Integer(0)
+    fn resolve_global(&self, variable: &str) -> Variable<'_> {
+        self.message.resolve_global(variable)
     }
 }
 
@@ -366,8 +402,16 @@ impl ResolveVariable for Message {
         }
     }
 
-    fn resolve_global(&self, _: &str) -> Variable<'_> {
-        Variable::Integer(0)
+    fn resolve_global(&self, variable: &str) -> Variable<'_> {
+        match variable.strip_prefix("header.") {
+            Some(name) => self
+                .headers
+                .iter()
+                .find(|(header, _)| header == name)
+                .map_or("", |(_, value)| value.as_str())
+                .into(),
+            None => Variable::Integer(0),
+        }
     }
 }
 
@@ -418,6 +462,27 @@ impl InstantFromTimestamp for u64 {
     }
 }
 
+/// Parses a `host[:port]` or `[IPv6][:port]` next hop, the port defaults to 25.
+pub fn parse_next_hop(next_hop: &str) -> Option<(&str, u16)> {
+    let (address, port) = if let Some(next_hop) = next_hop.strip_prefix('[') {
+        let (address, port) = next_hop.split_once(']')?;
+        address.parse::<Ipv6Addr>().ok()?;
+        match port {
+            "" => (address, None),
+            port => (address, Some(port.strip_prefix(':')?)),
+        }
+    } else {
+        match next_hop.rsplit_once(':') {
+            Some((address, port)) if !address.contains(':') => (address, Some(port)),
+            Some(_) => return None,
+            None => (next_hop, None),
+        }
+    };
+    let port = port.map_or(Some(25), |port| port.parse().ok())?;
+
+    (!address.is_empty() && port != 0).then_some((address, port))
+}
+
 pub trait DomainPart {
     fn domain_part(&self) -> &str;
 }
@@ -466,6 +531,12 @@ impl Display for Error {
             Error::Io(err) => {
                 write!(f, "Queue error: {err}")
             }
+            Error::DomainNotFound(err) => {
+                write!(f, "Domain not found: {err}")
+            }
+            Error::MessageTooLarge(limit) => {
+                write!(f, "Message exceeds remote size limit of {limit} bytes")
+            }
         }
     }
 }
@@ -505,6 +576,12 @@ impl Display for ArchivedError {
             ArchivedError::Io(err) => {
                 write!(f, "Queue error: {err}")
             }
+            ArchivedError::DomainNotFound(err) => {
+                write!(f, "Domain not found: {err}")
+            }
+            ArchivedError::MessageTooLarge(limit) => {
+                write!(f, "Message exceeds remote size limit of {limit} bytes")
+            }
         }
     }
 }
@@ -5,17 +5,21 @@
  */
 
 use super::{
-    ArchivedMessage, ArchivedStatus, Message, MessageSource, QueueEnvelope, QueueId, QueuedMessage,
-    QuotaKey, Recipient, Schedule, Status,
+    ArchivedMessage, ArchivedStatus, Error, ErrorDetails, Message, MessageSource, QueueEnvelope,
+    QueueId, QueuedMessage, QuotaKey, Recipient, Schedule, Status,
 };
+use crate::outbound::DeliveryReport;
 use crate::queue::manager::{LockedMessage, Queue};
 use crate::queue::{
     DomainPart, FROM_AUTHENTICATED, FROM_AUTOGENERATED, FROM_DSN, FROM_REPORT,
-    FROM_UNAUTHENTICATED, FROM_UNAUTHENTICATED_DMARC, MessageWrapper,
+    FROM_UNAUTHENTICATED, FROM_UNAUTHENTICATED_DMARC, MessageWrapper, QUARANTINED, parse_next_hop,
 };
+use ahash::AHashMap;
 use common::config::smtp::queue::{QueueExpiry, QueueName};
 use common::ipc::QueueEvent;
 use common::{KV_LOCK_QUEUE_MESSAGE, Server};
+use email::submission::{Delivered, DeliveryStatus, status::EmailSubmissionStatus};
+use smtp_proto::Response;
 use std::borrow::Cow;
 use std::collections::hash_map::Entry;
 use std::future::Future;
@@ -38,6 +42,36 @@ pub struct QueuedMessages {
     pub next_refresh: u64,
 }
 
+#[derive(Debug, Clone, Default)]
+pub struct QueueFilter {
+    pub rcpt_domain: Option<String>,
+    pub sender: Option<String>,
+    pub created_before: Option<u64>,
+    pub due_before: Option<u64>,
+    pub due_after: Option<u64>,
+    pub quarantined: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueSummary {
+    pub queue_id: QueueId,
+    pub return_path: String,
+    pub domains: Vec<String>,
+    pub created: u64,
+    pub next_due: u64,
+    pub retry_count: u32,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueueMetrics {
+    pub messages: u64,
+    pub recipients: u64,
+    pub oldest_age: u64,
+    pub domains: AHashMap<String, u64>,
+    pub next_wakeup: Option<u64>,
+}
+
 pub trait SmtpSpool: Sync + Send {
     fn new_message(
         &self,
@@ -71,6 +105,53 @@ pub trait SmtpSpool: Sync + Send {
         &self,
         id: QueueId,
     ) -> impl Future<Output = trc::Result<Option<Archive<AlignedBytes>>>> + Send;
+
+    fn requeue(
+        &self,
+        queue_id: QueueId,
+        domain: Option<&str>,
+        due: Option<u64>,
+    ) -> impl Future<Output = trc::Result<bool>> + Send;
+
+    fn hold(&self, queue_id: QueueId, rcpt: &str)
+    -> impl Future<Output = trc::Result<bool>> + Send;
+
+    fn release(
+        &self,
+        queue_id: QueueId,
+        rcpt: &str,
+    ) -> impl Future<Output = trc::Result<bool>> + Send;
+
+    fn list(
+        &self,
+        filter: &QueueFilter,
+    ) -> impl Future<Output = trc::Result<Vec<QueueSummary>>> + Send;
+
+    fn quarantine_release(
+        &self,
+        queue_id: QueueId,
+    ) -> impl Future<Output = trc::Result<bool>> + Send;
+
+    fn quarantine_delete(
+        &self,
+        queue_id: QueueId,
+    ) -> impl Future<Output = trc::Result<bool>> + Send;
+
+    fn cancel(&self, filter: &QueueFilter) -> impl Future<Output = trc::Result<usize>> + Send;
+
+    fn metrics(&self) -> impl Future<Output = trc::Result<QueueMetrics>> + Send;
+
+    fn reroute(
+        &self,
+        queue_id: QueueId,
+        rcpt_domain: &str,
+        relay_host: &str,
+    ) -> impl Future<Output = trc::Result<bool>> + Send;
+
+    fn test_deliver(
+        &self,
+        queue_id: QueueId,
+    ) -> impl Future<Output = trc::Result<DeliveryReport>> + Send;
 }
 
 impl SmtpSpool for Server {
@@ -104,6 +185,8 @@ impl SmtpSpool for Server {
                 quota_keys: Vec::new(),
                 received_from_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
                 received_via_port: 0,
+                headers: Vec::new(),
+                submission: None,
             },
         }
     }
@@ -134,8 +217,8 @@ impl SmtpSpool for Server {
         let result = self
             .store()
             .iterate(
-                IterateParams::new(from_key, to_key).ascending().no_values(),
-                |key, _| {
+                IterateParams::new(from_key, to_key).ascending(),
+                |key, value| {
                     let due = key.deserialize_be_u64(0)?;
 
                     if due <= now {
@@ -178,6 +261,9 @@ impl SmtpSpool for Server {
                                 due,
                                 queue_id,
                                 queue_name,
+                                priority: <[u8; 2]>::try_from(value)
+                                    .map(i16::from_be_bytes)
+                                    .unwrap_or_default(),
                             });
                         }
 
@@ -199,6 +285,11 @@ impl SmtpSpool for Server {
             );
         }
 
+        // Messages due at the same time are delivered in priority order
+        events
+            .messages
+            .sort_by_key(|event| (event.due, std::cmp::Reverse(event.priority)));
+
         events
     }
 
@@ -289,6 +380,362 @@ impl SmtpSpool for Server {
             )))
             .await
     }
+
+    async fn requeue(
+        &self,
+        queue_id: QueueId,
+        domain: Option<&str>,
+        due: Option<u64>,
+    ) -> trc::Result<bool> {
+        let mut message = read_message_or_fail(self, queue_id).await?;
+        let due = due.unwrap_or_else(now);
+        let mut has_changes = false;
+
+        for rcpt in &mut message.message.recipients {
+            if matches!(rcpt.status, Status::Scheduled | Status::TemporaryFailure(_))
+                && domain.is_none_or(|domain| rcpt.address_lcase.domain_part() == domain)
+                && !rcpt.is_held()
+            {
+                rcpt.retry.due = due;
+                if rcpt
+                    .expiration_time(message.message.created)
+                    .is_some_and(|expires| expires > due)
+                {
+                    rcpt.expires = QueueExpiry::Count(rcpt.retry.inner + 10);
+                }
+                has_changes = true;
+            }
+        }
+
+        if has_changes {
+            message.save_changes(self, None).await;
+            let _ = self.inner.ipc.queue_tx.send(QueueEvent::Refresh).await;
+        }
+
+        Ok(has_changes)
+    }
+
+    async fn hold(&self, queue_id: QueueId, rcpt: &str) -> trc::Result<bool> {
+        let mut message = read_message_or_fail(self, queue_id).await?;
+        let rcpt = rcpt.to_lowercase();
+
+        if let Some(rcpt) = message.message.recipients.iter_mut().find(|r| {
+            r.address_lcase == rcpt
+                && matches!(r.status, Status::Scheduled | Status::TemporaryFailure(_))
+                && !r.is_held()
+        }) {
+            rcpt.hold();
+            message.save_changes(self, None).await;
+            let _ = self.inner.ipc.queue_tx.send(QueueEvent::Refresh).await;
+
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    async fn release(&self, queue_id: QueueId, rcpt: &str) -> trc::Result<bool> {
+        let mut message = read_message_or_fail(self, queue_id).await?;
+        let rcpt = rcpt.to_lowercase();
+
+        if let Some(rcpt) = message
+            .message
+            .recipients
+            .iter_mut()
+            .find(|r| r.address_lcase == rcpt && r.is_held())
+        {
+            rcpt.unhold();
+            message.save_changes(self, None).await;
+            let _ = self.inner.ipc.queue_tx.send(QueueEvent::Refresh).await;
+
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    async fn list(&self, filter: &QueueFilter) -> trc::Result<Vec<QueueSummary>> {
+        let from_key = ValueKey::from(ValueClass::Queue(QueueClass::Message(0)));
+        let to_key = ValueKey::from(ValueClass::Queue(QueueClass::Message(u64::MAX)));
+        let sender = filter.sender.as_ref().map(|sender| sender.to_lowercase());
+        let rcpt_domain = filter
+            .rcpt_domain
+            .as_ref()
+            .map(|domain| domain.to_lowercase());
+        let mut result = Vec::new();
+
+        self.store()
+            .iterate(
+                IterateParams::new(from_key, to_key).ascending(),
+                |key, value| {
+                    let message_ = <Archive<AlignedBytes> as Deserialize>::deserialize(value)
+                        .add_context(|ctx| ctx.ctx(trc::Key::Key, key))?;
+                    let message = message_
+                        .unarchive::<Message>()
+                        .add_context(|ctx| ctx.ctx(trc::Key::Key, key))?;
+                    let created = u64::from(message.created);
+                    let next_due = message.next_delivery_event();
+
+                    // Quarantined messages are only listed when requested
+                    let is_quarantined = (u64::from(message.flags) & QUARANTINED) != 0;
+
+                    if is_quarantined == filter.quarantined
+                        && rcpt_domain.as_ref().is_none_or(|domain| {
+                            message
+                                .recipients
+                                .iter()
+                                .any(|r| r.address_lcase.domain_part() == domain)
+                        })
+                        && sender
+                            .as_ref()
+                            .is_none_or(|sender| message.return_path_lcase.as_str() == sender)
+                        && filter.created_before.is_none_or(|before| created < before)
+                        && filter.due_before.is_none_or(|before| next_due < before)
+                        && filter.due_after.is_none_or(|after| next_due > after)
+                    {
+                        let mut domains: Vec<String> = Vec::new();
+                        for rcpt in message.recipients.iter() {
+                            let domain = rcpt.address_lcase.domain_part();
+                            if !domains.iter().any(|d| d == domain) {
+                                domains.push(domain.to_string());
+                            }
+                        }
+
+                        result.push(QueueSummary {
+                            queue_id: key.deserialize_be_u64(0)?,
+                            return_path: message.return_path.to_string(),
+                            domains,
+                            created,
+                            next_due,
+                            retry_count: message
+                                .recipients
+                                .iter()
+                                .map(|r| u32::from(r.retry.inner))
+                                .max()
+                                .unwrap_or_default(),
+                            size: u64::from(message.size),
+                        });
+                    }
+
+                    Ok(true)
+                },
+            )
+            .await
+            .caused_by(trc::location!())
+            .map(|_| result)
+    }
+
+    async fn quarantine_release(&self, queue_id: QueueId) -> trc::Result<bool> {
+        let mut message = read_message_or_fail(self, queue_id).await?;
+        if !message.is_quarantined() {
+            return Ok(false);
+        }
+
+        // Released messages re-enter the queue as if they were just received
+        message.message.flags &= !QUARANTINED;
+        for rcpt in &mut message.message.recipients {
+            if rcpt.is_held() {
+                rcpt.unhold();
+            }
+        }
+        message.save_changes(self, None).await;
+        let _ = self.inner.ipc.queue_tx.send(QueueEvent::Refresh).await;
+
+        Ok(true)
+    }
+
+    async fn quarantine_delete(&self, queue_id: QueueId) -> trc::Result<bool> {
+        let message = read_message_or_fail(self, queue_id).await?;
+        if message.is_quarantined() {
+            Ok(message.remove(self, None).await)
+        } else {
+            Ok(false)
+        }
+    }
+
+    async fn cancel(&self, filter: &QueueFilter) -> trc::Result<usize> {
+        let mut removed = 0;
+
+        // Messages are dropped without generating DSNs
+        for summary in self.list(filter).await? {
+            if let Some(message) = self
+                .read_message(summary.queue_id, QueueName::default())
+                .await
+                && message.remove(self, None).await
+            {
+                removed += 1;
+            }
+        }
+
+        if removed > 0 {
+            let _ = self.inner.ipc.queue_tx.send(QueueEvent::Refresh).await;
+        }
+
+        Ok(removed)
+    }
+
+    async fn metrics(&self) -> trc::Result<QueueMetrics> {
+        let now = now();
+        let mut metrics = QueueMetrics::default();
+
+        // Count messages and pending recipients per domain
+        let from_key = ValueKey::from(ValueClass::Queue(QueueClass::Message(0)));
+        let to_key = ValueKey::from(ValueClass::Queue(QueueClass::Message(u64::MAX)));
+        self.store()
+            .iterate(
+                IterateParams::new(from_key, to_key).ascending(),
+                |key, value| {
+                    let message_ = <Archive<AlignedBytes> as Deserialize>::deserialize(value)
+                        .add_context(|ctx| ctx.ctx(trc::Key::Key, key))?;
+                    let message = message_
+                        .unarchive::<Message>()
+                        .add_context(|ctx| ctx.ctx(trc::Key::Key, key))?;
+
+                    metrics.messages += 1;
+                    metrics.oldest_age = std::cmp::max(
+                        metrics.oldest_age,
+                        now.saturating_sub(u64::from(message.created)),
+                    );
+                    for rcpt in message.recipients.iter().filter(|rcpt| {
+                        matches!(
+                            rcpt.status,
+                            ArchivedStatus::Scheduled | ArchivedStatus::TemporaryFailure(_)
+                        )
+                    }) {
+                        metrics.recipients += 1;
+                        *metrics
+                            .domains
+                            .entry(rcpt.address_lcase.domain_part().to_string())
+                            .or_default() += 1;
+                    }
+
+                    Ok(true)
+                },
+            )
+            .await
+            .caused_by(trc::location!())?;
+
+        // The earliest queue event is the next time the queue manager wakes up
+        let from_key = ValueKey::from(ValueClass::Queue(QueueClass::MessageEvent(
+            store::write::QueueEvent {
+                due: 0,
+                queue_id: 0,
+                queue_name: [0; 8],
+            },
+        )));
+        let to_key = ValueKey::from(ValueClass::Queue(QueueClass::MessageEvent(
+            store::write::QueueEvent {
+                due: u64::MAX,
+                queue_id: u64::MAX,
+                queue_name: [u8::MAX; 8],
+            },
+        )));
+        self.store()
+            .iterate(
+                IterateParams::new(from_key, to_key).ascending().no_values(),
+                |key, _| {
+                    metrics.next_wakeup = Some(key.deserialize_be_u64(0)?);
+                    Ok(false)
+                },
+            )
+            .await
+            .caused_by(trc::location!())
+            .map(|_| metrics)
+    }
+
+    async fn reroute(
+        &self,
+        queue_id: QueueId,
+        rcpt_domain: &str,
+        relay_host: &str,
+    ) -> trc::Result<bool> {
+        if parse_next_hop(relay_host).is_none() {
+            return Err(trc::ResourceEvent::BadParameters
+                .into_err()
+                .details("Invalid relay host.")
+                .ctx(trc::Key::Value, relay_host.to_string()));
+        }
+
+        let mut message = read_message_or_fail(self, queue_id).await?;
+        let rcpt_domain = rcpt_domain.to_lowercase();
+        let mut has_changes = false;
+
+        for rcpt in &mut message.message.recipients {
+            if matches!(rcpt.status, Status::Scheduled | Status::TemporaryFailure(_))
+                && rcpt.address_lcase.domain_part() == rcpt_domain
+            {
+                rcpt.next_hop = Some(relay_host.to_string());
+                has_changes = true;
+            }
+        }
+
+        if has_changes {
+            message.save_changes(self, None).await;
+            let _ = self.inner.ipc.queue_tx.send(QueueEvent::Refresh).await;
+        }
+
+        Ok(has_changes)
+    }
+
+    async fn test_deliver(&self, queue_id: QueueId) -> trc::Result<DeliveryReport> {
+        #![allow(clippy::large_futures)]
+        let mut message = read_message_or_fail(self, queue_id).await?;
+        message.span_id = self.inner.data.span_id_gen.generate();
+
+        // Resolve, connect and say EHLO to each next hop, resetting the session before DATA
+        let mut report = DeliveryReport::default();
+        QueuedMessage {
+            due: now(),
+            queue_id,
+            queue_name: message.queue_name,
+            priority: message.message.priority,
+        }
+        .deliver_task(self.clone(), message, Some(&mut report))
+        .await;
+
+        Ok(report)
+    }
+}
+
+fn format_response(response: &Response<String>) -> String {
+    format!(
+        "Code: {}, Enhanced code: {}.{}.{}, Message: {}",
+        response.code,
+        response.esc[0],
+        response.esc[1],
+        response.esc[2],
+        response.message.replace('\n', " "),
+    )
+}
+
+fn format_error(err: &ErrorDetails) -> String {
+    match &err.details {
+        Error::UnexpectedResponse(response) => format_response(&response.response),
+        Error::DnsError(details)
+        | Error::DomainNotFound(details)
+        | Error::Io(details)
+        | Error::ConnectionError(details)
+        | Error::TlsError(details)
+        | Error::DaneError(details)
+        | Error::MtaStsError(details) => details.clone(),
+        Error::RateLimited => "Rate limited".to_string(),
+        Error::ConcurrencyLimited => "Concurrency limited".to_string(),
+        Error::MessageTooLarge(limit) => {
+            format!("Message exceeds remote size limit of {limit} bytes")
+        }
+    }
+}
+
+async fn read_message_or_fail(server: &Server, queue_id: QueueId) -> trc::Result<MessageWrapper> {
+    server
+        .read_message(queue_id, QueueName::default())
+        .await
+        .ok_or_else(|| {
+            trc::ResourceEvent::NotFound
+                .into_err()
+                .id(queue_id)
+                .caused_by(trc::location!())
+        })
 }
 
 fn lock_id(queue_id: QueueId, queue_name: QueueName) -> [u8; 16] {
@@ -299,6 +746,17 @@ fn lock_id(queue_id: QueueId, queue_name: QueueName) -> [u8; 16] {
 }
 
 impl MessageWrapper {
+    pub fn quarantine(&mut self) {
+        self.message.flags |= QUARANTINED;
+        for rcpt in &mut self.message.recipients {
+            rcpt.hold();
+        }
+    }
+
+    pub fn is_quarantined(&self) -> bool {
+        (self.message.flags & QUARANTINED) != 0
+    }
+
     pub async fn queue(
         mut self,
         raw_headers: Option<&[u8]>,
@@ -362,6 +820,9 @@ impl MessageWrapper {
 
             return false;
         }
+
+        // Identical bodies share one blob, kept while any queued message links to it.
+        // It is always written, as skipping existing blobs would race with blob purges.
         if let Err(err) = server
             .blob_store()
             .put_blob(self.message.blob_hash.as_slice(), message.as_ref())
@@ -425,7 +886,7 @@ impl MessageWrapper {
                     queue_id: self.queue_id,
                     queue_name: queue_name.into_inner(),
                 })),
-                Vec::new(),
+                self.message.priority.to_be_bytes().to_vec(),
             );
         }
 
@@ -505,10 +966,13 @@ impl MessageWrapper {
             status: Status::Scheduled,
             flags: 0,
             orcpt: None,
+            tls: None,
+            transcript: None,
             retry: Schedule::now(),
             notify: Schedule::now(),
             expires: QueueExpiry::Count(0),
             queue: QueueName::default(),
+            next_hop: None,
         });
         let queue = server.get_queue_or_default(
             &server
@@ -528,6 +992,15 @@ impl MessageWrapper {
         recipient.notify = Schedule::later(queue.notify.first().copied().unwrap_or(86400) + now);
         recipient.expires = queue.expiry;
         recipient.queue = queue.virtual_queue;
+
+        // Messages received outside the delivery window wait until it opens,
+        // but never past their expiration
+        if let Some(window) = &queue.window {
+            recipient.retry.due = window.next_open(recipient.retry.due);
+            if let Some(expires_at) = recipient.expiration_time(self.message.created) {
+                recipient.retry.due = std::cmp::min(recipient.retry.due, expires_at);
+            }
+        }
     }
 
     pub async fn add_recipient(&mut self, rcpt: impl Into<String>, server: &Server) {
@@ -558,7 +1031,7 @@ impl MessageWrapper {
                     queue_id: self.queue_id,
                     queue_name: queue_name.into_inner(),
                 })),
-                Vec::new(),
+                self.message.priority.to_be_bytes().to_vec(),
             );
         }
 
@@ -689,6 +1162,52 @@ impl MessageWrapper {
         }
     }
 
+    pub async fn update_email_submission(&self, server: &Server) {
+        if let Some(submission) = self.message.submission {
+            // Persist the final status of each recipient, as the message is about to leave the queue
+            let delivery_status = self
+                .message
+                .recipients
+                .iter()
+                .map(|rcpt| {
+                    let (smtp_reply, delivered) = match &rcpt.status {
+                        Status::Completed(reply) => {
+                            (format_response(&reply.response), Delivered::Yes)
+                        }
+                        Status::PermanentFailure(err) => (format_error(err), Delivered::No),
+                        Status::TemporaryFailure(err) => (format_error(err), Delivered::Queued),
+                        Status::Scheduled => ("250 2.1.5 Queued".to_string(), Delivered::Queued),
+                    };
+
+                    (
+                        rcpt.address_lcase.clone(),
+                        DeliveryStatus {
+                            smtp_reply,
+                            delivered,
+                            displayed: false,
+                        },
+                    )
+                })
+                .collect();
+
+            if let Err(err) = server
+                .update_delivery_status(
+                    submission.account_id,
+                    submission.document_id,
+                    self.queue_id,
+                    delivery_status,
+                )
+                .await
+            {
+                trc::error!(
+                    err.details("Failed to update email submission.")
+                        .span_id(self.span_id)
+                        .caused_by(trc::location!())
+                );
+            }
+        }
+    }
+
     pub fn has_domain(&self, domains: &[String]) -> bool {
         self.message.recipients.iter().any(|r| {
             let domain = r.address_lcase.domain_part();
@@ -6,11 +6,16 @@
 
 use crate::core::throttle::NewKey;
 use common::{
-    KV_RATE_LIMIT_SMTP, Server, config::smtp::QueueRateLimiter, expr::functions::ResolveVariable,
+    KV_RATE_LIMIT_SMTP, Server,
+    config::smtp::QueueRateLimiter,
+    expr::functions::ResolveVariable,
+    listener::limiter::{ConcurrencyLimiter, InFlight},
 };
-use std::future::Future;
+use std::{future::Future, sync::atomic::Ordering};
 use store::write::now;
 
+const MAX_DOMAIN_LIMITERS: usize = 1024;
+
 pub trait IsAllowed: Sync + Send {
     fn is_allowed<'x>(
         &'x self,
@@ -18,6 +23,10 @@ pub trait IsAllowed: Sync + Send {
         envelope: &impl ResolveVariable,
         session_id: u64,
     ) -> impl Future<Output = Result<(), u64>> + Send;
+
+    fn is_domain_allowed(&self, domain: &str, max_concurrent: u64) -> Option<InFlight>;
+
+    fn domain_in_flight(&self, domain: &str) -> u64;
 }
 
 impl IsAllowed for Server {
@@ -64,4 +73,28 @@ impl IsAllowed for Server {
 
         Ok(())
     }
+
+    fn is_domain_allowed(&self, domain: &str, max_concurrent: u64) -> Option<InFlight> {
+        let mut limiters = self.inner.data.queue_domain_limiters.lock();
+
+        // Remove idle limiters before the map grows too large
+        if limiters.len() > MAX_DOMAIN_LIMITERS {
+            limiters.retain(|_, limiter| limiter.is_active());
+        }
+
+        let limiter = limiters
+            .entry(domain.to_string())
+            .or_insert_with(|| ConcurrencyLimiter::new(max_concurrent));
+        limiter.max_concurrent = max_concurrent;
+        limiter.is_allowed().into()
+    }
+
+    fn domain_in_flight(&self, domain: &str) -> u64 {
+        self.inner
+            .data
+            .queue_domain_limiters
+            .lock()
+            .get(domain)
+            .map_or(0, |limiter| limiter.concurrent.load(Ordering::Relaxed))
+    }
 }
@@ -6,8 +6,10 @@
 
 use std::{borrow::Cow, future::Future, sync::Arc, time::Instant};
 
+use ahash::AHashMap;
 use common::{Server, config::smtp::queue::QueueExpiry, scripts::plugins::PluginContext};
 
+use email::sieve::SeenIdHash;
 use mail_auth::common::headers::HeaderWriter;
 use mail_parser::{Encoding, Message, MessagePart, PartType};
 use sieve::{
@@ -18,6 +20,7 @@ use smtp_proto::{
     MAIL_BY_TRACE, MAIL_RET_FULL, MAIL_RET_HDRS, RCPT_NOTIFY_DELAY, RCPT_NOTIFY_FAILURE,
     RCPT_NOTIFY_NEVER, RCPT_NOTIFY_SUCCESS,
 };
+use store::dispatch::lookup::KeyValue;
 use trc::SieveEvent;
 
 use crate::{
@@ -73,6 +76,7 @@ impl RunScript for Server {
         let mut reject_reason = None;
         let mut modifications = vec![];
         let mut keep_id = usize::MAX;
+        let mut checked_ids: AHashMap<SeenIdHash, bool> = AHashMap::new();
 
         // Start event loop
         while let Some(result) = instance.run(input) {
@@ -145,6 +149,39 @@ impl RunScript for Server {
                         keep_id = message_id;
                         input = true.into();
                     }
+                    Event::DuplicateId { id, expiry, last } => {
+                        // Duplicate ids are tracked per script, outside of any account
+                        let id_hash = SeenIdHash::new(u32::MAX, 0, &format!("{script_id}:{id}"));
+                        if let Some(result) = checked_ids.get(&id_hash) {
+                            input = (*result).into();
+                        } else {
+                            let exists =
+                                match self.in_memory_store().key_get::<()>(id_hash.key()).await {
+                                    Ok(result) => result.is_some(),
+                                    Err(err) => {
+                                        trc::error!(
+                                            err.span_id(session_id).caused_by(trc::location!())
+                                        );
+                                        false
+                                    }
+                                };
+
+                            if !exists || last {
+                                let result = self
+                                    .in_memory_store()
+                                    .key_set(KeyValue::new(id_hash.key(), vec![]).expires(expiry))
+                                    .await;
+                                if let Err(err) = result {
+                                    trc::error!(
+                                        err.span_id(session_id).caused_by(trc::location!())
+                                    );
+                                }
+                            }
+
+                            checked_ids.insert(id_hash, exists);
+                            input = exists.into();
+                        }
+                    }
                     Event::Discard => {
                         keep_id = usize::MAX - 1;
                         input = true.into();
@@ -348,6 +385,10 @@ impl RunScript for Server {
                         messages.push(message);
                         input = true.into();
                     }
+                    Event::FileInto { folder, .. } if folder.eq_ignore_ascii_case("quarantine") => {
+                        modifications.push(ScriptModification::Quarantine);
+                        input = true.into();
+                    }
                     Event::SetEnvelope { envelope, value } => {
                         modifications.push(ScriptModification::SetEnvelope {
                             name: envelope,
@@ -404,6 +404,9 @@ impl SmtpEvent {
             SmtpEvent::MissingAuthDirectory => "Missing auth directory",
             SmtpEvent::MessageParseFailed => "Message parsing failed",
             SmtpEvent::MessageTooLarge => "Message too large",
+            SmtpEvent::BareLineEnding => "Bare CR or LF in message",
+            SmtpEvent::LineTooLong => "Message line too long",
+            SmtpEvent::SmugglingDetected => "SMTP smuggling attempt detected",
             SmtpEvent::LoopDetected => "Mail loop detected",
             SmtpEvent::DkimPass => "DKIM verification passed",
             SmtpEvent::DkimFail => "DKIM verification failed",
@@ -415,6 +418,7 @@ impl SmtpEvent {
             SmtpEvent::SpfFromFail => "SPF From check failed",
             SmtpEvent::DmarcPass => "DMARC check passed",
             SmtpEvent::DmarcFail => "DMARC check failed",
+            SmtpEvent::DmarcOverride => "DMARC policy overridden",
             SmtpEvent::IprevPass => "IPREV check passed",
             SmtpEvent::IprevFail => "IPREV check failed",
             SmtpEvent::TooManyMessages => "Too many messages",
@@ -437,6 +441,10 @@ impl SmtpEvent {
             SmtpEvent::RcptToRewritten => "RCPT TO address rewritten",
             SmtpEvent::RcptToMissing => "RCPT TO address missing",
             SmtpEvent::RcptToGreylisted => "RCPT TO greylisted",
+            SmtpEvent::RcptToBlocklisted => "RCPT TO blocklisted",
+            SmtpEvent::RcptToBackscatter => "RCPT TO rejected bounce",
+            SmtpEvent::RcptToCallout => "RCPT TO callout",
+            SmtpEvent::EarlyTalker => "Early talker",
             SmtpEvent::TooManyRecipients => "Too many recipients",
             SmtpEvent::TooManyInvalidRcpt => "Too many invalid recipients",
             SmtpEvent::RawInput => "Raw SMTP input received",
@@ -493,6 +501,15 @@ impl SmtpEvent {
             SmtpEvent::MissingAuthDirectory => "The auth directory was missing",
             SmtpEvent::MessageParseFailed => "Failed to parse the message",
             SmtpEvent::MessageTooLarge => "The message was rejected because it was too large",
+            SmtpEvent::BareLineEnding => {
+                "The message contains a CR or LF character not part of a CRLF sequence"
+            }
+            SmtpEvent::LineTooLong => {
+                "The message was rejected because a line exceeded the maximum length"
+            }
+            SmtpEvent::SmugglingDetected => {
+                "The message contains an end-of-data sequence delimited by a bare CR or LF"
+            }
             SmtpEvent::LoopDetected => {
                 "A mail loop was detected, the message contains too many Received headers"
             }
@@ -506,6 +523,7 @@ impl SmtpEvent {
             SmtpEvent::SpfFromFail => "MAIL FROM identity failed SPF check",
             SmtpEvent::DmarcPass => "Successful DMARC verification",
             SmtpEvent::DmarcFail => "Failed to verify DMARC policy",
+            SmtpEvent::DmarcOverride => "DMARC policy not enforced for a trusted forwarder",
             SmtpEvent::IprevPass => "Reverse IP check passed",
             SmtpEvent::IprevFail => "Reverse IP check failed",
             SmtpEvent::TooManyMessages => {
@@ -544,6 +562,14 @@ impl SmtpEvent {
             SmtpEvent::RcptToRewritten => "The envelope recipient address was rewritten",
             SmtpEvent::RcptToMissing => "The remote client issued a DATA command before RCPT TO",
             SmtpEvent::RcptToGreylisted => "The recipient was greylisted",
+            SmtpEvent::RcptToBlocklisted => {
+                "The recipient was rejected because the sender is on a DNS blocklist"
+            }
+            SmtpEvent::RcptToBackscatter => {
+                "A bounce was rejected because the recipient has not sent any recent messages"
+            }
+            SmtpEvent::RcptToCallout => "The recipient was verified at the next hop",
+            SmtpEvent::EarlyTalker => "The remote client sent data before the greeting",
             SmtpEvent::TooManyRecipients => {
                 "The remote client exceeded the number of recipients allowed"
             }
@@ -612,6 +638,8 @@ impl DeliveryEvent {
             DeliveryEvent::DomainDeliveryStart => "New delivery attempt for domain",
             DeliveryEvent::MxLookup => "MX record lookup",
             DeliveryEvent::MxLookupFailed => "MX record lookup failed",
+            DeliveryEvent::SrvLookup => "SRV record lookup",
+            DeliveryEvent::SrvLookupFailed => "SRV record lookup failed",
             DeliveryEvent::IpLookup => "IP address lookup",
             DeliveryEvent::IpLookupFailed => "IP address lookup failed",
             DeliveryEvent::NullMx => "Null MX record found",
@@ -634,6 +662,8 @@ impl DeliveryEvent {
             DeliveryEvent::StartTlsUnavailable => "STARTTLS unavailable",
             DeliveryEvent::StartTlsError => "STARTTLS error",
             DeliveryEvent::StartTlsDisabled => "STARTTLS disabled",
+            DeliveryEvent::StartTlsDowngrade => "STARTTLS downgraded to plaintext",
+            DeliveryEvent::StartTlsStripped => "STARTTLS stripping detected",
             DeliveryEvent::ImplicitTlsError => "Implicit TLS error",
             DeliveryEvent::ConcurrencyLimitExceeded => "Concurrency limit exceeded",
             DeliveryEvent::RateLimitExceeded => "Rate limit exceeded",
@@ -655,6 +685,10 @@ impl DeliveryEvent {
             DeliveryEvent::DomainDeliveryStart => "A new delivery attempt for a domain has started",
             DeliveryEvent::MxLookup => "Looking up MX records for the domain",
             DeliveryEvent::MxLookupFailed => "Failed to look up MX records for the domain",
+            DeliveryEvent::SrvLookup => "Looking up submission SRV records for the domain",
+            DeliveryEvent::SrvLookupFailed => {
+                "Failed to look up submission SRV records for the domain"
+            }
             DeliveryEvent::IpLookup => "Looking up IP address for the domain",
             DeliveryEvent::IpLookupFailed => "Failed to look up IP address for the domain",
             DeliveryEvent::NullMx => "The domain has a null MX record, delivery is impossible",
@@ -685,6 +719,12 @@ impl DeliveryEvent {
             DeliveryEvent::StartTlsDisabled => {
                 "STARTTLS has been disabled in the configuration for this host"
             }
+            DeliveryEvent::StartTlsDowngrade => {
+                "The TLS handshake failed and the message is being delivered in plaintext"
+            }
+            DeliveryEvent::StartTlsStripped => {
+                "A host previously known to support STARTTLS no longer advertises it"
+            }
             DeliveryEvent::ImplicitTlsError => "Error starting implicit TLS",
             DeliveryEvent::ConcurrencyLimitExceeded => {
                 "The concurrency limit was exceeded for the remote host"
@@ -718,7 +758,10 @@ impl QueueEvent {
             QueueEvent::QueueReport => "Queued report for delivery",
             QueueEvent::QueueDsn => "Queued DSN for delivery",
             QueueEvent::QueueAutogenerated => "Queued autogenerated message for delivery",
+            QueueEvent::MessageAccepted => "Message accepted into the queue",
             QueueEvent::BackPressure => "Queue backpressure detected",
+            QueueEvent::Quarantined => "Message quarantined",
+            QueueEvent::Drained => "Queue drained",
         }
     }
 
@@ -737,9 +780,16 @@ impl QueueEvent {
             QueueEvent::QueueReport => "A new report was queued for delivery",
             QueueEvent::QueueDsn => "A delivery status notification was queued for delivery",
             QueueEvent::QueueAutogenerated => "A system generated message was queued for delivery",
+            QueueEvent::MessageAccepted => {
+                "A message received over SMTP was accepted and stored in the queue"
+            }
             QueueEvent::BackPressure => {
                 "Queue congested, processing can't keep up with incoming message rate"
             }
+            QueueEvent::Quarantined => "The message was held in quarantine until released",
+            QueueEvent::Drained => {
+                "All in-flight deliveries completed and the queue manager stopped"
+            }
         }
     }
 }
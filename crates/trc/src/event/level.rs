@@ -170,6 +170,9 @@ impl EventType {
                 | SmtpEvent::MissingAuthDirectory
                 | SmtpEvent::MessageParseFailed
                 | SmtpEvent::MessageTooLarge
+                | SmtpEvent::BareLineEnding
+                | SmtpEvent::LineTooLong
+                | SmtpEvent::SmugglingDetected
                 | SmtpEvent::LoopDetected
                 | SmtpEvent::DkimPass
                 | SmtpEvent::DkimFail
@@ -181,6 +184,7 @@ impl EventType {
                 | SmtpEvent::SpfFromFail
                 | SmtpEvent::DmarcPass
                 | SmtpEvent::DmarcFail
+                | SmtpEvent::DmarcOverride
                 | SmtpEvent::IprevPass
                 | SmtpEvent::IprevFail
                 | SmtpEvent::TooManyMessages
@@ -191,6 +195,10 @@ impl EventType {
                 | SmtpEvent::RelayNotAllowed
                 | SmtpEvent::RcptTo
                 | SmtpEvent::RcptToGreylisted
+                | SmtpEvent::RcptToBlocklisted
+                | SmtpEvent::RcptToBackscatter
+                | SmtpEvent::RcptToCallout
+                | SmtpEvent::EarlyTalker
                 | SmtpEvent::TooManyInvalidRcpt
                 | SmtpEvent::Vrfy
                 | SmtpEvent::VrfyNotFound
@@ -429,6 +437,7 @@ impl EventType {
                 | DeliveryEvent::Failed
                 | DeliveryEvent::DomainDeliveryStart
                 | DeliveryEvent::MxLookupFailed
+                | DeliveryEvent::SrvLookupFailed
                 | DeliveryEvent::IpLookupFailed
                 | DeliveryEvent::NullMx
                 | DeliveryEvent::Connect
@@ -449,11 +458,14 @@ impl EventType {
                 | DeliveryEvent::DoubleBounce => Level::Info,
                 DeliveryEvent::ConcurrencyLimitExceeded
                 | DeliveryEvent::RateLimitExceeded
+                | DeliveryEvent::StartTlsDowngrade
+                | DeliveryEvent::StartTlsStripped
                 | DeliveryEvent::MissingOutboundHostname => Level::Warn,
                 DeliveryEvent::DsnSuccess
                 | DeliveryEvent::DsnTempFail
                 | DeliveryEvent::DsnPermFail => Level::Info,
                 DeliveryEvent::MxLookup
+                | DeliveryEvent::SrvLookup
                 | DeliveryEvent::IpLookup
                 | DeliveryEvent::Ehlo
                 | DeliveryEvent::Auth
@@ -468,10 +480,13 @@ impl EventType {
                 | QueueEvent::QueueReport
                 | QueueEvent::QueueDsn
                 | QueueEvent::QueueAutogenerated
+                | QueueEvent::MessageAccepted
                 | QueueEvent::RateLimitExceeded
                 | QueueEvent::ConcurrencyLimitExceeded
                 | QueueEvent::Rescheduled
-                | QueueEvent::QuotaExceeded => Level::Info,
+                | QueueEvent::QuotaExceeded
+                | QueueEvent::Quarantined
+                | QueueEvent::Drained => Level::Info,
                 QueueEvent::Locked | QueueEvent::BlobNotFound => Level::Debug,
             },
             EventType::TlsRpt(event) => match event {
@@ -499,6 +499,7 @@ impl EventType {
                 | SmtpEvent::SpfFromFail
                 | SmtpEvent::DmarcPass
                 | SmtpEvent::DmarcFail
+                | SmtpEvent::DmarcOverride
                 | SmtpEvent::IprevPass
                 | SmtpEvent::IprevFail
                 | SmtpEvent::TooManyMessages
@@ -636,6 +637,7 @@ impl EventType {
                 | QueueEvent::QueueReport
                 | QueueEvent::QueueDsn
                 | QueueEvent::QueueAutogenerated
+                | QueueEvent::MessageAccepted
                 | QueueEvent::Rescheduled
                 | QueueEvent::BlobNotFound
                 | QueueEvent::RateLimitExceeded
@@ -360,6 +360,9 @@ pub enum SmtpEvent {
     MissingAuthDirectory,
     MessageParseFailed,
     MessageTooLarge,
+    BareLineEnding,
+    LineTooLong,
+    SmugglingDetected,
     LoopDetected,
     DkimPass,
     DkimFail,
@@ -371,6 +374,7 @@ pub enum SmtpEvent {
     SpfFromFail,
     DmarcPass,
     DmarcFail,
+    DmarcOverride,
     IprevPass,
     IprevFail,
     TooManyMessages,
@@ -393,6 +397,10 @@ pub enum SmtpEvent {
     RcptToRewritten,
     RcptToMissing,
     RcptToGreylisted,
+    RcptToBlocklisted,
+    RcptToBackscatter,
+    RcptToCallout,
+    EarlyTalker,
     TooManyRecipients,
     TooManyInvalidRcpt,
     RawInput,
@@ -442,6 +450,8 @@ pub enum DeliveryEvent {
     DomainDeliveryStart,
     MxLookup,
     MxLookupFailed,
+    SrvLookup,
+    SrvLookupFailed,
     IpLookup,
     IpLookupFailed,
     NullMx,
@@ -464,6 +474,8 @@ pub enum DeliveryEvent {
     StartTlsUnavailable,
     StartTlsError,
     StartTlsDisabled,
+    StartTlsDowngrade,
+    StartTlsStripped,
     ImplicitTlsError,
     ConcurrencyLimitExceeded,
     RateLimitExceeded,
@@ -482,6 +494,7 @@ pub enum QueueEvent {
     QueueReport,
     QueueDsn,
     QueueAutogenerated,
+    MessageAccepted,
     Rescheduled,
     Locked,
     BlobNotFound,
@@ -489,6 +502,8 @@ pub enum QueueEvent {
     ConcurrencyLimitExceeded,
     QuotaExceeded,
     BackPressure,
+    Quarantined,
+    Drained,
 }
 
 #[event_type]
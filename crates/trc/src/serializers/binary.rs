@@ -895,6 +895,21 @@ impl EventType {
             EventType::Calendar(CalendarEvent::ItipMessageSent) => 583,
             EventType::Calendar(CalendarEvent::ItipMessageReceived) => 584,
             EventType::Calendar(CalendarEvent::ItipMessageError) => 585,
+            EventType::Smtp(SmtpEvent::DmarcOverride) => 586,
+            EventType::Smtp(SmtpEvent::RcptToBlocklisted) => 587,
+            EventType::Queue(QueueEvent::MessageAccepted) => 588,
+            EventType::Smtp(SmtpEvent::RcptToBackscatter) => 589,
+            EventType::Smtp(SmtpEvent::RcptToCallout) => 590,
+            EventType::Smtp(SmtpEvent::EarlyTalker) => 591,
+            EventType::Queue(QueueEvent::Quarantined) => 592,
+            EventType::Smtp(SmtpEvent::BareLineEnding) => 593,
+            EventType::Smtp(SmtpEvent::LineTooLong) => 594,
+            EventType::Smtp(SmtpEvent::SmugglingDetected) => 595,
+            EventType::Delivery(DeliveryEvent::StartTlsDowngrade) => 596,
+            EventType::Delivery(DeliveryEvent::StartTlsStripped) => 597,
+            EventType::Delivery(DeliveryEvent::SrvLookup) => 598,
+            EventType::Delivery(DeliveryEvent::SrvLookupFailed) => 599,
+            EventType::Queue(QueueEvent::Drained) => 600,
         }
     }
 
@@ -1526,6 +1541,21 @@ impl EventType {
             583 => Some(EventType::Calendar(CalendarEvent::ItipMessageSent)),
             584 => Some(EventType::Calendar(CalendarEvent::ItipMessageReceived)),
             585 => Some(EventType::Calendar(CalendarEvent::ItipMessageError)),
+            586 => Some(EventType::Smtp(SmtpEvent::DmarcOverride)),
+            587 => Some(EventType::Smtp(SmtpEvent::RcptToBlocklisted)),
+            588 => Some(EventType::Queue(QueueEvent::MessageAccepted)),
+            589 => Some(EventType::Smtp(SmtpEvent::RcptToBackscatter)),
+            590 => Some(EventType::Smtp(SmtpEvent::RcptToCallout)),
+            591 => Some(EventType::Smtp(SmtpEvent::EarlyTalker)),
+            592 => Some(EventType::Queue(QueueEvent::Quarantined)),
+            593 => Some(EventType::Smtp(SmtpEvent::BareLineEnding)),
+            594 => Some(EventType::Smtp(SmtpEvent::LineTooLong)),
+            595 => Some(EventType::Smtp(SmtpEvent::SmugglingDetected)),
+            596 => Some(EventType::Delivery(DeliveryEvent::StartTlsDowngrade)),
+            597 => Some(EventType::Delivery(DeliveryEvent::StartTlsStripped)),
+            598 => Some(EventType::Delivery(DeliveryEvent::SrvLookup)),
+            599 => Some(EventType::Delivery(DeliveryEvent::SrvLookupFailed)),
+            600 => Some(EventType::Queue(QueueEvent::Drained)),
             _ => None,
         }
     }
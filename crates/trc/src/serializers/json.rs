@@ -24,6 +24,7 @@ pub struct JsonEventSerializer<T> {
     with_spans: bool,
     with_description: bool,
     with_explanation: bool,
+    with_level: bool,
 }
 
 impl<T> JsonEventSerializer<T> {
@@ -34,6 +35,7 @@ impl<T> JsonEventSerializer<T> {
             with_spans: false,
             with_description: false,
             with_explanation: false,
+            with_level: false,
         }
     }
 
@@ -57,6 +59,11 @@ impl<T> JsonEventSerializer<T> {
         self
     }
 
+    pub fn with_level(mut self) -> Self {
+        self.with_level = true;
+        self
+    }
+
     pub fn into_inner(self) -> T {
         self.inner
     }
@@ -72,6 +79,7 @@ impl<T: AsRef<Event<EventDetails>>> Serialize for JsonEventSerializer<Vec<T>> {
             seq.serialize_element(&JsonEventSerializer {
                 inner: event,
                 with_id: self.with_id,
+                with_level: self.with_level,
                 with_spans: self.with_spans,
                 with_description: self.with_description,
                 with_explanation: self.with_explanation,
@@ -105,6 +113,9 @@ impl<T: AsRef<Event<EventDetails>>> Serialize for JsonEventSerializer<T> {
             &DateTime::from_timestamp(event.inner.timestamp as i64).to_rfc3339(),
         )?;
         map.serialize_entry("type", event.inner.typ.name())?;
+        if self.with_level {
+            map.serialize_entry("level", event.inner.level.as_str())?;
+        }
         map.serialize_entry(
             "data",
             &JsonEventSerializer {
@@ -121,6 +132,7 @@ impl<T: AsRef<Event<EventDetails>>> Serialize for JsonEventSerializer<T> {
                 with_description: self.with_description,
                 with_explanation: self.with_explanation,
                 with_id: self.with_id,
+                with_level: self.with_level,
             },
         )?;
         map.end()
@@ -148,6 +160,7 @@ impl Serialize for JsonEventSerializer<Keys<'_>> {
                         with_description: self.with_description,
                         with_explanation: self.with_explanation,
                         with_id: self.with_id,
+                        with_level: self.with_level,
                     },
                 )?;
             }
@@ -180,6 +193,7 @@ impl Serialize for JsonEventSerializer<&Error> {
                 with_description: self.with_description,
                 with_explanation: self.with_explanation,
                 with_id: self.with_id,
+                with_level: self.with_level,
             },
         )?;
         map.end()
@@ -210,6 +224,7 @@ impl Serialize for JsonEventSerializer<&Value> {
                 with_description: self.with_description,
                 with_explanation: self.with_explanation,
                 with_id: self.with_id,
+                with_level: self.with_level,
             }
             .serialize(serializer),
             Value::Array(value) => JsonEventSerializer {
@@ -218,6 +233,7 @@ impl Serialize for JsonEventSerializer<&Value> {
                 with_description: self.with_description,
                 with_explanation: self.with_explanation,
                 with_id: self.with_id,
+                with_level: self.with_level,
             }
             .serialize(serializer),
             Value::None => unreachable!(),
@@ -238,6 +254,7 @@ impl Serialize for JsonEventSerializer<&Vec<Value>> {
                 with_description: self.with_description,
                 with_explanation: self.with_explanation,
                 with_id: self.with_id,
+                with_level: self.with_level,
             })?;
         }
         seq.end()
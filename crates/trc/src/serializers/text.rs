@@ -10,12 +10,15 @@ use mail_parser::DateTime;
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 use crate::{Error, Event, EventDetails, Key, Level, Value};
+
+use super::json::JsonEventSerializer;
 use base64::{Engine, engine::general_purpose::STANDARD};
 
 pub struct FmtWriter<T: AsyncWrite + Unpin> {
     writer: T,
     ansi: bool,
     multiline: bool,
+    json: bool,
 }
 
 #[allow(dead_code)]
@@ -36,6 +39,7 @@ impl<T: AsyncWrite + Unpin> FmtWriter<T> {
             writer,
             ansi: false,
             multiline: false,
+            json: false,
         }
     }
 
@@ -47,7 +51,20 @@ impl<T: AsyncWrite + Unpin> FmtWriter<T> {
         Self { multiline, ..self }
     }
 
+    pub fn with_json(self, json: bool) -> Self {
+        Self { json, ..self }
+    }
+
     pub async fn write(&mut self, event: &Event<EventDetails>) -> std::io::Result<()> {
+        // JSON lines include the span keys, so related events share their span and queue ids
+        if self.json {
+            let mut line =
+                serde_json::to_vec(&JsonEventSerializer::new(event).with_spans().with_level())
+                    .map_err(std::io::Error::other)?;
+            line.push(b'\n');
+            return self.writer.write_all(&line).await;
+        }
+
         // Write timestamp
         if self.ansi {
             self.writer
@@ -283,6 +300,10 @@ impl<T: AsyncWrite + Unpin> FmtWriter<T> {
     pub fn update_writer(&mut self, writer: T) {
         self.writer = writer;
     }
+
+    pub fn into_inner(self) -> T {
+        self.writer
+    }
 }
 
 impl Color {
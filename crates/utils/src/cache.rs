@@ -18,7 +18,7 @@ use quick_cache::{
     sync::{DefaultLifecycle, PlaceholderGuard},
 };
 
-use crate::config::Config;
+use crate::{BlobHash, config::Config};
 
 pub struct Cache<K: Eq + Hash + CacheItemWeight, V: Clone + CacheItemWeight>(
     quick_cache::sync::Cache<K, V, CacheItemWeighter>,
@@ -241,6 +241,12 @@ impl CacheItemWeight for String {
     }
 }
 
+impl CacheItemWeight for BlobHash {
+    fn weight(&self) -> u64 {
+        std::mem::size_of::<BlobHash>() as u64
+    }
+}
+
 impl CacheItemWeight for u32 {
     fn weight(&self) -> u64 {
         std::mem::size_of::<u32>() as u64
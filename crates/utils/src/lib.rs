@@ -22,7 +22,7 @@ use compact_str::ToCompactString;
 use futures::StreamExt;
 use reqwest::Response;
 use rustls::{
-    ClientConfig, RootCertStore, SignatureScheme,
+    ClientConfig, ConfigBuilder, RootCertStore, SignatureScheme, WantsVerifier,
     client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
 };
 use rustls_pki_types::TrustAnchor;
@@ -295,8 +295,13 @@ pub async fn wait_for_shutdown() {
 }
 
 pub fn rustls_client_config(allow_invalid_certs: bool) -> ClientConfig {
-    let config = ClientConfig::builder();
+    rustls_client_config_with(ClientConfig::builder(), allow_invalid_certs)
+}
 
+pub fn rustls_client_config_with(
+    config: ConfigBuilder<ClientConfig, WantsVerifier>,
+    allow_invalid_certs: bool,
+) -> ClientConfig {
     if !allow_invalid_certs {
         let mut root_cert_store = RootCertStore::empty();
 
@@ -6,9 +6,12 @@
 
 use imap_proto::ResponseType;
 
-use crate::imap::{
-    AssertResult,
-    append::{assert_append_message, build_messages},
+use crate::{
+    imap::{
+        AssertResult,
+        append::{assert_append_message, build_messages},
+    },
+    jmap::delivery::SmtpConnection,
 };
 
 use super::{ImapConnection, Type};
@@ -277,4 +280,45 @@ pub async fn test(imap: &mut ImapConnection, imap_check: &mut ImapConnection) {
         .await
         .assert_count("FETCH (", 3)
         .assert_contains("VANISHED (EARLIER) 2");
+
+    // Local delivery advances the modseq
+    imap.send("SELECT INBOX (CONDSTORE)").await;
+    let hms = imap
+        .assert_read(Type::Tagged, ResponseType::Ok)
+        .await
+        .into_highest_modseq();
+    let mut lmtp = SmtpConnection::connect_port(11201).await;
+    lmtp.ingest(
+        "bill@example.com",
+        &["jdoe@example.com"],
+        concat!(
+            "From: bill@example.com\r\n",
+            "To: jdoe@example.com\r\n",
+            "Subject: TPS Report\r\n",
+            "\r\n",
+            "I'm going to need those TPS reports ASAP.\r\n"
+        ),
+    )
+    .await;
+    imap.send("SELECT INBOX (CONDSTORE)").await;
+    let new_hms = imap
+        .assert_read(Type::Tagged, ResponseType::Ok)
+        .await
+        .into_highest_modseq();
+    assert!(
+        new_hms.parse::<u64>().unwrap() > hms.parse::<u64>().unwrap(),
+        "{new_hms} <= {hms}"
+    );
+
+    // The delivered message carries the new modseq
+    imap.send(&format!(
+        "UID FETCH 1:* (FLAGS) (CHANGEDSINCE {} VANISHED)",
+        hms
+    ))
+    .await;
+    imap.assert_read(Type::Tagged, ResponseType::Ok)
+        .await
+        .assert_count("FETCH (", 1)
+        .assert_contains(&format!("MODSEQ ({new_hms})"))
+        .assert_count("VANISHED", 0);
 }
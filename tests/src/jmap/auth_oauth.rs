@@ -216,11 +216,35 @@ pub async fn test(params: &mut JMAPTest) {
     let mut smtp = SmtpConnection::connect().await;
     smtp.send(&format!("AUTH OAUTHBEARER {oauth_bearer_invalid_sasl}",))
         .await;
-    smtp.read(1, 4).await;
+    smtp.read(1, 5).await;
     smtp.send(&format!("AUTH OAUTHBEARER {oauth_bearer_sasl}",))
         .await;
     smtp.read(1, 2).await;
 
+    // Try SMTP XOAUTH2 auth
+    let xoauth2_invalid_sasl = general_purpose::STANDARD.encode(format!(
+        "user={}\u{1}auth=Bearer {}\u{1}\u{1}",
+        "jdoe@example.com", "invalid_token"
+    ));
+    let xoauth2_sasl = general_purpose::STANDARD.encode(format!(
+        "user={}\u{1}auth=Bearer {}\u{1}\u{1}",
+        "jdoe@example.com", token
+    ));
+    let mut smtp = SmtpConnection::connect().await;
+    smtp.send("EHLO localhost").await;
+    assert!(
+        smtp.read(1, 2)
+            .await
+            .iter()
+            .any(|line| line.contains("AUTH ") && line.contains("XOAUTH2")),
+        "XOAUTH2 not advertised"
+    );
+    smtp.send(&format!("AUTH XOAUTH2 {xoauth2_invalid_sasl}"))
+        .await;
+    assert!(smtp.read(1, 5).await[0].starts_with("535 "));
+    smtp.send(&format!("AUTH XOAUTH2 {xoauth2_sasl}")).await;
+    assert!(smtp.read(1, 2).await[0].starts_with("235 "));
+
     // Try IMAP OAUTHBEARER auth
     let mut imap = ImapConnection::connect(b"_x ").await;
     imap.assert_read(Type::Untagged, ResponseType::Ok).await;
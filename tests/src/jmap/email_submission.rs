@@ -11,13 +11,14 @@ use jmap_client::{
     email_submission::{Address, Delivered, DeliveryStatus, Displayed, UndoStatus, query::Filter},
     mailbox::Role,
 };
-use jmap_proto::types::id::Id;
+use jmap_proto::types::{id::Id, state::StateChange, type_state::DataType};
 use mail_parser::DateTime;
 use std::{
     sync::Arc,
     time::{Duration, Instant},
 };
 use store::parking_lot::Mutex;
+use utils::map::bitmap::Bitmap;
 
 use tokio::{
     io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
@@ -268,8 +269,65 @@ pub async fn test(params: &mut JMAPTest) {
         );
     }
 
-    // Confirm that the email submission status was updated
+    // Confirm that the delivery status was updated once the message left the queue
     tokio::time::sleep(Duration::from_millis(100)).await;
+    let email_submission = client
+        .email_submission_get(&email_submission_id, None)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(email_submission.undo_status().unwrap(), &UndoStatus::Final);
+    assert_eq!(
+        email_submission.delivery_status().unwrap(),
+        &AHashMap::from_iter(
+            [
+                "tim@foobar.com",
+                "secret_rcpt@test.com",
+                "james@other_domain.com"
+            ]
+            .map(|rcpt| (
+                rcpt.to_string(),
+                DeliveryStatus::new(
+                    "Code: 250, Enhanced code: 0.0.0, Message: OK",
+                    Delivered::Yes,
+                    Displayed::Unknown
+                )
+            ))
+        )
+    );
+
+    // Recipients rejected during delivery are reported as failed
+    let mut state_rx = server
+        .subscribe_state_manager(
+            Id::from_bytes(account_id.as_bytes()).unwrap().document_id(),
+            Bitmap::from_iter([DataType::EmailSubmission]),
+        )
+        .await
+        .unwrap();
+    let email_submission_id = client
+        .email_submission_create_envelope(
+            &email_id,
+            &identity_id,
+            "jdoe@example.com",
+            ["fail@test.com", "tim@foobar.com"],
+        )
+        .await
+        .unwrap()
+        .take_id();
+    let created = expect_state_change(&mut state_rx).await;
+    assert_message_delivery(
+        &mut smtp_rx,
+        MockMessage::new("<jdoe@example.com>", ["<tim@foobar.com>"], &email_body),
+    )
+    .await;
+    expect_nothing(&mut smtp_rx).await;
+
+    // The final delivery status is pushed to subscribers
+    let delivered = expect_state_change(&mut state_rx).await;
+    assert!(delivered.change_id > created.change_id);
+    assert!(delivered.types.contains(DataType::EmailSubmission));
+    drop(state_rx);
+
     let email_submission = client
         .email_submission_get(&email_submission_id, None)
         .await
@@ -280,16 +338,20 @@ pub async fn test(params: &mut JMAPTest) {
         email_submission.delivery_status().unwrap(),
         &AHashMap::from_iter([
             (
-                "tim@foobar.com".to_string(),
-                DeliveryStatus::new("250 2.1.5 Queued", Delivered::Unknown, Displayed::Unknown)
-            ),
-            (
-                "secret_rcpt@test.com".to_string(),
-                DeliveryStatus::new("250 2.1.5 Queued", Delivered::Unknown, Displayed::Unknown)
+                "fail@test.com".to_string(),
+                DeliveryStatus::new(
+                    "Code: 550, Enhanced code: 0.0.0, Message: I refuse to accept that recipient.",
+                    Delivered::No,
+                    Displayed::Unknown
+                )
             ),
             (
-                "james@other_domain.com".to_string(),
-                DeliveryStatus::new("250 2.1.5 Queued", Delivered::Unknown, Displayed::Unknown)
+                "tim@foobar.com".to_string(),
+                DeliveryStatus::new(
+                    "Code: 250, Enhanced code: 0.0.0, Message: OK",
+                    Delivered::Yes,
+                    Displayed::Unknown
+                )
             ),
         ])
     );
@@ -654,6 +716,15 @@ pub async fn assert_message_delivery(
     }
 }
 
+async fn expect_state_change(state_rx: &mut mpsc::Receiver<StateChange>) -> StateChange {
+    match tokio::time::timeout(Duration::from_millis(3000), state_rx.recv()).await {
+        Ok(Some(state_change)) => state_change,
+        result => {
+            panic!("Timeout waiting for state change, got: {:?}", result);
+        }
+    }
+}
+
 pub async fn expect_nothing(event_rx: &mut mpsc::Receiver<MockMessage>) {
     match tokio::time::timeout(Duration::from_millis(500), event_rx.recv()).await {
         Err(_) => {}
@@ -781,7 +781,7 @@ total = 5
 wait = "1ms"
 
 [session.auth]
-mechanisms = "[plain, login, oauthbearer]"
+mechanisms = "[plain, login, oauthbearer, xoauth2]"
 directory = "'{STORE}'"
 
 [session.data]
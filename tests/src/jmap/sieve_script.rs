@@ -10,12 +10,16 @@ use jmap_client::{
     email, mailbox,
     sieve::query::{Comparator, Filter},
 };
-use jmap_proto::types::id::Id;
+use jmap_proto::types::{blob::BlobId, id::Id};
 use std::{
     fs,
     path::PathBuf,
     time::{Duration, Instant},
 };
+use trc::{
+    Collector, EventType, Key, StoreEvent, Value,
+    ipc::subscriber::{Interests, SubscriberBuilder},
+};
 
 use crate::{
     directory::internal::TestInternalDirectory,
@@ -509,6 +513,123 @@ pub async fn test(params: &mut JMAPTest) {
         panic!("Email {:?} not found in: {:#?}", subject, emails);
     }
 
+    // Compiled scripts should be cached until the script changes
+    let mut interests = Interests::default();
+    interests.set(EventType::Store(StoreEvent::BlobRead));
+    let (_tx, mut events_rx) = SubscriberBuilder::new("sieve-blob-reads".to_string())
+        .with_interests(interests.clone())
+        .with_lossy(false)
+        .register();
+    Collector::union_interests(interests);
+    Collector::reload();
+    for (num, script) in [
+        "keep;",
+        "require \"imap4flags\"; addflag \"$cached\"; keep;",
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let blob_hash = BlobId::from_base32(
+            client
+                .sieve_script_create(
+                    format!("test_cache_{num}"),
+                    script.as_bytes().to_vec(),
+                    true,
+                )
+                .await
+                .unwrap()
+                .blob_id()
+                .unwrap(),
+        )
+        .unwrap()
+        .hash;
+        while let Ok(Some(_)) =
+            tokio::time::timeout(Duration::from_millis(100), events_rx.recv()).await
+        {}
+        let mut lmtp = SmtpConnection::connect().await;
+        for _ in 0..5 {
+            lmtp.ingest(
+                "bill@remote.org",
+                &["jdoe@example.com"],
+                concat!(
+                    "From: bill@remote.org\r\n",
+                    "To: jdoe@example.com\r\n",
+                    "Subject: TPS Report\r\n",
+                    "\r\n",
+                    "Did you get the memo?"
+                ),
+            )
+            .await;
+        }
+        let mut script_reads = 0;
+        while let Ok(Some(batch)) =
+            tokio::time::timeout(Duration::from_millis(200), events_rx.recv()).await
+        {
+            script_reads += batch
+                .iter()
+                .filter(|event| {
+                    matches!(event.value(Key::Key), Some(Value::Bytes(key)) if key == blob_hash.as_slice())
+                })
+                .count();
+        }
+        assert_eq!(script_reads, 1);
+    }
+
+    // Plus-addressed recipients are delivered to the user's mailbox with the tag
+    // available to the Sieve subaddress extension
+    client
+        .sieve_script_create(
+            "test_subaddress",
+            concat!(
+                "require [\"envelope\", \"subaddress\", \"fileinto\", \"mailbox\"];\n",
+                "if envelope :detail \"to\" \"news\" {\n",
+                "    fileinto :create \"News\";\n",
+                "}\n"
+            )
+            .as_bytes()
+            .to_vec(),
+            true,
+        )
+        .await
+        .unwrap();
+    lmtp.ingest(
+        "bill@remote.org",
+        &["jdoe+news@example.com"],
+        concat!(
+            "From: bill@remote.org\r\n",
+            "To: jdoe+news@example.com\r\n",
+            "Subject: Weekly newsletter\r\n",
+            "\r\n",
+            "This week's news."
+        ),
+    )
+    .await;
+    let mailbox_id = client
+        .mailbox_query(mailbox::query::Filter::name("News").into(), None::<Vec<_>>)
+        .await
+        .unwrap()
+        .take_ids()
+        .pop()
+        .expect("News mailbox not found");
+    let email_ids = client
+        .email_query(
+            email::query::Filter::in_mailbox(&mailbox_id).into(),
+            None::<Vec<_>>,
+        )
+        .await
+        .unwrap()
+        .take_ids();
+    assert_eq!(email_ids.len(), 1, "{email_ids:?}");
+    assert_eq!(
+        client
+            .email_get(&email_ids[0], [email::Property::Subject].into())
+            .await
+            .unwrap()
+            .unwrap()
+            .subject(),
+        Some("Weekly newsletter")
+    );
+
     // Remove test data
     client.sieve_script_deactivate().await.unwrap();
     let mut request = client.build();
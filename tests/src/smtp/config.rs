@@ -292,6 +292,50 @@ fn parse_throttles() {
     );
 }
 
+#[test]
+fn parse_retry_jitter() {
+    let mut config = Config::new(
+        r#"
+[queue.schedule.pct]
+retry = ["10m", "1h"]
+retry-jitter = "10%"
+
+[queue.schedule.fixed]
+retry = "10m"
+retry-jitter = "5s"
+
+[queue.schedule.none]
+retry = "10m"
+
+[queue.schedule.invalid]
+retry = "10m"
+retry-jitter = "150%"
+"#,
+    )
+    .unwrap();
+    let queue = queue::QueueConfig::parse(&mut config);
+
+    for (id, jitter, max_jitter) in [
+        ("pct", Some(queue::RetryJitter::Percentage(10)), 60),
+        ("fixed", Some(queue::RetryJitter::Fixed(5)), 5),
+        ("none", None, 0),
+    ] {
+        let strategy = queue.queue_strategy.get(id).unwrap();
+        assert_eq!(strategy.retry_jitter, jitter, "{id}");
+        assert_eq!(
+            strategy.retry_jitter.map_or(0, |j| j.max_jitter(600)),
+            max_jitter,
+            "{id}"
+        );
+    }
+    assert_eq!(queue.queue_strategy.get("invalid").unwrap().retry_jitter, None);
+    assert!(
+        config
+            .errors
+            .contains_key("queue.schedule.invalid.retry-jitter")
+    );
+}
+
 #[test]
 fn parse_servers() {
     let mut file = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
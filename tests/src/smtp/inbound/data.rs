@@ -4,8 +4,15 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use common::Core;
+use std::{sync::Arc, time::Duration};
+
+use common::{Core, config::server::ServerProtocol, listener::ServerInstance};
 use store::Stores;
+use tokio::sync::watch;
+use trc::{
+    Collector, EventType, SmtpEvent,
+    ipc::subscriber::{Interests, SubscriberBuilder},
+};
 use utils::config::Config;
 
 use crate::{
@@ -13,7 +20,11 @@ use crate::{
     smtp::{
         TempDir, TestSMTP,
         inbound::TestMessage,
-        session::{TestSession, VerifyResponse, load_test_message},
+        outbound::{
+            events::{spawn_mock_webhook_endpoint, subscribe_webhooks},
+            smtp::SMUGGLER,
+        },
+        session::{TestServerInstance, TestSession, VerifyResponse, load_test_message},
     },
 };
 use smtp::core::Session;
@@ -101,6 +112,57 @@ enable = true
 
 "#;
 
+const CONFIG_LINES: &str = r#"
+[session.rcpt]
+relay = true
+
+[session.data]
+bare-line-endings = "{POLICY}"
+
+[session.data.limits]
+line-length = 998
+
+[spam-filter]
+enable = false
+"#;
+
+const CONFIG_SMUGGLING: &str = r#"
+[session.rcpt]
+relay = true
+
+[spam-filter]
+enable = false
+"#;
+
+const CONFIG_BDAT: &str = r#"
+[session.rcpt]
+relay = true
+
+[session.data.limits]
+size = 1024
+
+[spam-filter]
+enable = false
+"#;
+
+const CONFIG_WEBHOOK: &str = r#"
+[session.rcpt]
+relay = true
+
+[session.data]
+notify-accepted = [{if = "sender_domain = 'ignore.org'", then = false},
+                   {else = true}]
+
+[spam-filter]
+enable = false
+
+[webhook."accepted"]
+url = "http://127.0.0.1:8823/hook"
+events = ["queue.message-accepted"]
+signature-key = "ovos-moles"
+throttle = "100ms"
+"#;
+
 #[tokio::test]
 async fn data() {
     // Enable logging
@@ -238,3 +300,496 @@ async fn data() {
         .assert_is_empty(test.server.blob_store().clone())
         .await;
 }
+
+const CONFIG_SIZE: &str = r#"
+[storage]
+data = "rocksdb"
+lookup = "rocksdb"
+blob = "rocksdb"
+fts = "rocksdb"
+directory = "local"
+
+[store."rocksdb"]
+type = "rocksdb"
+path = "{TMP}/queue.db"
+
+[spam-filter]
+enable = false
+
+[directory."local"]
+type = "memory"
+
+[[directory."local".principals]]
+name = "john"
+description = "John Doe"
+secret = "secret"
+email = "john@foobar.org"
+
+[[directory."local".principals]]
+name = "jane"
+description = "Jane Doe"
+secret = "p4ssw0rd"
+email = "jane@foobar.org"
+
+[session.rcpt]
+directory = "'local'"
+
+[session.auth]
+mechanisms = "[plain]"
+directory = "'local'"
+
+[session.data.limits]
+size = [{if = "!is_empty(authenticated_as)", then = 5000},
+        {else = 1000}]
+"#;
+
+#[tokio::test]
+async fn data_max_size() {
+    // Enable logging
+    crate::enable_logging();
+
+    let tmp_dir = TempDir::new("smtp_data_max_size_test", true);
+    let mut config = Config::new(tmp_dir.update_config(CONFIG_SIZE)).unwrap();
+    let stores = Stores::parse_all(&mut config, false).await;
+    let core = Core::parse(&mut config, stores, Default::default()).await;
+    config.assert_no_errors();
+
+    let test = TestSMTP::from_core(core);
+    let mut qr = test.queue_receiver;
+    let mut session = Session::test(test.server.clone());
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.stream.tls = true;
+
+    // The anonymous limit is advertised in EHLO
+    session
+        .ehlo("mx.foobar.org")
+        .await
+        .assert_contains("SIZE 1000");
+
+    // Declaring a larger size in MAIL FROM is rejected
+    session
+        .cmd("MAIL FROM:<bill@example.org> SIZE=2000", "552 5.3.4")
+        .await;
+
+    // Messages over the limit are rejected at DATA
+    let message = format!(
+        "From: bill@example.org\r\nTo: jane@foobar.org\r\nSubject: Large message\r\n\r\n{}",
+        "A".repeat(2000)
+    );
+    session
+        .send_message(
+            "bill@example.org",
+            &["jane@foobar.org"],
+            &message,
+            "552 5.3.4",
+        )
+        .await;
+    qr.assert_no_events();
+    qr.assert_queue_is_empty().await;
+    session.rset().await;
+
+    // Authenticated senders have a higher limit
+    session
+        .cmd("AUTH PLAIN AGpvaG4Ac2VjcmV0", "235 2.7.0")
+        .await;
+    session
+        .ehlo("mx.foobar.org")
+        .await
+        .assert_contains("SIZE 5000");
+    session
+        .send_message("john@foobar.org", &["jane@foobar.org"], &message, "250")
+        .await;
+    qr.expect_message().await;
+}
+
+const CONFIG_LMTP: &str = r#"
+[storage]
+data = "rocksdb"
+lookup = "rocksdb"
+blob = "rocksdb"
+fts = "rocksdb"
+directory = "local"
+
+[store."rocksdb"]
+type = "rocksdb"
+path = "{TMP}/queue.db"
+
+[spam-filter]
+enable = false
+
+[directory."local"]
+type = "memory"
+
+[[directory."local".principals]]
+name = "john"
+description = "John Doe"
+secret = "secret"
+email = "john@foobar.org"
+
+[[directory."local".principals]]
+name = "jane"
+description = "Jane Doe"
+secret = "p4ssw0rd"
+email = "jane@foobar.org"
+
+[session.rcpt]
+directory = "'local'"
+errors.wait = "5ms"
+"#;
+
+#[tokio::test]
+async fn data_lmtp() {
+    // Enable logging
+    crate::enable_logging();
+
+    let tmp_dir = TempDir::new("smtp_data_lmtp_test", true);
+    let mut config = Config::new(tmp_dir.update_config(CONFIG_LMTP)).unwrap();
+    let stores = Stores::parse_all(&mut config, false).await;
+    let core = Core::parse(&mut config, stores, Default::default()).await;
+    config.assert_no_errors();
+
+    let test = TestSMTP::from_core(core);
+    let mut qr = test.queue_receiver;
+    let mut session = Session::test(test.server.clone());
+    let mut instance = ServerInstance::test_with_shutdown(watch::channel(false).1);
+    instance.protocol = ServerProtocol::Lmtp;
+    session.instance = Arc::new(instance);
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+
+    // EHLO is not accepted on LMTP sessions
+    session.cmd("EHLO mx.foobar.org", "500 5.5.1").await;
+    session.cmd("LHLO mx.foobar.org", "250").await;
+
+    // One final response is sent for each accepted recipient
+    session.mail_from("bill@example.org", "250").await;
+    session.rcpt_to("john@foobar.org", "250").await;
+    session.rcpt_to("unknown@foobar.org", "550 5.1.2").await;
+    session.rcpt_to("jane@foobar.org", "250").await;
+    session.ingest(b"DATA\r\n").await.unwrap();
+    session.response().assert_code("354");
+    session
+        .ingest(b"From: bill@example.org\r\nSubject: LMTP test\r\n\r\nTest message\r\n.\r\n")
+        .await
+        .unwrap();
+    session
+        .response()
+        .assert_count("250 2.0.0", 2)
+        .assert_not_contains("550");
+
+    // Both recipients are queued for local delivery
+    let message = qr.expect_message().await;
+    assert_eq!(
+        message
+            .message
+            .recipients
+            .iter()
+            .map(|r| r.address.as_str())
+            .collect::<Vec<_>>(),
+        vec!["jane@foobar.org", "john@foobar.org"]
+    );
+}
+
+#[tokio::test]
+async fn data_line_endings() {
+    // Enable logging
+    crate::enable_logging();
+
+    let message = "From: john@doe.org\nTo: bill@foobar.org\nSubject: bare\r\n\nHello\rWorld";
+
+    // Subscribe to bare line ending events
+    let mut interests = Interests::default();
+    interests.set(EventType::Smtp(SmtpEvent::BareLineEnding));
+    let (_tx, mut events_rx) = SubscriberBuilder::new("bare-line-endings".to_string())
+        .with_interests(interests.clone())
+        .with_lossy(false)
+        .register();
+    Collector::union_interests(interests);
+    Collector::reload();
+
+    // Bare CR and LF characters are accepted as-is and no conversion is logged
+    let mut test = TestSMTP::new(
+        "smtp_data_lines_allow",
+        CONFIG_LINES.replace("{POLICY}", "allow"),
+    )
+    .await;
+    let mut session = test.new_session();
+    session.data.session_id = 0xba7e;
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.doe.org").await;
+    session
+        .send_message("john@doe.org", &["bill@foobar.org"], message, "250")
+        .await;
+    let contents = test
+        .queue_receiver
+        .expect_message()
+        .await
+        .read_message(&test.queue_receiver)
+        .await;
+    assert!(contents.contains("\nHello\rWorld"), "{contents:?}");
+    while let Ok(Some(batch)) =
+        tokio::time::timeout(Duration::from_millis(200), events_rx.recv()).await
+    {
+        assert!(
+            !batch.iter().any(|event| event.span_id() == Some(0xba7e)),
+            "{batch:?}"
+        );
+    }
+
+    // Bare CR and LF characters are converted to CRLF
+    let mut test = TestSMTP::new(
+        "smtp_data_lines_convert",
+        CONFIG_LINES.replace("{POLICY}", "convert"),
+    )
+    .await;
+    let mut session = test.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.doe.org").await;
+    session
+        .send_message("john@doe.org", &["bill@foobar.org"], message, "250")
+        .await;
+    let contents = test
+        .queue_receiver
+        .expect_message()
+        .await
+        .read_message(&test.queue_receiver)
+        .await;
+    assert!(
+        contents.contains("To: bill@foobar.org\r\nSubject: bare\r\n\r\nHello\r\nWorld"),
+        "{contents:?}"
+    );
+    assert!(
+        !contents
+            .as_bytes()
+            .windows(2)
+            .any(|w| (w[0] == b'\r') != (w[1] == b'\n')),
+        "{contents:?}"
+    );
+
+    // Lines longer than the configured limit are rejected
+    let line = "a".repeat(998);
+    session
+        .send_message(
+            "john@doe.org",
+            &["bill@foobar.org"],
+            &format!("From: john@doe.org\r\nSubject: long\r\n\r\n{line}"),
+            "250",
+        )
+        .await;
+    test.queue_receiver.expect_message().await;
+    session
+        .send_message(
+            "john@doe.org",
+            &["bill@foobar.org"],
+            &format!("From: john@doe.org\r\nSubject: long\r\n\r\n{line}a"),
+            "550 5.6.0",
+        )
+        .await;
+    test.queue_receiver.assert_no_events();
+
+    // Bare CR and LF characters are rejected
+    let mut test = TestSMTP::new(
+        "smtp_data_lines_reject",
+        CONFIG_LINES.replace("{POLICY}", "reject"),
+    )
+    .await;
+    let mut session = test.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.doe.org").await;
+    session
+        .send_message("john@doe.org", &["bill@foobar.org"], message, "550 5.6.0")
+        .await;
+    test.queue_receiver.assert_no_events();
+    session
+        .send_message(
+            "john@doe.org",
+            &["bill@foobar.org"],
+            &message.replace("\r\n", "\n").replace(['\r', '\n'], "\r\n"),
+            "250",
+        )
+        .await;
+    test.queue_receiver.expect_message().await;
+}
+
+#[tokio::test]
+async fn data_smuggling() {
+    // Enable logging
+    crate::enable_logging();
+
+    for (name, strict) in [
+        ("smtp_data_smuggling", false),
+        ("smtp_data_smuggling_strict", true),
+    ] {
+        let mut test = TestSMTP::new(
+            name,
+            format!("{CONFIG_SMUGGLING}\n[session.data]\nreject-smuggling = {strict}\n"),
+        )
+        .await;
+        let mut session = test.new_session();
+        session.data.remote_ip_str = "10.0.0.1".into();
+        session.eval_session_params().await;
+        session.ehlo("mx.doe.org").await;
+
+        for separator in ["\n", "\r"] {
+            let message = SMUGGLER
+                .replace('\r', "")
+                .replace('\n', "\r\n")
+                .replace("<SEP>", separator);
+
+            if strict {
+                // End-of-data sequences delimited by bare CR or LF are rejected
+                session
+                    .send_message("john@doe.org", &["bill@foobar.org"], &message, "554 5.7.0")
+                    .await;
+                test.queue_receiver.assert_no_events();
+            } else {
+                // The smuggled message is accepted as part of the original message
+                session
+                    .send_message("john@doe.org", &["bill@foobar.org"], &message, "250")
+                    .await;
+                test.queue_receiver
+                    .expect_message()
+                    .await
+                    .read_lines(&test.queue_receiver)
+                    .await
+                    .assert_contains("We lost the game.")
+                    .assert_contains("This is a smuggled message");
+            }
+        }
+
+        // Dot-stuffed lines are not mistaken for smuggling attempts
+        session
+            .send_message(
+                "john@doe.org",
+                &["bill@foobar.org"],
+                "From: john@doe.org\r\nSubject: dots\r\n\r\n..\r\n..hey\r\n",
+                "250",
+            )
+            .await;
+        test.queue_receiver.expect_message().await;
+    }
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn accepted_webhook() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start the webhook endpoint and subscribe it to accepted messages
+    let (_tx, mut webhook_rx) = spawn_mock_webhook_endpoint(8823);
+    subscribe_webhooks(CONFIG_WEBHOOK);
+
+    let mut test = TestSMTP::new("smtp_accepted_webhook", CONFIG_WEBHOOK).await;
+    let mut session = test.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.doe.org").await;
+
+    // Messages matching the filter expression are not notified
+    session
+        .send_message(
+            "john@ignore.org",
+            &["bill@foobar.org"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    test.queue_receiver.expect_message().await;
+
+    // Accepted messages are notified exactly once, keyed by their queue id
+    session
+        .send_message("john@doe.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    let queue_id = test.queue_receiver.expect_message().await.queue_id;
+
+    let event = tokio::time::timeout(Duration::from_secs(5), webhook_rx.recv())
+        .await
+        .expect("Webhook event not received")
+        .unwrap();
+    assert_eq!(event["type"], "queue.message-accepted");
+    assert_eq!(event["data"]["queueId"].as_u64(), Some(queue_id));
+    assert_eq!(event["data"]["from"], "john@doe.org");
+    assert_eq!(event["data"]["to"], serde_json::json!(["bill@foobar.org"]));
+    assert_eq!(
+        event["data"]["messageId"],
+        "20030712040037.46341.5F8J@football.example.com"
+    );
+    assert_eq!(event["data"]["details"], "Is dinner ready?");
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(webhook_rx.try_recv().is_err(), "Duplicate webhook event");
+}
+
+#[tokio::test]
+async fn data_bdat() {
+    // Enable logging
+    crate::enable_logging();
+
+    let mut test = TestSMTP::new("smtp_data_bdat", CONFIG_BDAT).await;
+    let mut session = test.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session
+        .ehlo("mx.doe.org")
+        .await
+        .assert_contains("CHUNKING")
+        .assert_contains("BINARYMIME");
+
+    // Binary content split across chunks is assembled verbatim
+    let chunks = [
+        "From: john@doe.org\r\nTo: bill@foobar.org\r\nSubject: binary\r\n\r\n",
+        "\0bare\rline\nendings\r\n.\r\n",
+        "..not stuffed\0\r\n",
+    ];
+    session
+        .mail_from("<john@doe.org> BODY=BINARYMIME", "250")
+        .await;
+    session.rcpt_to("bill@foobar.org", "250").await;
+    for (num, chunk) in chunks.iter().enumerate() {
+        let is_last = num == chunks.len() - 1;
+        session
+            .ingest(
+                format!(
+                    "BDAT {}{}\r\n{chunk}",
+                    chunk.len(),
+                    if is_last { " LAST" } else { "" }
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        session
+            .response()
+            .assert_code(if is_last { "250 2.0.0" } else { "250 2.6.0" });
+    }
+    let contents = test
+        .queue_receiver
+        .expect_message()
+        .await
+        .read_message(&test.queue_receiver)
+        .await;
+    assert!(contents.ends_with(&chunks.concat()), "{contents:?}");
+
+    // The size limit applies to the total of all chunks
+    let chunk = "a".repeat(600);
+    session
+        .mail_from("<john@doe.org> BODY=BINARYMIME", "250")
+        .await;
+    session.rcpt_to("bill@foobar.org", "250").await;
+    session
+        .ingest(format!("BDAT 600\r\n{chunk}").as_bytes())
+        .await
+        .unwrap();
+    session.response().assert_code("250 2.6.0");
+    session
+        .ingest(format!("BDAT 600 LAST\r\n{chunk}").as_bytes())
+        .await
+        .unwrap();
+    session.response().assert_code("552 5.3.4");
+    test.queue_receiver.assert_no_events();
+}
@@ -79,6 +79,7 @@ mail-from = [{if = "remote_ip = '10.0.0.2'", then = 'strict'},
 
 [auth.dmarc]
 verify = "strict"
+trusted-forwarder = "helo_domain = 'lists.example.org'"
 
 [auth.arc]
 verify = "strict"
@@ -287,6 +288,27 @@ async fn dmarc() {
         .await;
     qr.assert_no_events();
 
+    // Unaligned DMARC from a trusted forwarder should be accepted
+    session.ehlo("lists.example.org").await;
+    session
+        .send_message(
+            "joe@test.net",
+            &["jdoe@example.com"],
+            "test:invalid_dkim",
+            "250",
+        )
+        .await;
+    let message = qr.expect_message().await;
+    assert_eq!(
+        message.message.recipients.last().unwrap().address,
+        "jdoe@example.com"
+    );
+    message
+        .read_lines(&qr)
+        .await
+        .assert_contains("dmarc=none header.from=example.com policy.dmarc=reject");
+    session.ehlo("mx.example.com").await;
+
     // Messages passing DMARC should be accepted
     session
         .send_message(
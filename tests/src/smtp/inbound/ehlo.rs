@@ -101,3 +101,57 @@ async fn ehlo() {
         .assert_not_contains("FUTURERELEASE")
         .assert_not_contains("STARTTLS");
 }
+
+#[tokio::test]
+async fn ehlo_capabilities_order() {
+    // Enable logging
+    crate::enable_logging();
+
+    let mut config = Config::new(
+        r#"
+[session.extensions]
+order = ["SIZE", "X-EXPERIMENTAL", "PIPELINING", "8BITMIME"]
+custom = ["X-EXPERIMENTAL", "XCLIENT-TEST NAME ADDR", "X-INJECT\r\n250 INJECTED", "-BAD", "X_BAD"]
+
+[auth.spf.verify]
+ehlo = "disable"
+"#,
+    )
+    .unwrap();
+    let core = Core::parse(&mut config, Default::default(), Default::default()).await;
+
+    // Keywords that are not valid esmtp-keywords are rejected
+    assert_eq!(
+        config
+            .errors
+            .keys()
+            .filter(|key| key.starts_with("session.extensions.custom"))
+            .count(),
+        3,
+        "{:?}",
+        config.errors
+    );
+    config.errors.clear();
+
+    let mut session = Session::test(TestSMTP::from_core(core).server);
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.data.remote_ip = session.data.remote_ip_str.parse().unwrap();
+    session.eval_session_params().await;
+
+    // Configured keywords are listed first, custom ones are advertised
+    session
+        .cmd("EHLO mx1.foobar.org", "250")
+        .await
+        .assert_contains("X-EXPERIMENTAL")
+        .assert_contains("XCLIENT-TEST NAME ADDR")
+        .assert_not_contains("INJECTED")
+        .assert_not_contains("BAD")
+        .assert_order(&[
+            "localhost",
+            "SIZE",
+            "X-EXPERIMENTAL",
+            "PIPELINING",
+            "8BITMIME",
+            "ENHANCEDSTATUSCODES",
+        ]);
+}
@@ -6,8 +6,12 @@
 
 use std::time::{Duration, Instant};
 
-use common::Core;
-use tokio::sync::watch;
+use common::{Core, config::server::ServerProtocol};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::TcpStream,
+    sync::watch,
+};
 
 use smtp::core::Session;
 use utils::config::Config;
@@ -73,3 +77,176 @@ async fn limits() {
     session.handle_conn().await;
     session.response().assert_code("221 2.0.0");
 }
+
+const CONFIG_TARPIT: &str = r#"
+[session.tarpit]
+delay = [{if = "remote_ip = '10.0.0.66'", then = '500ms'},
+         {if = "sender_domain = 'spammer.org'", then = '500ms'},
+         {else = false}]
+"#;
+
+#[tokio::test]
+async fn tarpit() {
+    // Enable logging
+    crate::enable_logging();
+
+    let mut config = Config::new(CONFIG_TARPIT).unwrap();
+    let core = Core::parse(&mut config, Default::default(), Default::default()).await;
+    let server = TestSMTP::from_core(core).server;
+
+    // Responses to matching sessions are delayed
+    let mut session = Session::test(server.clone());
+    session.data.remote_ip_str = "10.0.0.66".into();
+    session.data.remote_ip = session.data.remote_ip_str.parse().unwrap();
+    session.eval_session_params().await;
+    let time = Instant::now();
+    session.cmd("NOOP", "250").await;
+    session.cmd("NOOP", "250").await;
+    assert!(time.elapsed() >= Duration::from_millis(1000));
+
+    // Clean sessions are not delayed
+    let mut session = Session::test(server);
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.data.remote_ip = session.data.remote_ip_str.parse().unwrap();
+    session.eval_session_params().await;
+    let time = Instant::now();
+    session.cmd("NOOP", "250").await;
+    session.cmd("NOOP", "250").await;
+    assert!(time.elapsed() < Duration::from_millis(500));
+
+    // Replies are delayed once a command matches the tarpit condition
+    session.ehlo("mx.spammer.org").await;
+    session.mail_from("john@spammer.org", "250").await;
+    assert!(time.elapsed() < Duration::from_millis(500));
+    session.cmd("NOOP", "250").await;
+    assert!(time.elapsed() >= Duration::from_millis(500));
+}
+
+const CONFIG_GREETING_DELAY: &str = r#"
+[session.connect]
+greeting-delay = [{if = "remote_ip = '10.0.0.77'", then = '1s'},
+                  {else = false}]
+"#;
+
+#[tokio::test]
+async fn greeting_delay() {
+    // Enable logging
+    crate::enable_logging();
+
+    let mut config = Config::new(CONFIG_GREETING_DELAY).unwrap();
+    let core = Core::parse(&mut config, Default::default(), Default::default()).await;
+    let server = TestSMTP::from_core(core).server;
+    let new_session = || {
+        let mut session = Session::test(server.clone());
+        session.data.remote_ip_str = "10.0.0.77".into();
+        session.data.remote_ip = session.data.remote_ip_str.parse().unwrap();
+        session
+    };
+
+    // Clients that talk before the greeting are rejected
+    let mut session = new_session();
+    session.write_rx("EHLO mx.foobar.org\r\n");
+    let time = Instant::now();
+    assert!(!session.init_conn().await);
+    assert!(time.elapsed() < Duration::from_secs(1));
+    session.response().assert_code("554 5.5.0");
+
+    // Clients that wait are greeted, without delaying other sessions
+    let mut session1 = new_session();
+    let mut session2 = new_session();
+    let time = Instant::now();
+    let (result1, result2) = tokio::join!(session1.init_conn(), session2.init_conn());
+    let elapsed = time.elapsed();
+    assert!(result1 && result2);
+    assert!(
+        elapsed >= Duration::from_secs(1) && elapsed < Duration::from_millis(1900),
+        "{elapsed:?}"
+    );
+    session1.response().assert_code("220");
+    session2.response().assert_code("220");
+    session1.ehlo("mx.foobar.org").await;
+
+    // Other clients are not delayed
+    let mut session = Session::test(server.clone());
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.data.remote_ip = session.data.remote_ip_str.parse().unwrap();
+    let time = Instant::now();
+    assert!(session.init_conn().await);
+    assert!(time.elapsed() < Duration::from_secs(1));
+    session.response().assert_code("220");
+}
+
+const CONFIG_CONNECT_ALLOW: &str = r#"
+[session.connect]
+allow = [{if = "remote_ip = '10.0.0.99'", then = false},
+         {else = true}]
+reject-message = "'5.7.1 Connection refused.'"
+"#;
+
+#[tokio::test]
+async fn connect_allow() {
+    // Enable logging
+    crate::enable_logging();
+
+    let mut config = Config::new(CONFIG_CONNECT_ALLOW).unwrap();
+    let core = Core::parse(&mut config, Default::default(), Default::default()).await;
+    let server = TestSMTP::from_core(core).server;
+
+    // Blocked clients receive the rejection banner and are disconnected
+    let mut session = Session::test(server.clone());
+    session.data.remote_ip_str = "10.0.0.99".into();
+    session.data.remote_ip = session.data.remote_ip_str.parse().unwrap();
+    assert!(!session.init_conn().await);
+    session
+        .response()
+        .assert_code("554 5.7.1")
+        .assert_contains("Connection refused");
+
+    // Other clients are greeted
+    let mut session = Session::test(server);
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.data.remote_ip = session.data.remote_ip_str.parse().unwrap();
+    assert!(session.init_conn().await);
+    session.response().assert_code("220");
+    session.ehlo("mx.foobar.org").await;
+}
+
+const CONFIG_MAX_CONNECTIONS: &str = r#"
+[session.connect]
+max-connections-per-ip = 2
+
+[spam-filter]
+enable = false
+"#;
+
+#[tokio::test]
+#[serial_test::serial]
+async fn max_connections_per_ip() {
+    // Enable logging
+    crate::enable_logging();
+
+    let test = TestSMTP::new("smtp_max_connections_per_ip", CONFIG_MAX_CONNECTIONS).await;
+    let _rx = test.start(&[ServerProtocol::Smtp]).await;
+
+    // Connections up to the limit are greeted
+    let mut connections = Vec::new();
+    for _ in 0..2 {
+        let mut lines = BufReader::new(TcpStream::connect("127.0.0.1:9925").await.unwrap()).lines();
+        let line = lines.next_line().await.unwrap().unwrap();
+        assert!(line.starts_with("220"), "{line}");
+        connections.push(lines);
+    }
+
+    // The next connection from the same address is refused
+    let mut lines = BufReader::new(TcpStream::connect("127.0.0.1:9925").await.unwrap()).lines();
+    let line = lines.next_line().await.unwrap().unwrap();
+    assert!(line.starts_with("421"), "{line}");
+    assert!(lines.next_line().await.unwrap().is_none());
+
+    // Closing a connection frees up a slot
+    connections.pop();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let mut lines = BufReader::new(TcpStream::connect("127.0.0.1:9925").await.unwrap()).lines();
+    let line = lines.next_line().await.unwrap().unwrap();
+    assert!(line.starts_with("220"), "{line}");
+}
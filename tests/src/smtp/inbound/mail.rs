@@ -321,3 +321,159 @@ async fn mail() {
     session.response().assert_code("501 5.5.4");
     session.rset().await;
 }
+
+#[tokio::test]
+async fn mail_spf_once() {
+    // Enable logging
+    crate::enable_logging();
+
+    let tmp_dir = TempDir::new("smtp_mail_spf_once_test", true);
+    let mut config =
+        Config::new(tmp_dir.update_config(CONFIG.to_string() + "[session.rcpt]\nrelay = true\n"))
+            .unwrap();
+    let stores = Stores::parse_all(&mut config, false).await;
+    let core = Core::parse(&mut config, stores, Default::default()).await;
+    let server = TestSMTP::from_core(core).server;
+
+    server.txt_add(
+        "foobar.org",
+        Spf::parse(b"v=spf1 ip4:10.0.0.3 -all").unwrap(),
+        Instant::now() + Duration::from_secs(5),
+    );
+
+    let mut session = Session::test(server.clone());
+    session.data.remote_ip_str = "10.0.0.3".into();
+    session.data.remote_ip = session.data.remote_ip_str.parse().unwrap();
+    session.eval_session_params().await;
+    session.ingest(b"EHLO mx1.foobar.org\r\n").await.unwrap();
+    session.response().assert_code("250");
+
+    // SPF is evaluated once at MAIL FROM, regardless of the number of recipients
+    session
+        .ingest(b"MAIL FROM:<bill@foobar.org>\r\n")
+        .await
+        .unwrap();
+    session.response().assert_code("250");
+    server.txt_add(
+        "foobar.org",
+        Spf::parse(b"v=spf1 -all").unwrap(),
+        Instant::now() + Duration::from_secs(5),
+    );
+    for rcpt in ["jane@example.org", "john@example.org", "mike@example.org"] {
+        session
+            .ingest(format!("RCPT TO:<{rcpt}>\r\n").as_bytes())
+            .await
+            .unwrap();
+        session.response().assert_code("250");
+    }
+    assert_eq!(
+        session.data.spf_mail_from.as_ref().unwrap().result(),
+        SpfResult::Pass
+    );
+    session.rset().await;
+
+    // A new transaction evaluates SPF again
+    session
+        .ingest(b"MAIL FROM:<bill@foobar.org>\r\n")
+        .await
+        .unwrap();
+    session.response().assert_code("250");
+    assert_eq!(
+        session.data.spf_mail_from.as_ref().unwrap().result(),
+        SpfResult::Fail
+    );
+}
+
+const CONFIG_IPREV: &str = r#"
+[storage]
+data = "rocksdb"
+lookup = "rocksdb"
+blob = "rocksdb"
+fts = "rocksdb"
+
+[store."rocksdb"]
+type = "rocksdb"
+path = "{TMP}/data.db"
+
+[auth.spf.verify]
+ehlo = 'disable'
+mail-from = 'disable'
+
+[auth.iprev]
+verify = [{if = "remote_ip = '10.0.0.5'", then = 'relaxed'},
+          {else = 'strict'}]
+"#;
+
+#[tokio::test]
+async fn mail_iprev() {
+    // Enable logging
+    crate::enable_logging();
+
+    let tmp_dir = TempDir::new("smtp_mail_iprev_test", true);
+    let mut config = Config::new(tmp_dir.update_config(CONFIG_IPREV)).unwrap();
+    let stores = Stores::parse_all(&mut config, false).await;
+    let core = Core::parse(&mut config, stores, Default::default()).await;
+    let server = TestSMTP::from_core(core).server;
+
+    // 10.0.0.3 and 10.0.0.5 point to a host that resolves to a different address
+    for ip in ["10.0.0.3", "10.0.0.5"] {
+        server.ptr_add(
+            ip.parse().unwrap(),
+            vec!["mx.spoofed.org.".to_string()],
+            Instant::now() + Duration::from_secs(5),
+        );
+    }
+    server.ipv4_add(
+        "mx.spoofed.org.",
+        vec!["10.0.0.9".parse().unwrap()],
+        Instant::now() + Duration::from_secs(5),
+    );
+
+    // 10.0.0.4 has a matching PTR and A pair
+    server.ptr_add(
+        "10.0.0.4".parse().unwrap(),
+        vec!["mx.foobar.org.".to_string()],
+        Instant::now() + Duration::from_secs(5),
+    );
+    server.ipv4_add(
+        "mx.foobar.org.",
+        vec!["10.0.0.4".parse().unwrap()],
+        Instant::now() + Duration::from_secs(5),
+    );
+
+    // Mismatched PTR and A records are rejected in strict mode
+    let mut session = Session::test(server.clone());
+    session.data.remote_ip_str = "10.0.0.3".into();
+    session.data.remote_ip = session.data.remote_ip_str.parse().unwrap();
+    session.eval_session_params().await;
+    session.ehlo("mx.spoofed.org").await;
+    session.mail_from("bill@foobar.org", "550 5.7.25").await;
+    assert!(matches!(
+        session.data.iprev.as_ref().unwrap().result(),
+        IprevResult::Fail(_)
+    ));
+
+    // Relaxed mode records the failure without rejecting
+    let mut session = Session::test(server.clone());
+    session.data.remote_ip_str = "10.0.0.5".into();
+    session.data.remote_ip = session.data.remote_ip_str.parse().unwrap();
+    session.eval_session_params().await;
+    session.ehlo("mx.spoofed.org").await;
+    session.mail_from("bill@foobar.org", "250").await;
+    assert!(matches!(
+        session.data.iprev.as_ref().unwrap().result(),
+        IprevResult::Fail(_)
+    ));
+
+    // Matching PTR and A records are accepted
+    let mut session = Session::test(server);
+    session.data.remote_ip_str = "10.0.0.4".into();
+    session.data.remote_ip = session.data.remote_ip_str.parse().unwrap();
+    session.eval_session_params().await;
+    session.ehlo("mx.foobar.org").await;
+    session.mail_from("bill@foobar.org", "250").await;
+    assert_eq!(
+        session.data.iprev.as_ref().unwrap().result(),
+        &IprevResult::Pass
+    );
+}
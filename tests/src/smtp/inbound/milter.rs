@@ -76,6 +76,38 @@ stages = ["data"]
 
 "#;
 
+const CONFIG_MILTER_CHAIN: &str = r#"
+[storage]
+data = "rocksdb"
+lookup = "rocksdb"
+blob = "rocksdb"
+fts = "rocksdb"
+
+[store."rocksdb"]
+type = "rocksdb"
+path = "{TMP}/queue.db"
+
+[session.rcpt]
+relay = true
+
+[[session.milter]]
+hostname = "127.0.0.1"
+port = 9334
+enable = true
+options.version = 6
+tls = false
+stages = ["data"]
+
+[[session.milter]]
+hostname = "127.0.0.1"
+port = 9335
+enable = true
+options.version = 6
+tls = false
+stages = ["data"]
+
+"#;
+
 const CONFIG_JMILTER: &str = r#"
 [storage]
 data = "rocksdb"
@@ -106,7 +138,7 @@ async fn milter_session() {
     let mut config = Config::new(tmp_dir.update_config(CONFIG_MILTER)).unwrap();
     let stores = Stores::parse_all(&mut config, false).await;
     let core = Core::parse(&mut config, stores, Default::default()).await;
-    let _rx = spawn_mock_milter_server();
+    let _rx = spawn_mock_milter_server(9332, None);
     tokio::time::sleep(Duration::from_millis(100)).await;
 
     // Build session
@@ -221,6 +253,74 @@ async fn milter_session() {
         .await
         .assert_contains("X-Spam: Yes")
         .assert_contains("123456");
+
+    // Test accept with quarantine
+    session
+        .send_message(
+            "5@doe.org",
+            &["bill@foobar.org"],
+            "test:no_dkim",
+            "250 2.0.0",
+        )
+        .await;
+    qr.expect_message()
+        .await
+        .read_lines(&qr)
+        .await
+        .assert_contains("X-Quarantine: Virus found!")
+        .assert_contains("References: <my-new-ref>")
+        .assert_contains("Are you hungry yet?");
+}
+
+#[tokio::test]
+async fn milter_chain_session() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Configure tests
+    let tmp_dir = TempDir::new("smtp_milter_chain_test", true);
+    let mut config = Config::new(tmp_dir.update_config(CONFIG_MILTER_CHAIN)).unwrap();
+    let stores = Stores::parse_all(&mut config, false).await;
+    let core = Core::parse(&mut config, stores, Default::default()).await;
+    let _rx_first = spawn_mock_milter_server(9334, None);
+    let _rx_second = spawn_mock_milter_server(9335, Some("X-Hello"));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // Build session
+    let test = TestSMTP::from_core(core);
+    let mut qr = test.queue_receiver;
+    let mut session = Session::test(test.server);
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.doe.org").await;
+
+    // The second milter rejects the header added by the first one
+    session
+        .send_message(
+            "0@doe.org",
+            &["bill@foobar.org"],
+            "test:no_dkim",
+            "503 5.5.3",
+        )
+        .await;
+    qr.assert_no_events();
+
+    // Modifications made by both milters are applied
+    session
+        .send_message(
+            "2@doe.org",
+            &["bill@foobar.org"],
+            "test:no_dkim",
+            "250 2.0.0",
+        )
+        .await;
+    qr.expect_message()
+        .await
+        .read_lines(&qr)
+        .await
+        .assert_contains("X-Spam: Yes")
+        .assert_contains("X-Chain: 9335")
+        .assert_contains("123456");
 }
 
 #[tokio::test]
@@ -612,7 +712,10 @@ async fn milter_client_test() {
     client.quit().await.unwrap();
 }
 
-pub fn spawn_mock_milter_server() -> watch::Sender<bool> {
+pub fn spawn_mock_milter_server(
+    port: u16,
+    reject_header: Option<&'static str>,
+) -> watch::Sender<bool> {
     let (tx, rx) = watch::channel(true);
     let tests = Arc::new(
         serde_json::from_str::<Vec<HeaderTest>>(
@@ -629,19 +732,25 @@ pub fn spawn_mock_milter_server() -> watch::Sender<bool> {
     );
 
     tokio::spawn(async move {
-        let listener = TcpListener::bind("127.0.0.1:9332")
+        let listener = TcpListener::bind(format!("127.0.0.1:{port}"))
             .await
             .unwrap_or_else(|e| {
-                panic!("Failed to bind mock Milter server to 127.0.0.1:9332: {e}");
+                panic!("Failed to bind mock Milter server to 127.0.0.1:{port}: {e}");
             });
         let mut rx_ = rx.clone();
-        //println!("Mock Milter server listening on port 9332");
+        //println!("Mock Milter server listening on port {port}");
         loop {
             tokio::select! {
                 stream = listener.accept() => {
                     match stream {
                         Ok((stream, _)) => {
-                            tokio::spawn(accept_milter(stream, rx.clone(), tests.clone()));
+                            tokio::spawn(accept_milter(
+                                stream,
+                                rx.clone(),
+                                tests.clone(),
+                                port,
+                                reject_header,
+                            ));
                         }
                         Err(err) => {
                             panic!("Something went wrong: {err}" );
@@ -663,6 +772,8 @@ async fn accept_milter(
     mut stream: TcpStream,
     mut rx: watch::Receiver<bool>,
     tests: Arc<Vec<HeaderTest>>,
+    port: u16,
+    reject_header: Option<&'static str>,
 ) {
     let mut buf = vec![0u8; 1024];
     let mut receiver = Receiver::with_max_frame_len(5000000);
@@ -698,6 +809,23 @@ async fn accept_milter(
 
                     let response = match cmd {
                         Command::Abort | Command::Macro { .. } => continue,
+                        Command::Header { name, .. }
+                            if reject_header
+                                .is_some_and(|h| h.as_bytes().eq_ignore_ascii_case(name)) =>
+                        {
+                            action = Action::Reject.into();
+                            Response::Action(Action::Accept)
+                        }
+                        Command::MailFrom { .. } if reject_header.is_some() => {
+                            // Filtering milters tag accepted messages with their port
+                            action = Action::Accept.into();
+                            modifications = vec![Modification::AddHeader {
+                                name: "X-Chain".into(),
+                                value: port.to_string(),
+                            }]
+                            .into();
+                            Response::Action(Action::Accept)
+                        }
                         Command::Body { .. }
                         | Command::Data
                         | Command::Connect { .. }
@@ -32,6 +32,7 @@ pub mod ehlo;
 pub mod limits;
 pub mod mail;
 pub mod milter;
+pub mod proxy;
 pub mod rcpt;
 pub mod rewrite;
 pub mod scripts;
@@ -144,6 +145,7 @@ impl QueueReceiver {
             due: self.message_due(queue_id).await,
             queue_id,
             queue_name: Default::default(),
+            priority: 0,
         }
     }
 
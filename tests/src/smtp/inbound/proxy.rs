@@ -0,0 +1,94 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs LLC <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use common::config::server::Listeners;
+use smtp::core::SmtpSessionManager;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use utils::config::Config;
+
+use crate::{AssertConfig, smtp::TestSMTP};
+
+const CONFIG: &str = r#"
+[session.rcpt]
+relay = "remote_ip = '192.0.2.10'"
+
+[spam-filter]
+enable = false
+"#;
+
+const LISTENER: &str = r#"
+[server.listener.smtp-proxy]
+bind = ['127.0.0.1:9923']
+protocol = 'smtp'
+proxy.trusted-networks = ['127.0.0.0/8']
+"#;
+
+#[tokio::test]
+#[serial_test::serial]
+async fn proxy_protocol() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start a listener that trusts PROXY headers from localhost
+    let test = TestSMTP::new("smtp_proxy_protocol", CONFIG).await;
+    let mut config = Config::new(LISTENER).unwrap();
+    let mut servers = Listeners::parse(&mut config);
+    servers.parse_tcp_acceptors(&mut config, test.server.inner.clone());
+    servers.bind_and_drop_priv(&mut config);
+    config.assert_no_errors();
+    let _shutdown_tx = servers
+        .spawn(|server, acceptor, shutdown_rx| {
+            server.spawn(
+                SmtpSessionManager::new(test.server.inner.clone()),
+                test.server.inner.clone(),
+                acceptor,
+                shutdown_rx,
+            );
+        })
+        .0;
+
+    // PROXY v2 header advertising 192.0.2.10:4000 -> 127.0.0.1:9923
+    let mut header_v2 = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+    header_v2.extend_from_slice(&[0x21, 0x11, 0x00, 0x0c]);
+    header_v2.extend_from_slice(&[192, 0, 2, 10, 127, 0, 0, 1]);
+    header_v2.extend_from_slice(&4000u16.to_be_bytes());
+    header_v2.extend_from_slice(&9923u16.to_be_bytes());
+
+    // PROXY v1 header advertising an address that is not allowed to relay
+    let header_v1 = b"PROXY TCP4 198.51.100.1 127.0.0.1 4000 9923\r\n".to_vec();
+
+    // Relaying is evaluated against the advertised address
+    for (header, expected_code) in [(header_v2, "250"), (header_v1, "550")] {
+        let stream = TcpStream::connect("127.0.0.1:9923").await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        writer.write_all(&header).await.unwrap();
+        assert!(lines.next_line().await.unwrap().unwrap().starts_with("220"));
+
+        writer.write_all(b"EHLO mx.test.org\r\n").await.unwrap();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            assert!(line.starts_with("250"), "{line}");
+            if line.starts_with("250 ") {
+                break;
+            }
+        }
+        writer
+            .write_all(b"MAIL FROM:<john@test.org>\r\n")
+            .await
+            .unwrap();
+        assert!(lines.next_line().await.unwrap().unwrap().starts_with("250"));
+        writer
+            .write_all(b"RCPT TO:<bill@foobar.org>\r\n")
+            .await
+            .unwrap();
+        let line = lines.next_line().await.unwrap().unwrap();
+        assert!(line.starts_with(expected_code), "{line}");
+        writer.write_all(b"QUIT\r\n").await.unwrap();
+    }
+}
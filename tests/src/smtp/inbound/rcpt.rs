@@ -4,18 +4,33 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use std::time::Duration;
+use std::{
+    net::Ipv4Addr,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
 
 use common::Core;
+use mail_auth::MX;
 
 use smtp_proto::{RCPT_NOTIFY_DELAY, RCPT_NOTIFY_FAILURE, RCPT_NOTIFY_SUCCESS};
 use store::Stores;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+};
 use utils::config::Config;
 
-use smtp::core::{Session, State};
+use smtp::{
+    core::{Session, State},
+    inbound::rcpt::dnsbl_listing,
+};
 
 use crate::smtp::{
-    TempDir, TestSMTP,
+    DnsCache, TempDir, TestSMTP,
     session::{TestSession, VerifyResponse},
 };
 
@@ -163,3 +178,450 @@ async fn rcpt() {
     assert!((rcpt.flags & (RCPT_NOTIFY_DELAY | RCPT_NOTIFY_SUCCESS | RCPT_NOTIFY_FAILURE)) != 0);
     assert_eq!(rcpt.dsn_info.as_ref().unwrap(), "Jane.Doe@Foobar.org");
 }
+
+const CONFIG_GREYLIST: &str = r#"
+[storage]
+data = "rocksdb"
+lookup = "rocksdb"
+blob = "rocksdb"
+fts = "rocksdb"
+
+[store."rocksdb"]
+type = "rocksdb"
+path = "{TMP}/queue.db"
+
+[directory."local"]
+type = "memory"
+
+[[directory."local".principals]]
+name = "jane"
+description = "Jane Doe"
+secret = "p4ssw0rd"
+email = "jane@foobar.org"
+
+[session.rcpt]
+directory = "'local'"
+
+[spam-filter.grey-list]
+duration = "1h"
+delay = "1s"
+"#;
+
+#[tokio::test]
+async fn rcpt_greylist() {
+    // Enable logging
+    crate::enable_logging();
+
+    let tmp_dir = TempDir::new("smtp_rcpt_greylist_test", true);
+    let mut config = Config::new(tmp_dir.update_config(CONFIG_GREYLIST)).unwrap();
+    let stores = Stores::parse_all(&mut config, false).await;
+    let core = Core::parse(&mut config, stores, Default::default()).await;
+
+    // First contact is greylisted
+    let mut session = Session::test(TestSMTP::from_core(core).server);
+    session.data.remote_ip_str = "10.0.0.31".into();
+    session.eval_session_params().await;
+    session.ehlo("mx1.foobar.org").await;
+    session.mail_from("john@example.net", "250").await;
+    session.rcpt_to("jane@foobar.org", "451 4.7.1").await;
+
+    // Retrying before the delay has elapsed is still greylisted
+    session.rcpt_to("jane@foobar.org", "451 4.7.1").await;
+
+    // Retrying after the delay is accepted
+    tokio::time::sleep(Duration::from_millis(2100)).await;
+    session.rcpt_to("jane@foobar.org", "250").await;
+
+    // The triplet is now allowlisted
+    session.rset().await;
+    session.mail_from("john@example.net", "250").await;
+    session.rcpt_to("jane@foobar.org", "250").await;
+
+    // The same sender and recipient from another IP are greylisted
+    session.data.remote_ip_str = "10.0.0.4".into();
+    session.eval_session_params().await;
+    session.rset().await;
+    session.mail_from("john@example.net", "250").await;
+    session.rcpt_to("jane@foobar.org", "451 4.7.1").await;
+
+    // Triplets that only differ in where the IP and sender are split are greylisted
+    session.data.remote_ip_str = "10.0.0.3".into();
+    session.eval_session_params().await;
+    session.rset().await;
+    session.mail_from("1john@example.net", "250").await;
+    session.rcpt_to("jane@foobar.org", "451 4.7.1").await;
+}
+
+const CONFIG_DNSBL: &str = r#"
+[storage]
+data = "rocksdb"
+lookup = "rocksdb"
+blob = "rocksdb"
+fts = "rocksdb"
+
+[store."rocksdb"]
+type = "rocksdb"
+path = "{TMP}/queue.db"
+
+[directory."local"]
+type = "memory"
+
+[[directory."local".principals]]
+name = "jane"
+description = "Jane Doe"
+secret = "p4ssw0rd"
+email = "jane@foobar.org"
+
+[session.rcpt]
+directory = "'local'"
+errors.wait = "5ms"
+
+[session.rcpt.dnsbl]
+ip = ["zen.test"]
+domain = ["dbl.test."]
+"#;
+
+#[tokio::test]
+async fn rcpt_dnsbl() {
+    // Enable logging
+    crate::enable_logging();
+
+    let tmp_dir = TempDir::new("smtp_rcpt_dnsbl_test", true);
+    let mut config = Config::new(tmp_dir.update_config(CONFIG_DNSBL)).unwrap();
+    let stores = Stores::parse_all(&mut config, false).await;
+    let core = Core::parse(&mut config, stores, Default::default()).await;
+    let server = TestSMTP::from_core(core).server;
+
+    // Populate the DNSBL cache
+    let valid_until = Instant::now() + Duration::from_secs(100);
+    server.dnsbl_add(
+        "3.0.0.10.zen.test",
+        vec![Ipv4Addr::new(127, 0, 0, 2)],
+        valid_until,
+    );
+    server.dnsbl_add(
+        "spammer.org.dbl.test",
+        vec![Ipv4Addr::new(127, 0, 0, 2)],
+        valid_until,
+    );
+    for name in [
+        "4.0.0.10.zen.test",
+        "example.net.dbl.test",
+        "foobar.org.dbl.test",
+    ] {
+        server
+            .inner
+            .cache
+            .dns_rbl
+            .insert(name.to_string(), None, Duration::from_secs(100));
+    }
+
+    // Listed IP address
+    let mut session = Session::test(server.clone());
+    session.data.remote_ip_str = "10.0.0.3".into();
+    session.data.remote_ip = session.data.remote_ip_str.parse().unwrap();
+    session.eval_session_params().await;
+    session.ehlo("mx1.foobar.org").await;
+    session.mail_from("john@example.net", "250").await;
+    session.rcpt_to("jane@foobar.org", "550 5.7.1").await;
+    assert_eq!(session.data.dnsbl, vec!["zen.test".to_string()]);
+
+    // Clean IP address and sender domain
+    let mut session = Session::test(server.clone());
+    session.data.remote_ip_str = "10.0.0.4".into();
+    session.data.remote_ip = session.data.remote_ip_str.parse().unwrap();
+    session.eval_session_params().await;
+    session.ehlo("mx1.foobar.org").await;
+    session.mail_from("john@example.net", "250").await;
+    session.rcpt_to("jane@foobar.org", "250").await;
+
+    // Listed sender domain
+    session.rset().await;
+    session.mail_from("john@spammer.org", "250").await;
+    session.rcpt_to("jane@foobar.org", "550 5.7.1").await;
+    assert_eq!(session.data.dnsbl, vec!["dbl.test".to_string()]);
+
+    // Only 127.0.0.2 to 127.0.0.255 are listings, 127.255.255.0/24 are query errors
+    for (response, expected) in [
+        (
+            vec![Ipv4Addr::new(127, 0, 0, 2)],
+            Ok(Some(Ipv4Addr::new(127, 0, 0, 2))),
+        ),
+        (
+            vec![Ipv4Addr::new(127, 0, 0, 255)],
+            Ok(Some(Ipv4Addr::new(127, 0, 0, 255))),
+        ),
+        (vec![Ipv4Addr::new(127, 0, 0, 1)], Ok(None)),
+        (vec![Ipv4Addr::new(127, 0, 1, 2)], Ok(None)),
+        (vec![Ipv4Addr::new(10, 0, 0, 2)], Ok(None)),
+        (
+            vec![
+                Ipv4Addr::new(127, 0, 0, 2),
+                Ipv4Addr::new(127, 255, 255, 254),
+            ],
+            Err(Ipv4Addr::new(127, 255, 255, 254)),
+        ),
+        (
+            vec![Ipv4Addr::new(127, 255, 255, 252)],
+            Err(Ipv4Addr::new(127, 255, 255, 252)),
+        ),
+    ] {
+        assert_eq!(dnsbl_listing(&response), expected, "{response:?}");
+    }
+}
+
+const CONFIG_BOUNCE: &str = r#"
+[storage]
+data = "rocksdb"
+lookup = "rocksdb"
+blob = "rocksdb"
+fts = "rocksdb"
+directory = "local"
+
+[store."rocksdb"]
+type = "rocksdb"
+path = "{TMP}/queue.db"
+
+[directory."local"]
+type = "memory"
+
+[[directory."local".principals]]
+name = "john"
+description = "John Doe"
+secret = "secret"
+email = "john@foobar.org"
+
+[[directory."local".principals]]
+name = "jane"
+description = "Jane Doe"
+secret = "p4ssw0rd"
+email = "jane@foobar.org"
+
+[session.auth]
+mechanisms = "[plain]"
+directory = "'local'"
+
+[session.rcpt]
+directory = "'local'"
+relay = "!is_empty(authenticated_as)"
+errors.wait = "5ms"
+bounce-correlation.enable = true
+
+[spam-filter]
+enable = false
+"#;
+
+#[tokio::test]
+async fn rcpt_bounce_correlation() {
+    // Enable logging
+    crate::enable_logging();
+
+    let tmp_dir = TempDir::new("smtp_rcpt_bounce_test", true);
+    let mut config = Config::new(tmp_dir.update_config(CONFIG_BOUNCE)).unwrap();
+    let stores = Stores::parse_all(&mut config, false).await;
+    let core = Core::parse(&mut config, stores, Default::default()).await;
+    let test = TestSMTP::from_core(core);
+    let mut qr = test.queue_receiver;
+
+    // Bounces to addresses that never sent mail are rejected
+    let mut session = Session::test(test.server.clone());
+    session.data.remote_ip_str = "10.0.0.3".into();
+    session.eval_session_params().await;
+    session.ehlo("mx1.example.net").await;
+    session.mail_from("<>", "250").await;
+    session.rcpt_to("john@foobar.org", "550 5.7.1").await;
+
+    // Regular messages are not affected
+    session.rset().await;
+    session.mail_from("bill@example.net", "250").await;
+    session.rcpt_to("john@foobar.org", "250").await;
+
+    // Sending a message records the sender
+    let mut sender = Session::test(test.server);
+    sender.data.remote_ip_str = "10.0.0.4".into();
+    sender.eval_session_params().await;
+    sender.stream.tls = true;
+    sender.ehlo("mx.foobar.org").await;
+    sender.cmd("AUTH PLAIN AGpvaG4Ac2VjcmV0", "235 2.7.0").await;
+    sender
+        .send_message(
+            "john@foobar.org",
+            &["bill@example.net"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    qr.expect_message().await;
+
+    // Bounces correlated to the sent message are accepted
+    session.rset().await;
+    session.mail_from("<>", "250").await;
+    session.rcpt_to("john@foobar.org", "250").await;
+    session.rcpt_to("jane@foobar.org", "550 5.7.1").await;
+}
+
+const CONFIG_CALLOUT: &str = r#"
+[storage]
+data = "rocksdb"
+lookup = "rocksdb"
+blob = "rocksdb"
+fts = "rocksdb"
+
+[store."rocksdb"]
+type = "rocksdb"
+path = "{TMP}/queue.db"
+
+[session.rcpt]
+relay = true
+errors.wait = "5ms"
+verify = [{if = "rcpt_domain == 'foobar.org' || rcpt_domain == 'relayed.org'", then = "callout"},
+          {else = false}]
+
+[session.rcpt.dnsbl]
+ip = ["zen.test"]
+
+[queue.strategy]
+gateway = [{if = "rcpt_domain == 'relayed.org'", then = "'relay'"},
+           {else = "'mx'"}]
+
+[queue.gateway.relay]
+type = "relay"
+address = "relay.internal"
+port = 9925
+protocol = "smtp"
+tls.implicit = false
+
+[spam-filter]
+enable = false
+"#;
+
+#[tokio::test]
+#[serial_test::serial]
+async fn rcpt_callout() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Mock next hop that only accepts bill
+    let rcpt_count = Arc::new(AtomicUsize::new(0));
+    let rcpt_count_ = rcpt_count.clone();
+    let listener = TcpListener::bind("127.0.0.1:9925").await.unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let rcpt_count = rcpt_count_.clone();
+            tokio::spawn(async move {
+                let (reader, mut writer) = stream.into_split();
+                let mut lines = BufReader::new(reader).lines();
+                writer
+                    .write_all(b"220 mx.foobar.org ESMTP\r\n")
+                    .await
+                    .unwrap();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let response: &[u8] = if line.starts_with("EHLO") {
+                        b"250-mx.foobar.org\r\n250 8BITMIME\r\n"
+                    } else if line.starts_with("RCPT") {
+                        rcpt_count.fetch_add(1, Ordering::Relaxed);
+                        if line.contains("<bill@") {
+                            b"250 2.1.5 OK\r\n"
+                        } else {
+                            b"550 5.1.1 User unknown\r\n"
+                        }
+                    } else if line.starts_with("QUIT") {
+                        writer.write_all(b"221 2.0.0 Bye\r\n").await.unwrap();
+                        break;
+                    } else {
+                        b"250 2.0.0 OK\r\n"
+                    };
+                    writer.write_all(response).await.unwrap();
+                }
+            });
+        }
+    });
+
+    let tmp_dir = TempDir::new("smtp_rcpt_callout_test", true);
+    let mut config = Config::new(tmp_dir.update_config(CONFIG_CALLOUT)).unwrap();
+    let stores = Stores::parse_all(&mut config, false).await;
+    let core = Core::parse(&mut config, stores, Default::default()).await;
+    let test = TestSMTP::from_core(core);
+    test.server.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx.foobar.org".into()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    test.server.ipv4_add(
+        "mx.foobar.org",
+        vec!["127.0.0.2".parse().unwrap(), "127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+    test.server.ipv4_add(
+        "relay.internal",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+    test.server.dnsbl_add(
+        "9.0.0.10.zen.test",
+        vec![Ipv4Addr::new(127, 0, 0, 2)],
+        Instant::now() + Duration::from_secs(10),
+    );
+    test.server.inner.cache.dns_rbl.insert(
+        "1.0.0.127.zen.test".to_string(),
+        None,
+        Duration::from_secs(10),
+    );
+
+    // RCPT reflects the result of the callout, addresses that refuse
+    // connections are skipped
+    let mut session = Session::test(test.server.clone());
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.example.net").await;
+    session.mail_from("john@example.net", "250").await;
+    session.rcpt_to("bill@foobar.org", "250").await;
+    session.rcpt_to("jane@foobar.org", "550 5.1.1").await;
+    assert_eq!(rcpt_count.load(Ordering::Relaxed), 2);
+
+    // Blocklisted clients are rejected before any callout is made
+    let mut listed = Session::test(test.server.clone());
+    listed.data.remote_ip_str = "10.0.0.9".into();
+    listed.data.remote_ip = listed.data.remote_ip_str.parse().unwrap();
+    listed.eval_session_params().await;
+    listed.ehlo("mx.example.net").await;
+    listed.mail_from("john@example.net", "250").await;
+    listed.rcpt_to("ann@foobar.org", "550 5.7.1").await;
+    assert_eq!(rcpt_count.load(Ordering::Relaxed), 2);
+
+    // Results are cached and other domains are not verified
+    session.rset().await;
+    session.mail_from("john@example.net", "250").await;
+    session.rcpt_to("bill@foobar.org", "250").await;
+    session.rcpt_to("jane@foobar.org", "550 5.1.1").await;
+    session.rcpt_to("jane@example.org", "250").await;
+    assert_eq!(rcpt_count.load(Ordering::Relaxed), 2);
+
+    // Recipients are verified at the next hop chosen by the queue gateway
+    session.rcpt_to("bill@relayed.org", "250").await;
+    session.rcpt_to("jane@relayed.org", "550 5.1.1").await;
+    assert_eq!(rcpt_count.load(Ordering::Relaxed), 4);
+
+    // Callouts to ourselves are skipped, whether the next hop greets with
+    // our hostname or is the listener that accepted the session
+    let mut session = Session::test(test.server.clone());
+    session.hostname = "mx.foobar.org".into();
+    session.data.remote_ip_str = "10.0.0.2".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.example.net").await;
+    session.mail_from("john@example.net", "250").await;
+    session.rcpt_to("tom@foobar.org", "250").await;
+    assert_eq!(rcpt_count.load(Ordering::Relaxed), 4);
+
+    let mut session = Session::test(test.server);
+    session.data.local_port = 9925;
+    session.data.remote_ip_str = "10.0.0.3".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.example.net").await;
+    session.mail_from("john@example.net", "250").await;
+    session.rcpt_to("tom@relayed.org", "250").await;
+    assert_eq!(rcpt_count.load(Ordering::Relaxed), 4);
+}
@@ -21,6 +21,7 @@ use smtp::{
     core::Session,
     scripts::{ScriptResult, event_loop::RunScript},
 };
+use smtp_proto::{RCPT_NOTIFY_DELAY, RCPT_NOTIFY_FAILURE};
 use store::Stores;
 use utils::config::Config;
 
@@ -368,4 +369,103 @@ async fn sieve_scripts() {
         .assert_contains("Received: ")
         .assert_contains("Authentication-Results: ");
     qr.assert_no_events();
+    qr.clear_queue(&test.server).await;
+
+    // Expect a redirected copy along with the original message
+    session
+        .send_message(
+            "test@example.net",
+            &["mike@foobar.gov"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    qr.read_event().await.assert_refresh();
+    qr.read_event().await.assert_refresh();
+    let messages = qr.read_queued_messages().await;
+    assert_eq!(messages.len(), 2);
+    let redirect = messages
+        .iter()
+        .find(|m| m.message.recipients[0].address == "copy@somewhere.email")
+        .expect("redirected copy");
+    assert_eq!(redirect.message.return_path, "");
+    assert_eq!(
+        redirect.message.recipients[0].flags & (RCPT_NOTIFY_FAILURE | RCPT_NOTIFY_DELAY),
+        RCPT_NOTIFY_FAILURE | RCPT_NOTIFY_DELAY
+    );
+    let original = messages
+        .iter()
+        .find(|m| m.message.recipients[0].address == "mike@foobar.gov")
+        .expect("original message");
+    assert_eq!(original.message.return_path, "test@example.net");
+    for message in messages {
+        message
+            .read_lines(&qr)
+            .await
+            .assert_contains("Subject: Is dinner ready?")
+            .assert_contains("From: Joe SixPack <joe@football.example.com>");
+    }
+    qr.assert_no_events();
+    qr.clear_queue(&test.server).await;
+
+    // Expect a single vacation reply per sender within the window
+    session
+        .send_message(
+            "test@example.net",
+            &["away@foobar.gov"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    qr.read_event().await.assert_refresh();
+    qr.read_event().await.assert_refresh();
+    let messages = qr.read_queued_messages().await;
+    assert_eq!(messages.len(), 2);
+    let reply = messages
+        .iter()
+        .find(|m| m.message.recipients[0].address == "test@example.net")
+        .expect("vacation reply");
+    assert_eq!(reply.message.return_path, "");
+    reply
+        .read_lines(&qr)
+        .await
+        .assert_contains("Subject: Out of office")
+        .assert_contains("I am away until Monday.");
+    qr.clear_queue(&test.server).await;
+
+    session
+        .send_message(
+            "test@example.net",
+            &["away@foobar.gov"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    qr.read_event().await.assert_refresh();
+    let messages = qr.read_queued_messages().await;
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].message.recipients[0].address, "away@foobar.gov");
+    qr.assert_no_events();
+    qr.clear_queue(&test.server).await;
+
+    // Expect the second delivery of the same Message-ID to match duplicate
+    session
+        .send_message(
+            "test@example.net",
+            &["dup@foobar.gov"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    qr.expect_message().await;
+    qr.clear_queue(&test.server).await;
+    session
+        .send_message(
+            "test@example.net",
+            &["dup@foobar.gov"],
+            "test:no_dkim",
+            "503 5.5.3 Duplicate message.",
+        )
+        .await;
+    qr.assert_no_events();
 }
@@ -6,13 +6,15 @@
 
 use std::time::{Duration, Instant};
 
-use common::Core;
+use common::{Core, Server};
 
 use mail_auth::{
+    AuthenticatedMessage, DkimResult,
     common::{parse::TxtRecordParser, verify::DomainKey},
     spf::Spf,
 };
-use store::Stores;
+use mail_parser::DateTime;
+use store::{Stores, write::now};
 use utils::config::Config;
 
 use crate::smtp::{
@@ -170,6 +172,18 @@ async fn sign_and_seal() {
         .unwrap(),
         Instant::now() + Duration::from_secs(5),
     );
+    test.server.txt_add(
+        "ed._domainkey.example.com",
+        DomainKey::parse(
+            concat!(
+                "v=DKIM1; k=ed25519; ",
+                "p=qgmCKM1i01iLwa3o4KFoCYBx3cIKW1kvigiYw0WDuD8="
+            )
+            .as_bytes(),
+        )
+        .unwrap(),
+        Instant::now() + Duration::from_secs(5),
+    );
 
     // Test DKIM signing
     let mut qr = test.queue_receiver;
@@ -197,25 +211,257 @@ async fn sign_and_seal() {
     session
         .send_message("bill@foobar.org", &["jdoe@example.com"], "test:arc", "250")
         .await;
-    qr.expect_message()
-        .await
+    let message = qr.expect_message().await;
+    message
         .read_lines(&qr)
         .await
         .assert_contains("ARC-Seal: i=3; a=ed25519-sha256; s=ed; d=example.com; cv=pass;")
         .assert_contains(
             "ARC-Message-Signature: i=3; a=ed25519-sha256; s=ed; d=example.com; c=relaxed/simple;",
         );
+    assert_arc_pass(&session.server, &message.read_message(&qr).await).await;
 
     // Test ARC sealing of a DKIM signed message
     session
         .send_message("bill@foobar.org", &["jdoe@example.com"], "test:dkim", "250")
         .await;
-    qr.expect_message()
-        .await
+    let message = qr.expect_message().await;
+    message
         .read_lines(&qr)
         .await
         .assert_contains("ARC-Seal: i=1; a=ed25519-sha256; s=ed; d=example.com; cv=none;")
         .assert_contains(
             "ARC-Message-Signature: i=1; a=ed25519-sha256; s=ed; d=example.com; c=relaxed/simple;",
         );
+    assert_arc_pass(&session.server, &message.read_message(&qr).await).await;
+}
+
+#[tokio::test]
+async fn sign_rsa_and_ed25519() {
+    // Enable logging
+    crate::enable_logging();
+
+    let tmp_dir = TempDir::new("smtp_sign_multi_test", true);
+    let mut config = Config::new(tmp_dir.update_config(
+        CONFIG.replace("sign = \"['rsa']\"", "sign = \"['rsa', 'ed']\"") + SIGNATURES,
+    ))
+    .unwrap();
+    let stores = Stores::parse_all(&mut config, false).await;
+    let core = Core::parse(&mut config, stores, Default::default()).await;
+    let test = TestSMTP::from_core(core);
+
+    test.server.txt_add(
+        "mx.example.com",
+        Spf::parse(b"v=spf1 ip4:10.0.0.1 ip4:10.0.0.2 -all").unwrap(),
+        Instant::now() + Duration::from_secs(5),
+    );
+    add_example_keys(&test.server);
+
+    // One signature is added per key
+    let mut qr = test.queue_receiver;
+    let mut session = Session::test(test.server);
+    session.data.remote_ip_str = "10.0.0.2".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.example.com").await;
+    session
+        .send_message(
+            "bill@foobar.org",
+            &["jdoe@example.com"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    let message = qr.expect_message().await;
+    message
+        .read_lines(&qr)
+        .await
+        .assert_count("DKIM-Signature:", 2)
+        .assert_contains("DKIM-Signature: v=1; a=rsa-sha256; s=rsa; d=example.com;")
+        .assert_contains("DKIM-Signature: v=1; a=ed25519-sha256; s=ed; d=example.com;");
+
+    // Both signatures verify
+    let dkim_output = verify_dkim(&session.server, &message.read_message(&qr).await).await;
+    assert_eq!(dkim_output.len(), 2, "{dkim_output:?}");
+    assert!(
+        dkim_output.iter().all(|result| result == &DkimResult::Pass),
+        "{dkim_output:?}"
+    );
+}
+
+#[tokio::test]
+async fn sign_oversign() {
+    // Enable logging
+    crate::enable_logging();
+
+    let tmp_dir = TempDir::new("smtp_sign_oversign_test", true);
+    let mut config = Config::new(
+        tmp_dir.update_config(
+            CONFIG.to_string()
+                + SIGNATURES
+                    .replace("report = true\n", "report = true\noversign = ['From']\n")
+                    .as_str(),
+        ),
+    )
+    .unwrap();
+    let stores = Stores::parse_all(&mut config, false).await;
+    let core = Core::parse(&mut config, stores, Default::default()).await;
+    let test = TestSMTP::from_core(core);
+
+    test.server.txt_add(
+        "mx.example.com",
+        Spf::parse(b"v=spf1 ip4:10.0.0.1 ip4:10.0.0.2 -all").unwrap(),
+        Instant::now() + Duration::from_secs(5),
+    );
+    add_example_keys(&test.server);
+
+    let mut qr = test.queue_receiver;
+    let mut session = Session::test(test.server);
+    session.data.remote_ip_str = "10.0.0.2".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.example.com").await;
+    session
+        .send_message(
+            "bill@foobar.org",
+            &["jdoe@example.com"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    let message = qr.expect_message().await.read_message(&qr).await;
+
+    // The untouched message verifies
+    let dkim_output = verify_dkim(&session.server, &message).await;
+    assert_eq!(dkim_output.len(), 1, "{dkim_output:?}");
+    assert_eq!(dkim_output[0], DkimResult::Pass, "{dkim_output:?}");
+
+    // Adding a second From header after signing breaks the signature
+    let message = format!("From: Mallory <mallory@evil.org>\r\n{message}");
+    let dkim_output = verify_dkim(&session.server, &message).await;
+    assert_eq!(dkim_output.len(), 1, "{dkim_output:?}");
+    assert_ne!(dkim_output[0], DkimResult::Pass, "{dkim_output:?}");
+}
+
+#[tokio::test]
+async fn sign_selector_rotation() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Rotate from the 'rsa' selector to the 'ed' selector a few seconds from now
+    let tmp_dir = TempDir::new("smtp_sign_rotation_test", true);
+    let rotate_at = DateTime::from_timestamp((now() + 3) as i64).to_rfc3339();
+    let mut config = Config::new(tmp_dir.update_config(
+        CONFIG.replace("sign = \"['rsa']\"", "sign = \"['rsa', 'ed']\"") + SIGNATURES,
+    ))
+    .unwrap();
+    config
+        .keys
+        .insert("signature.rsa.valid-until".to_string(), rotate_at.clone());
+    config
+        .keys
+        .insert("signature.ed.valid-from".to_string(), rotate_at);
+    let stores = Stores::parse_all(&mut config, false).await;
+    let core = Core::parse(&mut config, stores, Default::default()).await;
+    let test = TestSMTP::from_core(core);
+
+    test.server.txt_add(
+        "mx.example.com",
+        Spf::parse(b"v=spf1 ip4:10.0.0.1 ip4:10.0.0.2 -all").unwrap(),
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    let mut qr = test.queue_receiver;
+    let mut session = Session::test(test.server);
+    session.data.remote_ip_str = "10.0.0.2".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.example.com").await;
+
+    // Only the 'rsa' selector is active
+    session
+        .send_message(
+            "bill@foobar.org",
+            &["jdoe@example.com"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    qr.expect_message()
+        .await
+        .read_lines(&qr)
+        .await
+        .assert_contains("DKIM-Signature: v=1; a=rsa-sha256; s=rsa; d=example.com;")
+        .assert_not_contains("s=ed;");
+
+    // After the rotation only the 'ed' selector is used
+    tokio::time::sleep(Duration::from_secs(4)).await;
+    session
+        .send_message(
+            "bill@foobar.org",
+            &["jdoe@example.com"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    qr.expect_message()
+        .await
+        .read_lines(&qr)
+        .await
+        .assert_contains("DKIM-Signature: v=1; a=ed25519-sha256; s=ed; d=example.com;")
+        .assert_not_contains("s=rsa;");
+}
+
+pub fn add_example_keys(server: &Server) {
+    server.txt_add(
+        "rsa._domainkey.example.com",
+        DomainKey::parse(
+            concat!(
+                "v=DKIM1; k=rsa; p=MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAv9XYXG3uK951",
+                "15mB4nJ37nGeNe2CrARm1agrbcnSk5oIaEfMZLUR/X8gPzoiNHZcfMZEVR6bAytxUhc5EvZI",
+                "ZrjSuEEeny+fFd/cTvcm3cOUUbIaUmSACj0dL2/KwW0LyUaza9z9zor7I5XdIl1M53qVd5GI",
+                "62XBB76FH+Q0bWPZNkT4NclzTLspD/MTpNCCPhySM4Kdg5CuDczTH4aNzyS0TqgXdtw6A4Sd",
+                "sp97VXT9fkPW9rso3lrkpsl/9EQ1mR/DWK6PBmRfIuSFuqnLKY6v/z2hXHxF7IoojfZLa2kZ",
+                "r9Aed4l9WheQOTA19k5r2BmlRw/W9CrgCBo0Sdj+KQIDAQAB",
+            )
+            .as_bytes(),
+        )
+        .unwrap(),
+        Instant::now() + Duration::from_secs(5),
+    );
+    server.txt_add(
+        "ed._domainkey.example.com",
+        DomainKey::parse(
+            concat!(
+                "v=DKIM1; k=ed25519; ",
+                "p=qgmCKM1i01iLwa3o4KFoCYBx3cIKW1kvigiYw0WDuD8="
+            )
+            .as_bytes(),
+        )
+        .unwrap(),
+        Instant::now() + Duration::from_secs(5),
+    );
+}
+
+pub async fn verify_dkim(server: &Server, message: &str) -> Vec<DkimResult> {
+    let message = AuthenticatedMessage::parse(message.as_bytes()).unwrap();
+    server
+        .core
+        .smtp
+        .resolvers
+        .dns
+        .verify_dkim(server.inner.cache.build_auth_parameters(&message))
+        .await
+        .iter()
+        .map(|output| output.result().clone())
+        .collect()
+}
+
+async fn assert_arc_pass(server: &Server, message: &str) {
+    let message = AuthenticatedMessage::parse(message.as_bytes()).unwrap();
+    let arc_output = server
+        .core
+        .smtp
+        .resolvers
+        .dns
+        .verify_arc(server.inner.cache.build_auth_parameters(&message))
+        .await;
+    assert_eq!(arc_output.result(), &DkimResult::Pass, "{arc_output:?}");
 }
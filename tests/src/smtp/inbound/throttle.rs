@@ -4,9 +4,12 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
-use crate::smtp::{TempDir, TestSMTP, session::TestSession};
+use crate::{
+    AssertConfig,
+    smtp::{TempDir, TestSMTP, session::TestSession},
+};
 use common::Core;
 use smtp::core::{Session, SessionAddress};
 use store::Stores;
@@ -99,3 +102,184 @@ async fn throttle_inbound() {
     session.data.remote_ip_str = "10.0.0.2".into();
     assert!(session.is_allowed().await, "Rate limiter too strict.");
 }
+
+const CONFIG_RCPT: &str = r#"
+[storage]
+data = "rocksdb"
+lookup = "rocksdb"
+blob = "rocksdb"
+fts = "rocksdb"
+
+[store."rocksdb"]
+type = "rocksdb"
+path = "{TMP}/data.db"
+
+[directory."local"]
+type = "memory"
+
+[[directory."local".principals]]
+name = "jane"
+description = "Jane Doe"
+secret = "p4ssw0rd"
+email = ["jane@foobar.org", "jane.doe@foobar.org", "j.doe@foobar.org"]
+
+[session.rcpt]
+directory = "'local'"
+
+[[queue.limiter.inbound]]
+match = "!is_empty(rcpt)"
+key = 'remote_ip'
+rate = '2/1h'
+enable = true
+"#;
+
+#[tokio::test]
+async fn throttle_inbound_rcpt() {
+    // Enable logging
+    crate::enable_logging();
+
+    let tmp_dir = TempDir::new("smtp_inbound_throttle_rcpt", true);
+    let mut config = Config::new(tmp_dir.update_config(CONFIG_RCPT)).unwrap();
+    let stores = Stores::parse_all(&mut config, false).await;
+    let core = Core::parse(&mut config, stores, Default::default()).await;
+
+    // Recipients are rate limited per remote IP
+    let mut session = Session::test(TestSMTP::from_core(core).server);
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx1.foobar.org").await;
+    session.mail_from("john@example.net", "250").await;
+    session.rcpt_to("jane@foobar.org", "250").await;
+    session.rcpt_to("jane.doe@foobar.org", "250").await;
+    session.rcpt_to("j.doe@foobar.org", "452 4.4.5").await;
+
+    // Other remote IPs are not affected
+    session.data.remote_ip_str = "10.0.0.2".into();
+    session.eval_session_params().await;
+    session.rcpt_to("j.doe@foobar.org", "250").await;
+}
+
+const CONFIG_SEND_QUOTA: &str = r#"
+[storage]
+data = "rocksdb"
+lookup = "rocksdb"
+blob = "rocksdb"
+fts = "rocksdb"
+directory = "local"
+
+[store."rocksdb"]
+type = "rocksdb"
+path = "{TMP}/data.db"
+
+[directory."local"]
+type = "memory"
+
+[[directory."local".principals]]
+name = "john"
+description = "John Doe"
+secret = "secret"
+email = "john@example.org"
+
+[session.auth]
+mechanisms = "[plain]"
+directory = "'local'"
+
+[session.rcpt]
+relay = true
+
+[session.data.send-quota]
+messages = [{if = "authenticated_as == 'john'", then = 2},
+            {else = 0}]
+period = "3s"
+hard = true
+
+[[queue.quota]]
+match = "rcpt_domain = 'full.org'"
+key = ['rcpt_domain']
+size = 10
+enable = true
+
+[spam-filter]
+enable = false
+"#;
+
+#[tokio::test]
+async fn throttle_send_quota() {
+    // Enable logging
+    crate::enable_logging();
+
+    let tmp_dir = TempDir::new("smtp_inbound_send_quota", true);
+    let mut config = Config::new(tmp_dir.update_config(CONFIG_SEND_QUOTA)).unwrap();
+    let stores = Stores::parse_all(&mut config, false).await;
+    let core = Core::parse(&mut config, stores, Default::default()).await;
+    config.assert_no_errors();
+    let test = TestSMTP::from_core(core);
+    let mut qr = test.queue_receiver;
+
+    let mut session = Session::test(test.server);
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.stream.tls = true;
+    session.ehlo("mx.example.org").await;
+    session
+        .cmd("AUTH PLAIN AGpvaG4Ac2VjcmV0", "235 2.7.0")
+        .await;
+
+    // Start at the beginning of a quota period
+    wait_for_period(3).await;
+
+    // Submissions rejected by the queue quota do not count towards the quota
+    session
+        .send_message(
+            "john@example.org",
+            &["bill@full.org"],
+            "test:no_dkim",
+            "452 4.3.1",
+        )
+        .await;
+    qr.assert_no_events();
+
+    // Submissions are accepted up to the quota
+    for _ in 0..2 {
+        session
+            .send_message(
+                "john@example.org",
+                &["bill@foobar.org"],
+                "test:no_dkim",
+                "250",
+            )
+            .await;
+        qr.expect_message().await;
+    }
+
+    // Further submissions are rejected until the period ends
+    session
+        .send_message(
+            "john@example.org",
+            &["bill@foobar.org"],
+            "test:no_dkim",
+            "552 5.3.4",
+        )
+        .await;
+    qr.assert_no_events();
+
+    // The counter resets after the period
+    wait_for_period(3).await;
+    session
+        .send_message(
+            "john@example.org",
+            &["bill@foobar.org"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    qr.expect_message().await;
+}
+
+async fn wait_for_period(secs: u64) {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    tokio::time::sleep(Duration::from_millis(secs * 1000 - (now % (secs * 1000)))).await;
+}
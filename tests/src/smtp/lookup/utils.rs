@@ -4,7 +4,7 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use std::net::IpAddr;
+use std::{net::IpAddr, sync::atomic::Ordering, time::Duration};
 
 use crate::smtp::TestSMTP;
 use ::smtp::outbound::NextHop;
@@ -20,7 +20,7 @@ use mail_auth::{IpLookupStrategy, MX};
 use mail_parser::DateTime;
 use smtp::{
     outbound::{
-        lookup::{SourceIp, ToNextHop},
+        lookup::{MX_SHUFFLE_SEED, SourceIp, ToNextHop},
         mta_sts::parse::ParsePolicy,
     },
     queue::{
@@ -35,6 +35,7 @@ use utils::config::Config;
 const CONFIG: &str = r#"
 [queue.connection.test.timeout]
 connect = "10s"
+data-end = "30s"
 
 [[queue.connection.test.source-ip]]
 address = "10.0.0.1"
@@ -80,6 +81,8 @@ type = "mx"
 ip-lookup-strategy = "ipv6_then_ipv4"
 
 [queue.strategy]
+connection = [{if = "rcpt_domain == 'foobar.com'", then = "'test'"},
+              {else = "'default'"}]
 schedule = "source + ' ' + received_from_ip + ' ' + received_via_port + ' ' + queue_name + ' ' + last_error + ' ' + rcpt_domain + ' ' + size + ' ' + queue_age"
 
 "#;
@@ -128,6 +131,9 @@ async fn strategies() {
         .unwrap();
 
     assert_eq!(conn.ehlo_hostname.as_ref().unwrap(), "test.example.com");
+    assert_eq!(conn.timeout_connect, Duration::from_secs(10));
+    assert_eq!(conn.timeout_data_end, Duration::from_secs(30));
+    assert_eq!(conn.timeout_data, Duration::from_secs(10 * 60));
 
     for is_ipv4 in [true, false] {
         for _ in 0..10 {
@@ -146,6 +152,30 @@ async fn strategies() {
         }
     }
 
+    // Source IPs are rotated evenly across the pool
+    for is_ipv4 in [true, false] {
+        let mut counts = [0; 4];
+        for _ in 0..20 {
+            let ip_host = conn.source_ip(is_ipv4).unwrap();
+            let pool = if is_ipv4 { &ipv4 } else { &ipv6 };
+            counts[pool.iter().position(|&ip| ip == ip_host.ip).unwrap()] += 1;
+        }
+        assert_eq!(counts, [5; 4]);
+    }
+
+    // Each address family keeps its own rotation when lookups are interleaved
+    let mut counts = [[0; 4]; 2];
+    for is_ipv4 in [true, false].into_iter().cycle().take(40) {
+        let ip_host = conn.source_ip(is_ipv4).unwrap();
+        let (pool, family_counts) = if is_ipv4 {
+            (&ipv4, &mut counts[0])
+        } else {
+            (&ipv6, &mut counts[1])
+        };
+        family_counts[pool.iter().position(|&ip| ip == ip_host.ip).unwrap()] += 1;
+    }
+    assert_eq!(counts, [[5; 4]; 2]);
+
     // Test strategy resolution
     let message = Message {
         created: now() - 123,
@@ -162,18 +192,23 @@ async fn strategies() {
             notify: Schedule::now(),
             expires: QueueExpiry::Duration(3600),
             queue: QueueName::new("test").unwrap(),
+            next_hop: None,
             status: Status::TemporaryFailure(ErrorDetails {
                 entity: "test.example.com".to_string(),
                 details: Error::TlsError("TLS handshake failed".to_string()),
             }),
             flags: 0,
             orcpt: None,
+            tls: None,
+            transcript: None,
         }],
         flags: FROM_AUTHENTICATED,
         env_id: None,
         priority: 0,
         size: 978,
         quota_keys: vec![],
+        headers: vec![],
+        submission: None,
     };
 
     assert_eq!(
@@ -187,6 +222,29 @@ async fn strategies() {
             .unwrap_or_else(|| "default".to_string()),
         "authenticated 1.2.3.4 7911 test tls foobar.com 978 123"
     );
+
+    // Source IPs are selected per recipient domain
+    let mut rcpt = message.recipients[0].clone();
+    for (domain, expected) in [("foobar.com", "test"), ("foobar.org", "default")] {
+        rcpt.address_lcase = format!("recipient@{domain}");
+        assert_eq!(
+            test.server
+                .eval_if::<String, _>(
+                    &test.server.core.smtp.queue.connection,
+                    &QueueEnvelope::new(&message, &rcpt),
+                    0,
+                )
+                .await
+                .unwrap(),
+            expected
+        );
+    }
+    assert!(
+        test.server
+            .get_connection_or_default("default", 0)
+            .source_ip(true)
+            .is_none()
+    );
 }
 
 #[test]
@@ -218,8 +276,10 @@ fn to_remote_hosts() {
         max_mx: 7,
         max_multi_homed: 2,
         ip_lookup_strategy: IpLookupStrategy::Ipv4thenIpv6,
+        implicit_mx: true,
+        srv_lookup: false,
     };
-    let hosts = mx.to_remote_hosts("domain", &mx_config).unwrap();
+    let hosts = mx.to_remote_hosts("domain", &mx_config, None).unwrap();
     assert_eq!(hosts.len(), 7);
     for host in hosts {
         if let NextHop::MX { host, .. } = host {
@@ -230,7 +290,38 @@ fn to_remote_hosts() {
         exchanges: vec![".".to_string()],
         preference: 0,
     }];
-    assert!(mx.to_remote_hosts("domain", &mx_config).is_none());
+    assert!(mx.to_remote_hosts("domain", &mx_config, None).is_none());
+
+    // Hosts sharing a preference rotate deterministically under a seed,
+    // and the host that failed last is tried after its peers
+    let mx = vec![
+        MX {
+            exchanges: vec!["mx1".to_string(), "mx2".to_string(), "mx3".to_string()],
+            preference: 10,
+        },
+        MX {
+            exchanges: vec!["mx4".to_string()],
+            preference: 20,
+        },
+    ];
+    let hostnames = |last_failed: Option<&str>| {
+        mx.to_remote_hosts("domain", &mx_config, last_failed)
+            .unwrap()
+            .iter()
+            .map(|host| host.hostname().to_string())
+            .collect::<Vec<_>>()
+    };
+    MX_SHUFFLE_SEED.store(1234, Ordering::Relaxed);
+    let hosts = hostnames(None);
+    assert_eq!(hosts, hostnames(None));
+    assert_eq!(hosts.last().unwrap(), "mx4");
+    for last_failed in ["mx1", "mx2", "mx3"] {
+        let hosts = hostnames(Some(last_failed));
+        assert_eq!(hosts.len(), 4);
+        assert_eq!(hosts[2], last_failed);
+        assert_eq!(hosts[3], "mx4");
+    }
+    MX_SHUFFLE_SEED.store(0, Ordering::Relaxed);
 }
 
 #[test]
@@ -14,7 +14,7 @@ use common::{
     Core, Data, Inner, Server,
     config::{
         server::{Listeners, ServerProtocol},
-        smtp::resolver::Tlsa,
+        smtp::resolver::{Policy, Srv, Tlsa},
         spamfilter::IpResolver,
     },
     ipc::{QueueEvent, ReportingEvent},
@@ -316,6 +316,19 @@ pub trait DnsCache {
         value: Arc<Tlsa>,
         valid_until: std::time::Instant,
     );
+    fn mta_sts_add(&self, domain: &str, value: Arc<Policy>, valid_until: std::time::Instant);
+    fn srv_add<'x>(
+        &self,
+        name: impl IntoFqdn<'x>,
+        value: Arc<Srv>,
+        valid_until: std::time::Instant,
+    );
+    fn soa_add(
+        &self,
+        name: &str,
+        negative_ttl: std::time::Duration,
+        valid_until: std::time::Instant,
+    );
 }
 
 impl DnsCache for Server {
@@ -388,6 +401,32 @@ impl DnsCache for Server {
         );
     }
 
+    fn srv_add<'x>(
+        &self,
+        name: impl IntoFqdn<'x>,
+        value: Arc<Srv>,
+        valid_until: std::time::Instant,
+    ) {
+        self.inner.cache.dns_srv.insert_with_expiry(
+            name.into_fqdn().into_owned(),
+            value,
+            valid_until,
+        );
+    }
+
+    fn soa_add(
+        &self,
+        name: &str,
+        negative_ttl: std::time::Duration,
+        valid_until: std::time::Instant,
+    ) {
+        self.inner.cache.dns_soa.insert_with_expiry(
+            name.to_string(),
+            negative_ttl.as_secs(),
+            valid_until,
+        );
+    }
+
     fn tlsa_add<'x>(
         &self,
         name: impl IntoFqdn<'x>,
@@ -400,4 +439,11 @@ impl DnsCache for Server {
             valid_until,
         );
     }
+
+    fn mta_sts_add(&self, domain: &str, value: Arc<Policy>, valid_until: std::time::Instant) {
+        self.inner
+            .cache
+            .dbs_mta_sts
+            .insert_with_expiry(domain.to_string(), value, valid_until);
+    }
 }
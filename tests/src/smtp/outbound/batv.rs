@@ -0,0 +1,132 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs LLC <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::time::{Duration, Instant};
+
+use common::config::server::ServerProtocol;
+use mail_auth::MX;
+
+use crate::smtp::{DnsCache, TestSMTP, inbound::TestQueueEvent, session::TestSession};
+
+const LOCAL: &str = r#"
+[session.rcpt]
+relay = true
+
+[auth.batv]
+sign = true
+verify = [{if = "rcpt_domain = 'test.org'", then = true},
+          {else = false}]
+secret = "batv-test-secret"
+max-age = "7d"
+
+[spam-filter]
+enable = false
+"#;
+
+const REMOTE: &str = r#"
+[session.rcpt]
+relay = true
+
+[session.ehlo]
+reject-non-fqdn = false
+
+[session.extensions]
+chunking = false
+
+[spam-filter]
+enable = false
+"#;
+
+#[tokio::test]
+#[serial_test::serial]
+async fn batv_round_trip() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start test server
+    let mut remote = TestSMTP::new("smtp_batv_remote", REMOTE).await;
+    let _rx = remote.start(&[ServerProtocol::Smtp]).await;
+    let mut local = TestSMTP::new("smtp_batv_local", LOCAL).await;
+
+    let core = local.build_smtp();
+    core.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx.foobar.org".into()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.ipv4_add(
+        "mx.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    // Outbound envelope senders are tagged
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    local.queue_receiver.read_event().await.assert_done();
+    let return_path = remote
+        .queue_receiver
+        .expect_message()
+        .await
+        .message
+        .return_path;
+    assert!(
+        return_path.starts_with("prvs=") && return_path.ends_with("=john@test.org"),
+        "{return_path}"
+    );
+    remote.queue_receiver.assert_no_events();
+
+    // Bounces to the tagged address are accepted
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.2".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.foobar.org").await;
+    session.mail_from("<>", "250").await;
+    session.rcpt_to(&return_path, "250").await;
+    assert_eq!(
+        session.data.rcpt_to.last().unwrap().address,
+        "john@test.org"
+    );
+
+    // Bounces to the untagged address or with a forged tag are rejected
+    session.rset().await;
+    session.mail_from("<>", "250").await;
+    session.rcpt_to("john@test.org", "550 5.7.1").await;
+    session
+        .rcpt_to("prvs=0000abcdef=john@test.org", "550 5.7.1")
+        .await;
+    session
+        .rcpt_to("prvs=0abécdefg=john@test.org", "550 5.7.1")
+        .await;
+
+    // The verify rule is evaluated against the recipient being added
+    session.rset().await;
+    session.mail_from("<>", "250").await;
+    session.rcpt_to("jane@foobar.org", "250").await;
+    session.rcpt_to("john@test.org", "550 5.7.1").await;
+    session.rset().await;
+    session.mail_from("<>", "250").await;
+    session.rcpt_to(&return_path, "250").await;
+    session.rcpt_to("jane@foobar.org", "250").await;
+
+    // Regular messages to untagged addresses are not affected
+    session.rset().await;
+    session.mail_from("jane@foobar.org", "250").await;
+    session.rcpt_to("john@test.org", "250").await;
+}
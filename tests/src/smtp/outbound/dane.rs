@@ -123,9 +123,9 @@ async fn dane_verify() {
         .await
         .read_lines(&local.queue_receiver)
         .await
-        .assert_contains("<bill@foobar.org> (DANE failed to authenticate")
-        .assert_contains("No TLSA reco=")
-        .assert_contains("rds found");
+        .assert_contains("<bill@foobar.org> (DANE validation failed")
+        .assert_contains("No TLSA recor=")
+        .assert_contains("ds found)");
     local.queue_receiver.read_event().await.assert_done();
     local.queue_receiver.assert_no_events();
 
@@ -170,17 +170,17 @@ async fn dane_verify() {
         .expect_message_then_deliver()
         .await
         .try_deliver(core.clone());
-    local
-        .queue_receiver
-        .expect_message()
-        .await
-        .read_lines(&local.queue_receiver)
-        .await
-        .assert_contains("<bill@foobar.org> (DANE failed to authenticate")
-        .assert_contains("No matching ")
-        .assert_contains("certificates found");
-    local.queue_receiver.read_event().await.assert_done();
+    local.queue_receiver.read_event().await.assert_refresh();
     local.queue_receiver.assert_no_events();
+    let message = local.queue_receiver.last_queued_message().await;
+    assert_eq!(
+        message.message.recipients[0].status,
+        Status::TemporaryFailure(ErrorDetails {
+            entity: "mx.foobar.org".into(),
+            details: Error::DaneError("No matching certificates found in TLSA records".into())
+        })
+    );
+    local.queue_receiver.clear_queue(&core).await;
 
     // Expect TLS failure report
     let report = local.report_receiver.read_report().await.unwrap_tls();
@@ -249,6 +249,7 @@ async fn dane_test() {
                 .with_options(opts)
                 .build(),
         },
+        negative_ttl: Duration::ZERO,
     };
     let r = TestSMTP::from_core(core).build_smtp();
 
@@ -343,7 +344,7 @@ async fn dane_test() {
         certs.remove(0);
         assert_eq!(
             tlsa.verify(0, &host, Some(&certs)),
-            Err(Status::PermanentFailure(ErrorDetails {
+            Err(Status::TemporaryFailure(ErrorDetails {
                 entity: host,
                 details: Error::DaneError("No matching certificates found in TLSA records".into())
             }))
@@ -0,0 +1,159 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs LLC <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::time::{Duration, Instant};
+
+use common::config::{server::ServerProtocol, smtp::queue::QueueName};
+use mail_auth::MX;
+use smtp::{
+    outbound::DeliveryStep,
+    queue::{Status, spool::SmtpSpool},
+};
+
+use crate::smtp::{DnsCache, TestSMTP, session::TestSession};
+
+const LOCAL: &str = r#"
+[session.rcpt]
+relay = true
+
+[spam-filter]
+enable = false
+"#;
+
+const REMOTE: &str = r#"
+[session.rcpt]
+relay = true
+
+[session.ehlo]
+reject-non-fqdn = false
+
+[session.extensions]
+chunking = false
+
+[spam-filter]
+enable = false
+"#;
+
+#[tokio::test]
+#[serial_test::serial]
+async fn dry_run() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start test server
+    let mut remote = TestSMTP::new("smtp_dry_run_remote", REMOTE).await;
+    let _rx = remote.start(&[ServerProtocol::Smtp]).await;
+
+    // Add mock DNS entries, foobar.net is unreachable
+    let mut local = TestSMTP::new("smtp_dry_run_local", LOCAL).await;
+    let core = local.build_smtp();
+    for (domain, ip) in [("foobar.org", "127.0.0.1"), ("foobar.net", "127.0.0.2")] {
+        core.mx_add(
+            domain,
+            vec![MX {
+                exchanges: vec![format!("mx.{domain}")],
+                preference: 10,
+            }],
+            Instant::now() + Duration::from_secs(10),
+        );
+        core.ipv4_add(
+            &format!("mx.{domain}"),
+            vec![ip.parse().unwrap()],
+            Instant::now() + Duration::from_secs(10),
+        );
+    }
+
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message(
+            "john@test.org",
+            &["bill@foobar.org", "jane@foobar.net"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    let queue_id = local.queue_receiver.expect_message().await.queue_id;
+
+    // Each step up to EHLO is reported
+    let report = core.test_deliver(queue_id).await.unwrap();
+    let steps = &report.steps;
+    assert!(
+        steps.contains(&DeliveryStep::NextHop {
+            domain: "foobar.org".into(),
+            hosts: vec!["mx.foobar.org".into()],
+        }),
+        "{steps:#?}"
+    );
+    assert!(
+        steps.contains(&DeliveryStep::Connect {
+            hostname: "mx.foobar.org".into(),
+            remote_ip: "127.0.0.1".parse().unwrap(),
+            result: Ok(()),
+        }),
+        "{steps:#?}"
+    );
+    assert!(
+        steps.iter().any(|step| matches!(
+            step,
+            DeliveryStep::Tls { hostname, details }
+                if hostname == "mx.foobar.org" && details.version == "TLSv1.3"
+        )),
+        "{steps:#?}"
+    );
+    assert!(
+        steps.iter().any(|step| matches!(
+            step,
+            DeliveryStep::Ehlo { hostname, capabilities }
+                if hostname == "mx.foobar.org" && !capabilities.is_empty()
+        )),
+        "{steps:#?}"
+    );
+    assert!(
+        steps.iter().any(|step| matches!(
+            step,
+            DeliveryStep::Rset { hostname, result: Ok(response) }
+                if hostname == "mx.foobar.org" && response.code == 250
+        )),
+        "{steps:#?}"
+    );
+
+    // Unreachable hosts are reported as failures
+    assert!(
+        steps.iter().any(|step| matches!(
+            step,
+            DeliveryStep::Connect { hostname, result: Err(_), .. } if hostname == "mx.foobar.net"
+        )),
+        "{steps:#?}"
+    );
+    assert!(
+        steps.iter().any(|step| matches!(
+            step,
+            DeliveryStep::Failed { entity, .. } if entity == "mx.foobar.net"
+        )),
+        "{steps:#?}"
+    );
+
+    // The message is neither sent nor modified
+    remote.queue_receiver.assert_no_events();
+    local.queue_receiver.assert_no_events();
+    let message = core
+        .read_message(queue_id, QueueName::default())
+        .await
+        .expect("Message not found in queue");
+    assert!(
+        message
+            .message
+            .recipients
+            .iter()
+            .all(|rcpt| rcpt.status == Status::Scheduled && rcpt.retry.inner == 0),
+        "{:?}",
+        message.message.recipients
+    );
+    local.queue_receiver.clear_queue(&core).await;
+}
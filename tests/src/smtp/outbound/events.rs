@@ -0,0 +1,690 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs LLC <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::time::{Duration, Instant};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use common::{
+    config::{server::ServerProtocol, telemetry::Telemetry},
+    manager::webadmin::Resource,
+    telemetry::tracers::otel::SpanCollector,
+};
+use http_proto::{ToHttpResponse, request::fetch_body};
+use hyper::{body, server::conn::http1, service::service_fn};
+use hyper_util::rt::TokioIo;
+use mail_auth::MX;
+use ring::hmac;
+use store::Stores;
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, watch},
+};
+use trc::{
+    Collector, DeliveryEvent, EventType, Key, SmtpEvent,
+    ipc::subscriber::{Interests, SubscriberBuilder},
+    serializers::text::FmtWriter,
+};
+use utils::config::Config;
+
+use crate::smtp::{DnsCache, TestSMTP, session::TestSession};
+
+const LOCAL: &str = r#"
+[session.rcpt]
+relay = true
+"#;
+
+const REMOTE: &str = r#"
+[session.ehlo]
+reject-non-fqdn = false
+
+[session.rcpt]
+relay = true
+"#;
+
+const REMOTE_REJECT: &str = r#"
+[session.ehlo]
+reject-non-fqdn = false
+
+[session.rcpt]
+relay = false
+errors.wait = "5ms"
+"#;
+
+const WEBHOOK: &str = r#"
+[webhook."delivery"]
+url = "http://127.0.0.1:8822/hook"
+events = ["delivery.delivered", "delivery.dsn-perm-fail"]
+signature-key = "ovos-moles"
+throttle = "100ms"
+"#;
+
+const METRICS: &str = r#"
+[metrics.prometheus]
+enable = true
+"#;
+
+#[tokio::test]
+#[serial_test::serial]
+async fn delivery_events() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Subscribe to delivery outcomes
+    let mut interests = Interests::default();
+    interests.set(EventType::Delivery(DeliveryEvent::Delivered));
+    let (_tx, mut events_rx) = SubscriberBuilder::new("delivery-events".to_string())
+        .with_interests(interests.clone())
+        .with_lossy(false)
+        .register();
+    Collector::union_interests(interests);
+    Collector::reload();
+
+    // Start test server
+    let mut remote = TestSMTP::new("smtp_events_remote", REMOTE).await;
+    let _rx = remote.start(&[ServerProtocol::Smtp]).await;
+
+    // Add mock DNS entries
+    let mut local = TestSMTP::new("smtp_events_local", LOCAL).await;
+    let core = local.build_smtp();
+    core.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx.foobar.org".to_string()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.ipv4_add(
+        "mx.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    // Deliver a message
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    let attempt = local.queue_receiver.expect_message_then_deliver().await;
+    let queue_id = attempt.queue_id;
+    attempt.try_deliver(core.clone());
+    remote.queue_receiver.expect_message().await;
+
+    // Each delivered recipient is reported with its queue id, host and response
+    let event = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let batch = events_rx.recv().await.expect("Subscriber closed");
+            if let Some(event) = batch
+                .into_iter()
+                .find(|event| event.value_as_uint(Key::QueueId) == Some(queue_id))
+            {
+                break event;
+            }
+        }
+    })
+    .await
+    .expect("No delivery event received");
+
+    assert_eq!(
+        event.inner.typ,
+        EventType::Delivery(DeliveryEvent::Delivered)
+    );
+    assert_eq!(event.value_as_str(Key::Hostname), Some("mx.foobar.org"));
+    assert_eq!(event.value_as_str(Key::To), Some("bill@foobar.org"));
+    assert_eq!(event.value_as_uint(Key::Code), Some(250));
+    assert_eq!(event.value_as_str(Key::Version), Some("TLSv1.3"));
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn delivery_failure_events() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Subscribe to failed recipients
+    let mut interests = Interests::default();
+    interests.set(EventType::Delivery(DeliveryEvent::RcptToRejected));
+    let (_tx, mut events_rx) = SubscriberBuilder::new("delivery-failure-events".to_string())
+        .with_interests(interests.clone())
+        .with_lossy(false)
+        .register();
+    Collector::union_interests(interests);
+    Collector::reload();
+
+    // Start test server that does not accept any recipients
+    let remote = TestSMTP::new("smtp_failure_events_remote", REMOTE_REJECT).await;
+    let _rx = remote.start(&[ServerProtocol::Smtp]).await;
+
+    // Add mock DNS entries
+    let mut local = TestSMTP::new("smtp_failure_events_local", LOCAL).await;
+    let core = local.build_smtp();
+    core.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx.foobar.org".to_string()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.ipv4_add(
+        "mx.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    // Attempt delivery
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    let attempt = local.queue_receiver.expect_message_then_deliver().await;
+    let queue_id = attempt.queue_id;
+    attempt.try_deliver(core.clone());
+
+    // The rejected recipient is reported with its queue id, TLS version and duration
+    let event = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let batch = events_rx.recv().await.expect("Subscriber closed");
+            if let Some(event) = batch
+                .into_iter()
+                .find(|event| event.value_as_uint(Key::QueueId) == Some(queue_id))
+            {
+                break event;
+            }
+        }
+    })
+    .await
+    .expect("No delivery event received");
+
+    assert_eq!(
+        event.inner.typ,
+        EventType::Delivery(DeliveryEvent::RcptToRejected)
+    );
+    assert_eq!(event.value_as_str(Key::Hostname), Some("mx.foobar.org"));
+    assert_eq!(event.value_as_str(Key::To), Some("bill@foobar.org"));
+    assert_eq!(event.value_as_uint(Key::Code), Some(550));
+    assert_eq!(event.value_as_str(Key::Version), Some("TLSv1.3"));
+    assert!(event.value(Key::Elapsed).is_some());
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn delivery_webhook() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start the webhook endpoint and subscribe it to delivery outcomes
+    let (_tx, mut webhook_rx) = spawn_mock_webhook_endpoint(8822);
+    subscribe_webhooks(WEBHOOK);
+
+    // Start test server
+    let mut remote = TestSMTP::new("smtp_webhook_remote", REMOTE).await;
+    let _rx = remote.start(&[ServerProtocol::Smtp]).await;
+
+    // Add mock DNS entries
+    let mut local = TestSMTP::new("smtp_webhook_local", LOCAL).await;
+    let core = local.build_smtp();
+    core.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx.foobar.org".to_string()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.ipv4_add(
+        "mx.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    // Deliver a message to one valid and one rejected recipient
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message(
+            "john@test.org",
+            &["ok@foobar.org", "fail@foobar.org"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    let attempt = local.queue_receiver.expect_message_then_deliver().await;
+    let queue_id = attempt.queue_id;
+    attempt.try_deliver(core.clone());
+    remote.queue_receiver.expect_message().await;
+
+    // Wait for both outcomes to be posted
+    let mut delivered = None;
+    let mut bounced = None;
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while delivered.is_none() || bounced.is_none() {
+            let event = webhook_rx.recv().await.expect("Webhook endpoint closed");
+            if event["data"]["queueId"].as_u64() != Some(queue_id) {
+                continue;
+            }
+            match event["type"].as_str() {
+                Some("delivery.delivered") => delivered = Some(event),
+                Some("delivery.dsn-perm-fail") => bounced = Some(event),
+                _ => {}
+            }
+        }
+    })
+    .await
+    .expect("Webhook events not received");
+
+    let delivered = delivered.unwrap();
+    assert!(delivered["createdAt"].is_string());
+    assert_eq!(delivered["data"]["to"], "ok@foobar.org");
+    assert_eq!(delivered["data"]["hostname"], "mx.foobar.org");
+    assert_eq!(delivered["data"]["code"], 250);
+    assert!(delivered["data"]["details"].is_string());
+
+    let bounced = bounced.unwrap();
+    assert!(bounced["createdAt"].is_string());
+    assert_eq!(bounced["data"]["to"], "fail@foobar.org");
+    assert_eq!(bounced["data"]["hostname"], "mx.foobar.org");
+    assert!(bounced["data"]["details"].is_string());
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn delivery_metrics() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Enable metrics collection
+    Collector::set_metrics(
+        Telemetry::parse(&mut Config::new(METRICS).unwrap(), &Stores::default()).metrics,
+    );
+
+    // Start test server
+    let mut remote = TestSMTP::new("smtp_metrics_remote", REMOTE).await;
+    let _rx = remote.start(&[ServerProtocol::Smtp]).await;
+
+    // Add mock DNS entries
+    let mut local = TestSMTP::new("smtp_metrics_local", LOCAL).await;
+    let core = local.build_smtp();
+    core.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx.foobar.org".to_string()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.ipv4_add(
+        "mx.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+    let metrics = local.server.export_prometheus_metrics().await.unwrap();
+    let delivered = prometheus_value(&metrics, "delivery_delivered");
+    let delivery_time = prometheus_value(&metrics, "delivery_total_time_count");
+
+    // Deliver a message
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    remote.queue_receiver.expect_message().await;
+
+    // The delivery counter and latency histogram are updated once the attempt completes
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let metrics = local.server.export_prometheus_metrics().await.unwrap();
+            if prometheus_value(&metrics, "delivery_delivered") > delivered
+                && prometheus_value(&metrics, "delivery_total_time_count") > delivery_time
+            {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .expect("Delivery metrics were not updated");
+}
+
+fn prometheus_value(metrics: &str, name: &str) -> f64 {
+    metrics
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .find_map(|line| {
+            line.strip_prefix(name)
+                .and_then(|value| value.strip_prefix(' '))
+                .and_then(|value| value.trim().parse().ok())
+        })
+        .unwrap_or_default()
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn delivery_spans() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Subscribe to all events
+    let mut interests = Interests::default();
+    for event in EventType::variants() {
+        interests.set(event);
+    }
+    let (_tx, mut events_rx) = SubscriberBuilder::new("delivery-spans".to_string())
+        .with_interests(interests.clone())
+        .with_lossy(false)
+        .register();
+    Collector::union_interests(interests);
+    Collector::reload();
+
+    // Start test server
+    let mut remote = TestSMTP::new("smtp_spans_remote", REMOTE).await;
+    let _rx = remote.start(&[ServerProtocol::Smtp]).await;
+
+    // Add mock DNS entries
+    let mut local = TestSMTP::new("smtp_spans_local", LOCAL).await;
+    let core = local.build_smtp();
+    core.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx.foobar.org".to_string()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.ipv4_add(
+        "mx.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    // Receive a message within an inbound session span, then deliver it
+    let session_id = local.server.inner.data.span_id_gen.generate();
+    let mut session = local.new_session();
+    session.data.session_id = session_id;
+    session.data.remote_ip_str = "10.0.0.1".into();
+    trc::event!(Smtp(SmtpEvent::ConnectionStart), SpanId = session_id);
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    trc::event!(Smtp(SmtpEvent::ConnectionEnd), SpanId = session_id);
+    let attempt = local.queue_receiver.expect_message_then_deliver().await;
+    let queue_id = attempt.queue_id;
+    attempt.try_deliver(core.clone());
+    remote.queue_receiver.expect_message().await;
+
+    // Build the spans of the inbound session and the delivery attempt
+    let mut collector = SpanCollector::default();
+    let mut spans = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            for event in events_rx.recv().await.expect("Subscriber closed") {
+                collector.ingest(event, &mut spans);
+            }
+            spans.retain(|span| {
+                let trace_id = u128::from_be_bytes(span.span_context.trace_id().to_bytes());
+                trace_id as u64 == queue_id || trace_id == session_id as u128
+            });
+            if spans
+                .iter()
+                .any(|span| span.name == "delivery.attempt-start")
+                && spans
+                    .iter()
+                    .any(|span| span.name == "smtp.connection-start")
+            {
+                break;
+            }
+        }
+    })
+    .await
+    .expect("Spans were not received");
+
+    // The message trace starts with the queued message and contains the delivery
+    // attempt, while the session has its own trace linked to the message
+    let span = |name: &str| spans.iter().find(|span| span.name == name).unwrap();
+    let session = span("smtp.connection-start");
+    let queued = span("queue.queue-message");
+    let delivery = span("delivery.attempt-start");
+    assert_eq!(u64::from_be_bytes(queued.parent_span_id.to_bytes()), 0);
+    assert_eq!(delivery.parent_span_id, queued.span_context.span_id());
+    assert_eq!(
+        queued.span_context.trace_id(),
+        delivery.span_context.trace_id()
+    );
+    assert_ne!(
+        session.span_context.trace_id(),
+        queued.span_context.trace_id()
+    );
+    assert_ne!(
+        u64::from_be_bytes(queued.span_context.span_id().to_bytes()),
+        queue_id
+    );
+    assert_eq!(u64::from_be_bytes(session.parent_span_id.to_bytes()), 0);
+    assert_eq!(
+        session
+            .links
+            .iter()
+            .map(|link| &link.span_context)
+            .collect::<Vec<_>>(),
+        vec![&queued.span_context]
+    );
+    assert_eq!(
+        queued
+            .links
+            .iter()
+            .map(|link| &link.span_context)
+            .collect::<Vec<_>>(),
+        vec![&session.span_context]
+    );
+
+    let attribute = |name: &str| {
+        delivery
+            .attributes
+            .iter()
+            .find(|kv| kv.key.as_str() == name)
+            .map(|kv| kv.value.as_str().into_owned())
+    };
+    assert_eq!(attribute("rcpt_domain").as_deref(), Some("foobar.org"));
+    assert_eq!(attribute("mx_host").as_deref(), Some("mx.foobar.org"));
+    assert_eq!(attribute("smtp_status").as_deref(), Some("250"));
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn delivery_json_logs() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Subscribe to delivery events
+    let mut interests = Interests::default();
+    for event in EventType::variants() {
+        if matches!(event, EventType::Delivery(_)) {
+            interests.set(event);
+        }
+    }
+    let (_tx, mut events_rx) = SubscriberBuilder::new("delivery-logs".to_string())
+        .with_interests(interests.clone())
+        .with_lossy(false)
+        .register();
+    Collector::union_interests(interests);
+    Collector::reload();
+
+    // Start test server
+    let mut remote = TestSMTP::new("smtp_logs_remote", REMOTE).await;
+    let _rx = remote.start(&[ServerProtocol::Smtp]).await;
+
+    // Add mock DNS entries
+    let mut local = TestSMTP::new("smtp_logs_local", LOCAL).await;
+    let core = local.build_smtp();
+    core.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx.foobar.org".to_string()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.ipv4_add(
+        "mx.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    // Deliver a message
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    let attempt = local.queue_receiver.expect_message_then_deliver().await;
+    let queue_id = attempt.queue_id;
+    attempt.try_deliver(core.clone());
+    remote.queue_receiver.expect_message().await;
+
+    // Log the delivery attempt as JSON lines
+    let mut writer = FmtWriter::new(Vec::new()).with_json(true);
+    let mut span_id = None;
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            for event in events_rx.recv().await.expect("Subscriber closed") {
+                if event.inner.typ == EventType::Delivery(DeliveryEvent::AttemptStart)
+                    && event.value_as_uint(Key::QueueId) == Some(queue_id)
+                {
+                    span_id = event.span_id();
+                }
+                if span_id.is_some() && event.span_id() == span_id {
+                    writer.write(&event).await.unwrap();
+                    if event.inner.typ == EventType::Delivery(DeliveryEvent::AttemptEnd) {
+                        return;
+                    }
+                }
+            }
+        }
+    })
+    .await
+    .expect("Delivery attempt did not complete");
+
+    // Every line of the attempt carries the session and queue ids
+    let logs = String::from_utf8(writer.into_inner()).unwrap();
+    let lines = logs
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert!(lines.len() > 2, "Unexpected log output: {logs}");
+    for line in &lines {
+        assert!(line["level"].is_string(), "Missing level: {line}");
+        assert_eq!(line["data"]["spanId"].as_u64(), span_id, "{line}");
+        assert_eq!(line["data"]["queueId"].as_u64(), Some(queue_id), "{line}");
+    }
+    assert!(
+        lines
+            .iter()
+            .any(|line| line["type"] == "delivery.delivered")
+    );
+}
+
+pub fn subscribe_webhooks(config: &str) {
+    let mut config = Config::new(config).unwrap();
+    for tracer in Telemetry::parse(&mut config, &Stores::default())
+        .tracers
+        .subscribers
+    {
+        Collector::union_interests(tracer.interests.clone());
+        tracer.typ.spawn(
+            SubscriberBuilder::new(tracer.id)
+                .with_interests(tracer.interests)
+                .with_lossy(false),
+            false,
+        );
+    }
+    Collector::reload();
+}
+
+pub fn spawn_mock_webhook_endpoint(
+    port: u16,
+) -> (
+    watch::Sender<bool>,
+    mpsc::UnboundedReceiver<serde_json::Value>,
+) {
+    let (tx, mut rx) = watch::channel(true);
+    let (events_tx, events_rx) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        let listener = TcpListener::bind(format!("127.0.0.1:{port}"))
+            .await
+            .unwrap_or_else(|e| {
+                panic!("Failed to bind mock Webhooks server to 127.0.0.1:{port}: {e}");
+            });
+
+        loop {
+            tokio::select! {
+                stream = listener.accept() => {
+                    let (stream, _) = stream.expect("Failed to accept connection");
+                    let events_tx = events_tx.clone();
+                    let _ = http1::Builder::new()
+                        .keep_alive(false)
+                        .serve_connection(
+                            TokioIo::new(stream),
+                            service_fn(move |mut req: hyper::Request<body::Incoming>| {
+                                let events_tx = events_tx.clone();
+
+                                async move {
+                                    // Verify HMAC signature
+                                    let key = hmac::Key::new(hmac::HMAC_SHA256, b"ovos-moles");
+                                    let body = fetch_body(&mut req, usize::MAX, 0).await.unwrap();
+                                    let tag = STANDARD
+                                        .decode(req.headers().get("X-Signature").unwrap().to_str().unwrap())
+                                        .unwrap();
+                                    hmac::verify(&key, &body, &tag).expect("Invalid signature");
+
+                                    #[derive(serde::Deserialize)]
+                                    struct WebhookRequest {
+                                        events: Vec<serde_json::Value>,
+                                    }
+                                    for event in serde_json::from_slice::<WebhookRequest>(&body)
+                                        .expect("Failed to parse JSON")
+                                        .events
+                                    {
+                                        let _ = events_tx.send(event);
+                                    }
+
+                                    Ok::<_, hyper::Error>(
+                                        Resource::new("application/json", "[]".to_string().into_bytes())
+                                            .into_http_response()
+                                            .build(),
+                                    )
+                                }
+                            }),
+                        )
+                        .await;
+                }
+                _ = rx.changed() => {
+                    break;
+                }
+            };
+        }
+    });
+
+    (tx, events_rx)
+}
@@ -6,15 +6,30 @@
 
 use std::time::{Duration, Instant};
 
-use common::config::server::ServerProtocol;
+use common::{
+    Server,
+    config::{server::ServerProtocol, smtp::queue::QueueName},
+};
 use mail_auth::MX;
+use mail_parser::MessageParser;
+use smtp::queue::{Error, ErrorDetails, Status, spool::SmtpSpool};
 use smtp_proto::{MAIL_REQUIRETLS, MAIL_RET_HDRS, MAIL_SMTPUTF8, RCPT_NOTIFY_NEVER};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+    sync::mpsc,
+};
+use trc::{
+    Collector, DeliveryEvent, EventType, Key,
+    ipc::subscriber::{Interests, SubscriberBuilder},
+};
 
 use crate::smtp::{
     DnsCache, TestSMTP,
     inbound::{TestMessage, TestQueueEvent},
     session::{TestSession, VerifyResponse},
 };
+use smtp::queue::TLS_OPTIONAL;
 
 const LOCAL: &str = r#"
 [session.rcpt]
@@ -47,6 +62,63 @@ date = true
 return-path = false
 "#;
 
+const REMOTE_NO_REQUIRETLS: &str = r#"
+[session.ehlo]
+reject-non-fqdn = false
+
+[session.rcpt]
+relay = true
+
+[session.extensions]
+requiretls = false
+"#;
+
+const LOCAL_CHUNKING: &str = r#"
+[session.rcpt]
+relay = true
+
+[queue.connection.default]
+chunk-size = 128
+"#;
+
+const LOCAL_8BITMIME_DEFER: &str = r#"
+[session.rcpt]
+relay = true
+
+[queue.outbound]
+8bitmime = "defer"
+"#;
+
+const LOCAL_PIPELINING: &str = r#"
+[session.rcpt]
+relay = true
+
+[queue.connection.default]
+timeout.mail-from = "2s"
+timeout.rcpt-to = "2s"
+"#;
+
+const MESSAGE_8BIT: &str = "From: john@test.org\r
+To: bill@foobar.org\r
+Subject: Dessert\r
+MIME-Version: 1.0\r
+Content-Type: text/plain; charset=utf-8\r
+Content-Transfer-Encoding: 8bit\r
+\r
+Café crème brûlée.\r
+";
+
+const REMOTE_CHUNKING: &str = r#"
+[session.ehlo]
+reject-non-fqdn = false
+
+[session.rcpt]
+relay = true
+
+[session.extensions]
+chunking = true
+"#;
+
 #[tokio::test]
 #[serial_test::serial]
 async fn extensions() {
@@ -112,7 +184,7 @@ async fn extensions() {
         .await
         .assert_contains("using TLSv1.3 with cipher");
 
-    // Test SIZE extension
+    // Messages exceeding the remote SIZE limit are bounced before MAIL FROM
     session
         .send_message("john@test.org", &["bill@foobar.org"], "test:arc", "250")
         .await;
@@ -127,10 +199,10 @@ async fn extensions() {
         .await
         .read_lines(&local.queue_receiver)
         .await
-        .assert_contains("<bill@foobar.org> (host 'mx.foobar.org' rejected command 'MAIL FROM:")
+        .assert_contains("<bill@foobar.org> (message exceeds the size limit of 1500 bytes advertised b=")
         .assert_contains("Action: failed")
-        .assert_contains("Diagnostic-Code: smtp;552")
-        .assert_contains("Status: 5.3.4");
+        .assert_contains("Status: 5.3.4")
+        .assert_not_contains("rejected command 'MAIL FROM:");
     local.queue_receiver.read_event().await.assert_done();
     remote.queue_receiver.assert_no_events();
 
@@ -156,3 +228,598 @@ async fn extensions() {
     assert!((message.message.flags & MAIL_SMTPUTF8) != 0);
     assert!((message.message.recipients.last().unwrap().flags & RCPT_NOTIFY_NEVER) != 0);
 }
+
+#[tokio::test]
+#[serial_test::serial]
+async fn requiretls() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start test server
+    let mut remote = TestSMTP::new("smtp_requiretls_remote", REMOTE_NO_REQUIRETLS).await;
+    let _rx = remote.start(&[ServerProtocol::Smtp]).await;
+
+    // Add mock DNS entries
+    let mut local = TestSMTP::new("smtp_requiretls_local", LOCAL).await;
+    let core = local.build_smtp();
+    core.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx.foobar.org".to_string()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.ipv4_add(
+        "mx.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    // REQUIRETLS messages must not be relayed to hosts that do not support it
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message(
+            "<john@test.org> REQUIRETLS",
+            &["bill@foobar.org"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    local
+        .queue_receiver
+        .expect_message()
+        .await
+        .read_lines(&local.queue_receiver)
+        .await
+        .assert_contains("<bill@foobar.org> (TLS error from 'mx.foobar.org'")
+        .assert_contains("REQUIRETLS not advertised=")
+        .assert_contains("Action: failed");
+    local.queue_receiver.read_event().await.assert_done();
+    remote.queue_receiver.assert_no_events();
+
+    // TLS-Required: No is recorded on the queued message
+    session
+        .send_message(
+            "john@test.org",
+            &["bill@foobar.org"],
+            concat!(
+                "From: john@test.org\r\n",
+                "To: bill@foobar.org\r\n",
+                "TLS-Required: No\r\n",
+                "Subject: Not so secret\r\n",
+                "\r\n",
+                "Deliver even if TLS policies cannot be met."
+            ),
+            "250",
+        )
+        .await;
+    let message = local.queue_receiver.expect_message().await;
+    assert_ne!(message.message.flags & TLS_OPTIONAL, 0);
+    local
+        .queue_receiver
+        .delivery_attempt(message.queue_id)
+        .await
+        .try_deliver(core.clone());
+    local.queue_receiver.read_event().await.assert_done();
+    remote.queue_receiver.expect_message().await;
+
+    // The header is ignored when REQUIRETLS is requested
+    session
+        .send_message(
+            "<john@test.org> REQUIRETLS",
+            &["bill@foobar.org"],
+            concat!(
+                "From: john@test.org\r\n",
+                "To: bill@foobar.org\r\n",
+                "TLS-Required: No\r\n",
+                "Subject: Secret\r\n",
+                "\r\n",
+                "This one requires TLS."
+            ),
+            "250",
+        )
+        .await;
+    let message = local.queue_receiver.expect_message().await;
+    assert_eq!(message.message.flags & TLS_OPTIONAL, 0);
+    local.queue_receiver.clear_queue(&core).await;
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn chunking() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Subscribe to the raw SMTP output
+    let mut interests = Interests::default();
+    interests.set(EventType::Delivery(DeliveryEvent::RawOutput));
+    let (_tx, mut events_rx) = SubscriberBuilder::new("chunking-events".to_string())
+        .with_interests(interests.clone())
+        .with_lossy(false)
+        .register();
+    Collector::union_interests(interests);
+    Collector::reload();
+
+    // Start test server
+    let mut remote = TestSMTP::new("smtp_chunking_remote", REMOTE_CHUNKING).await;
+    let _rx = remote.start(&[ServerProtocol::Smtp]).await;
+
+    // Add mock DNS entries
+    let mut local = TestSMTP::new("smtp_chunking_local", LOCAL_CHUNKING).await;
+    let core = local.build_smtp();
+    core.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx.foobar.org".to_string()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.ipv4_add(
+        "mx.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    // Messages larger than the chunk size are sent using multiple BDAT commands
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    let message = local.queue_receiver.expect_message().await;
+    let size = message.message.size;
+    local
+        .queue_receiver
+        .delivery_attempt(message.queue_id)
+        .await
+        .try_deliver(core.clone());
+    let commands = tokio::time::timeout(Duration::from_secs(5), async {
+        let mut commands = Vec::new();
+        loop {
+            let batch = events_rx.recv().await.expect("Subscriber closed");
+            for event in batch {
+                if let Some(cmd) = event
+                    .value_as_str(Key::Contents)
+                    .filter(|cmd| cmd.starts_with("BDAT "))
+                {
+                    commands.push(cmd.to_string());
+                    if cmd.ends_with(" LAST\r\n") {
+                        return commands;
+                    }
+                }
+            }
+        }
+    })
+    .await
+    .expect("No BDAT LAST command received");
+    local.queue_receiver.read_event().await.assert_done();
+    remote
+        .queue_receiver
+        .expect_message()
+        .await
+        .read_lines(&remote.queue_receiver)
+        .await
+        .assert_contains("We lost the game. Are you hungry yet?")
+        .assert_contains("Joe.");
+
+    assert_eq!(commands.len() as u64, size.div_ceil(128));
+    let mut total = 0;
+    for (pos, cmd) in commands.iter().enumerate() {
+        let mut parts = cmd.trim_end().split(' ').skip(1);
+        let chunk_size = parts.next().unwrap().parse::<u64>().unwrap();
+        if pos + 1 < commands.len() {
+            assert_eq!(chunk_size, 128, "{cmd:?}");
+            assert_eq!(parts.next(), None, "{cmd:?}");
+        } else {
+            assert_eq!(parts.next(), Some("LAST"), "{cmd:?}");
+        }
+        total += chunk_size;
+    }
+    assert_eq!(total, size);
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn smtputf8() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start test server
+    let mut remote = TestSMTP::new("smtp_utf8_remote", REMOTE_NO_REQUIRETLS).await;
+    let _rx = remote.start(&[ServerProtocol::Smtp]).await;
+
+    // Add mock DNS entries
+    let mut local = TestSMTP::new("smtp_utf8_local", LOCAL).await;
+    let core = local.build_smtp();
+    core.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx.foobar.org".to_string()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.ipv4_add(
+        "mx.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    // Internationalized addresses are relayed using SMTPUTF8
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message(
+            "<john@test.org> SMTPUTF8",
+            &["jöhn@foobar.org"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    local.queue_receiver.read_event().await.assert_done();
+    let message = remote.queue_receiver.expect_message().await;
+    assert_ne!(message.message.flags & MAIL_SMTPUTF8, 0);
+    assert_eq!(message.message.recipients[0].address, "jöhn@foobar.org");
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn smtputf8_not_supported() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start a mock server that does not advertise SMTPUTF8
+    let listener = TcpListener::bind("127.0.0.1:9925").await.unwrap();
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            writer.write_all(b"220 mx.foobar.org ESMTP\r\n").await.unwrap();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let response: &[u8] = if line.starts_with("EHLO") {
+                    b"250-mx.foobar.org\r\n250 8BITMIME\r\n"
+                } else if line.starts_with("DATA") {
+                    writer.write_all(b"354 Start mail input\r\n").await.unwrap();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        if line == "." {
+                            break;
+                        }
+                    }
+                    b"250 2.0.0 Message queued\r\n"
+                } else if line.starts_with("QUIT") {
+                    b"221 2.0.0 Bye\r\n"
+                } else {
+                    if line.starts_with("RCPT TO") {
+                        tx.send(line).unwrap();
+                    }
+                    b"250 2.0.0 OK\r\n"
+                };
+                writer.write_all(response).await.unwrap();
+            }
+        }
+    });
+
+    // Add mock DNS entries
+    let mut local = TestSMTP::new("smtp_utf8_mock_local", LOCAL).await;
+    let core = local.build_smtp();
+    for domain in ["foobar.org", "bücher.org"] {
+        core.mx_add(
+            domain,
+            vec![MX {
+                exchanges: vec!["mx.foobar.org".to_string()],
+                preference: 10,
+            }],
+            Instant::now() + Duration::from_secs(10),
+        );
+    }
+    core.ipv4_add(
+        "mx.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    // Non-ASCII local parts are bounced when SMTPUTF8 is not available,
+    // internationalized domains are sent as A-labels
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message(
+            "<john@test.org> SMTPUTF8",
+            &["jöhn@foobar.org", "jane@bücher.org"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    local
+        .queue_receiver
+        .expect_message()
+        .await
+        .read_lines(&local.queue_receiver)
+        .await
+        .assert_contains("<j=C3=B6hn@foobar.org> (connection to 'mx.foobar.org' failed: SMTPUTF8 not a=")
+        .assert_contains("Final-Recipient: rfc822;j=C3=B6hn@foobar.org")
+        .assert_contains("Action: failed")
+        .assert_not_contains("jane@");
+    local.queue_receiver.read_event().await.assert_done();
+    assert_eq!(rx.recv().await.unwrap(), "RCPT TO:<jane@xn--bcher-kva.org>");
+    assert!(rx.try_recv().is_err());
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn eight_bit_mime_not_supported() {
+    // Enable logging
+    crate::enable_logging();
+
+    // 8-bit messages are downgraded to 7-bit by default
+    let mut rx = start_mock_without_8bitmime().await;
+    let mut local = TestSMTP::new("smtp_8bitmime_mock_local", LOCAL).await;
+    let core = local.build_smtp();
+    add_mock_dns(&core);
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], MESSAGE_8BIT, "250")
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    local.queue_receiver.read_event().await.assert_done();
+    let data = rx.recv().await.expect("Message not received");
+    assert!(data.is_ascii(), "{data}");
+    assert!(
+        data.contains("Content-Transfer-Encoding: quoted-printable"),
+        "{data}"
+    );
+    assert!(!data.contains("Content-Transfer-Encoding: 8bit"), "{data}");
+    let message = MessageParser::new().parse(data.as_bytes()).unwrap();
+    assert_eq!(
+        message.body_text(0).unwrap().trim_end(),
+        "Café crème brûlée."
+    );
+
+    // Delivery is deferred when conversion is disabled
+    let _rx = start_mock_without_8bitmime().await;
+    let mut local = TestSMTP::new("smtp_8bitmime_mock_defer", LOCAL_8BITMIME_DEFER).await;
+    let core = local.build_smtp();
+    add_mock_dns(&core);
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], MESSAGE_8BIT, "250")
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    local.queue_receiver.read_event().await.assert_refresh();
+    local.queue_receiver.assert_no_events();
+    let message = local.queue_receiver.last_queued_message().await;
+    assert_eq!(
+        message.message.recipients[0].status,
+        Status::TemporaryFailure(ErrorDetails {
+            entity: "mx.foobar.org".into(),
+            details: Error::ConnectionError("8BITMIME not advertised by host.".into())
+        })
+    );
+    local.queue_receiver.clear_queue(&core).await;
+}
+
+async fn start_mock_without_8bitmime() -> mpsc::UnboundedReceiver<String> {
+    let listener = TcpListener::bind("127.0.0.1:9925").await.unwrap();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        drop(listener);
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        writer.write_all(b"220 mx.foobar.org ESMTP\r\n").await.unwrap();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let response: &[u8] = if line.starts_with("EHLO") {
+                b"250 mx.foobar.org\r\n"
+            } else if line.starts_with("DATA") {
+                writer.write_all(b"354 Start mail input\r\n").await.unwrap();
+                let mut data = String::new();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    if line == "." {
+                        break;
+                    }
+                    data.push_str(&line);
+                    data.push_str("\r\n");
+                }
+                tx.send(data).unwrap();
+                b"250 2.0.0 Message queued\r\n"
+            } else if line.starts_with("QUIT") {
+                b"221 2.0.0 Bye\r\n"
+            } else {
+                b"250 2.0.0 OK\r\n"
+            };
+            writer.write_all(response).await.unwrap();
+        }
+    });
+    rx
+}
+
+fn add_mock_dns(core: &Server) {
+    core.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx.foobar.org".to_string()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.ipv4_add(
+        "mx.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn pipelining() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start a mock server that advertises PIPELINING and only replies to
+    // the envelope once all of its commands have been received
+    let listener = TcpListener::bind("127.0.0.1:9925").await.unwrap();
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        drop(listener);
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        let mut envelope = Vec::new();
+        writer.write_all(b"220 mx.foobar.org ESMTP\r\n").await.unwrap();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let response: &[u8] = if line.starts_with("EHLO") {
+                b"250-mx.foobar.org\r\n250 PIPELINING\r\n"
+            } else if line.starts_with("MAIL FROM") || line.starts_with("RCPT TO") {
+                envelope.push(if line.starts_with("RCPT TO:<fail@") {
+                    b"550 5.1.1 Mailbox does not exist\r\n".as_slice()
+                } else if line.starts_with("RCPT TO:<delay@") {
+                    b"450 4.2.1 Mailbox busy\r\n".as_slice()
+                } else {
+                    b"250 2.1.0 OK\r\n".as_slice()
+                });
+                tx.send(line).unwrap();
+                if envelope.len() == 4 {
+                    writer.write_all(&envelope.concat()).await.unwrap();
+                    envelope.clear();
+                }
+                continue;
+            } else if line.starts_with("DATA") {
+                writer.write_all(b"354 Start mail input\r\n").await.unwrap();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    if line == "." {
+                        break;
+                    }
+                }
+                b"250 2.0.0 Message queued\r\n"
+            } else if line.starts_with("QUIT") {
+                b"221 2.0.0 Bye\r\n"
+            } else {
+                b"250 2.0.0 OK\r\n"
+            };
+            writer.write_all(response).await.unwrap();
+        }
+    });
+
+    // Add mock DNS entries
+    let mut local = TestSMTP::new("smtp_pipelining_local", LOCAL_PIPELINING).await;
+    let core = local.build_smtp();
+    add_mock_dns(&core);
+
+    // The envelope is sent in a single round trip and each recipient
+    // keeps its own status
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message(
+            "john@test.org",
+            &["ok@foobar.org", "fail@foobar.org", "delay@foobar.org"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    let message = local.queue_receiver.expect_message().await;
+    let queue_id = message.queue_id;
+    local
+        .queue_receiver
+        .delivery_attempt(queue_id)
+        .await
+        .try_deliver(core.clone());
+    local
+        .queue_receiver
+        .expect_message()
+        .await
+        .read_lines(&local.queue_receiver)
+        .await
+        .assert_contains("<fail@foobar.org> (host 'mx.foobar.org' rejected command");
+    local.queue_receiver.read_event().await.assert_refresh();
+
+    let mut envelope = Vec::new();
+    while let Ok(line) = rx.try_recv() {
+        envelope.push(line);
+    }
+    assert_eq!(
+        envelope,
+        [
+            "MAIL FROM:<john@test.org>",
+            "RCPT TO:<delay@foobar.org>",
+            "RCPT TO:<fail@foobar.org>",
+            "RCPT TO:<ok@foobar.org>"
+        ]
+    );
+
+    let message = core
+        .read_message(queue_id, QueueName::default())
+        .await
+        .unwrap();
+    assert!(
+        matches!(message.message.recipients[2].status, Status::Completed(_)),
+        "{:?}",
+        message.message.recipients[2].status
+    );
+    assert!(
+        matches!(
+            &message.message.recipients[1].status,
+            Status::PermanentFailure(ErrorDetails {
+                details: Error::UnexpectedResponse(response),
+                ..
+            }) if response.response.code == 550
+        ),
+        "{:?}",
+        message.message.recipients[1].status
+    );
+    assert!(
+        matches!(
+            &message.message.recipients[0].status,
+            Status::TemporaryFailure(ErrorDetails {
+                details: Error::UnexpectedResponse(response),
+                ..
+            }) if response.response.code == 450
+        ),
+        "{:?}",
+        message.message.recipients[0].status
+    );
+    local.queue_receiver.clear_queue(&core).await;
+}
@@ -0,0 +1,326 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs LLC <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::time::{Duration, Instant};
+
+use common::config::server::ServerProtocol;
+use mail_auth::{DkimResult, MX};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+    sync::mpsc,
+};
+
+use crate::smtp::{
+    DnsCache, TestSMTP,
+    inbound::{
+        TestMessage, TestQueueEvent,
+        sign::{SIGNATURES, add_example_keys, verify_dkim},
+    },
+    session::{TestSession, VerifyResponse},
+};
+
+const LOCAL: &str = r#"
+[session.rcpt]
+relay = true
+max-recipients = 100
+
+[queue.outbound]
+add-headers = [{if = "rcpt_domain == 'foobar.org'", then = "['X-Tenant-Id: tenant1']"},
+               {else = false}]
+remove-headers = [{if = "rcpt_domain == 'foobar.org'", then = "['Date']"},
+                  {else = false}]
+
+[spam-filter]
+enable = false
+"#;
+
+const LOCAL_DKIM: &str = r#"
+[session.rcpt]
+relay = true
+
+[auth.dkim]
+sign = "['rsa']"
+
+[queue.outbound]
+dkim-sign = "['rsa']"
+add-headers = [{if = "rcpt_domain == 'foobar.org'", then = "['X-Tenant-Id: tenant1']"},
+               {else = false}]
+remove-headers = [{if = "rcpt_domain == 'foobar.org'", then = "['Date']"},
+                  {else = false}]
+
+[spam-filter]
+enable = false
+"#;
+
+const LOCAL_DKIM_REWRITE: &str = r#"
+[session.rcpt]
+relay = true
+
+[auth.dkim]
+sign = "['rsa']"
+
+[queue.outbound]
+dkim-sign = "['rsa']"
+rewrite.sender = [{if = "matches('^([^@]+)@internal$', sender)", then = "$1 + '@example.com'"},
+                  {else = false}]
+
+[spam-filter]
+enable = false
+"#;
+
+const REMOTE: &str = r#"
+[session.rcpt]
+relay = true
+
+[session.ehlo]
+reject-non-fqdn = false
+
+[session.extensions]
+chunking = false
+
+[spam-filter]
+enable = false
+"#;
+
+#[tokio::test]
+#[serial_test::serial]
+async fn rcpt_domain_headers() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start test server
+    let mut remote = TestSMTP::new("smtp_headers_remote", REMOTE).await;
+    let _rx = remote.start(&[ServerProtocol::Smtp]).await;
+    let mut local = TestSMTP::new("smtp_headers_local", LOCAL).await;
+
+    // Both domains are delivered to the test server
+    let core = local.build_smtp();
+    for domain in ["foobar.org", "foobar.net"] {
+        core.mx_add(
+            domain,
+            vec![MX {
+                exchanges: vec![format!("mx.{domain}")],
+                preference: 10,
+            }],
+            Instant::now() + Duration::from_secs(10),
+        );
+        core.ipv4_add(
+            &format!("mx.{domain}"),
+            vec!["127.0.0.1".parse().unwrap()],
+            Instant::now() + Duration::from_secs(10),
+        );
+    }
+
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message(
+            "john@test.org",
+            &["bill@foobar.org", "jane@foobar.net"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+
+    // Only the copy sent to foobar.org is rewritten
+    remote.queue_receiver.read_event().await.assert_refresh();
+    remote.queue_receiver.read_event().await.assert_refresh();
+    let messages = remote.queue_receiver.read_queued_messages().await;
+    assert_eq!(messages.len(), 2);
+    for message in messages {
+        let lines = message.read_lines(&remote.queue_receiver).await;
+        if message.message.recipients[0].address_lcase == "bill@foobar.org" {
+            lines
+                .assert_contains("X-Tenant-Id: tenant1")
+                .assert_not_contains("Date: Fri, 11 Jul 2003")
+                .assert_contains("Subject: Is dinner ready?");
+        } else {
+            lines
+                .assert_not_contains("X-Tenant-Id")
+                .assert_contains("Date: Fri, 11 Jul 2003")
+                .assert_contains("Subject: Is dinner ready?");
+        }
+    }
+    local.queue_receiver.read_event().await.assert_done();
+    remote.queue_receiver.assert_no_events();
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn rcpt_domain_headers_dkim() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start test server
+    let mut remote = TestSMTP::new("smtp_headers_dkim_remote", REMOTE).await;
+    let _rx = remote.start(&[ServerProtocol::Smtp]).await;
+    let mut local = TestSMTP::new(
+        "smtp_headers_dkim_local",
+        LOCAL_DKIM.to_string() + SIGNATURES,
+    )
+    .await;
+
+    let core = local.build_smtp();
+    add_example_keys(&core);
+    core.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx.foobar.org".into()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.ipv4_add(
+        "mx.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    local.queue_receiver.read_event().await.assert_done();
+
+    // The message is signed again after removing a signed header
+    let message = remote.queue_receiver.expect_message().await;
+    message
+        .read_lines(&remote.queue_receiver)
+        .await
+        .assert_contains("X-Tenant-Id: tenant1")
+        .assert_not_contains("Date: Fri, 11 Jul 2003")
+        .assert_count("DKIM-Signature:", 1);
+    let contents = message.read_message(&remote.queue_receiver).await;
+    assert_eq!(
+        verify_dkim(&core, &contents).await,
+        vec![DkimResult::Pass],
+        "{contents}"
+    );
+    remote.queue_receiver.assert_no_events();
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn rcpt_domain_headers_dkim_rewrites() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start a mock server that does not advertise 8BITMIME and records the bytes received
+    let listener = TcpListener::bind("127.0.0.1:9925").await.unwrap();
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        writer
+            .write_all(b"220 mx.foobar.org ESMTP\r\n")
+            .await
+            .unwrap();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let response: &[u8] = if line.starts_with("EHLO") {
+                b"250 mx.foobar.org\r\n"
+            } else if line.starts_with("DATA") {
+                writer.write_all(b"354 Start mail input\r\n").await.unwrap();
+                let mut data = String::new();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    if line == "." {
+                        break;
+                    }
+                    data.push_str(line.strip_prefix('.').unwrap_or(&line));
+                    data.push_str("\r\n");
+                }
+                tx.send(data).unwrap();
+                b"250 2.0.0 Message queued\r\n"
+            } else if line.starts_with("QUIT") {
+                b"221 2.0.0 Bye\r\n"
+            } else {
+                b"250 2.0.0 OK\r\n"
+            };
+            writer.write_all(response).await.unwrap();
+        }
+    });
+
+    let mut local = TestSMTP::new(
+        "smtp_headers_dkim_rewrite_local",
+        LOCAL_DKIM_REWRITE.to_string() + SIGNATURES,
+    )
+    .await;
+    let core = local.build_smtp();
+    add_example_keys(&core);
+    core.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx.foobar.org".into()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.ipv4_add(
+        "mx.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message(
+            "sales@internal",
+            &["bill@foobar.org"],
+            concat!(
+                "From: Sales <sales@internal>\r\n",
+                "To: bill@foobar.org\r\n",
+                "Subject: Dessert\r\n",
+                "MIME-Version: 1.0\r\n",
+                "Content-Type: text/plain; charset=utf-8\r\n",
+                "Content-Transfer-Encoding: 8bit\r\n",
+                "\r\n",
+                "Café crème brûlée.\r\n",
+                ".. is dot-stuffed\r\n",
+            ),
+            "250",
+        )
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    local.queue_receiver.read_event().await.assert_done();
+
+    // The signature covers the exact bytes sent after masquerading and the 8BITMIME downgrade
+    let data = rx.recv().await.expect("Message not received");
+    assert!(data.is_ascii(), "{data}");
+    assert!(data.contains("From: Sales <sales@example.com>"), "{data}");
+    assert!(
+        data.contains("Content-Transfer-Encoding: quoted-printable"),
+        "{data}"
+    );
+    assert_eq!(data.matches("DKIM-Signature:").count(), 1, "{data}");
+    assert_eq!(
+        verify_dkim(&core, &data).await,
+        vec![DkimResult::Pass],
+        "{data}"
+    );
+}
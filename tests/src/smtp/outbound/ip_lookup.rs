@@ -4,12 +4,24 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use std::time::{Duration, Instant};
+use std::{
+    net::IpAddr,
+    time::{Duration, Instant},
+};
 
 use common::config::server::ServerProtocol;
 use mail_auth::{IpLookupStrategy, MX};
+use tokio::net::{TcpSocket, TcpStream};
+use trc::{
+    Collector, DeliveryEvent, EventType, Key, Value,
+    ipc::subscriber::{Interests, SubscriberBuilder},
+};
 
-use crate::smtp::{DnsCache, TestSMTP, session::TestSession};
+use crate::smtp::{
+    DnsCache, TestSMTP,
+    inbound::{TestMessage, TestQueueEvent},
+    session::{TestSession, VerifyResponse},
+};
 
 const LOCAL: &str = r#"
 [session.rcpt]
@@ -19,6 +31,56 @@ relay = true
 ip-lookup = "ipv6_then_ipv4"
 "#;
 
+const LOCAL_NEGATIVE_CACHE: &str = r#"
+[session.rcpt]
+relay = true
+
+[resolver.cache]
+negative-ttl = "1h"
+"#;
+
+const LOCAL_HAPPY_EYEBALLS: &str = r#"
+[session.rcpt]
+relay = true
+
+[queue.gateway.mx]
+type = "mx"
+ip-lookup = "ipv6_then_ipv4"
+
+[queue.connection.default]
+happy-eyeballs = "250ms"
+
+[queue.connection.default.timeout]
+connect = "30s"
+"#;
+
+const LOCAL_HAPPY_EYEBALLS_RACE: &str = r#"
+[session.rcpt]
+relay = true
+
+[queue.gateway.mx]
+type = "mx"
+ip-lookup = "ipv4_then_ipv6"
+
+[queue.connection.default]
+happy-eyeballs = "250ms"
+
+[[queue.limiter.outbound]]
+match = "remote_ip = '::1'"
+key = ['remote_ip']
+rate = '1/30m'
+enable = true
+"#;
+
+const LOCAL_NO_IMPLICIT_MX: &str = r#"
+[session.rcpt]
+relay = true
+
+[queue.gateway.mx]
+type = "mx"
+implicit-mx = false
+"#;
+
 const REMOTE: &str = r#"
 [session.ehlo]
 reject-non-fqdn = false
@@ -90,3 +152,313 @@ async fn ip_lookup_strategy() {
         }
     }
 }
+
+#[tokio::test]
+#[serial_test::serial]
+async fn negative_mx_cache() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Subscribe to failed MX lookups
+    let mut interests = Interests::default();
+    interests.set(EventType::Delivery(DeliveryEvent::MxLookupFailed));
+    let (_tx, mut events_rx) = SubscriberBuilder::new("mx-lookup-events".to_string())
+        .with_interests(interests.clone())
+        .with_lossy(false)
+        .register();
+    Collector::union_interests(interests);
+    Collector::reload();
+
+    // The mock resolver answers NXDOMAIN for names without cached records,
+    // and the zone's SOA publishes a negative TTL of one second
+    let mut local = TestSMTP::new("smtp_negative_cache_local", LOCAL_NEGATIVE_CACHE).await;
+    let core = local.build_smtp();
+    core.soa_add(
+        "nxdomain.test",
+        Duration::from_secs(1),
+        Instant::now() + Duration::from_secs(10),
+    );
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+
+    // Send two messages to a domain without MX records
+    for _ in 0..2 {
+        session
+            .send_message(
+                "john@test.org",
+                &["bill@nxdomain.test"],
+                "test:no_dkim",
+                "250",
+            )
+            .await;
+        local
+            .queue_receiver
+            .expect_message_then_deliver()
+            .await
+            .try_deliver(core.clone());
+        while local.queue_receiver.try_read_event().await.is_some() {}
+        local.queue_receiver.clear_queue(&core).await;
+    }
+
+    // The non-existent domain is cached and looked up only once
+    assert_eq!(
+        core.inner
+            .cache
+            .dns_mx
+            .get("nxdomain.test.")
+            .map(|mx| mx.is_empty()),
+        Some(true)
+    );
+    let mut lookups = 0;
+    while let Ok(Some(batch)) =
+        tokio::time::timeout(Duration::from_millis(200), events_rx.recv()).await
+    {
+        lookups += batch
+            .iter()
+            .filter(|event| event.value_as_str(Key::Domain) == Some("nxdomain.test"))
+            .count();
+    }
+    assert_eq!(lookups, 1);
+
+    // The entry expires after the SOA negative TTL rather than the configured maximum
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert!(core.inner.cache.dns_mx.get("nxdomain.test.").is_none());
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn happy_eyeballs() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start test server
+    let mut remote = TestSMTP::new("smtp_happy_eyeballs_remote", REMOTE).await;
+    let _rx = remote.start(&[ServerProtocol::Smtp]).await;
+
+    // Listen on the IPv6 loopback with a full accept queue, so that
+    // connections to it are never established
+    let socket = TcpSocket::new_v6().unwrap();
+    socket.bind("[::1]:9925".parse().unwrap()).unwrap();
+    let _listener = socket.listen(0).unwrap();
+    let mut backlog = Vec::new();
+    while let Ok(Ok(stream)) =
+        tokio::time::timeout(Duration::from_millis(100), TcpStream::connect("[::1]:9925")).await
+    {
+        backlog.push(stream);
+    }
+
+    // Add mock DNS entries
+    let mut local = TestSMTP::new("smtp_happy_eyeballs_local", LOCAL_HAPPY_EYEBALLS).await;
+    let core = local.build_smtp();
+    core.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx.foobar.org".to_string()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.ipv6_add(
+        "mx.foobar.org",
+        vec!["::1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.ipv4_add(
+        "mx.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    // The IPv4 connection is started after the stagger delay and wins
+    // the race long before the connect timeout
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    let time = Instant::now();
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    let mut delivered = false;
+    while !delivered && time.elapsed() < Duration::from_secs(5) {
+        delivered = remote.queue_receiver.try_read_event().await.is_some();
+    }
+    let elapsed = time.elapsed();
+    assert!(delivered, "Message was not delivered over IPv4");
+    assert!(
+        elapsed >= Duration::from_millis(250) && elapsed < Duration::from_secs(2),
+        "Unexpected delivery time {elapsed:?}"
+    );
+    remote.queue_receiver.last_queued_message().await;
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn happy_eyeballs_race() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Subscribe to connection errors and exceeded limits
+    let mut interests = Interests::default();
+    interests.set(EventType::Delivery(DeliveryEvent::ConnectError));
+    interests.set(EventType::Delivery(DeliveryEvent::RateLimitExceeded));
+    let (_tx, mut events_rx) = SubscriberBuilder::new("happy-eyeballs-events".to_string())
+        .with_interests(interests.clone())
+        .with_lossy(false)
+        .register();
+    Collector::union_interests(interests);
+    Collector::reload();
+
+    // Add mock DNS entries
+    let mut local =
+        TestSMTP::new("smtp_happy_eyeballs_race_local", LOCAL_HAPPY_EYEBALLS_RACE).await;
+    let core = local.build_smtp();
+    core.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx.foobar.org".to_string()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.ipv4_add(
+        "mx.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.ipv6_add(
+        "mx.foobar.org",
+        vec!["::1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+
+    // No host is listening, both addresses fail and each is tried once
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    local.queue_receiver.read_event().await.assert_refresh();
+    local.queue_receiver.clear_queue(&core).await;
+    let mut errors = Vec::new();
+    while let Ok(Some(batch)) =
+        tokio::time::timeout(Duration::from_millis(200), events_rx.recv()).await
+    {
+        for event in batch {
+            if event.inner.typ == EventType::Delivery(DeliveryEvent::ConnectError) {
+                errors.extend(event.value(Key::RemoteIp).cloned());
+            }
+        }
+    }
+    assert_eq!(
+        errors,
+        vec![
+            Value::from("::1".parse::<IpAddr>().unwrap()),
+            Value::from("127.0.0.1".parse::<IpAddr>().unwrap())
+        ]
+    );
+
+    // The IPv4 address connects before the stagger delay, so the IPv6
+    // address is never raced nor counted against its limiter
+    let mut remote = TestSMTP::new("smtp_happy_eyeballs_race_remote", REMOTE).await;
+    let _rx = remote.start(&[ServerProtocol::Smtp]).await;
+    for _ in 0..2 {
+        session
+            .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+            .await;
+        local
+            .queue_receiver
+            .expect_message_then_deliver()
+            .await
+            .try_deliver(core.clone());
+        local.queue_receiver.read_event().await.assert_done();
+        remote.queue_receiver.expect_message().await;
+    }
+    let mut events = Vec::new();
+    while let Ok(Some(batch)) =
+        tokio::time::timeout(Duration::from_millis(200), events_rx.recv()).await
+    {
+        events.extend(batch);
+    }
+    assert!(events.is_empty(), "{events:?}");
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn implicit_mx() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start test server
+    let mut remote = TestSMTP::new("smtp_implicit_mx_remote", REMOTE).await;
+    let _rx = remote.start(&[ServerProtocol::Smtp]).await;
+
+    // Domains without MX records are delivered to their address records
+    let mut local = TestSMTP::new("smtp_implicit_mx_local", LOCAL).await;
+    let core = local.build_smtp();
+    core.ipv4_add(
+        "foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    local.queue_receiver.read_event().await.assert_done();
+    remote.queue_receiver.expect_message().await;
+
+    // Implicit MX can be disabled
+    let mut local = TestSMTP::new("smtp_no_implicit_mx_local", LOCAL_NO_IMPLICIT_MX).await;
+    let core = local.build_smtp();
+    core.ipv4_add(
+        "foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    local
+        .queue_receiver
+        .expect_message()
+        .await
+        .read_lines(&local.queue_receiver)
+        .await
+        .assert_contains("<bill@foobar.org> (failed to lookup 'foobar.org'")
+        .assert_contains("No MX records found.")
+        .assert_contains("Action: failed");
+    local.queue_receiver.read_event().await.assert_done();
+    remote.queue_receiver.assert_no_events();
+}
@@ -4,12 +4,24 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
+pub mod batv;
 pub mod dane;
+pub mod dry_run;
+pub mod events;
 pub mod extensions;
 pub mod fallback_relay;
+pub mod headers;
 pub mod ip_lookup;
 pub mod lmtp;
 pub mod mta_sts;
+pub mod next_mx;
+pub mod reroute;
+pub mod retry_hint;
+pub mod rewrite;
+pub mod smarthost;
 pub mod smtp;
+pub mod source_ip;
+pub mod srv;
 pub mod throttle;
+pub mod timeout;
 pub mod tls;
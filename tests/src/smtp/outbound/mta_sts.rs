@@ -25,7 +25,10 @@ use crate::smtp::{
     inbound::{TestMessage, TestQueueEvent, TestReportingEvent},
     session::{TestSession, VerifyResponse},
 };
-use smtp::outbound::mta_sts::{lookup::STS_TEST_POLICY, parse::ParsePolicy};
+use smtp::outbound::mta_sts::{
+    lookup::{MtaStsLookup, STS_TEST_POLICY},
+    parse::ParsePolicy,
+};
 
 const LOCAL: &str = r#"
 [session.rcpt]
@@ -243,4 +246,34 @@ async fn mta_sts_verify() {
         )
     );
     assert!(report.failure.is_none());
+
+    // Injected policies are served from the cache when the policy id matches
+    let policy = Arc::new(
+        Policy::parse(
+            concat!(
+                "version: STSv1\n",
+                "mode: testing\n",
+                "mx: mx.foobar.com\n",
+                "max_age: 86400\n"
+            ),
+            "cached_policy".to_string(),
+        )
+        .unwrap(),
+    );
+    core.txt_add(
+        "_mta-sts.foobar.com",
+        MtaSts::parse(b"v=STSv1; id=cached_policy;").unwrap(),
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.mta_sts_add(
+        "foobar.com",
+        policy.clone(),
+        Instant::now() + Duration::from_secs(10),
+    );
+    assert_eq!(
+        core.lookup_mta_sts_policy("foobar.com", Duration::from_secs(1))
+            .await
+            .unwrap(),
+        policy
+    );
 }
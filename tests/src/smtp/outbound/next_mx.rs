@@ -0,0 +1,140 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs LLC <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::time::{Duration, Instant};
+
+use common::config::server::ServerProtocol;
+use mail_auth::MX;
+use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+use crate::smtp::{
+    DnsCache, TestSMTP,
+    inbound::{TestMessage, TestQueueEvent},
+    session::{TestSession, VerifyResponse},
+};
+
+const LOCAL: &str = r#"
+[queue.strategy]
+connection = [{if = "rcpt_domain = 'foobar.net'", then = "'defer'"},
+              {else = "'default'"}]
+
+[queue.connection.defer]
+transient-failure = "defer"
+
+[session.rcpt]
+relay = true
+max-recipients = 100
+
+[queue.tls.default]
+allow-invalid-certs = true
+
+"#;
+
+const REMOTE: &str = r#"
+[session.rcpt]
+relay = true
+
+[session.ehlo]
+reject-non-fqdn = false
+
+[session.extensions]
+chunking = false
+"#;
+
+#[tokio::test]
+#[serial_test::serial]
+async fn next_mx_on_transient_greeting() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start a mock server on the primary MX that rejects every connection with a 421 greeting
+    let listener = TcpListener::bind("127.0.0.2:9925").await.unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let _ = stream
+                .write_all(b"421 4.3.2 Service not available\r\n")
+                .await;
+        }
+    });
+
+    // Start test server on the secondary MX
+    let mut remote = TestSMTP::new("smtp_next_mx_remote", REMOTE).await;
+    let _rx = remote.start(&[ServerProtocol::Smtp]).await;
+    let mut local = TestSMTP::new("smtp_next_mx_local", LOCAL).await;
+
+    // Add mock DNS entries
+    let core = local.build_smtp();
+    for domain in ["foobar.org", "foobar.net"] {
+        core.mx_add(
+            domain,
+            vec![
+                MX {
+                    exchanges: vec![format!("mx1.{domain}")],
+                    preference: 10,
+                },
+                MX {
+                    exchanges: vec![format!("mx2.{domain}")],
+                    preference: 20,
+                },
+            ],
+            Instant::now() + Duration::from_secs(10),
+        );
+        core.ipv4_add(
+            &format!("mx1.{domain}"),
+            vec!["127.0.0.2".parse().unwrap()],
+            Instant::now() + Duration::from_secs(10),
+        );
+        core.ipv4_add(
+            &format!("mx2.{domain}"),
+            vec!["127.0.0.1".parse().unwrap()],
+            Instant::now() + Duration::from_secs(10),
+        );
+    }
+
+    // A 421 greeting from the primary MX moves on to the secondary MX in the same attempt
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    remote
+        .queue_receiver
+        .expect_message()
+        .await
+        .read_lines(&remote.queue_receiver)
+        .await
+        .assert_contains("Subject: ");
+    local.queue_receiver.read_event().await.assert_done();
+    local.queue_receiver.assert_queue_is_empty().await;
+
+    // When configured to defer, the secondary MX is not tried and the domain is retried later
+    session
+        .send_message("john@test.org", &["bill@foobar.net"], "test:no_dkim", "250")
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    let retry = local.queue_receiver.expect_message().await;
+    assert_eq!(retry.message.recipients[0].retry.inner, 1);
+    assert!(
+        retry.message.recipients[0]
+            .status
+            .to_string()
+            .contains("Temporary Failure for mx1.foobar.net"),
+        "{}",
+        retry.message.recipients[0].status
+    );
+    remote.queue_receiver.assert_no_events();
+}
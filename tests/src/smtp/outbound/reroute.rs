@@ -0,0 +1,160 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs LLC <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::time::{Duration, Instant};
+
+use common::config::{server::ServerProtocol, smtp::queue::QueueName};
+use mail_auth::MX;
+use smtp::queue::{parse_next_hop, spool::SmtpSpool};
+
+use crate::smtp::{
+    DnsCache, TestSMTP,
+    inbound::{TestMessage, TestQueueEvent},
+    session::{TestSession, VerifyResponse},
+};
+
+const LOCAL: &str = r#"
+[session.rcpt]
+relay = true
+max-recipients = 100
+
+[queue.tls.default]
+allow-invalid-certs = true
+
+"#;
+
+const REMOTE: &str = r#"
+[session.rcpt]
+relay = true
+
+[session.ehlo]
+reject-non-fqdn = false
+
+[session.extensions]
+chunking = false
+"#;
+
+#[tokio::test]
+#[serial_test::serial]
+async fn reroute() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start test server
+    let mut remote = TestSMTP::new("smtp_reroute_remote", REMOTE).await;
+    let _rx = remote.start(&[ServerProtocol::Smtp]).await;
+    let mut local = TestSMTP::new("smtp_reroute_local", LOCAL).await;
+
+    // The MX for foobar.org cannot be resolved, only the relay host is reachable
+    let core = local.build_smtp();
+    core.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["_dns_error.foobar.org".to_string()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.ipv4_add(
+        "relay.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    let queue_id = local.queue_receiver.expect_message().await.queue_id;
+
+    // Invalid relay hosts and unknown domains are not accepted
+    assert!(
+        core.reroute(queue_id, "foobar.org", "relay.foobar.org:abc")
+            .await
+            .is_err()
+    );
+    assert!(
+        !core
+            .reroute(queue_id, "example.org", "relay.foobar.org:9925")
+            .await
+            .unwrap()
+    );
+
+    // Pin delivery to the relay host
+    assert!(
+        core.reroute(queue_id, "FOOBAR.ORG", "relay.foobar.org:9925")
+            .await
+            .unwrap()
+    );
+    local.queue_receiver.read_event().await.assert_refresh();
+    let message = core
+        .read_message(queue_id, QueueName::default())
+        .await
+        .unwrap();
+    assert_eq!(
+        message.message.recipients[0].next_hop.as_deref(),
+        Some("relay.foobar.org:9925")
+    );
+
+    // Delivery goes to the relay host instead of the MX
+    local
+        .queue_receiver
+        .delivery_attempt(queue_id)
+        .await
+        .try_deliver(core.clone());
+    remote
+        .queue_receiver
+        .expect_message()
+        .await
+        .read_lines(&remote.queue_receiver)
+        .await
+        .assert_contains("Subject: ");
+    local.queue_receiver.read_event().await.assert_done();
+    local.queue_receiver.assert_queue_is_empty().await;
+
+    // Relay hosts can be given as IP addresses
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    let queue_id = local.queue_receiver.expect_message().await.queue_id;
+    assert!(
+        core.reroute(queue_id, "foobar.org", "127.0.0.1:9925")
+            .await
+            .unwrap()
+    );
+    local.queue_receiver.read_event().await.assert_refresh();
+    local
+        .queue_receiver
+        .delivery_attempt(queue_id)
+        .await
+        .try_deliver(core.clone());
+    remote.queue_receiver.expect_message().await;
+    local.queue_receiver.read_event().await.assert_done();
+    local.queue_receiver.assert_queue_is_empty().await;
+}
+
+#[test]
+fn next_hop_parse() {
+    for (next_hop, expected) in [
+        ("relay.foobar.org", Some(("relay.foobar.org", 25))),
+        ("relay.foobar.org:587", Some(("relay.foobar.org", 587))),
+        ("127.0.0.1:9925", Some(("127.0.0.1", 9925))),
+        ("[::1]", Some(("::1", 25))),
+        ("[2001:db8::1]:2525", Some(("2001:db8::1", 2525))),
+        ("relay.foobar.org:abc", None),
+        ("relay.foobar.org:0", None),
+        (":25", None),
+        ("::1:25", None),
+        ("[::1", None),
+        ("[::1]2525", None),
+        ("[relay.foobar.org]:25", None),
+    ] {
+        assert_eq!(parse_next_hop(next_hop), expected, "{next_hop}");
+    }
+}
@@ -0,0 +1,143 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs LLC <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::time::{Duration, Instant};
+
+use mail_auth::MX;
+use store::write::now;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+};
+
+use crate::smtp::{DnsCache, TestSMTP, session::TestSession};
+
+const LOCAL: &str = r#"
+[session.rcpt]
+relay = true
+max-recipients = 100
+
+[queue.schedule.default]
+retry = ["1m", "2m", "1h"]
+notify = "1d"
+expire = "1d"
+queue-name = "default"
+
+"#;
+
+#[tokio::test]
+#[serial_test::serial]
+async fn retry_hint() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start a mock server that defers recipients with backoff hints
+    let listener = TcpListener::bind("127.0.0.1:9925").await.unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        writer
+            .write_all(b"220 mx.foobar.org ESMTP\r\n")
+            .await
+            .unwrap();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let response: &[u8] = if line.starts_with("EHLO") {
+                b"250-mx.foobar.org\r\n250 8BITMIME\r\n"
+            } else if line.starts_with("RCPT TO:<bill@") {
+                b"451 4.7.28 Rate limited, please retry in 30 minutes\r\n"
+            } else if line.starts_with("RCPT TO:<jane@") {
+                b"451 4.7.1 Too many messages, try again in 3 days\r\n"
+            } else if line.starts_with("RCPT TO:<mike@") {
+                b"451 4.7.1 Sender temporarily deferred\r\n"
+            } else if line.starts_with("RCPT TO:<john@") {
+                b"451 4.3.0 Temporary failure\r\n"
+            } else if line.starts_with("QUIT") {
+                writer.write_all(b"221 Bye\r\n").await.unwrap();
+                break;
+            } else {
+                b"250 OK\r\n"
+            };
+            writer.write_all(response).await.unwrap();
+        }
+    });
+
+    // Add mock DNS entries
+    let mut local = TestSMTP::new("smtp_retry_hint_local", LOCAL).await;
+    let core = local.build_smtp();
+    core.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx.foobar.org".to_string()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.ipv4_add(
+        "mx.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message(
+            "sender@test.org",
+            &[
+                "bill@foobar.org",
+                "jane@foobar.org",
+                "mike@foobar.org",
+                "john@foobar.org",
+            ],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    let start = now();
+    let retry = local.queue_receiver.expect_message().await;
+    let expires_at = retry.message.created + 86400;
+    let due = |address: &str| {
+        retry
+            .message
+            .recipients
+            .iter()
+            .find(|rcpt| rcpt.address_lcase == address)
+            .map(|rcpt| rcpt.retry.due)
+            .unwrap()
+    };
+
+    // An explicit hint extends the next retry
+    assert!(
+        (start + 1795..=start + 1805).contains(&due("bill@foobar.org")),
+        "{}",
+        due("bill@foobar.org")
+    );
+
+    // Hints are capped by the message expiration
+    assert_eq!(due("jane@foobar.org"), expires_at);
+
+    // Policy deferrals without a hint skip ahead in the schedule
+    assert!(
+        (start + 115..=start + 125).contains(&due("mike@foobar.org")),
+        "{}",
+        due("mike@foobar.org")
+    );
+
+    // Other temporary failures follow the schedule
+    assert!(
+        (start + 55..=start + 65).contains(&due("john@foobar.org")),
+        "{}",
+        due("john@foobar.org")
+    );
+}
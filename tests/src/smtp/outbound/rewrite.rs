@@ -0,0 +1,107 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs LLC <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::time::{Duration, Instant};
+
+use common::config::server::ServerProtocol;
+use mail_auth::MX;
+
+use crate::smtp::{
+    DnsCache, TestSMTP,
+    inbound::{TestMessage, TestQueueEvent},
+    session::{TestSession, VerifyResponse},
+};
+
+const LOCAL: &str = r#"
+[session.rcpt]
+relay = true
+
+[queue.outbound]
+rewrite.sender = [{if = "matches('^([^@]+)@internal$', sender)", then = "$1 + '@public.example'"},
+                  {else = false}]
+
+[spam-filter]
+enable = false
+"#;
+
+const REMOTE: &str = r#"
+[session.rcpt]
+relay = true
+
+[session.ehlo]
+reject-non-fqdn = false
+
+[session.extensions]
+chunking = false
+
+[spam-filter]
+enable = false
+"#;
+
+#[tokio::test]
+#[serial_test::serial]
+async fn sender_masquerade() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start test server
+    let mut remote = TestSMTP::new("smtp_rewrite_remote", REMOTE).await;
+    let _rx = remote.start(&[ServerProtocol::Smtp]).await;
+    let mut local = TestSMTP::new("smtp_rewrite_local", LOCAL).await;
+
+    let core = local.build_smtp();
+    core.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx.foobar.org".into()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.ipv4_add(
+        "mx.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message(
+            "sales@internal",
+            &["bill@foobar.org"],
+            concat!(
+                "From: Sales <sales@internal>\r\n",
+                "Reply-To: sales@internal\r\n",
+                "To: bill@foobar.org\r\n",
+                "Subject: Quote\r\n",
+                "\r\n",
+                "Contact sales@internal for details.\r\n"
+            ),
+            "250",
+        )
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    local.queue_receiver.read_event().await.assert_done();
+
+    // The envelope sender and From header are masqueraded
+    let message = remote.queue_receiver.expect_message().await;
+    assert_eq!(message.message.return_path, "sales@public.example");
+    message
+        .read_lines(&remote.queue_receiver)
+        .await
+        .assert_contains("From: Sales <sales@public.example>")
+        .assert_contains("Reply-To: sales@internal")
+        .assert_contains("Contact sales@internal for details.")
+        .assert_not_contains("From: Sales <sales@internal>");
+    remote.queue_receiver.assert_no_events();
+}
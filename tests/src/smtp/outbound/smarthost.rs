@@ -0,0 +1,162 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs LLC <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::time::{Duration, Instant};
+
+use common::config::server::ServerProtocol;
+
+use crate::smtp::{
+    DnsCache, TestSMTP,
+    inbound::{TestMessage, TestQueueEvent},
+    session::{TestSession, VerifyResponse},
+};
+
+const LOCAL: &str = r#"
+[session.rcpt]
+relay = true
+
+[queue.strategy]
+gateway = [{if = "rcpt_domain = 'foobar.org'", then = "'smarthost'"},
+           {else = "'mx'"}]
+
+[queue.gateway.smarthost]
+type = "relay"
+address = smarthost.foobar.org
+port = 9925
+protocol = 'smtp'
+
+[queue.gateway.smarthost.auth]
+username = "relay-user"
+secret = "relay-pass"
+
+[queue.gateway.smarthost.tls]
+implicit = false
+allow-invalid-certs = true
+
+"#;
+
+const REMOTE: &str = r#"
+[session.rcpt]
+relay = true
+
+[session.ehlo]
+reject-non-fqdn = false
+
+[session.auth]
+mechanisms = "[plain, login]"
+directory = "'local'"
+require = true
+must-match-sender = false
+
+[session.data.add-headers]
+received = true
+
+[session.extensions]
+chunking = false
+
+[directory."local"]
+type = "memory"
+
+[[directory."local".principals]]
+name = "relay-user"
+description = "Relay User"
+secret = "relay-pass"
+email = "relay@foobar.org"
+"#;
+
+#[tokio::test]
+#[serial_test::serial]
+async fn smarthost() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start test server
+    let mut remote = TestSMTP::new("smtp_smarthost_remote", REMOTE).await;
+    let _rx = remote.start(&[ServerProtocol::Smtp]).await;
+    let mut local = TestSMTP::new("smtp_smarthost_local", LOCAL).await;
+
+    // Only the smarthost is resolvable, no MX lookup is performed for foobar.org
+    let core = local.build_smtp();
+    core.ipv4_add(
+        "smarthost.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+
+    // The message was relayed over TLS by an authenticated session
+    remote
+        .queue_receiver
+        .expect_message()
+        .await
+        .read_lines(&remote.queue_receiver)
+        .await
+        .assert_contains("with ESMTPSA");
+    local.queue_receiver.read_event().await.assert_done();
+    local.queue_receiver.assert_queue_is_empty().await;
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn smarthost_auth_after_starttls() {
+    // Enable logging
+    crate::enable_logging();
+
+    // AUTH is only advertised by the remote after STARTTLS
+    let mut remote = TestSMTP::new(
+        "smtp_smarthost_tls_auth_remote",
+        REMOTE.replace(
+            "mechanisms = \"[plain, login]\"",
+            "mechanisms = [{if = \"is_tls\", then = \"[plain, login]\"}, {else = false}]",
+        ),
+    )
+    .await;
+    let _rx = remote.start(&[ServerProtocol::Smtp]).await;
+    let mut local = TestSMTP::new("smtp_smarthost_tls_auth_local", LOCAL).await;
+
+    let core = local.build_smtp();
+    core.ipv4_add(
+        "smarthost.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+
+    // The capabilities obtained from the second EHLO are used to authenticate
+    remote
+        .queue_receiver
+        .expect_message()
+        .await
+        .read_lines(&remote.queue_receiver)
+        .await
+        .assert_contains("with ESMTPSA");
+    local.queue_receiver.read_event().await.assert_done();
+    local.queue_receiver.assert_queue_is_empty().await;
+}
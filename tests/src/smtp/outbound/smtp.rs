@@ -74,7 +74,21 @@ enable = false
 
 "#;
 
-const SMUGGLER: &str = r#"From: Joe SixPack <john@foobar.net>
+const LOCAL_TRANSCRIPT: &str = r#"
+[session.rcpt]
+relay = true
+
+[session.extensions]
+dsn = true
+
+[report.dsn]
+include-transcript = true
+
+[spam-filter]
+enable = false
+"#;
+
+pub const SMUGGLER: &str = r#"From: Joe SixPack <john@foobar.net>
 To: Suzie Q <suzie@foobar.org>
 Subject: Is dinner ready?
 
@@ -165,7 +179,9 @@ async fn smtp_delivery() {
     loop {
         match local.queue_receiver.try_read_event().await {
             Some(QueueEvent::Refresh | QueueEvent::WorkerDone { .. }) => {}
-            Some(QueueEvent::Paused(_)) | Some(QueueEvent::ReloadSettings) => unreachable!(),
+            Some(QueueEvent::Paused(_))
+            | Some(QueueEvent::Drain)
+            | Some(QueueEvent::ReloadSettings) => unreachable!(),
             None | Some(QueueEvent::Stop) => {
                 break;
             }
@@ -329,3 +345,67 @@ async fn smtp_delivery() {
         );
     }
 }
+
+#[tokio::test]
+#[serial_test::serial]
+async fn smtp_delivery_transcript() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start test server
+    let mut remote = TestSMTP::new("smtp_transcript_remote", REMOTE).await;
+    let _rx = remote.start(&[ServerProtocol::Smtp]).await;
+
+    // Add mock DNS entries
+    let mut local = TestSMTP::new("smtp_transcript_local", LOCAL_TRANSCRIPT).await;
+    let core = local.build_smtp();
+    core.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx.foobar.org".to_string()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.ipv4_add(
+        "mx.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    // The failure DSN includes the full SMTP conversation
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message(
+            "john@test.org",
+            &["<fail@foobar.org> NOTIFY=FAILURE"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    local
+        .queue_receiver
+        .expect_message()
+        .await
+        .read_lines(&local.queue_receiver)
+        .await
+        .assert_contains("<fail@foobar.org> (host 'mx.foobar.org' rejected command")
+        .assert_contains("----- Transcript of session follows -----")
+        .assert_contains("S: 220 ")
+        .assert_contains("C: EHLO ")
+        .assert_contains("C: MAIL FROM:<john@test.org>")
+        .assert_contains("C: RCPT TO:<fail@foobar.org>")
+        .assert_contains("S: 503 5.5.1 Invalid recipient.")
+        .assert_contains("C: QUIT")
+        .assert_contains("Action: failed");
+    local.queue_receiver.read_event().await.assert_done();
+    remote.queue_receiver.assert_no_events();
+}
@@ -0,0 +1,248 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs LLC <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::time::{Duration, Instant};
+
+use common::config::server::ServerProtocol;
+use mail_auth::MX;
+
+use store::write::now;
+
+use crate::smtp::{
+    DnsCache, TestSMTP,
+    inbound::{TestMessage, TestQueueEvent},
+    session::{TestSession, VerifyResponse},
+};
+
+const LOCAL: &str = r#"
+[session.rcpt]
+relay = true
+
+[queue.connection.default]
+ehlo-hostname = "fallback.example.org"
+
+[[queue.connection.default.source-ip]]
+address = "127.0.0.1"
+ehlo-hostname = "mx1.example.org"
+"#;
+
+const LOCAL_RATE: &str = r#"
+[session.rcpt]
+relay = true
+
+[queue.connection.default]
+ehlo-hostname = "fallback.example.org"
+
+[[queue.connection.default.source-ip]]
+address = "127.0.0.1"
+ehlo-hostname = "mx1.example.org"
+
+[[queue.limiter.outbound]]
+match = "local_ip = '127.0.0.1'"
+key = ['local_ip', 'rcpt_domain']
+rate = '1/30m'
+enable = true
+"#;
+
+const LOCAL_DOMAIN: &str = r#"
+[session.rcpt]
+relay = true
+
+[queue.strategy]
+connection = [{if = "rcpt_domain == 'foobar.org'", then = "'test'"},
+              {else = "'default'"}]
+
+[queue.connection.default]
+ehlo-hostname = "mx1.example.org"
+
+[queue.connection.test]
+ehlo-hostname = "mx2.example.org"
+
+[[queue.connection.test.source-ip]]
+address = "127.0.0.2"
+ehlo-hostname = "mx2.example.org"
+"#;
+
+const REMOTE: &str = r#"
+[session.ehlo]
+reject-non-fqdn = false
+
+[session.rcpt]
+relay = true
+
+[session.data.add-headers]
+received = true
+"#;
+
+#[tokio::test]
+#[serial_test::serial]
+async fn source_ip_ehlo_hostname() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start test server
+    let mut remote = TestSMTP::new("smtp_source_ip_remote", REMOTE).await;
+    let _rx = remote.start(&[ServerProtocol::Smtp]).await;
+
+    // Add mock DNS entries
+    let mut local = TestSMTP::new("smtp_source_ip_local", LOCAL).await;
+    let core = local.build_smtp();
+    core.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx.foobar.org".to_string()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.ipv4_add(
+        "mx.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    // The EHLO hostname of the selected source IP is sent to the remote host
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    local.queue_receiver.read_event().await.assert_done();
+    remote
+        .queue_receiver
+        .expect_message()
+        .await
+        .read_lines(&remote.queue_receiver)
+        .await
+        .assert_contains("Received: from mx1.example.org")
+        .assert_not_contains("fallback.example.org");
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn source_ip_rate_limit() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start test server
+    let mut remote = TestSMTP::new("smtp_source_ip_rate_remote", REMOTE).await;
+    let _rx = remote.start(&[ServerProtocol::Smtp]).await;
+
+    // Add mock DNS entries
+    let mut local = TestSMTP::new("smtp_source_ip_rate_local", LOCAL_RATE).await;
+    let core = local.build_smtp();
+    core.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx.foobar.org".to_string()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.ipv4_add(
+        "mx.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+
+    // The first message is delivered from the source IP
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    local.queue_receiver.read_event().await.assert_done();
+    remote.queue_receiver.expect_message().await;
+
+    // The second message to the same domain exceeds the rate of the source IP
+    // and is deferred rather than bounced
+    session
+        .send_message("john@test.org", &["jane@foobar.org"], "test:no_dkim", "250")
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    local.queue_receiver.read_event().await.assert_refresh();
+    let due = local.queue_receiver.last_queued_due().await as i64 - now() as i64;
+    assert!(due > 0 && due <= 1800, "Unexpected due: {due}");
+    remote.queue_receiver.assert_no_events();
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn source_ip_per_domain() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start test server
+    let mut remote = TestSMTP::new("smtp_source_ip_domain_remote", REMOTE).await;
+    let _rx = remote.start(&[ServerProtocol::Smtp]).await;
+
+    // Add mock DNS entries
+    let mut local = TestSMTP::new("smtp_source_ip_domain_local", LOCAL_DOMAIN).await;
+    let core = local.build_smtp();
+    for domain in ["foobar.org", "foobar.net"] {
+        core.mx_add(
+            domain,
+            vec![MX {
+                exchanges: vec![format!("mx.{domain}")],
+                preference: 10,
+            }],
+            Instant::now() + Duration::from_secs(10),
+        );
+        core.ipv4_add(
+            format!("mx.{domain}"),
+            vec!["127.0.0.1".parse().unwrap()],
+            Instant::now() + Duration::from_secs(10),
+        );
+    }
+
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+
+    // The remote host sees the connection coming from the source IP
+    // selected for the recipient domain, or from the OS default otherwise
+    for (rcpt, expected_ip) in [
+        ("bill@foobar.org", "[127.0.0.2]"),
+        ("jane@foobar.net", "[127.0.0.1]"),
+    ] {
+        session
+            .send_message("john@test.org", &[rcpt], "test:no_dkim", "250")
+            .await;
+        local
+            .queue_receiver
+            .expect_message_then_deliver()
+            .await
+            .try_deliver(core.clone());
+        local.queue_receiver.read_event().await.assert_done();
+        remote
+            .queue_receiver
+            .expect_message()
+            .await
+            .read_lines(&remote.queue_receiver)
+            .await
+            .assert_contains(expected_ip);
+    }
+}
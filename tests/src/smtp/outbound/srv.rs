@@ -0,0 +1,142 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs LLC <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use common::config::{
+    server::Listeners,
+    smtp::resolver::{Srv, SrvEntry},
+};
+use mail_auth::MX;
+use smtp::core::SmtpSessionManager;
+use utils::config::Config;
+
+use crate::{
+    AssertConfig,
+    smtp::{
+        DnsCache, TestSMTP,
+        inbound::{TestMessage, TestQueueEvent},
+        session::{TestSession, VerifyResponse},
+    },
+};
+
+const LOCAL: &str = r#"
+[session.rcpt]
+relay = true
+
+[queue.strategy]
+gateway = [{if = "rcpt_domain = 'foobar.org'", then = "'internal'"},
+           {else = "'mx'"}]
+
+[queue.gateway.internal]
+type = "mx"
+srv-lookup = true
+
+[queue.connection.default.timeout]
+connect = "1s"
+
+[spam-filter]
+enable = false
+"#;
+
+const REMOTE: &str = r#"
+[session.rcpt]
+relay = true
+
+[session.ehlo]
+reject-non-fqdn = false
+
+[spam-filter]
+enable = false
+"#;
+
+const REMOTE_LISTENER: &str = r#"
+[server.listener.smtp-submission]
+bind = ['127.0.0.1:9926']
+protocol = 'smtp'
+"#;
+
+#[tokio::test]
+#[serial_test::serial]
+async fn srv_next_hop() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start a test server on a port that is only published through SRV
+    let mut remote = TestSMTP::new("smtp_srv_remote", REMOTE).await;
+    let mut config = Config::new(REMOTE_LISTENER).unwrap();
+    let mut servers = Listeners::parse(&mut config);
+    servers.parse_tcp_acceptors(&mut config, remote.server.inner.clone());
+    servers.bind_and_drop_priv(&mut config);
+    config.assert_no_errors();
+    let _shutdown_tx = servers
+        .spawn(|server, acceptor, shutdown_rx| {
+            server.spawn(
+                SmtpSessionManager::new(remote.server.inner.clone()),
+                remote.server.inner.clone(),
+                acceptor,
+                shutdown_rx,
+            );
+        })
+        .0;
+
+    // The MX record points to a host that is not listening
+    let mut local = TestSMTP::new("smtp_srv_local", LOCAL).await;
+    let core = local.build_smtp();
+    core.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx.foobar.org".to_string()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.srv_add(
+        "_submission._tcp.foobar.org",
+        Arc::new(Srv {
+            entries: vec![SrvEntry {
+                target: "submission.foobar.org".to_string(),
+                port: 9926,
+                priority: 0,
+                weight: 1,
+            }],
+        }),
+        Instant::now() + Duration::from_secs(10),
+    );
+    for host in ["mx.foobar.org", "submission.foobar.org"] {
+        core.ipv4_add(
+            host,
+            vec!["127.0.0.1".parse().unwrap()],
+            Instant::now() + Duration::from_secs(10),
+        );
+    }
+
+    // Delivery follows the SRV target and port instead of the MX
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    local.queue_receiver.read_event().await.assert_done();
+    remote
+        .queue_receiver
+        .expect_message()
+        .await
+        .read_lines(&remote.queue_receiver)
+        .await
+        .assert_contains("Subject: Is dinner ready?");
+    remote.queue_receiver.assert_no_events();
+}
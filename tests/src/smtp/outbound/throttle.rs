@@ -11,7 +11,10 @@ use crate::smtp::{
     session::TestSession,
 };
 use mail_auth::MX;
-use smtp::queue::{DomainPart, Message, QueueEnvelope, Recipient, throttle::IsAllowed};
+use smtp::queue::{
+    DomainPart, Error, ErrorDetails, Message, QueueEnvelope, Recipient, Status,
+    throttle::IsAllowed,
+};
 use std::{
     net::{IpAddr, Ipv4Addr},
     time::{Duration, Instant},
@@ -27,6 +30,10 @@ retry = "1h"
 notify = "1h"
 expire = "1h"
 
+[queue.outbound]
+concurrency = [{if = "rcpt_domain = 'example.com'", then = 1},
+               {else = 0}]
+
 [[queue.limiter.outbound]]
 match = "sender_domain = 'foobar.org'"
 key = 'sender_domain'
@@ -273,6 +280,44 @@ async fn throttle_outbound() {
     local.queue_receiver.read_event().await.assert_refresh();
     let due = local.queue_receiver.last_queued_due().await - now();
     assert!(due > 0, "Due: {}", due);
+    local.queue_receiver.clear_queue(&core).await;
+
+    // Expect concurrency limit for recipient domain 'example.com'
+    let in_flight = core.is_domain_allowed("example.com", 1).unwrap();
+    assert_eq!(core.domain_in_flight("example.com"), 1);
+    assert!(core.is_domain_allowed("example.com", 1).is_none());
+
+    session
+        .send_message(
+            "john@test.net",
+            &["jane@example.com"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    local.queue_receiver.read_event().await.assert_refresh();
+    assert_eq!(core.domain_in_flight("example.com"), 1);
+    let message = local.queue_receiver.last_queued_message().await;
+    assert!(matches!(
+        &message.message.recipients[0].status,
+        Status::TemporaryFailure(ErrorDetails {
+            details: Error::ConcurrencyLimited,
+            ..
+        })
+    ));
+    assert_eq!(message.message.recipients[0].retry.inner, 0);
+
+    // Releasing the slot lets the domain accept new deliveries
+    drop(in_flight);
+    assert_eq!(core.domain_in_flight("example.com"), 0);
+    assert!(core.is_domain_allowed("example.com", 1).is_some());
+    assert_eq!(core.domain_in_flight("example.com"), 0);
 }
 
 pub trait TestQueueEnvelope<'x> {
@@ -0,0 +1,113 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs LLC <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::time::{Duration, Instant};
+
+use mail_auth::MX;
+use smtp::queue::{Error, ErrorDetails, Status};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+};
+
+use crate::smtp::{DnsCache, TestSMTP, session::TestSession};
+
+const LOCAL: &str = r#"
+[session.rcpt]
+relay = true
+
+[queue.schedule.default]
+retry = "1h"
+notify = "1d"
+expire = "1d"
+queue-name = "default"
+
+[queue.connection.default]
+timeout.data-end = "1s"
+
+"#;
+
+#[tokio::test]
+#[serial_test::serial]
+async fn data_end_timeout() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start a mock server that never replies to the end of data
+    let listener = TcpListener::bind("127.0.0.1:9925").await.unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        writer
+            .write_all(b"220 mx.foobar.org ESMTP\r\n")
+            .await
+            .unwrap();
+        let mut in_data = false;
+        while let Ok(Some(line)) = lines.next_line().await {
+            let response: &[u8] = if line.starts_with("QUIT") {
+                writer.write_all(b"221 Bye\r\n").await.unwrap();
+                break;
+            } else if in_data {
+                continue;
+            } else if line.starts_with("EHLO") {
+                b"250-mx.foobar.org\r\n250 8BITMIME\r\n"
+            } else if line.starts_with("DATA") {
+                in_data = true;
+                b"354 Start mail input\r\n"
+            } else {
+                b"250 OK\r\n"
+            };
+            writer.write_all(response).await.unwrap();
+        }
+    });
+
+    // Add mock DNS entries
+    let mut local = TestSMTP::new("smtp_data_end_timeout_local", LOCAL).await;
+    let core = local.build_smtp();
+    core.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx.foobar.org".to_string()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.ipv4_add(
+        "mx.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+
+    // The domain is deferred with the timeout as the reason
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    let retry = local.queue_receiver.expect_message().await;
+    let rcpt = &retry.message.recipients[0];
+    match &rcpt.status {
+        Status::TemporaryFailure(ErrorDetails {
+            entity,
+            details: Error::ConnectionError(reason),
+        }) => {
+            assert_eq!(entity, "mx.foobar.org");
+            assert_eq!(reason, "Timeout while reading SMTP DATA response");
+        }
+        status => panic!("Unexpected status: {status:?}"),
+    }
+    assert_eq!(rcpt.retry.inner, 1);
+}
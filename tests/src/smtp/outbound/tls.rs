@@ -4,15 +4,31 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use std::time::{Duration, Instant};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use common::config::server::ServerProtocol;
 use mail_auth::MX;
+use rustls::{ServerConfig, version::TLS12};
+use rustls_pemfile::{certs, pkcs8_private_keys};
+use rustls_pki_types::PrivateKeyDer;
+use smtp::{
+    outbound::starttls::StartTlsCache,
+    queue::{Error, ErrorDetails, Status},
+};
 use store::write::now;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpListener,
+    sync::mpsc,
+};
+use tokio_rustls::TlsAcceptor;
 
 use crate::smtp::{
     DnsCache, TestSMTP,
-    inbound::TestMessage,
+    inbound::{TestMessage, TestQueueEvent},
     session::{TestSession, VerifyResponse},
 };
 
@@ -33,6 +49,61 @@ allow-invalid-certs = true
 
 "#;
 
+const LOCAL_POLICY: &str = r#"
+[session.rcpt]
+relay = true
+
+[queue.strategy]
+tls = [ { if = "rcpt_domain == 'strict.org'", then = "'strict'"},
+        { else = "'relaxed'" }]
+
+[queue.tls.relaxed]
+min-version = "TLSv1.2"
+ciphers = ["TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256"]
+
+[queue.tls.strict]
+min-version = "TLSv1.3"
+
+"#;
+
+const LOCAL_TLS_DETAILS: &str = r#"
+[session.rcpt]
+relay = true
+
+[session.extensions]
+dsn = true
+
+[report.dsn]
+tls-details = true
+
+"#;
+
+const LOCAL_HANDSHAKE: &str = r#"
+[session.rcpt]
+relay = true
+
+[queue.strategy]
+tls = [ { if = "rcpt_domain == 'plaintext.org'", then = "'plaintext'"},
+        { if = "rcpt_domain == 'bounce.org'", then = "'bounce'"},
+        { else = "'default'" }]
+
+[queue.tls.plaintext]
+on-handshake-failure = "plaintext"
+
+[queue.tls.bounce]
+on-handshake-failure = "bounce"
+
+"#;
+
+const LOCAL_STRIPPING: &str = r#"
+[session.rcpt]
+relay = true
+
+[queue.tls.default]
+allow-invalid-certs = true
+
+"#;
+
 const REMOTE: &str = r#"
 [session.rcpt]
 relay = true
@@ -43,6 +114,9 @@ reject-non-fqdn = false
 [session.extensions]
 dsn = true
 chunking = false
+
+[session.data.add-headers]
+received = true
 "#;
 
 #[tokio::test]
@@ -106,3 +180,564 @@ async fn starttls_optional() {
         .await
         .assert_not_contains("using TLSv1.3 with cipher");
 }
+
+#[tokio::test]
+#[serial_test::serial]
+async fn tls_policy_relaxed() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start test server
+    let mut remote = TestSMTP::new("smtp_tls_relaxed_remote", REMOTE).await;
+    let _rx = remote.start(&[ServerProtocol::Smtp]).await;
+
+    // Add mock DNS entries
+    let mut local = TestSMTP::new("smtp_tls_relaxed_local", LOCAL_POLICY).await;
+    let core = local.build_smtp();
+    core.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx.foobar.org".to_string()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.ipv4_add(
+        "mx.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    // The relaxed policy only allows a TLS 1.2 cipher suite
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    local.queue_receiver.read_event().await.assert_done();
+    remote
+        .queue_receiver
+        .expect_message()
+        .await
+        .read_lines(&remote.queue_receiver)
+        .await
+        .assert_contains("using TLSv1.2 with cipher TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256");
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn tls_policy_strict() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start a mock server that only supports TLS 1.2
+    let listener = TcpListener::bind("127.0.0.1:9925").await.unwrap();
+    let acceptor = tls12_acceptor();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+        stream
+            .get_mut()
+            .write_all(b"220 mx.strict.org ESMTP\r\n")
+            .await
+            .unwrap();
+        let mut line = String::new();
+        while stream.read_line(&mut line).await.unwrap_or(0) > 0 {
+            if line.starts_with("EHLO") {
+                stream
+                    .get_mut()
+                    .write_all(b"250-mx.strict.org\r\n250 STARTTLS\r\n")
+                    .await
+                    .unwrap();
+            } else if line.starts_with("STARTTLS") {
+                stream
+                    .get_mut()
+                    .write_all(b"220 2.0.0 Ready to start TLS\r\n")
+                    .await
+                    .unwrap();
+                let _ = acceptor.accept(stream.into_inner()).await;
+                break;
+            } else {
+                stream
+                    .get_mut()
+                    .write_all(b"250 2.0.0 OK\r\n")
+                    .await
+                    .unwrap();
+            }
+            line.clear();
+        }
+    });
+
+    // Add mock DNS entries
+    let mut local = TestSMTP::new("smtp_tls_strict_local", LOCAL_POLICY).await;
+    let core = local.build_smtp();
+    core.mx_add(
+        "strict.org",
+        vec![MX {
+            exchanges: vec!["mx.strict.org".to_string()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.ipv4_add(
+        "mx.strict.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    // The strict policy requires TLS 1.3, delivery is deferred
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message("john@test.org", &["bill@strict.org"], "test:no_dkim", "250")
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    local.queue_receiver.read_event().await.assert_refresh();
+    local.queue_receiver.assert_no_events();
+    let message = local.queue_receiver.last_queued_message().await;
+    let status = message.message.recipients[0].status.to_string();
+    assert!(
+        status.contains("Handshake failed"),
+        "Message: {:?}",
+        message
+    );
+    local.queue_receiver.clear_queue(&core).await;
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn tls_details() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start test server
+    let mut remote = TestSMTP::new("smtp_tls_details_remote", REMOTE).await;
+    let _rx = remote.start(&[ServerProtocol::Smtp]).await;
+
+    // Add mock DNS entries, foobar.net is unreachable
+    let mut local = TestSMTP::new("smtp_tls_details_local", LOCAL_TLS_DETAILS).await;
+    let core = local.build_smtp();
+    for (domain, ip) in [("foobar.org", "127.0.0.1"), ("foobar.net", "127.0.0.2")] {
+        core.mx_add(
+            domain,
+            vec![MX {
+                exchanges: vec![format!("mx.{domain}")],
+                preference: 10,
+            }],
+            Instant::now() + Duration::from_secs(10),
+        );
+        core.ipv4_add(
+            &format!("mx.{domain}"),
+            vec![ip.parse().unwrap()],
+            Instant::now() + Duration::from_secs(10),
+        );
+    }
+
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message(
+            "john@test.org",
+            &["<bill@foobar.org> NOTIFY=SUCCESS", "jane@foobar.net"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    let queue_id = local.queue_receiver.expect_message().await.queue_id;
+    local
+        .queue_receiver
+        .delivery_attempt(queue_id)
+        .await
+        .try_deliver(core.clone());
+
+    // The success DSN includes the TLS session details
+    local
+        .queue_receiver
+        .expect_message()
+        .await
+        .read_lines(&local.queue_receiver)
+        .await
+        .assert_contains("<bill@foobar.org> (delivered to 'mx.foobar.org' using TLSv1.3 (TLS13_");
+    local.queue_receiver.read_event().await.assert_refresh();
+    remote.queue_receiver.expect_message().await;
+
+    // The TLS session details are stored on the delivered recipient
+    let message = local
+        .queue_receiver
+        .read_queued_messages()
+        .await
+        .into_iter()
+        .find(|message| message.queue_id == queue_id)
+        .expect("Message not found in queue");
+    let rcpt = |address: &str| {
+        message
+            .message
+            .recipients
+            .iter()
+            .find(|rcpt| rcpt.address_lcase == address)
+            .unwrap()
+    };
+    let tls = rcpt("bill@foobar.org")
+        .tls
+        .as_ref()
+        .expect("Missing TLS details");
+    assert_eq!(tls.version, "TLSv1.3");
+    assert!(tls.cipher_suite.starts_with("TLS13_"), "{tls:?}");
+    assert_eq!(tls.peer_subject.as_deref(), Some("CN=localhost"));
+    assert_eq!(rcpt("jane@foobar.net").tls, None);
+    local.queue_receiver.clear_queue(&core).await;
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn tls_handshake_failure() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start a mock server that advertises STARTTLS but drops the handshake
+    let listener = TcpListener::bind("127.0.0.1:9925").await.unwrap();
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        // One connection per attempt, plus the plaintext reconnection
+        for _ in 0..5 {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            stream
+                .get_mut()
+                .write_all(b"220 mx.foobar.org ESMTP\r\n")
+                .await
+                .unwrap();
+            let mut line = String::new();
+            let mut in_data = false;
+            while stream.read_line(&mut line).await.unwrap_or(0) > 0 {
+                let response: &[u8] = if in_data {
+                    if line == ".\r\n" {
+                        in_data = false;
+                        let _ = tx.send(());
+                        b"250 2.0.0 Queued\r\n"
+                    } else {
+                        b""
+                    }
+                } else if line.starts_with("EHLO") {
+                    b"250-mx.foobar.org\r\n250 STARTTLS\r\n"
+                } else if line.starts_with("STARTTLS") {
+                    let _ = stream
+                        .get_mut()
+                        .write_all(b"220 2.0.0 Ready to start TLS\r\n")
+                        .await;
+                    break;
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    b"354 Start mail input\r\n"
+                } else if line.starts_with("QUIT") {
+                    let _ = stream.get_mut().write_all(b"221 Bye\r\n").await;
+                    break;
+                } else {
+                    b"250 2.0.0 OK\r\n"
+                };
+                if !response.is_empty() {
+                    stream.get_mut().write_all(response).await.unwrap();
+                }
+                line.clear();
+            }
+        }
+    });
+
+    // Add mock DNS entries
+    let mut local = TestSMTP::new("smtp_tls_handshake_local", LOCAL_HANDSHAKE).await;
+    let core = local.build_smtp();
+    for domain in ["defer.org", "plaintext.org", "bounce.org"] {
+        core.mx_add(
+            domain,
+            vec![MX {
+                exchanges: vec![format!("mx.{domain}")],
+                preference: 10,
+            }],
+            Instant::now() + Duration::from_secs(10),
+        );
+        core.ipv4_add(
+            &format!("mx.{domain}"),
+            vec!["127.0.0.1".parse().unwrap()],
+            Instant::now() + Duration::from_secs(10),
+        );
+    }
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+
+    // Deferred messages are retried later
+    session
+        .send_message("john@test.org", &["bill@defer.org"], "test:no_dkim", "250")
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    local.queue_receiver.read_event().await.assert_refresh();
+    let message = local.queue_receiver.last_queued_message().await;
+    let rcpt = &message.message.recipients[0];
+    assert!(
+        matches!(
+            &rcpt.status,
+            Status::TemporaryFailure(ErrorDetails {
+                details: Error::TlsError(_),
+                ..
+            })
+        ),
+        "{:?}",
+        rcpt.status
+    );
+    local.queue_receiver.clear_queue(&core).await;
+
+    // Bounced messages generate a failure DSN
+    session
+        .send_message("john@test.org", &["bill@bounce.org"], "test:no_dkim", "250")
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    let dsn = local.queue_receiver.expect_message().await;
+    assert!(dsn.message.return_path.is_empty());
+    dsn.read_lines(&local.queue_receiver)
+        .await
+        .assert_contains("Final-Recipient: rfc822;bill@bounce.org")
+        .assert_contains("Action: failed")
+        .assert_contains("TLS error from 'mx.bounce.org'");
+    local.queue_receiver.read_event().await.assert_done();
+    local.queue_receiver.clear_queue(&core).await;
+
+    // Downgraded messages are delivered in plaintext over a new connection
+    session
+        .send_message(
+            "john@test.org",
+            &["bill@plaintext.org"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    local.queue_receiver.read_event().await.assert_done();
+    local.queue_receiver.assert_queue_is_empty().await;
+    tokio::time::timeout(Duration::from_secs(1), rx.recv())
+        .await
+        .expect("Message not delivered")
+        .unwrap();
+    assert!(rx.try_recv().is_err());
+
+    // Hosts known to support TLS are not downgraded to plaintext
+    core.set_starttls_support("mx.plaintext.org", Duration::from_secs(60), 0)
+        .await;
+    session
+        .send_message(
+            "john@test.org",
+            &["bill@plaintext.org"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    local.queue_receiver.read_event().await.assert_refresh();
+    let message = local.queue_receiver.last_queued_message().await;
+    let rcpt = &message.message.recipients[0];
+    assert!(
+        matches!(
+            &rcpt.status,
+            Status::TemporaryFailure(ErrorDetails {
+                details: Error::TlsError(_),
+                ..
+            })
+        ),
+        "{:?}",
+        rcpt.status
+    );
+    assert!(rx.try_recv().is_err());
+    local.queue_receiver.clear_queue(&core).await;
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn starttls_stripping() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start a mock server that stops advertising STARTTLS after the first connection
+    let listener = TcpListener::bind("127.0.0.1:9925").await.unwrap();
+    let acceptor = tls12_acceptor();
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        for starttls in [true, false] {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream
+                .write_all(b"220 mx.foobar.org ESMTP\r\n")
+                .await
+                .unwrap();
+            if let Some(stream) = mock_smtp_session(stream, starttls, &tx).await {
+                let stream = acceptor.accept(stream).await.unwrap();
+                mock_smtp_session(stream, false, &tx).await;
+            }
+        }
+    });
+
+    // Add mock DNS entries
+    let mut local = TestSMTP::new("smtp_starttls_stripping_local", LOCAL_STRIPPING).await;
+    let core = local.build_smtp();
+    core.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx.foobar.org".to_string()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.ipv4_add(
+        "mx.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+
+    // The first message is delivered over TLS
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    local.queue_receiver.read_event().await.assert_done();
+    tokio::time::timeout(Duration::from_secs(1), rx.recv())
+        .await
+        .expect("Message not delivered")
+        .unwrap();
+
+    // Delivery is deferred once STARTTLS is no longer advertised
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    local
+        .queue_receiver
+        .expect_message_then_deliver()
+        .await
+        .try_deliver(core.clone());
+    local.queue_receiver.read_event().await.assert_refresh();
+    let message = local.queue_receiver.last_queued_message().await;
+    let status = &message.message.recipients[0].status;
+    assert!(
+        matches!(
+            status,
+            Status::TemporaryFailure(ErrorDetails {
+                details: Error::TlsError(details),
+                ..
+            }) if details.contains("downgrade")
+        ),
+        "{status:?}"
+    );
+    assert!(rx.try_recv().is_err());
+    local.queue_receiver.clear_queue(&core).await;
+}
+
+async fn mock_smtp_session<T: AsyncRead + AsyncWrite + Unpin>(
+    stream: T,
+    starttls: bool,
+    tx: &mpsc::UnboundedSender<()>,
+) -> Option<T> {
+    let mut stream = BufReader::new(stream);
+    let mut line = String::new();
+    let mut in_data = false;
+    while stream.read_line(&mut line).await.unwrap_or(0) > 0 {
+        let response: &[u8] = if in_data {
+            if line == ".\r\n" {
+                in_data = false;
+                let _ = tx.send(());
+                b"250 2.0.0 Queued\r\n"
+            } else {
+                b""
+            }
+        } else if line.starts_with("EHLO") {
+            if starttls {
+                b"250-mx.foobar.org\r\n250 STARTTLS\r\n"
+            } else {
+                b"250-mx.foobar.org\r\n250 8BITMIME\r\n"
+            }
+        } else if line.starts_with("STARTTLS") {
+            stream
+                .get_mut()
+                .write_all(b"220 2.0.0 Ready to start TLS\r\n")
+                .await
+                .unwrap();
+            return Some(stream.into_inner());
+        } else if line.starts_with("DATA") {
+            in_data = true;
+            b"354 Start mail input\r\n"
+        } else if line.starts_with("QUIT") {
+            let _ = stream.get_mut().write_all(b"221 Bye\r\n").await;
+            break;
+        } else {
+            b"250 2.0.0 OK\r\n"
+        };
+        if !response.is_empty() {
+            stream.get_mut().write_all(response).await.unwrap();
+        }
+        line.clear();
+    }
+
+    None
+}
+
+fn tls12_acceptor() -> TlsAcceptor {
+    let cert_file = std::fs::read(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/resources/smtp/certs/tls_cert.pem"
+    ))
+    .unwrap();
+    let pk_file = std::fs::read(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/resources/smtp/certs/tls_privatekey.pem"
+    ))
+    .unwrap();
+    let cert_chain = certs(&mut cert_file.as_slice())
+        .map(|r| r.unwrap())
+        .collect();
+    let key = pkcs8_private_keys(&mut pk_file.as_slice())
+        .map(|v| PrivateKeyDer::Pkcs8(v.unwrap()))
+        .next()
+        .expect("Could not locate PKCS 8 private keys.");
+
+    TlsAcceptor::from(Arc::new(
+        ServerConfig::builder_with_protocol_versions(&[&TLS12])
+            .with_no_client_auth()
+            .with_single_cert(cert_chain, key)
+            .unwrap(),
+    ))
+}
@@ -12,11 +12,18 @@ use std::{
 };
 
 use common::config::smtp::queue::{QueueExpiry, QueueName};
-use smtp_proto::{RCPT_NOTIFY_DELAY, RCPT_NOTIFY_FAILURE, RCPT_NOTIFY_SUCCESS, Response};
+use mail_auth::hickory_resolver::proto::op::ResponseCode;
+use smtp_proto::{
+    MAIL_RET_FULL, RCPT_NOTIFY_DELAY, RCPT_NOTIFY_FAILURE, RCPT_NOTIFY_SUCCESS, Response,
+};
 use store::write::now;
 use utils::BlobHash;
 
-use crate::smtp::{QueueReceiver, TestSMTP, inbound::sign::SIGNATURES};
+use crate::smtp::{
+    QueueReceiver, TestSMTP,
+    inbound::{TestMessage, sign::SIGNATURES},
+    session::{TestSession, VerifyResponse},
+};
 use smtp::queue::{
     Error, ErrorDetails, HostResponse, Message, MessageWrapper, Recipient, Schedule, Status,
     UnexpectedResponse, dsn::SendDsn,
@@ -82,10 +89,13 @@ async fn generate_dsn() {
                 }),
                 flags: 0,
                 orcpt: None,
+                tls: None,
+                transcript: None,
                 retry: Schedule::now(),
                 notify: Schedule::now(),
                 expires: QueueExpiry::Duration(10),
                 queue: QueueName::default(),
+                next_hop: None,
             }],
             flags: 0,
             env_id: None,
@@ -94,6 +104,8 @@ async fn generate_dsn() {
             quota_keys: vec![],
             received_from_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
             received_via_port: 0,
+            headers: vec![],
+            submission: None,
         },
     };
 
@@ -136,10 +148,13 @@ async fn generate_dsn() {
         }),
         flags,
         orcpt: None,
+        tls: None,
+        transcript: None,
         retry: Schedule::now(),
         notify: Schedule::now(),
         expires: QueueExpiry::Duration(10),
         queue: QueueName::default(),
+        next_hop: None,
     });
     core.send_dsn(&mut message).await;
     let dsn_message = qr.expect_message().await;
@@ -155,10 +170,13 @@ async fn generate_dsn() {
         }),
         flags,
         orcpt: Some("jdoe@example.org".into()),
+        tls: None,
+        transcript: None,
         retry: Schedule::now(),
         notify: Schedule::now(),
         expires: QueueExpiry::Duration(10),
         queue: QueueName::default(),
+        next_hop: None,
     });
     core.send_dsn(&mut message).await;
     let dsn_message = qr.expect_message().await;
@@ -178,6 +196,415 @@ async fn generate_dsn() {
     assert_eq!(queue.len(), 4);
 }
 
+#[tokio::test]
+async fn generate_dsn_template() {
+    // Enable logging
+    crate::enable_logging();
+
+    let mut local = TestSMTP::new(
+        "smtp_dsn_template_test",
+        CONFIG.to_string()
+            + "template = \"{{rcpt}} via {{host}}: {{smtp_response}}\"\n"
+            + SIGNATURES,
+    )
+    .await;
+    let core = local.build_smtp();
+    let qr = &mut local.queue_receiver;
+
+    let flags = RCPT_NOTIFY_FAILURE | RCPT_NOTIFY_DELAY | RCPT_NOTIFY_SUCCESS;
+    let mut message = test_message(
+        BlobHash::generate(b"Subject: test\r\n\r\ntest"),
+        0,
+        vec![
+            test_rcpt(
+                "foobar@example.org",
+                user_unknown("foobar@example.org"),
+                flags,
+            ),
+            test_rcpt("jane@example.org", accepted(), flags),
+        ],
+    );
+
+    // The human-readable part is rendered from the template
+    core.send_dsn(&mut message).await;
+    qr.expect_message()
+        .await
+        .read_lines(qr)
+        .await
+        .assert_contains("foobar@example.org via mx.example.org: 550 (5.1.2) User does not exist")
+        .assert_contains("jane@example.org via mx2.example.org: 250 (2.1.5) Message accepted")
+        .assert_not_contains("(host 'mx.example.org' rejected")
+        .assert_contains("Action: failed")
+        .assert_contains("Status: 5.1.2")
+        .assert_contains("Action: delivered");
+}
+
+#[tokio::test]
+async fn generate_dsn_large_message() {
+    // Enable logging
+    crate::enable_logging();
+
+    let mut local = TestSMTP::new("smtp_dsn_large_test", CONFIG.to_string() + SIGNATURES).await;
+    let core = local.build_smtp();
+    let qr = &mut local.queue_receiver;
+
+    let mut contents = String::from("From: sender@foobar.org\r\nSubject: Bulk mailing\r\n\r\n");
+    for _ in 0..10000 {
+        contents.push_str("This line is part of a very large message body.\r\n");
+    }
+    let blob_hash = BlobHash::generate(contents.as_bytes());
+    qr.blob_store
+        .put_blob(blob_hash.as_slice(), contents.as_bytes())
+        .await
+        .unwrap();
+
+    let mut message = test_message(
+        blob_hash,
+        contents.len() as u64,
+        vec![test_rcpt(
+            "jane@example.org",
+            accepted(),
+            RCPT_NOTIFY_SUCCESS,
+        )],
+    );
+    message.message.flags = MAIL_RET_FULL;
+
+    // Success DSNs only return the original headers, even with RET=FULL
+    core.send_dsn(&mut message).await;
+    let dsn_message = qr.expect_message().await;
+    assert!(dsn_message.message.size < 4096);
+    dsn_message
+        .read_lines(qr)
+        .await
+        .assert_contains("Action: delivered")
+        .assert_contains("Subject: Bulk mailing")
+        .assert_not_contains("very large message body");
+}
+
+#[tokio::test]
+async fn generate_dsn_sender() {
+    // Enable logging
+    crate::enable_logging();
+
+    let mut local = TestSMTP::new(
+        "smtp_dsn_sender_test",
+        CONFIG
+            .replace(
+                "'Mail Delivery Subsystem'",
+                "'Postmaster of ' + sender_domain",
+            )
+            .replace(
+                "'MAILER-DAEMON@example.org'",
+                "'postmaster@' + sender_domain",
+            )
+            + SIGNATURES,
+    )
+    .await;
+    let core = local.build_smtp();
+    let qr = &mut local.queue_receiver;
+
+    let contents = "From: sender@foobar.org\r\nSubject: Test\r\n\r\nHello\r\n";
+    let blob_hash = BlobHash::generate(contents.as_bytes());
+    qr.blob_store
+        .put_blob(blob_hash.as_slice(), contents.as_bytes())
+        .await
+        .unwrap();
+
+    let mut message = test_message(
+        blob_hash,
+        contents.len() as u64,
+        vec![test_rcpt(
+            "jane@example.org",
+            user_unknown("jane@example.org"),
+            RCPT_NOTIFY_FAILURE,
+        )],
+    );
+
+    // The From header is built from the configured name and address,
+    // while the envelope sender of the DSN remains null
+    core.send_dsn(&mut message).await;
+    let dsn_message = qr.expect_message().await;
+    assert!(dsn_message.message.return_path.is_empty());
+    assert_eq!(dsn_message.message.recipients.len(), 1);
+    assert_eq!(
+        dsn_message.message.recipients[0].address,
+        "sender@foobar.org"
+    );
+    dsn_message
+        .read_lines(qr)
+        .await
+        .assert_contains("Postmaster of foobar.org")
+        .assert_contains("<postmaster@foobar.org>")
+        .assert_not_contains("MAILER-DAEMON@example.org");
+}
+
+#[tokio::test]
+async fn generate_dsn_orcpt() {
+    // Enable logging
+    crate::enable_logging();
+
+    let mut local = TestSMTP::new(
+        "smtp_dsn_orcpt_test",
+        CONFIG.to_string() + "\n[session.extensions]\ndsn = true\n" + SIGNATURES,
+    )
+    .await;
+    let core = local.build_smtp();
+    let mut session = local.new_session();
+    let qr = &mut local.queue_receiver;
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+
+    // The xtext encoded ORCPT is decoded when received
+    session
+        .send_message(
+            "john@test.org",
+            &["<bill@foobar.org> NOTIFY=FAILURE ORCPT=rfc822;Bill+2BDoe+3Dx+20y@foobar.org"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    let mut message = qr.expect_message().await;
+    let rcpt = &mut message.message.recipients[0];
+    assert_eq!(rcpt.orcpt.as_deref(), Some("Bill+Doe=x y@foobar.org"));
+    rcpt.status = Status::PermanentFailure(ErrorDetails {
+        entity: "mx.foobar.org".into(),
+        details: Error::UnexpectedResponse(UnexpectedResponse {
+            command: "RCPT TO:<bill@foobar.org>".into(),
+            response: Response {
+                code: 550,
+                esc: [5, 1, 2],
+                message: "User does not exist".into(),
+            },
+        }),
+    });
+
+    // The Original-Recipient field is encoded back as xtext
+    core.send_dsn(&mut message).await;
+    qr.expect_message()
+        .await
+        .read_lines(qr)
+        .await
+        .assert_contains("Original-Recipient: rfc822;Bill+2BDoe+3Dx+20y@foobar.org")
+        .assert_contains("Final-Recipient: rfc822;bill@foobar.org")
+        .assert_contains("Action: failed");
+}
+
+#[tokio::test]
+async fn generate_dsn_delay_window() {
+    // Enable logging
+    crate::enable_logging();
+
+    let mut local = TestSMTP::new(
+        "smtp_dsn_delay_window_test",
+        CONFIG.replace(
+            "sign = \"['rsa']\"",
+            "sign = \"['rsa']\"\ndelay-window = \"1m\"",
+        ) + SIGNATURES,
+    )
+    .await;
+    let core = local.build_smtp();
+    let qr = &mut local.queue_receiver;
+
+    let contents = "From: sender@foobar.org\r\nSubject: Test\r\n\r\nHello\r\n";
+    let blob_hash = BlobHash::generate(contents.as_bytes());
+    qr.blob_store
+        .put_blob(blob_hash.as_slice(), contents.as_bytes())
+        .await
+        .unwrap();
+
+    let delayed_rcpt = |addr: &str, host: &str, notify_in: u64| Recipient {
+        retry: Schedule::later(60),
+        notify: Schedule::later(notify_in),
+        ..test_rcpt(
+            addr,
+            Status::TemporaryFailure(ErrorDetails {
+                entity: host.into(),
+                details: Error::ConnectionError("Connection refused".into()),
+            }),
+            RCPT_NOTIFY_DELAY,
+        )
+    };
+    let mut message = test_message(
+        blob_hash,
+        contents.len() as u64,
+        vec![
+            delayed_rcpt("jane@example.org", "mx.example.org", 0),
+            delayed_rcpt("john@example.net", "mx.example.net", 30),
+        ],
+    );
+
+    // Both delayed recipients are reported in a single DSN
+    core.send_dsn(&mut message).await;
+    qr.expect_message()
+        .await
+        .read_lines(qr)
+        .await
+        .assert_contains("Warning: Delay in message delivery")
+        .assert_contains("Final-Recipient: rfc822;jane@example.org")
+        .assert_contains("Final-Recipient: rfc822;john@example.net");
+    qr.assert_no_events();
+    for rcpt in &message.message.recipients {
+        assert_eq!(rcpt.notify.inner, 1, "{}", rcpt.address);
+        assert!(rcpt.notify.due > now() + 30, "{}", rcpt.address);
+    }
+
+    // No further delay DSN is sent once the window has been consumed
+    core.send_dsn(&mut message).await;
+    qr.assert_no_events();
+}
+
+#[tokio::test]
+async fn generate_dsn_status_codes() {
+    // Enable logging
+    crate::enable_logging();
+
+    let mut local = TestSMTP::new(
+        "smtp_dsn_status_codes_test",
+        CONFIG.to_string() + SIGNATURES,
+    )
+    .await;
+    let core = local.build_smtp();
+    let qr = &mut local.queue_receiver;
+
+    let contents = "From: sender@foobar.org\r\nSubject: Test\r\n\r\nHello\r\n";
+    let blob_hash = BlobHash::generate(contents.as_bytes());
+    qr.blob_store
+        .put_blob(blob_hash.as_slice(), contents.as_bytes())
+        .await
+        .unwrap();
+
+    for (status, action, expected_status) in [
+        // Non-existent domain
+        (
+            Status::from_mail_auth_error(
+                "nxdomain.org",
+                mail_auth::Error::DnsRecordNotFound(ResponseCode::NXDomain),
+            ),
+            "failed",
+            "5.1.2",
+        ),
+        // DNS resolution failure that has expired
+        (
+            Status::from_mail_auth_error(
+                "foobar.org",
+                mail_auth::Error::DnsError("SERVFAIL".into()),
+            )
+            .into_permanent(),
+            "failed",
+            "5.4.4",
+        ),
+        // MX host without address records
+        (
+            Status::PermanentFailure(ErrorDetails {
+                entity: "mx.foobar.org".into(),
+                details: Error::DnsError("record not found for MX".into()),
+            }),
+            "failed",
+            "5.4.4",
+        ),
+        // All MX hosts unreachable
+        (
+            Status::TemporaryFailure(ErrorDetails {
+                entity: "mx.foobar.org".into(),
+                details: Error::ConnectionError("Connection refused".into()),
+            }),
+            "delayed",
+            "4.4.1",
+        ),
+    ] {
+        let mut message = test_message(
+            blob_hash.clone(),
+            contents.len() as u64,
+            vec![test_rcpt(
+                "bill@foobar.org",
+                status,
+                RCPT_NOTIFY_FAILURE | RCPT_NOTIFY_DELAY,
+            )],
+        );
+
+        core.send_dsn(&mut message).await;
+        qr.expect_message()
+            .await
+            .read_lines(qr)
+            .await
+            .assert_contains(&format!("Action: {action}"))
+            .assert_contains(&format!("Status: {expected_status}"));
+    }
+}
+
+fn test_message(blob_hash: BlobHash, size: u64, recipients: Vec<Recipient>) -> MessageWrapper {
+    MessageWrapper {
+        queue_id: 0,
+        span_id: 0,
+        is_multi_queue: false,
+        queue_name: QueueName::default(),
+        message: Message {
+            size,
+            created: now(),
+            return_path: "sender@foobar.org".into(),
+            return_path_lcase: "sender@foobar.org".into(),
+            return_path_domain: "foobar.org".into(),
+            recipients,
+            flags: 0,
+            env_id: None,
+            priority: 0,
+            blob_hash,
+            quota_keys: vec![],
+            received_from_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            received_via_port: 0,
+            headers: vec![],
+            submission: None,
+        },
+    }
+}
+
+fn test_rcpt(
+    address: &str,
+    status: Status<HostResponse<String>, ErrorDetails>,
+    flags: u64,
+) -> Recipient {
+    Recipient {
+        address: address.into(),
+        address_lcase: address.into(),
+        status,
+        flags,
+        orcpt: None,
+        tls: None,
+        transcript: None,
+        retry: Schedule::now(),
+        notify: Schedule::now(),
+        expires: QueueExpiry::Duration(86400),
+        queue: QueueName::default(),
+        next_hop: None,
+    }
+}
+
+fn user_unknown(address: &str) -> Status<HostResponse<String>, ErrorDetails> {
+    Status::PermanentFailure(ErrorDetails {
+        entity: "mx.example.org".into(),
+        details: Error::UnexpectedResponse(UnexpectedResponse {
+            command: format!("RCPT TO:<{address}>"),
+            response: Response {
+                code: 550,
+                esc: [5, 1, 2],
+                message: "User does not exist".into(),
+            },
+        }),
+    })
+}
+
+fn accepted() -> Status<HostResponse<String>, ErrorDetails> {
+    Status::Completed(HostResponse {
+        hostname: "mx2.example.org".into(),
+        response: Response {
+            code: 250,
+            esc: [2, 1, 5],
+            message: "Message accepted".into(),
+        },
+    })
+}
+
 impl QueueReceiver {
     async fn compare_dsn(&self, message: Message, test: &str) {
         let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
@@ -5,18 +5,29 @@
  */
 
 use crate::smtp::{
-    TestSMTP,
+    DnsCache, TestSMTP,
+    inbound::TestQueueEvent,
     queue::{QueuedEvents, build_rcpt},
+    session::TestSession,
 };
-use common::config::smtp::queue::QueueName;
+use ahash::AHashMap;
+use common::{
+    config::{server::ServerProtocol, smtp::queue::QueueName},
+    ipc::{QueueEvent, QueueEventStatus},
+};
+use mail_auth::MX;
 use smtp::queue::{
-    Error, ErrorDetails, Message, MessageWrapper, Recipient, Status, spool::SmtpSpool,
+    Error, ErrorDetails, Message, MessageWrapper, Recipient, Status,
+    manager::{Queue, QueueStats},
+    spool::{QueueFilter, SmtpSpool},
 };
 use std::{
     net::{IpAddr, Ipv4Addr},
-    time::Duration,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
 };
 use store::write::now;
+use tokio::sync::mpsc;
 
 const CONFIG: &str = r#"
 [session.ehlo]
@@ -26,6 +37,20 @@ reject-non-fqdn = false
 relay = true
 "#;
 
+const REMOTE: &str = r#"
+[session.ehlo]
+reject-non-fqdn = false
+
+[session.rcpt]
+relay = true
+
+[session.extensions]
+chunking = false
+
+[spam-filter]
+enable = false
+"#;
+
 #[tokio::test]
 async fn queue_due() {
     // Enable logging
@@ -72,6 +97,234 @@ async fn queue_due() {
     qr.assert_queue_is_empty().await;
 }
 
+#[tokio::test]
+async fn queue_priority() {
+    // Enable logging
+    crate::enable_logging();
+
+    let local = TestSMTP::new("smtp_queue_priority_test", CONFIG).await;
+    let core = local.build_smtp();
+
+    // Messages due at the same time are returned by descending priority
+    let due = now();
+    for (queue_id, priority) in [(20, 0), (21, 5), (22, -3), (23, 5)] {
+        let mut message = new_message(queue_id);
+        message.message.priority = priority;
+        let mut rcpt = build_rcpt("a@foobar.org", 0, 3600, 7200);
+        rcpt.retry.due = due;
+        message.message.recipients.push(rcpt);
+        message.save_changes(&core, 0.into()).await;
+    }
+
+    let queued = core.all_queued_messages().await;
+    assert_eq!(
+        queued
+            .messages
+            .iter()
+            .map(|event| (event.queue_id, event.priority))
+            .collect::<Vec<_>>(),
+        vec![(21, 5), (23, 5), (20, 0), (22, -3)]
+    );
+
+    local.queue_receiver.clear_queue(&core).await;
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn queue_drain() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Start test server
+    let mut remote = TestSMTP::new("smtp_queue_drain_remote", REMOTE).await;
+    let _rx = remote.start(&[ServerProtocol::Smtp]).await;
+    let mut local = TestSMTP::new("smtp_queue_drain_test", CONFIG).await;
+
+    let core = local.build_smtp();
+    core.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx.foobar.org".into()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.ipv4_add(
+        "mx.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    // Queue two messages
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    for rcpt in ["bill@foobar.org", "jane@foobar.org"] {
+        session
+            .send_message("john@test.org", &[rcpt], "test:no_dkim", "250")
+            .await;
+        local.queue_receiver.expect_message().await;
+    }
+
+    // Start a queue manager that delivers one message at a time
+    let (tx, rx) = mpsc::channel(128);
+    let mut queue = Queue::new(core.inner.clone(), rx);
+    queue.stats.insert(
+        QueueName::default(),
+        QueueStats {
+            in_flight: 0,
+            max_in_flight: 1,
+            last_warning: Instant::now(),
+        },
+    );
+    let manager = tokio::spawn(async move { queue.start().await });
+
+    // Drain the queue while the first delivery is in flight
+    let mut delivered = None;
+    for _ in 0..50 {
+        if remote.queue_receiver.try_read_event().await.is_some() {
+            delivered = Some(remote.queue_receiver.last_queued_message().await);
+            break;
+        }
+    }
+    let delivered = delivered.expect("No delivery attempt was started");
+    tx.send(QueueEvent::Drain).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(core.inner.data.queue_draining.load(Ordering::Relaxed));
+    assert!(!manager.is_finished());
+
+    // New messages are rejected at MAIL FROM
+    session.rset().await;
+    session.mail_from("john@test.org", "452 4.3.2").await;
+
+    // The in-flight delivery completes and the drain finishes
+    let event = tokio::time::timeout(Duration::from_secs(5), local.queue_receiver.queue_rx.recv())
+        .await
+        .expect("In-flight delivery did not complete")
+        .unwrap();
+    assert!(matches!(
+        event,
+        QueueEvent::WorkerDone {
+            status: QueueEventStatus::Completed,
+            ..
+        }
+    ));
+    tx.send(event).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(1), manager)
+        .await
+        .expect("Queue manager did not stop")
+        .unwrap();
+
+    // No new delivery attempts were started
+    let queued = local.queue_receiver.read_queued_messages().await;
+    assert_eq!(queued.len(), 1);
+    assert_ne!(
+        queued[0].message.recipients[0].address,
+        delivered.message.recipients[0].address
+    );
+    local.queue_receiver.assert_no_events();
+    remote.queue_receiver.assert_no_events();
+
+    local.queue_receiver.clear_queue(&core).await;
+    remote
+        .queue_receiver
+        .clear_queue(&remote.build_smtp())
+        .await;
+}
+
+#[tokio::test]
+async fn queue_requeue() {
+    // Enable logging
+    crate::enable_logging();
+
+    let mut local = TestSMTP::new("smtp_queue_requeue_test", CONFIG).await;
+    let core = local.build_smtp();
+
+    let mut message = new_message(10);
+    message
+        .message
+        .recipients
+        .push(build_rcpt("a@foobar.org", 3600, 7200, 86400));
+    message
+        .message
+        .recipients
+        .push(build_rcpt("b@foobar.net", 3600, 7200, 86400));
+    message.save_changes(&core, 0.into()).await;
+
+    // Requeue a single recipient domain
+    assert!(core.requeue(10, Some("foobar.org"), None).await.unwrap());
+    local.queue_receiver.read_event().await.assert_refresh();
+    let message = core.read_message(10, QueueName::default()).await.unwrap();
+    assert!(message.message.recipients[0].retry.due <= now());
+    assert!(message.message.recipients[1].retry.due > now() + 3000);
+
+    // Requeue all recipients at a given time
+    let due = now() + 60;
+    assert!(core.requeue(10, None, Some(due)).await.unwrap());
+    local.queue_receiver.read_event().await.assert_refresh();
+    let message = core.read_message(10, QueueName::default()).await.unwrap();
+    for rcpt in &message.message.recipients {
+        assert_eq!(rcpt.retry.due, due);
+    }
+
+    // Unknown domains and messages
+    assert!(!core.requeue(10, Some("example.org"), None).await.unwrap());
+    assert!(core.requeue(11, None, None).await.is_err());
+    local.queue_receiver.assert_no_events();
+    local.queue_receiver.clear_queue(&core).await;
+}
+
+#[tokio::test]
+async fn queue_hold() {
+    // Enable logging
+    crate::enable_logging();
+
+    let mut local = TestSMTP::new("smtp_queue_hold_test", CONFIG).await;
+    let core = local.build_smtp();
+
+    let mut message = new_message(20);
+    message
+        .message
+        .recipients
+        .push(build_rcpt("a@foobar.org", 10, 20, 30));
+    message
+        .message
+        .recipients
+        .push(build_rcpt("b@foobar.net", 40, 50, 60));
+    message.save_changes(&core, 0.into()).await;
+
+    // Held recipients are excluded from scheduling
+    assert!(core.hold(20, "A@foobar.org").await.unwrap());
+    local.queue_receiver.read_event().await.assert_refresh();
+    assert!(!core.hold(20, "a@foobar.org").await.unwrap());
+    assert!(!core.hold(20, "c@foobar.org").await.unwrap());
+    assert!(core.hold(21, "a@foobar.org").await.is_err());
+    let message = core.read_message(20, QueueName::default()).await.unwrap();
+    assert!(message.message.recipients[0].is_held());
+    assert!(!message.message.recipients[1].is_held());
+    let expires = message.message.recipients[0].expiration_time(message.message.created);
+    assert_eq!(
+        message.message.next_event(None),
+        Some(message.message.recipients[1].retry.due)
+    );
+    assert_eq!(message.message.next_events().len(), 1);
+
+    // Releasing resumes delivery without shortening the expiration
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert!(core.release(20, "a@foobar.org").await.unwrap());
+    local.queue_receiver.read_event().await.assert_refresh();
+    assert!(!core.release(20, "a@foobar.org").await.unwrap());
+    let message = core.read_message(20, QueueName::default()).await.unwrap();
+    let rcpt = &message.message.recipients[0];
+    assert!(!rcpt.is_held());
+    assert!(rcpt.retry.due <= now());
+    assert!(rcpt.expiration_time(message.message.created) > expires);
+    assert_eq!(message.message.next_event(None), Some(rcpt.retry.due));
+    local.queue_receiver.assert_no_events();
+    local.queue_receiver.clear_queue(&core).await;
+}
+
 #[test]
 fn delivery_events() {
     let mut message = new_message(0).message;
@@ -161,6 +414,339 @@ fn delivery_events() {
     assert!(message.next_event(None).is_none());
 }
 
+#[tokio::test]
+async fn queue_list() {
+    // Enable logging
+    crate::enable_logging();
+
+    let local = TestSMTP::new("smtp_queue_list_test", CONFIG).await;
+    let core = local.build_smtp();
+
+    for (queue_id, sender, rcpts, retry) in [
+        (
+            20,
+            "bill@example.org",
+            &["a@foobar.org", "b@foobar.org"][..],
+            60,
+        ),
+        (21, "jane@example.org", &["c@foobar.net"][..], 3600),
+        (
+            22,
+            "bill@example.org",
+            &["d@foobar.net", "e@foobar.org"][..],
+            7200,
+        ),
+    ] {
+        let mut message = new_message(queue_id);
+        message.message.return_path = sender.into();
+        message.message.return_path_lcase = sender.into();
+        message.message.size = 1024 * queue_id;
+        for rcpt in rcpts {
+            message
+                .message
+                .recipients
+                .push(build_rcpt(rcpt, retry, retry + 3600, 86400));
+        }
+        message.save_changes(&core, 0.into()).await;
+    }
+
+    // List all messages
+    let list = core.list(&QueueFilter::default()).await.unwrap();
+    assert_eq!(
+        list.iter().map(|m| m.queue_id).collect::<Vec<_>>(),
+        vec![20, 21, 22]
+    );
+    assert_eq!(list[0].return_path, "bill@example.org");
+    assert_eq!(list[0].domains, vec!["foobar.org".to_string()]);
+    assert_eq!(
+        list[2].domains,
+        vec!["foobar.net".to_string(), "foobar.org".to_string()]
+    );
+    assert_eq!(list[1].size, 1024 * 21);
+    assert_eq!(list[1].retry_count, 0);
+
+    // Filter by recipient domain
+    let list = core
+        .list(&QueueFilter {
+            rcpt_domain: Some("FOOBAR.NET".into()),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        list.iter().map(|m| m.queue_id).collect::<Vec<_>>(),
+        vec![21, 22]
+    );
+
+    // Filter by recipient domain and sender
+    let list = core
+        .list(&QueueFilter {
+            rcpt_domain: Some("foobar.org".into()),
+            sender: Some("bill@example.org".into()),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        list.iter().map(|m| m.queue_id).collect::<Vec<_>>(),
+        vec![20, 22]
+    );
+
+    // Filter by next retry
+    let list = core
+        .list(&QueueFilter {
+            due_after: Some(now() + 1800),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        list.iter().map(|m| m.queue_id).collect::<Vec<_>>(),
+        vec![21, 22]
+    );
+    let list = core
+        .list(&QueueFilter {
+            due_before: Some(now() + 1800),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        list.iter().map(|m| m.queue_id).collect::<Vec<_>>(),
+        vec![20]
+    );
+
+    // Filter by age
+    assert!(
+        core.list(&QueueFilter {
+            created_before: Some(now() - 3600),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .is_empty()
+    );
+    local.queue_receiver.clear_queue(&core).await;
+}
+
+#[tokio::test]
+async fn queue_cancel() {
+    // Enable logging
+    crate::enable_logging();
+
+    let mut local = TestSMTP::new("smtp_queue_cancel_test", CONFIG).await;
+    let core = local.build_smtp();
+
+    for (queue_id, sender) in [
+        (40, "spammer@example.org"),
+        (41, "jane@example.org"),
+        (42, "spammer@example.org"),
+    ] {
+        let mut message = new_message(queue_id);
+        message.message.return_path = sender.into();
+        message.message.return_path_lcase = sender.into();
+        message
+            .message
+            .recipients
+            .push(build_rcpt("a@foobar.org", 3600, 7200, 86400));
+        message.save_changes(&core, 0.into()).await;
+    }
+
+    // Cancel all messages from the sender
+    let filter = QueueFilter {
+        sender: Some("spammer@example.org".into()),
+        ..Default::default()
+    };
+    assert_eq!(core.cancel(&filter).await.unwrap(), 2);
+    local.queue_receiver.read_event().await.assert_refresh();
+    assert!(core.list(&filter).await.unwrap().is_empty());
+    assert!(core.read_message(40, QueueName::default()).await.is_none());
+    assert!(core.read_message(42, QueueName::default()).await.is_none());
+
+    // No DSNs are generated and other messages are left untouched
+    assert_eq!(
+        core.list(&QueueFilter::default())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|m| m.queue_id)
+            .collect::<Vec<_>>(),
+        vec![41]
+    );
+
+    // Cancel the remaining messages
+    assert_eq!(core.cancel(&QueueFilter::default()).await.unwrap(), 1);
+    local.queue_receiver.read_event().await.assert_refresh();
+    assert_eq!(core.cancel(&QueueFilter::default()).await.unwrap(), 0);
+    local.queue_receiver.assert_no_events();
+    local.queue_receiver.assert_queue_is_empty().await;
+}
+
+const CONFIG_QUARANTINE: &str = r#"
+[session.ehlo]
+reject-non-fqdn = false
+
+[session.rcpt]
+relay = true
+
+[session.data]
+script = "'quarantine'"
+
+[spam-filter]
+enable = false
+
+[sieve.trusted.scripts."quarantine"]
+contents = '''
+require "fileinto";
+
+if header :contains "Subject" "suspicious" {
+    fileinto "Quarantine";
+}
+'''
+"#;
+
+#[tokio::test]
+async fn queue_quarantine() {
+    // Enable logging
+    crate::enable_logging();
+
+    let mut local = TestSMTP::new("smtp_queue_quarantine_test", CONFIG_QUARANTINE).await;
+    let core = local.build_smtp();
+    let mut session = local.new_session();
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+
+    // Messages flagged by the script are held in quarantine
+    for subject in ["A suspicious offer", "Another suspicious offer"] {
+        session
+            .send_message(
+                "john@test.org",
+                &["bill@foobar.org"],
+                &format!("From: john@test.org\r\nSubject: {subject}\r\n\r\nHello\r\n"),
+                "250",
+            )
+            .await;
+        let message = local.queue_receiver.expect_message().await;
+        assert!(message.is_quarantined());
+        assert!(message.message.recipients.iter().all(|rcpt| rcpt.is_held()));
+    }
+    let quarantine = core
+        .list(&QueueFilter {
+            quarantined: true,
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|m| m.queue_id)
+        .collect::<Vec<_>>();
+    assert_eq!(quarantine.len(), 2);
+    assert!(core.list(&QueueFilter::default()).await.unwrap().is_empty());
+    assert!(core.all_queued_messages().await.messages.is_empty());
+    assert!(local.queue_receiver.read_queued_events().await.is_empty());
+
+    // Released messages re-enter the queue and are scheduled for delivery
+    assert!(core.quarantine_release(quarantine[0]).await.unwrap());
+    local.queue_receiver.read_event().await.assert_refresh();
+    assert!(!core.quarantine_release(quarantine[0]).await.unwrap());
+    let message = core
+        .read_message(quarantine[0], QueueName::default())
+        .await
+        .unwrap();
+    assert!(!message.is_quarantined());
+    assert!(
+        message
+            .message
+            .recipients
+            .iter()
+            .all(|rcpt| !rcpt.is_held())
+    );
+    assert_eq!(
+        core.all_queued_messages()
+            .await
+            .messages
+            .into_iter()
+            .map(|m| m.queue_id)
+            .collect::<Vec<_>>(),
+        vec![quarantine[0]]
+    );
+    assert!(!core.quarantine_delete(quarantine[0]).await.unwrap());
+
+    // Deleted messages are removed without being delivered
+    assert!(core.quarantine_delete(quarantine[1]).await.unwrap());
+    assert!(
+        core.read_message(quarantine[1], QueueName::default())
+            .await
+            .is_none()
+    );
+    assert!(core.quarantine_release(quarantine[1]).await.is_err());
+
+    // Other messages are queued as usual
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    assert!(!local.queue_receiver.expect_message().await.is_quarantined());
+    local.queue_receiver.clear_queue(&core).await;
+}
+
+#[tokio::test]
+async fn queue_metrics() {
+    // Enable logging
+    crate::enable_logging();
+
+    let local = TestSMTP::new("smtp_queue_metrics_test", CONFIG).await;
+    let core = local.build_smtp();
+
+    // An empty queue reports no activity
+    assert_eq!(core.metrics().await.unwrap(), Default::default());
+
+    for (queue_id, age, rcpts, retry) in [
+        (40, 0, &["a@foobar.org", "b@foobar.org"][..], 600),
+        (41, 7200, &["c@foobar.net"][..], 60),
+        (
+            42,
+            3600,
+            &["d@foobar.net", "e@foobar.org", "f@example.org"][..],
+            1800,
+        ),
+    ] {
+        let mut message = new_message(queue_id);
+        message.message.created = now() - age;
+        for rcpt in rcpts {
+            message
+                .message
+                .recipients
+                .push(build_rcpt(rcpt, retry, retry + 3600, 86400));
+        }
+        if queue_id == 42 {
+            message.message.rcpt_mut("f@example.org").status =
+                Status::PermanentFailure(ErrorDetails {
+                    entity: "localhost".into(),
+                    details: Error::ConcurrencyLimited,
+                });
+        }
+        message.save_changes(&core, 0.into()).await;
+    }
+
+    // Only pending recipients are counted
+    let metrics = core.metrics().await.unwrap();
+    assert_eq!(metrics.messages, 3);
+    assert_eq!(metrics.recipients, 5);
+    assert_eq!(
+        metrics.domains,
+        AHashMap::from_iter([("foobar.org".to_string(), 3), ("foobar.net".to_string(), 2)])
+    );
+    assert!((7200..7210).contains(&metrics.oldest_age), "{metrics:?}");
+    let next_wakeup = metrics.next_wakeup.unwrap();
+    assert!(
+        (now() + 50..=now() + 60).contains(&next_wakeup),
+        "{metrics:?}"
+    );
+
+    local.queue_receiver.clear_queue(&core).await;
+}
+
 pub fn new_message(queue_id: u64) -> MessageWrapper {
     MessageWrapper {
         queue_id,
@@ -181,6 +767,8 @@ pub fn new_message(queue_id: u64) -> MessageWrapper {
             blob_hash: Default::default(),
             received_from_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
             received_via_port: 0,
+            headers: vec![],
+            submission: None,
         },
     }
 }
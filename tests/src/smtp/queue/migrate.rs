@@ -0,0 +1,292 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs LLC <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use common::config::smtp::queue::{QueueExpiry, QueueName};
+use migration::queue::{
+    LegacyDomain, LegacyError, LegacyErrorDetails, LegacyRecipient, MessageV012,
+    migrate_queue_v012, migrate_queue_v013,
+};
+use smtp::queue::{
+    Error, ErrorDetails, HostResponse, Schedule, Status, UnexpectedResponse, spool::SmtpSpool,
+};
+use std::net::{IpAddr, Ipv4Addr};
+use store::{
+    Serialize,
+    write::{Archiver, BatchBuilder, QueueClass, ValueClass, now},
+};
+use utils::BlobHash;
+
+use crate::smtp::TestSMTP;
+
+#[tokio::test]
+async fn migrate_legacy_message() {
+    // Enable logging
+    crate::enable_logging();
+
+    let test = TestSMTP::new("smtp_queue_migrate", "").await;
+    let server = test.server;
+
+    // Write a message using the v0.12 layout, where retry and expiration
+    // settings were kept per domain rather than per recipient
+    let queue_id = 1234;
+    let created = now();
+    let legacy = MessageV012 {
+        queue_id,
+        created,
+        blob_hash: BlobHash::generate(b"Subject: test\r\n\r\ntest"),
+        return_path: "sender@foobar.org".into(),
+        return_path_lcase: "sender@foobar.org".into(),
+        return_path_domain: "foobar.org".into(),
+        recipients: vec![
+            LegacyRecipient {
+                domain_idx: 0,
+                address: "john@example.org".into(),
+                address_lcase: "john@example.org".into(),
+                status: Status::Scheduled,
+                flags: 0,
+                orcpt: None,
+            },
+            LegacyRecipient {
+                domain_idx: 1,
+                address: "Jane@Example.net".into(),
+                address_lcase: "jane@example.net".into(),
+                status: Status::Scheduled,
+                flags: 0,
+                orcpt: Some("rfc822;jane@example.net".into()),
+            },
+        ],
+        domains: vec![
+            LegacyDomain {
+                domain: "example.org".into(),
+                retry: Schedule {
+                    due: created + 60,
+                    inner: 1,
+                },
+                notify: Schedule {
+                    due: created + 3600,
+                    inner: 0,
+                },
+                expires: created + 86400,
+                status: Status::TemporaryFailure(LegacyError::RateLimited),
+            },
+            LegacyDomain {
+                domain: "example.net".into(),
+                retry: Schedule {
+                    due: created + 120,
+                    inner: 2,
+                },
+                notify: Schedule {
+                    due: created + 7200,
+                    inner: 1,
+                },
+                expires: created + 86400,
+                status: Status::PermanentFailure(LegacyError::UnexpectedResponse(HostResponse {
+                    hostname: LegacyErrorDetails {
+                        entity: "mx.example.net".into(),
+                        details: "RCPT TO:<jane@example.net>".into(),
+                    },
+                    response: smtp_proto::Response {
+                        code: 550,
+                        esc: [5, 1, 1],
+                        message: "User unknown".into(),
+                    },
+                })),
+            },
+        ],
+        flags: 0,
+        env_id: Some("envelope1".into()),
+        priority: 0,
+        size: 1024,
+        quota_keys: vec![],
+        span_id: 0,
+    };
+    let mut batch = BatchBuilder::new();
+    batch.set(
+        ValueClass::Queue(QueueClass::Message(queue_id)),
+        Archiver::new(legacy).serialize().unwrap(),
+    );
+    server.store().write(batch.build_all()).await.unwrap();
+
+    migrate_queue_v012(&server).await.unwrap();
+
+    // Domain settings are now stored on each recipient
+    let message = server
+        .read_message(queue_id, QueueName::default())
+        .await
+        .expect("Migrated message not found")
+        .message;
+    assert_eq!(message.created, created);
+    assert_eq!(message.return_path, "sender@foobar.org");
+    assert_eq!(message.env_id.as_deref(), Some("envelope1"));
+    assert_eq!(message.size, 1024);
+    assert_eq!(message.recipients.len(), 2);
+
+    let john = &message.recipients[0];
+    assert_eq!(john.address_lcase, "john@example.org");
+    assert_eq!(john.queue, QueueName::default());
+    assert_eq!(john.retry.due, created + 60);
+    assert_eq!(john.retry.inner, 1);
+    assert_eq!(john.notify.due, created + 3600);
+    assert!(
+        matches!(john.expires, QueueExpiry::Duration(secs) if secs > 0 && secs <= 86400),
+        "{:?}",
+        john.expires
+    );
+    assert_eq!(
+        john.status,
+        Status::TemporaryFailure(ErrorDetails {
+            entity: "example.org".into(),
+            details: Error::RateLimited,
+        })
+    );
+
+    let jane = &message.recipients[1];
+    assert_eq!(jane.address, "Jane@Example.net");
+    assert_eq!(jane.orcpt.as_deref(), Some("rfc822;jane@example.net"));
+    assert_eq!(jane.retry.due, created + 120);
+    assert_eq!(
+        jane.status,
+        Status::PermanentFailure(ErrorDetails {
+            entity: "mx.example.net".into(),
+            details: Error::UnexpectedResponse(UnexpectedResponse {
+                command: "RCPT TO:<jane@example.net>".into(),
+                response: smtp_proto::Response {
+                    code: 550,
+                    esc: [5, 1, 1],
+                    message: "User unknown".into(),
+                },
+            }),
+        })
+    );
+}
+
+// A message written by v0.13, before headers, submission ids, next hops, TLS
+// details and transcripts were stored, with recipients that failed temporarily
+// and permanently
+const MESSAGE_V013: &[u8] = &[
+    0x73, 0x65, 0x6e, 0x64, 0x65, 0x72, 0x40, 0x66, 0x6f, 0x6f, 0x62, 0x61, 0x72, 0x2e, 0x6f, 0x72,
+    0x67, 0x73, 0x65, 0x6e, 0x64, 0x65, 0x72, 0x40, 0x66, 0x6f, 0x6f, 0x62, 0x61, 0x72, 0x2e, 0x6f,
+    0x72, 0x67, 0x66, 0x6f, 0x6f, 0x62, 0x61, 0x72, 0x2e, 0x6f, 0x72, 0x67, 0x4a, 0x6f, 0x68, 0x6e,
+    0x40, 0x45, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e, 0x6f, 0x72, 0x67, 0x6a, 0x6f, 0x68, 0x6e,
+    0x40, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e, 0x6f, 0x72, 0x67, 0x6d, 0x78, 0x2e, 0x65,
+    0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e, 0x6f, 0x72, 0x67, 0x43, 0x6f, 0x6e, 0x6e, 0x65, 0x63,
+    0x74, 0x69, 0x6f, 0x6e, 0x20, 0x72, 0x65, 0x66, 0x75, 0x73, 0x65, 0x64, 0x72, 0x66, 0x63, 0x38,
+    0x32, 0x32, 0x3b, 0x6a, 0x6f, 0x68, 0x6e, 0x40, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e,
+    0x6f, 0x72, 0x67, 0x6a, 0x61, 0x6e, 0x65, 0x40, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e,
+    0x6e, 0x65, 0x74, 0x6a, 0x61, 0x6e, 0x65, 0x40, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e,
+    0x6e, 0x65, 0x74, 0x6d, 0x78, 0x2e, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e, 0x6e, 0x65,
+    0x74, 0x52, 0x43, 0x50, 0x54, 0x20, 0x54, 0x4f, 0x3a, 0x3c, 0x6a, 0x61, 0x6e, 0x65, 0x40, 0x65,
+    0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e, 0x6e, 0x65, 0x74, 0x3e, 0x55, 0x73, 0x65, 0x72, 0x20,
+    0x75, 0x6e, 0x6b, 0x6e, 0x6f, 0x77, 0x6e, 0x00, 0x90, 0x00, 0x00, 0x00, 0x54, 0xff, 0xff, 0xff,
+    0x90, 0x00, 0x00, 0x00, 0x5c, 0xff, 0xff, 0xff, 0x3c, 0xf1, 0x53, 0x65, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0xff, 0x53, 0x65, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x64, 0x65, 0x66, 0x61, 0x75, 0x6c, 0x74, 0x00,
+    0x02, 0x00, 0x00, 0x00, 0x8e, 0x00, 0x00, 0x00, 0x28, 0xff, 0xff, 0xff, 0x02, 0x00, 0x00, 0x00,
+    0x92, 0x00, 0x00, 0x00, 0x2a, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x00, 0x00, 0x97, 0x00, 0x00, 0x00, 0x18, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00,
+    0x90, 0x00, 0x00, 0x00, 0x23, 0xff, 0xff, 0xff, 0x90, 0x00, 0x00, 0x00, 0x2b, 0xff, 0xff, 0xff,
+    0x78, 0xf1, 0x53, 0x65, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x20, 0x0d, 0x54, 0x65, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x51, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x64, 0x65, 0x66, 0x61, 0x75, 0x6c, 0x74, 0x00, 0x03, 0x00, 0x00, 0x00, 0x8e, 0x00, 0x00, 0x00,
+    0xf7, 0xfe, 0xff, 0xff, 0x01, 0x00, 0x00, 0x00, 0x9a, 0x00, 0x00, 0x00, 0xf9, 0xfe, 0xff, 0xff,
+    0x26, 0x02, 0x05, 0x01, 0x01, 0x00, 0x00, 0x00, 0x8c, 0x00, 0x00, 0x00, 0x03, 0xff, 0xff, 0xff,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x65, 0x6e, 0x76, 0x65, 0x6c, 0x6f, 0x70, 0x65,
+    0x32, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xf1, 0x53, 0x65, 0x00, 0x00, 0x00, 0x00,
+    0x46, 0xe7, 0xf9, 0x44, 0x31, 0x62, 0xab, 0xf5, 0xf4, 0xfa, 0xc1, 0x05, 0x2e, 0x48, 0x5f, 0xfc,
+    0xf0, 0x7a, 0xd2, 0xa2, 0x47, 0xd6, 0xf8, 0x6b, 0x48, 0xd4, 0xc5, 0x33, 0x88, 0xb7, 0xdc, 0x84,
+    0x00, 0x0a, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x19, 0x00, 0x91, 0x00, 0x00, 0x00, 0xcc, 0xfd, 0xff, 0xff, 0x91, 0x00, 0x00, 0x00,
+    0xd5, 0xfd, 0xff, 0xff, 0x8a, 0x00, 0x00, 0x00, 0xde, 0xfd, 0xff, 0xff, 0x8c, 0xfe, 0xff, 0xff,
+    0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x00, 0x00, 0x89, 0x00, 0x00, 0x00, 0x84, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0x00,
+    0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00,
+    0x99, 0x78, 0x0c, 0x2c, 0xa0,
+];
+
+#[tokio::test]
+async fn migrate_v013_message() {
+    // Enable logging
+    crate::enable_logging();
+
+    let test = TestSMTP::new("smtp_queue_migrate_v013", "").await;
+    let server = test.server;
+
+    let queue_id = 5678;
+    let mut batch = BatchBuilder::new();
+    batch.set(
+        ValueClass::Queue(QueueClass::Message(queue_id)),
+        MESSAGE_V013.to_vec(),
+    );
+    server.store().write(batch.build_all()).await.unwrap();
+
+    migrate_queue_v013(&server).await.unwrap();
+
+    let created = 1_700_000_000;
+    let message = server
+        .read_message(queue_id, QueueName::default())
+        .await
+        .expect("Migrated message not found")
+        .message;
+    assert_eq!(message.created, created);
+    assert_eq!(
+        message.blob_hash,
+        BlobHash::generate(b"Subject: test\r\n\r\ntest")
+    );
+    assert_eq!(
+        message.received_from_ip,
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))
+    );
+    assert_eq!(message.received_via_port, 25);
+    assert_eq!(message.return_path, "sender@foobar.org");
+    assert_eq!(message.env_id.as_deref(), Some("envelope2"));
+    assert_eq!(message.priority, -1);
+    assert_eq!(message.size, 2048);
+    assert!(message.headers.is_empty());
+    assert!(message.submission.is_none());
+    assert_eq!(message.recipients.len(), 2);
+
+    let john = &message.recipients[0];
+    assert_eq!(john.address, "John@Example.org");
+    assert_eq!(john.orcpt.as_deref(), Some("rfc822;john@example.org"));
+    assert_eq!(john.retry.due, created + 60);
+    assert_eq!(john.retry.inner, 1);
+    assert_eq!(john.notify.due, created + 3600);
+    assert_eq!(john.expires, QueueExpiry::Count(5));
+    assert_eq!(
+        john.status,
+        Status::TemporaryFailure(ErrorDetails {
+            entity: "mx.example.org".into(),
+            details: Error::ConnectionError("Connection refused".into()),
+        })
+    );
+    assert!(john.next_hop.is_none());
+    assert!(john.tls.is_none());
+    assert!(john.transcript.is_none());
+
+    let jane = &message.recipients[1];
+    assert_eq!(jane.address_lcase, "jane@example.net");
+    assert_eq!(jane.retry.due, created + 120);
+    assert_eq!(jane.retry.inner, 2);
+    assert_eq!(jane.expires, QueueExpiry::Duration(86400));
+    assert_eq!(
+        jane.status,
+        Status::PermanentFailure(ErrorDetails {
+            entity: "mx.example.net".into(),
+            details: Error::UnexpectedResponse(UnexpectedResponse {
+                command: "RCPT TO:<jane@example.net>".into(),
+                response: smtp_proto::Response {
+                    code: 550,
+                    esc: [5, 1, 1],
+                    message: "User unknown".into(),
+                },
+            }),
+        })
+    );
+}
@@ -18,7 +18,9 @@ use tokio::sync::mpsc;
 pub mod concurrent;
 pub mod dsn;
 pub mod manager;
+pub mod migrate;
 pub mod retry;
+pub mod spool;
 pub mod virtualq;
 
 pub fn build_rcpt(address: &str, retry: u64, notify: u64, expires: u64) -> Recipient {
@@ -31,7 +33,10 @@ pub fn build_rcpt(address: &str, retry: u64, notify: u64, expires: u64) -> Recip
         status: Status::Scheduled,
         flags: 0,
         orcpt: None,
+        tls: None,
+        transcript: None,
         queue: QueueName::default(),
+        next_hop: None,
     }
 }
 
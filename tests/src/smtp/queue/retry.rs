@@ -17,7 +17,14 @@ use common::{
     config::smtp::queue::QueueName,
     ipc::{QueueEvent, QueueEventStatus},
 };
-use smtp::queue::spool::{QUEUE_REFRESH, SmtpSpool};
+use smtp::{
+    outbound::delivery::RETRY_JITTER_SEED,
+    queue::{
+        Error, ErrorDetails, Status,
+        spool::{QUEUE_REFRESH, SmtpSpool},
+    },
+};
+use std::sync::atomic::Ordering;
 use store::write::now;
 
 const CONFIG: &str = r#"
@@ -49,6 +56,82 @@ schedule = [{if = "sender_domain == 'test.org'", then = "'sender-test'"},
            {else = "'sender-default'"}]
 "#;
 
+const MAX_ATTEMPTS_CONFIG: &str = r#"
+[session.ehlo]
+reject-non-fqdn = false
+
+[session.rcpt]
+relay = true
+
+[queue.schedule.rcpt-limited]
+retry = "1s"
+notify = "1d"
+max-attempts = 4
+queue-name = "default"
+
+[queue.strategy]
+schedule = [{if = "rcpt_domain == '_dns_error.org'", then = "'rcpt-limited'"},
+           {else = "'default'"}]
+"#;
+
+const HEADER_CONFIG: &str = r#"
+[session.ehlo]
+reject-non-fqdn = false
+
+[session.rcpt]
+relay = true
+
+[queue.schedule.urgent]
+retry = "1m"
+notify = "1d"
+expire = "1d"
+queue-name = "default"
+
+[queue.schedule.bulk]
+retry = "1h"
+notify = "1d"
+expire = "1d"
+queue-name = "default"
+
+[queue.strategy]
+schedule = [{if = "header.x-priority == 'urgent'", then = "'urgent'"},
+           {else = "'bulk'"}]
+"#;
+
+const WINDOW_CONFIG: &str = r#"
+[session.ehlo]
+reject-non-fqdn = false
+
+[session.rcpt]
+relay = true
+
+[queue.schedule.business-hours]
+retry = "1m"
+notify = "1d"
+expire = "1d"
+queue-name = "default"
+window = "{WINDOW}"
+
+[queue.strategy]
+schedule = [{if = "rcpt_domain == 'partner.org'", then = "'business-hours'"},
+           {else = "'default'"}]
+"#;
+
+const JITTER_CONFIG: &str = r#"
+[session.ehlo]
+reject-non-fqdn = false
+
+[session.rcpt]
+relay = true
+
+[queue.schedule.default]
+retry = "10m"
+retry-jitter = "50%"
+notify = "1d"
+expire = "1d"
+queue-name = "default"
+"#;
+
 #[tokio::test]
 async fn queue_retry() {
     // Enable logging
@@ -116,7 +199,10 @@ async fn queue_retry() {
                 }
             }
             Some(QueueEvent::Refresh) | Some(QueueEvent::ReloadSettings) => (),
-            None | Some(QueueEvent::Stop) | Some(QueueEvent::Paused(_)) => break,
+            None
+            | Some(QueueEvent::Stop)
+            | Some(QueueEvent::Paused(_))
+            | Some(QueueEvent::Drain) => break,
         }
 
         let now = now();
@@ -232,3 +318,344 @@ async fn queue_retry() {
         [3599, 3600].contains(&(schedule.message.recipients.first().unwrap().notify.due - now()))
     );
 }
+
+#[tokio::test]
+async fn queue_max_attempts() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Create temp dir for queue
+    let mut local = TestSMTP::new("smtp_queue_max_attempts_test", MAX_ATTEMPTS_CONFIG).await;
+
+    // Create test message
+    let core = local.build_smtp();
+    let mut session = local.new_session();
+    let qr = &mut local.queue_receiver;
+
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message(
+            "john@test.org",
+            &["jane@_dns_error.org"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    let mut attempt = qr.expect_message_then_deliver().await;
+
+    // Failed attempts are retried until the limit is reached
+    for num in 1..=4 {
+        attempt.try_deliver(core.clone());
+        let mut message = qr.expect_message().await;
+        let queue_id = message.queue_id;
+        let rcpt = message.message.recipients.first().unwrap();
+        assert_eq!(rcpt.retry.inner, num);
+        assert!(matches!(rcpt.status, Status::TemporaryFailure(_)));
+        let prev_due = rcpt.retry.due;
+        message.message.recipients[0].retry.due = now();
+        message.save_changes(&core, prev_due.into()).await;
+        attempt = qr.delivery_attempt(queue_id).await;
+    }
+
+    // The next attempt bounces the recipient
+    attempt.try_deliver(core.clone());
+    let message = qr.expect_message().await;
+    assert_eq!(message.message.return_path, "");
+    message
+        .read_lines(qr)
+        .await
+        .assert_contains("<jane@_dns_error.org> (failed to lookup '_dns_error.org'")
+        .assert_contains("Final-Recipient: rfc822;jane@_dns_error.org")
+        .assert_contains("Action: failed");
+    qr.read_event().await.assert_done();
+}
+
+#[tokio::test]
+async fn queue_retry_header() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Create temp dir for queue
+    let mut local = TestSMTP::new("smtp_queue_retry_header_test", HEADER_CONFIG).await;
+
+    // Only the headers referenced by queue expressions are kept
+    let core = local.build_smtp();
+    assert_eq!(core.core.smtp.queue.headers, vec!["x-priority".to_string()]);
+
+    let mut session = local.new_session();
+    let qr = &mut local.queue_receiver;
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+
+    // The retry interval is selected by the X-Priority header
+    for (priority, retry) in [("urgent", 60), ("low", 3600)] {
+        session
+            .send_message(
+                "john@test.org",
+                &["jane@_dns_error.org"],
+                &format!(
+                    "From: john@test.org\r\nTo: jane@_dns_error.org\r\nX-Priority:  {priority} \r\nSubject: Retry\r\n\r\nTest.\r\n"
+                ),
+                "250",
+            )
+            .await;
+        let message = qr.expect_message().await;
+        assert_eq!(
+            message.message.headers,
+            vec![("x-priority".to_string(), priority.to_string())]
+        );
+
+        qr.delivery_attempt(message.queue_id)
+            .await
+            .try_deliver(core.clone());
+        let message = qr.expect_message().await;
+        let rcpt = message.message.recipients.first().unwrap();
+        assert!(matches!(rcpt.status, Status::TemporaryFailure(_)));
+        assert!(
+            [retry - 1, retry].contains(&(rcpt.retry.due - now())),
+            "{priority}: {}",
+            rcpt.retry.due - now()
+        );
+    }
+    qr.clear_queue(&core).await;
+}
+
+#[tokio::test]
+async fn queue_delivery_window() {
+    // Enable logging
+    crate::enable_logging();
+
+    // The window opens two hours from now and stays open for one hour
+    let now = now();
+    let start = (now % 86400 / 60 + 120) % 1440;
+    let end = (start + 60) % 1440;
+    let window = format!(
+        "{:02}:{:02}-{:02}:{:02}",
+        start / 60,
+        start % 60,
+        end / 60,
+        end % 60
+    );
+    let mut window_start = now - now % 86400 + start * 60;
+    if window_start < now {
+        window_start += 86400;
+    }
+
+    let mut local = TestSMTP::new(
+        "smtp_queue_window_test",
+        WINDOW_CONFIG.replace("{WINDOW}", &window),
+    )
+    .await;
+    let core = local.build_smtp();
+    let mut session = local.new_session();
+    let qr = &mut local.queue_receiver;
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+
+    // Messages received outside the window are deferred until it opens
+    session
+        .send_message(
+            "john@test.org",
+            &["jane@partner.org", "bill@foobar.org"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    let mut message = qr.expect_message().await;
+    let (idx, other_idx) = if message.message.recipients[0].address_lcase == "jane@partner.org" {
+        (0, 1)
+    } else {
+        (1, 0)
+    };
+    let rcpt = &message.message.recipients[idx];
+    assert_eq!(rcpt.address_lcase, "jane@partner.org");
+    assert_eq!(rcpt.retry.due, window_start, "{window}");
+    assert_eq!(rcpt.retry.inner, 0);
+    assert!(message.message.recipients[other_idx].retry.due <= now + 1);
+
+    // Retries are deferred until the window opens
+    let failure = Status::TemporaryFailure(ErrorDetails {
+        entity: "mx.partner.org".into(),
+        details: Error::ConcurrencyLimited,
+    });
+    message.set_rcpt_status(failure.clone(), idx, &core).await;
+    let rcpt = &message.message.recipients[idx];
+    assert_eq!(rcpt.retry.due, window_start, "{window}");
+    assert_eq!(rcpt.retry.inner, 1);
+
+    // Deferred retries never go past the message expiration
+    message.message.created = now - 86400 + 1800;
+    message.set_rcpt_status(failure, idx, &core).await;
+    assert_eq!(message.message.recipients[idx].retry.due, now + 1800);
+    qr.clear_queue(&core).await;
+}
+
+#[tokio::test]
+async fn queue_future_release() {
+    // Enable logging
+    crate::enable_logging();
+
+    let mut local = TestSMTP::new("smtp_queue_future_release_test", CONFIG).await;
+    let core = local.build_smtp();
+    let mut session = local.new_session();
+    let qr = &mut local.queue_receiver;
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session
+        .ehlo("mx.test.org")
+        .await
+        .assert_contains("FUTURERELEASE 3600");
+
+    // Messages are not scheduled for delivery before the release time
+    let hold_until = now() + 1800;
+    session
+        .send_message(
+            &format!("<john@test.org> HOLDUNTIL={hold_until}"),
+            &["bill@foobar.org"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    let message = qr.expect_message().await;
+    let due = qr.message_due(message.queue_id).await;
+    assert!((hold_until - 1..=hold_until).contains(&due), "{due}");
+    assert_eq!(message.message.next_delivery_event(None), Some(due));
+    assert!(core.all_queued_messages().await.messages.is_empty());
+    qr.assert_no_events();
+    qr.clear_queue(&core).await;
+}
+
+#[tokio::test]
+async fn queue_deliver_by() {
+    // Enable logging
+    crate::enable_logging();
+
+    let mut local = TestSMTP::new("smtp_queue_deliver_by_test", CONFIG).await;
+    let core = local.build_smtp();
+    let mut session = local.new_session();
+    let qr = &mut local.queue_receiver;
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session
+        .ehlo("mx.test.org")
+        .await
+        .assert_contains("DELIVERBY 3600");
+
+    // Deferred messages are bounced once the deadline passes
+    let start = now();
+    session
+        .send_message(
+            "<bill@foobar.org> BY=2;R",
+            &["jane@_dns_error.org"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    let attempt = qr.expect_message_then_deliver().await;
+    let mut in_fight = AHashSet::from_iter([attempt.queue_id]);
+    let mut dsn = Vec::new();
+    attempt.try_deliver(core.clone());
+
+    loop {
+        match qr.try_read_event().await {
+            Some(QueueEvent::WorkerDone { queue_id, .. }) => {
+                in_fight.remove(&queue_id);
+            }
+            Some(QueueEvent::Refresh) | Some(QueueEvent::ReloadSettings) => (),
+            _ => break,
+        }
+
+        let now = now();
+        let mut events = core.all_queued_messages().await;
+        if events.messages.is_empty() {
+            if events.next_refresh < now + QUEUE_REFRESH {
+                tokio::time::sleep(Duration::from_secs(events.next_refresh - now)).await;
+                events = core.all_queued_messages().await;
+            } else if in_fight.is_empty() {
+                break;
+            }
+        }
+
+        for event in events.messages {
+            if in_fight.contains(&event.queue_id) {
+                continue;
+            }
+
+            let message = core
+                .read_message(event.queue_id, QueueName::default())
+                .await
+                .unwrap();
+            if message.message.return_path.is_empty() {
+                message.clone().remove(&core, event.due.into()).await;
+                dsn.push(message);
+            } else {
+                in_fight.insert(event.queue_id);
+                event.try_deliver(core.clone());
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+    qr.assert_queue_is_empty().await;
+    let elapsed = now() - start;
+    assert!((2..6).contains(&elapsed), "{elapsed}");
+    assert_eq!(dsn.len(), 1);
+    dsn.pop()
+        .unwrap()
+        .read_lines(qr)
+        .await
+        .assert_contains("Final-Recipient: rfc822;jane@_dns_error.org")
+        .assert_contains("Action: failed");
+}
+
+#[tokio::test]
+async fn queue_retry_jitter() {
+    // Enable logging
+    crate::enable_logging();
+
+    let mut local = TestSMTP::new("smtp_queue_jitter_test", JITTER_CONFIG).await;
+    let core = local.build_smtp();
+    let mut session = local.new_session();
+    let qr = &mut local.queue_receiver;
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    let mut message = qr.expect_message().await;
+    let failure = Status::TemporaryFailure(ErrorDetails {
+        entity: "mx.foobar.org".into(),
+        details: Error::ConcurrencyLimited,
+    });
+
+    // Retries are delayed by up to half of the interval
+    for _ in 0..10 {
+        let start = now();
+        message.set_rcpt_status(failure.clone(), 0, &core).await;
+        let due = message.message.recipients[0].retry.due;
+        assert!(
+            (start + 600..=now() + 900).contains(&due),
+            "{due} not within [{}, {}]",
+            start + 600,
+            start + 900
+        );
+    }
+
+    // Seeded jitter is deterministic
+    RETRY_JITTER_SEED.store(1234, Ordering::Relaxed);
+    let mut jitter = Vec::new();
+    for _ in 0..2 {
+        let start = now();
+        message.set_rcpt_status(failure.clone(), 0, &core).await;
+        let due = message.message.recipients[0].retry.due;
+        assert!((start + 600..=now() + 900).contains(&due), "{due}");
+        jitter.push(due - start);
+    }
+    RETRY_JITTER_SEED.store(0, Ordering::Relaxed);
+    assert!(jitter[0].abs_diff(jitter[1]) <= 1, "{jitter:?}");
+    qr.clear_queue(&core).await;
+}
@@ -0,0 +1,192 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs LLC <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::path::Path;
+
+use common::{Core, config::smtp::queue::QueueName};
+use smtp::{
+    core::Session,
+    queue::{MessageSource, spool::SmtpSpool},
+};
+use store::Stores;
+use utils::config::Config;
+
+use crate::{
+    AssertConfig,
+    smtp::{TempDir, TestSMTP, inbound::TestMessage, session::TestSession},
+};
+
+const CONFIG: &str = r#"
+[storage]
+data = "rocksdb"
+lookup = "rocksdb"
+blob = "fs"
+fts = "rocksdb"
+directory = "local"
+
+[store."rocksdb"]
+type = "rocksdb"
+path = "{TMP}/queue.db"
+
+[store."fs"]
+type = "fs"
+path = "{TMP}/blobs"
+
+[directory."local"]
+type = "memory"
+
+[spam-filter]
+enable = false
+
+[session.rcpt]
+relay = true
+"#;
+
+#[tokio::test]
+async fn spool_blob_store() {
+    // Enable logging
+    crate::enable_logging();
+
+    // Keep message bodies on the filesystem and metadata in RocksDB
+    let (tmp_dir, test) = build_spool_test("smtp_spool_blob_test").await;
+    let mut qr = test.queue_receiver;
+
+    // Queue a message
+    let mut session = Session::test(test.server.clone());
+    session.data.remote_ip_str = "10.0.0.1".into();
+    session.eval_session_params().await;
+    session.ehlo("mx.doe.org").await;
+    session
+        .send_message("john@doe.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    let message = qr.expect_message().await;
+
+    // The body is written to the blob store
+    let blob = test
+        .server
+        .blob_store()
+        .get_blob(message.message.blob_hash.as_slice(), 0..usize::MAX)
+        .await
+        .unwrap()
+        .expect("Message body not found");
+    assert!(
+        String::from_utf8_lossy(&blob).contains("Subject: Is dinner ready?"),
+        "Unexpected message body"
+    );
+    assert!(
+        contains_file(&tmp_dir.temp_dir.join("blobs"), &blob),
+        "Message body was not written to the filesystem"
+    );
+
+    // Metadata round-trips through the data store
+    let read_message = test
+        .server
+        .read_message(message.queue_id, QueueName::default())
+        .await
+        .expect("Queued message not found");
+    assert_eq!(read_message.message, message.message);
+
+    // Removed messages are no longer readable
+    assert!(
+        message
+            .remove(&test.server, qr.last_queued_due().await.into())
+            .await
+    );
+    assert!(
+        test.server
+            .read_message(read_message.queue_id, QueueName::default())
+            .await
+            .is_none()
+    );
+}
+
+#[tokio::test]
+async fn spool_dedup() {
+    // Enable logging
+    crate::enable_logging();
+
+    let (tmp_dir, test) = build_spool_test("smtp_spool_dedup_test").await;
+    let server = test.server;
+    let mut qr = test.queue_receiver;
+    let blob_path = tmp_dir.temp_dir.join("blobs");
+
+    // Queue two messages with identical bodies
+    let body = b"Subject: Newsletter\r\n\r\nSame body for everyone.\r\n";
+    let mut messages = Vec::new();
+    for rcpt in ["jane@foobar.org", "bill@foobar.org"] {
+        let mut message = server.new_message("john@doe.org", "john@doe.org", "doe.org", 0);
+        message.add_recipient(rcpt, &server).await;
+        assert!(
+            message
+                .queue(None, body, 0, &server, MessageSource::Autogenerated)
+                .await
+        );
+        messages.push(qr.expect_message().await);
+    }
+    assert_ne!(messages[0].queue_id, messages[1].queue_id);
+    assert_eq!(messages[0].message.blob_hash, messages[1].message.blob_hash);
+    let blob_hash = messages[0].message.blob_hash.clone();
+    assert_eq!(count_files(&blob_path), 1);
+
+    // The body is kept while any queued message references it
+    let message = messages.remove(0);
+    let due = qr.message_due(message.queue_id).await;
+    assert!(message.remove(&server, due.into()).await);
+    server
+        .store()
+        .purge_blobs(server.blob_store().clone())
+        .await
+        .unwrap();
+    assert!(server.store().blob_exists(&blob_hash).await.unwrap());
+    assert_eq!(count_files(&blob_path), 1);
+    assert_eq!(
+        messages[0].read_message(&qr).await.as_bytes(),
+        body.as_slice()
+    );
+
+    // Removing the last reference deletes the body
+    let message = messages.remove(0);
+    let due = qr.message_due(message.queue_id).await;
+    assert!(message.remove(&server, due.into()).await);
+    server
+        .store()
+        .purge_blobs(server.blob_store().clone())
+        .await
+        .unwrap();
+    assert!(!server.store().blob_exists(&blob_hash).await.unwrap());
+    assert_eq!(count_files(&blob_path), 0);
+}
+
+async fn build_spool_test(name: &str) -> (TempDir, TestSMTP) {
+    let tmp_dir = TempDir::new(name, true);
+    let mut config = Config::new(tmp_dir.update_config(CONFIG)).unwrap();
+    let stores = Stores::parse_all(&mut config, false).await;
+    let core = Core::parse(&mut config, stores, Default::default()).await;
+    config.assert_no_errors();
+    (tmp_dir, TestSMTP::from_core(core))
+}
+
+fn count_files(path: &Path) -> usize {
+    std::fs::read_dir(path).map_or(0, |entries| {
+        entries
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                if path.is_dir() { count_files(&path) } else { 1 }
+            })
+            .sum()
+    })
+}
+
+fn contains_file(path: &Path, contents: &[u8]) -> bool {
+    std::fs::read_dir(path).unwrap().any(|entry| {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            contains_file(&path, contents)
+        } else {
+            std::fs::read(&path).unwrap() == contents
+        }
+    })
+}
@@ -216,6 +216,19 @@ async fn report_tls() {
         assert_eq!(report.organization_name.unwrap(), "Foobar, Inc.");
         assert_eq!(report.contact_info.unwrap(), "https://foobar.org/contact");
         assert_eq!(report.policies.len(), 1);
+
+        // Verify RFC 8460 field names
+        let json: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(json["organization-name"], "Foobar, Inc.");
+        assert_eq!(json["contact-info"], "https://foobar.org/contact");
+        assert!(json["date-range"]["start-datetime"].is_string(), "{json}");
+        assert!(json["date-range"]["end-datetime"].is_string(), "{json}");
+        assert!(json["report-id"].is_string(), "{json}");
+        let policy = &json["policies"][0];
+        assert_eq!(policy["policy"]["policy-type"], "no-policy-found");
+        assert_eq!(policy["policy"]["policy-domain"], "foobar.org");
+        assert_eq!(policy["summary"]["total-successful-session-count"], 2);
+        assert_eq!(policy["summary"]["total-failure-session-count"], 0);
     }
     qr.assert_report_is_empty().await;
 }
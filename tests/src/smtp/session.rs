@@ -259,6 +259,7 @@ impl TestSession for Session<DummyIo> {
                         dsn_info: None,
                     },
                 ],
+                vec![],
                 self.server.inner.data.queue_id_gen.generate(),
                 0,
             )
@@ -286,6 +287,7 @@ pub trait VerifyResponse {
     fn assert_contains(self, expected_text: &str) -> Self;
     fn assert_not_contains(self, expected_text: &str) -> Self;
     fn assert_count(self, text: &str, occurrences: usize) -> Self;
+    fn assert_order(self, expected_texts: &[&str]) -> Self;
 }
 
 impl VerifyResponse for Vec<String> {
@@ -328,6 +330,24 @@ impl VerifyResponse for Vec<String> {
         );
         self
     }
+
+    fn assert_order(self, expected_texts: &[&str]) -> Self {
+        let positions = expected_texts
+            .iter()
+            .map(|text| {
+                self.iter()
+                    .position(|line| line.contains(text))
+                    .unwrap_or_else(|| panic!("Expected {:?} but got {}.", text, self.join("\n")))
+            })
+            .collect::<Vec<_>>();
+        assert!(
+            positions.windows(2).all(|w| w[0] < w[1]),
+            "Expected {:?} in this order but got {}.",
+            expected_texts,
+            self.join("\n")
+        );
+        self
+    }
 }
 
 pub trait TestServerInstance {